@@ -4,30 +4,47 @@ extern crate log;
 extern crate env_logger;
 
 // Std lib imports
-use std::net::Ipv4Addr;
-use std::path::Path;
+use std::process;
 
 // Local imports
 use spacefort::*;
+use config::init_config;
 #[cfg(feature = "default")]
-use game::client::init_client;
+use game::client::{init_client, init_replay_viewer};
 #[cfg(feature = "term")]
 use game::term_client::init_client;
+use net::client::init_network;
 
 /// Graphical 2d tile client binary for SpaceFort
 fn main() {   
     env_logger::init().unwrap();
     info!("Initializing client");
 
-    // Root points to the directory containing
-    // static where assets are loaded from
-    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    // From the command line and config file, see config::USAGE
+    let config = init_config();
+    let root = config.root.as_path();
     debug!("Loading from root at {:?}", root);
 
-    // TODO Change this to be a command line parameter
-    let server_ip = Ipv4Addr::new(18, 248, 0, 121);
-
-    init_client(root, server_ip).start();
+    // Watch a recorded game, or join a live one
+    match config.replay {
+        #[cfg(feature = "default")]
+        Some(ref path) => init_replay_viewer(root, path).start(),
+        #[cfg(feature = "term")]
+        Some(_) => {
+            eprintln!("The terminal client can not watch replays, use the tile client for that");
+            process::exit(2);
+        },
+        _ => {
+            let comm = match init_network(config.server, config.port, config.max_msg_size) {
+                Ok(comm) => comm,
+                Err(err) => {
+                    eprintln!("Could not connect to {}:{}: {}", config.server, config.port, err);
+                    process::exit(1);
+                },
+            };
+            init_client(root, comm, &config.name, config.spectate).start()
+        },
+    }
 
     info!("Closing client");
 }
@@ -5,24 +5,29 @@ extern crate bincode;
 extern crate spacefort;
 
 // Std lib imports
-use std::net::Ipv4Addr;
-use std::path::Path;
+use std::process;
 
 // Local imports
 use spacefort::*;
+use config::init_config;
 use game::server::init_server;
+use net::server::init_network;
 
 
 fn main() {   
     env_logger::init().unwrap();
     info!("Initializing server");
 
-    // Root points to the directory containing
-    // static where assets are loaded from
-    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    // From the command line and config file, see config::USAGE
+    let config = init_config();
 
-    let server_ip = Ipv4Addr::new(0, 0, 0, 0);
-    init_server(root, server_ip).start();
+    let comm = init_network(config.bind, config.port, config.web_port, config.max_msg_size);
+    let mut server = init_server(&config.root, comm);
+    if let Err(err) = server.configure(&config) {
+        error!("Could not start the lobby on that map: {}", err);
+        process::exit(1);
+    }
+    server.start();
 
     info!("Closing server");
 }
@@ -5,34 +5,64 @@ extern crate bincode;
 extern crate spacefort;
 
 // Std lib imports
-use std::net::Ipv4Addr;
-use std::path::Path;
+use std::process;
+use std::sync::mpsc::channel;
 use std::thread;
 
 // Local imports
 use spacefort::*;
+use config::init_config;
 use game::server::init_server;
 #[cfg(feature = "default")]
 use game::client::init_client;
 #[cfg(feature = "term")]
 use game::term_client::init_client;
+use net::client::init_network_over;
+use net::server::init_local_network;
 
 
 fn main() {   
     env_logger::init().unwrap();
     info!("Starting solo");
 
-    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    // From the command line and config file, see config::USAGE
+    let config = init_config();
+    // Solo always plays its own live game
+    if config.spectate || config.replay.is_some() {
+        eprintln!("Solo can not spectate or watch a replay, use the client for that");
+        process::exit(2);
+    }
 
-    let server_ip = Ipv4Addr::new(0, 0, 0, 0);
+    // The client talks to the server in memory, so no ports are opened
+    let (server_comm, transport) = init_local_network();
+    let server_config = config.clone();
+    let (send_configured, recv_configured) = channel();
     thread::spawn(move|| {
         info!("Starting server");
-        init_server(root, server_ip).start();
+        let mut server = init_server(&server_config.root, server_comm);
+        let configured = server.configure(&server_config);
+        let ok = configured.is_ok();
+        send_configured.send(configured).unwrap();
+        if ok {
+            server.start();
+        }
     });
 
+    // Without a server there is nothing for the client to join
+    if let Err(err) = recv_configured.recv().unwrap() {
+        error!("Could not start the lobby on that map: {}", err);
+        process::exit(1);
+    }
+
     info!("Starting client");
-    let localhost = Ipv4Addr::new(127, 0, 0, 1);
-    init_client(root, localhost).start();
+    let comm = match init_network_over(Box::new(transport)) {
+        Ok(comm) => comm,
+        Err(err) => {
+            eprintln!("Could not connect to the server: {}", err);
+            process::exit(1);
+        },
+    };
+    init_client(&config.root, comm, &config.name, false).start();
 
     info!("Closing solo");
 }
@@ -0,0 +1,224 @@
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+
+use toml;
+
+use game::lobby::{DEFAULT_GEN_SIZE, MapChoice};
+use gen::map::Seed;
+use net::base::{MAX_MSG_SIZE, SERVER_PORT, WEB_PORT};
+
+
+// Read from the working directory when no config file is given
+const DEFAULT_CONFIG: &'static str = "spacefort.toml";
+pub const DEFAULT_NAME: &'static str = "player";
+// Ticks a second, which FRAME_RATE_NS is the length of one of
+const DEFAULT_TICK_RATE: u32 = 60;
+
+pub const USAGE: &'static str = "\
+Options, which can also be set in a TOML config file, with underscores for dashes:
+    --config <file>      Config file to read instead of ./spacefort.toml
+    --bind <ip>          Address the server listens on
+    --port <port>        Port the server listens on and clients connect to
+    --web-port <port>    Port the server takes WebSocket connections on
+    --server <ip>        Address of the server to connect to
+    --map <name>         Map file in the data packs, or path to one, for the lobby to start on
+    --seed <seed>        Start the lobby on a map generated from a seed instead
+    --tick-rate <rate>   Ticks a second the server runs at
+    --max-msg-size <n>   Largest message in bytes that is sent or taken over the network
+    --root <dir>         Directory containing static, where assets are loaded from
+    --name <name>        Name to play under
+    --spectate           Watch the game without playing
+    --record <file>      Record the match to a replay file
+    --replay <file>      Watch a replay file instead of connecting
+    --help               Show this message";
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+/// Settings shared by the server, client and solo binaries, each of which
+/// uses the ones that concern it
+pub struct Config {
+    pub bind: Ipv4Addr,
+    pub port: u16,
+    pub web_port: u16,
+    pub server: Ipv4Addr,
+    // A map file in the data packs or the path to one, or a seed to generate
+    // one from, for the lobby to start on until the host picks another
+    pub map: Option<String>,
+    pub seed: Option<Seed>,
+    pub tick_rate: u32,
+    // In bytes, past which a connection sending one is dropped
+    pub max_msg_size: usize,
+    pub root: PathBuf,
+    pub name: String,
+    pub spectate: bool,
+    pub record: Option<PathBuf>,
+    pub replay: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    UnknownOption(String),
+    MissingValue(String),
+    BadValue { option: String, value: String },
+    // Two settings that can not both be given
+    Conflict(&'static str, &'static str),
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind: Ipv4Addr::new(0, 0, 0, 0),
+            port: SERVER_PORT,
+            web_port: WEB_PORT,
+            server: Ipv4Addr::new(127, 0, 0, 1),
+            map: None,
+            seed: None,
+            tick_rate: DEFAULT_TICK_RATE,
+            max_msg_size: MAX_MSG_SIZE,
+            root: PathBuf::from(env!("CARGO_MANIFEST_DIR")),
+            name: DEFAULT_NAME.to_owned(),
+            spectate: false,
+            record: None,
+            replay: None,
+        }
+    }
+}
+
+/// The config for a binary from its command line. Asking for help, or
+/// getting the options wrong, prints the usage and exits.
+pub fn init_config() -> Config {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", USAGE);
+        process::exit(0);
+    }
+
+    match parse_config(&args) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            process::exit(2);
+        },
+    }
+}
+
+/// Read the config file, if there is one, then apply the command line on top
+pub fn parse_config(args: &[String]) -> Result<Config, ConfigError> {
+    let given = args.iter().position(|arg| arg == "--config").map(|index| args.get(index + 1));
+    let mut config = match given {
+        Some(Some(path)) => try!(load_config(Path::new(path))),
+        Some(None) => return Err(ConfigError::MissingValue("--config".to_owned())),
+        None if Path::new(DEFAULT_CONFIG).exists() => try!(load_config(Path::new(DEFAULT_CONFIG))),
+        None => Config::default(),
+    };
+
+    try!(config.apply_args(args));
+    try!(config.check());
+    Ok(config)
+}
+
+/// Read a config file, with anything it leaves out at the defaults
+pub fn load_config(path: &Path) -> Result<Config, ConfigError> {
+    let mut contents = String::new();
+    try!(File::open(path).and_then(|mut file| file.read_to_string(&mut contents))
+                         .map_err(|err| ConfigError::Io(path.to_path_buf(), err)));
+
+    toml::from_str(&contents).map_err(|err| ConfigError::Parse(path.to_path_buf(), err))
+}
+
+impl Config {
+    /// The map the lobby starts on, if one was picked
+    pub fn map_choice(&self) -> Option<MapChoice> {
+        match (self.map.as_ref(), self.seed) {
+            (_, Some(seed)) => Some(MapChoice::Generated { seed: seed, size: DEFAULT_GEN_SIZE }),
+            (Some(name), None) => Some(MapChoice::File(name.clone())),
+            (None, None) => None,
+        }
+    }
+
+    fn apply_args(&mut self, args: &[String]) -> Result<(), ConfigError> {
+        let mut args = args.iter();
+        while let Some(option) = args.next() {
+            let mut value = || args.next().ok_or_else(|| ConfigError::MissingValue(option.clone()));
+            match option.as_str() {
+                // Already read
+                "--config" => { try!(value()); },
+                "--bind" => self.bind = try!(parse_value(option, try!(value()))),
+                "--port" => self.port = try!(parse_value(option, try!(value()))),
+                "--web-port" => self.web_port = try!(parse_value(option, try!(value()))),
+                "--server" => self.server = try!(parse_value(option, try!(value()))),
+                // Picking one kind of map on the command line overrides the other in the file
+                "--map" => {
+                    self.map = Some(try!(value()).clone());
+                    self.seed = None;
+                },
+                "--seed" => {
+                    self.seed = Some(try!(parse_value(option, try!(value()))));
+                    self.map = None;
+                },
+                "--tick-rate" => self.tick_rate = try!(parse_value(option, try!(value()))),
+                "--max-msg-size" => self.max_msg_size = try!(parse_value(option, try!(value()))),
+                "--root" => self.root = PathBuf::from(try!(value())),
+                "--name" => self.name = try!(value()).clone(),
+                "--spectate" => self.spectate = true,
+                "--record" => self.record = Some(PathBuf::from(try!(value()))),
+                "--replay" => self.replay = Some(PathBuf::from(try!(value()))),
+                _ => return Err(ConfigError::UnknownOption(option.clone())),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check(&self) -> Result<(), ConfigError> {
+        if self.tick_rate == 0 {
+            return Err(ConfigError::BadValue { option: "tick_rate".to_owned(),
+                                               value: self.tick_rate.to_string() });
+        }
+        if self.max_msg_size == 0 {
+            return Err(ConfigError::BadValue { option: "max_msg_size".to_owned(),
+                                               value: self.max_msg_size.to_string() });
+        }
+        if self.map.is_some() && self.seed.is_some() {
+            return Err(ConfigError::Conflict("map", "seed"));
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_value<T: FromStr>(option: &str, value: &str) -> Result<T, ConfigError> {
+    value.parse().map_err(|_| {
+        ConfigError::BadValue { option: option.to_owned(), value: value.to_owned() }
+    })
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref path, ref err) => {
+                write!(f, "Could not read {}: {}", path.display(), err)
+            },
+            ConfigError::Parse(ref path, ref err) => {
+                write!(f, "Bad config in {}: {}", path.display(), err)
+            },
+            ConfigError::UnknownOption(ref option) => write!(f, "Unknown option {}", option),
+            ConfigError::MissingValue(ref option) => write!(f, "{} needs a value", option),
+            ConfigError::BadValue { ref option, ref value } => {
+                write!(f, "Bad value {} for {}", value, option)
+            },
+            ConfigError::Conflict(first, second) => {
+                write!(f, "Only one of {} and {} can be set", first, second)
+            },
+        }
+    }
+}
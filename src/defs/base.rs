@@ -0,0 +1,15 @@
+use entities::creatures::CreatureMap;
+use map::material::Materials;
+use objects::buildings::BuildingTypes;
+use objects::items::ItemTypes;
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+/// Every definition a game runs with, which the server sends to joining
+/// clients so that both sides agree on what each id means
+pub struct Defs {
+    pub materials: Materials,
+    pub creature_types: CreatureMap,
+    pub item_types: ItemTypes,
+    pub building_types: BuildingTypes,
+}
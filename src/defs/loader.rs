@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::hash::Hash;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use toml;
+
+use defs::packs::{DataPack, PackConflicts, merge_tables};
+
+
+/// A raw entry of a definition file, such as a material or creature
+///
+/// Entries can name another entry as their template, in which case they
+/// start out as a copy of it and only overwrite the fields they set.
+pub trait Proto: Clone + DeserializeOwned {
+    type ID: Copy + Eq + Hash + fmt::Display;
+    type Def: Clone;
+    // Anything else needed to build a definition, such as other tables in the file
+    type Context;
+
+    fn name(&self) -> &str;
+    fn id(&self) -> Self::ID;
+    fn template(&self) -> Option<&str>;
+
+    /// Build the definition of an entry without a template
+    fn build(&self, ctx: &Self::Context) -> Result<Self::Def, DefError>;
+
+    /// Build the definition of an entry on top of its template's
+    fn inherit(&self, template: Self::Def, ctx: &Self::Context) -> Result<Self::Def, DefError>;
+}
+
+#[derive(Debug)]
+pub enum DefError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    // The file has no table with this name
+    MissingTable(String),
+    // A data pack listed in packs.toml has no folder
+    MissingPack(String),
+    // Entry names that template each other in a loop, starting and ending with the same one
+    Cycle(Vec<String>),
+    MissingTemplate { entry: String, template: String },
+    DuplicateName(String),
+    DuplicateID { entry: String, id: String },
+    // An entry without a template must set every required field
+    MissingField { entry: String, field: &'static str },
+    // A field names something, such as an attack, that is not defined
+    UnknownReference { entry: String, reference: String },
+}
+
+impl fmt::Display for DefError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DefError::Io(ref err) => write!(f, "{}", err),
+            DefError::Parse(ref err) => write!(f, "{}", err),
+            DefError::MissingTable(ref table) => write!(f, "No [[{}]] table", table),
+            DefError::MissingPack(ref pack) => write!(f, "No folder for data pack {}", pack),
+            DefError::Cycle(ref chain) => write!(f, "Template cycle {}", chain.join(" -> ")),
+            DefError::MissingTemplate { ref entry, ref template } => {
+                write!(f, "{} has template {} which does not exist", entry, template)
+            },
+            DefError::DuplicateName(ref entry) => write!(f, "{} is defined more than once", entry),
+            DefError::DuplicateID { ref entry, ref id } => {
+                write!(f, "{} reuses the id {}", entry, id)
+            },
+            DefError::MissingField { ref entry, field } => {
+                write!(f, "{} is missing the field {}", entry, field)
+            },
+            DefError::UnknownReference { ref entry, ref reference } => {
+                write!(f, "{} refers to {} which does not exist", entry, reference)
+            },
+        }
+    }
+}
+
+impl Error for DefError {
+    fn description(&self) -> &str {
+        "invalid definition file"
+    }
+}
+
+impl From<io::Error> for DefError {
+    fn from(err: io::Error) -> DefError {
+        DefError::Io(err)
+    }
+}
+
+impl From<toml::de::Error> for DefError {
+    fn from(err: toml::de::Error) -> DefError {
+        DefError::Parse(err)
+    }
+}
+
+/// Unwrap a field that every entry without a template must set
+pub fn required<T: Clone>(entry: &str, field: &'static str,
+                          value: &Option<T>) -> Result<T, DefError> {
+    match *value {
+        Some(ref value) => Ok(value.clone()),
+        None => Err(DefError::MissingField { entry: entry.to_owned(), field: field }),
+    }
+}
+
+/// Read a toml file into a table of values
+pub fn read_toml(path: &Path) -> Result<toml::Value, DefError> {
+    let mut file = try!(File::open(path));
+    let mut contents = String::new();
+    try!(file.read_to_string(&mut contents));
+
+    Ok(try!(contents.parse::<toml::Value>()))
+}
+
+/// Deserialize one of the [[table]] arrays of a toml file
+pub fn read_table<T: DeserializeOwned>(file: &toml::Value, table: &str) -> Result<Vec<T>, DefError> {
+    match file.get(table) {
+        Some(value) => Ok(try!(value.clone().try_into())),
+        None => Err(DefError::MissingTable(table.to_owned())),
+    }
+}
+
+/// Load the definitions in the [[table]] array of a toml file
+pub fn load_defs<P: Proto>(path: &Path, table: &str,
+                           ctx: &P::Context) -> Result<HashMap<P::ID, P::Def>, DefError> {
+    let file = try!(read_toml(path));
+    let protos: Vec<P> = try!(read_table(&file, table));
+    resolve_defs(&protos, ctx)
+}
+
+/// Load the definitions in a [[table]] array merged across data packs
+///
+/// # Arguments
+///
+/// * `rel_path` - Where the file is within a pack e.g. inc/items/items.toml
+///
+/// # Returns
+/// * The definitions, and the entries that later packs overrode
+pub fn load_pack_defs<P: Proto>(packs: &[DataPack], rel_path: &str, table: &str, ctx: &P::Context)
+                                -> Result<(HashMap<P::ID, P::Def>, PackConflicts), DefError> {
+    let (protos, conflicts) = try!(merge_tables::<P>(packs, rel_path, table));
+    Ok((try!(resolve_defs(&protos, ctx)), conflicts))
+}
+
+/// Resolve the templates of a set of entries into their definitions
+pub fn resolve_defs<P: Proto>(protos: &[P],
+                              ctx: &P::Context) -> Result<HashMap<P::ID, P::Def>, DefError> {
+    let mut proto_map = HashMap::new();
+    let mut ids = HashMap::new();
+    for proto in protos {
+        if proto_map.insert(proto.name().to_owned(), proto).is_some() {
+            return Err(DefError::DuplicateName(proto.name().to_owned()));
+        }
+        if ids.insert(proto.id(), proto.name()).is_some() {
+            return Err(DefError::DuplicateID { entry: proto.name().to_owned(),
+                                               id: proto.id().to_string() });
+        }
+    }
+
+    let mut resolved = HashMap::new();
+    let mut defs = HashMap::new();
+    for proto in protos {
+        let def = try!(resolve(proto, &proto_map, ctx, &mut resolved, &mut Vec::new()));
+        defs.insert(proto.id(), def);
+    }
+
+    Ok(defs)
+}
+
+fn resolve<P: Proto>(proto: &P, proto_map: &HashMap<String, &P>, ctx: &P::Context,
+                     resolved: &mut HashMap<String, P::Def>,
+                     visiting: &mut Vec<String>) -> Result<P::Def, DefError> {
+    let name = proto.name().to_owned();
+    if let Some(def) = resolved.get(&name) {
+        return Ok(def.clone());
+    }
+
+    // Coming back around to an entry that is still being resolved means a loop
+    if let Some(start) = visiting.iter().position(|entry| *entry == name) {
+        let mut chain = visiting[start..].to_vec();
+        chain.push(name);
+        return Err(DefError::Cycle(chain));
+    }
+    visiting.push(name.clone());
+
+    let def = match proto.template() {
+        None => try!(proto.build(ctx)),
+        Some(template) => {
+            let template_proto = match proto_map.get(template) {
+                Some(template_proto) => *template_proto,
+                None => return Err(DefError::MissingTemplate { entry: name,
+                                                               template: template.to_owned() }),
+            };
+            let template_def = try!(resolve(template_proto, proto_map, ctx, resolved, visiting));
+            try!(proto.inherit(template_def, ctx))
+        },
+    };
+
+    visiting.pop();
+    resolved.insert(name, def.clone());
+    Ok(def)
+}
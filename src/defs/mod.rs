@@ -0,0 +1,3 @@
+pub mod base;
+pub mod loader;
+pub mod packs;
@@ -0,0 +1,283 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use defs::loader::{DefError, Proto, read_table, read_toml};
+use net::base::JSON_SAFE_MAX;
+
+
+pub type PackHash = u64;
+pub type Packs = Vec<DataPack>;
+pub type PackInfos = Vec<PackInfo>;
+pub type PackConflicts = Vec<PackConflict>;
+
+// The pack shipped with the game, which lives in static instead of mods
+pub const BASE_PACK: &'static str = "base";
+// Where a pack keeps its textures, the only part of it that clients load
+pub const TEXTURES_DIR: &'static str = "inc/textures";
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+#[derive(Debug, Clone, PartialEq)]
+/// A directory of definitions, maps and textures laid out like static
+pub struct DataPack {
+    pub name: String,
+    pub dir: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+/// What a client and server tell each other about each pack they run, so the
+/// server can check they match
+pub struct PackInfo {
+    pub name: String,
+    // Of the textures only, as clients get everything else from the server
+    pub hash: PackHash,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+/// A definition that a later pack replaced
+pub struct PackConflict {
+    // The pack doing the overriding
+    pub pack: String,
+    pub entry: String,
+    // The pack that defined the entry before
+    pub overridden: String,
+}
+
+#[derive(Deserialize)]
+struct PackList {
+    pub packs: Vec<String>,
+}
+
+impl DataPack {
+    /// The pack of a name, which is either the base pack or a folder in mods
+    pub fn new(root: &Path, name: &str) -> DataPack {
+        let dir = if name == BASE_PACK {
+            root.join("static")
+        } else {
+            root.join("mods").join(name)
+        };
+
+        DataPack {
+            name: name.to_owned(),
+            dir: dir,
+        }
+    }
+
+    /// Hash the files in the textures folder, which packs without textures leave out
+    pub fn texture_hash(&self) -> Result<PackHash, io::Error> {
+        let dir = self.dir.join(TEXTURES_DIR);
+        if dir.is_dir() {
+            self.hash_dir(&dir)
+        } else {
+            Ok(FNV_OFFSET)
+        }
+    }
+
+    pub fn info(&self) -> Result<PackInfo, io::Error> {
+        Ok(PackInfo {
+            name: self.name.clone(),
+            hash: try!(self.texture_hash()) & JSON_SAFE_MAX,
+        })
+    }
+
+    fn hash_dir(&self, dir: &Path) -> Result<PackHash, io::Error> {
+        let mut files = Vec::new();
+        try!(list_files(dir, &mut files));
+        files.sort();
+
+        let mut hash = FNV_OFFSET;
+        for file in files {
+            // Include the path so that moving a file changes the hash, joined
+            // with slashes whatever the platform uses
+            let rel_path = file.strip_prefix(&self.dir).unwrap_or(&file);
+            let parts: Vec<_> = rel_path.iter().map(|part| part.to_string_lossy()).collect();
+            hash = fnv1a(hash, parts.join("/").as_bytes());
+
+            let mut contents = Vec::new();
+            try!(File::open(&file).and_then(|mut f| f.read_to_end(&mut contents)));
+            hash = fnv1a(hash, &contents);
+        }
+
+        Ok(hash)
+    }
+}
+
+/// The packs to run, in load order, as listed in packs.toml
+pub fn init_packs(root: &Path) -> Packs {
+    load_packs(root).unwrap_or_else(|err| panic!("packs.toml invalid: {}", err))
+}
+
+/// Read packs.toml from root, which holds a list such as
+/// `packs = ["base", "more_trolls"]`. Without one only the base pack is used.
+pub fn load_packs(root: &Path) -> Result<Packs, DefError> {
+    let list_path = root.join("packs.toml");
+    if !list_path.exists() {
+        return Ok(vec![DataPack::new(root, BASE_PACK)]);
+    }
+
+    let file = try!(read_toml(&list_path));
+    let list: PackList = try!(file.try_into());
+
+    let mut packs = Packs::new();
+    for name in list.packs {
+        if packs.iter().any(|pack: &DataPack| pack.name == name) {
+            return Err(DefError::DuplicateName(name));
+        }
+
+        let pack = DataPack::new(root, &name);
+        if !pack.dir.is_dir() {
+            return Err(DefError::MissingPack(name));
+        }
+        packs.push(pack);
+    }
+
+    Ok(packs)
+}
+
+pub fn pack_infos(packs: &[DataPack]) -> Result<PackInfos, io::Error> {
+    packs.iter().map(|pack| pack.info()).collect()
+}
+
+/// Compare the packs a client runs against the server's
+///
+/// # Returns
+/// * Why the two differ, if they do
+pub fn pack_mismatch(client: &[PackInfo], server: &[PackInfo]) -> Option<String> {
+    for (i, server_pack) in server.iter().enumerate() {
+        match client.get(i) {
+            Some(client_pack) if client_pack.name != server_pack.name => {
+                return Some(format!("Expected pack {} in place of {}",
+                                    server_pack.name, client_pack.name));
+            },
+            Some(client_pack) if client_pack.hash != server_pack.hash => {
+                return Some(format!("Pack {} differs from the server's copy", server_pack.name));
+            },
+            Some(_) => {},
+            None => return Some(format!("Missing pack {}", server_pack.name)),
+        }
+    }
+
+    if client.len() > server.len() {
+        return Some(format!("Server does not run pack {}", client[server.len()].name));
+    }
+
+    None
+}
+
+/// The copy of a file, such as a map or texture, from the last pack that has one
+///
+/// # Arguments
+///
+/// * `rel_path` - Where the file is within a pack e.g. inc/maps/arena.sfm
+pub fn find_file(packs: &[DataPack], rel_path: &str) -> Option<PathBuf> {
+    packs.iter()
+         .rev()
+         .map(|pack| pack.dir.join(rel_path))
+         .find(|path| path.is_file())
+}
+
+/// The names of the files in a directory of any pack that end with suffix,
+/// sorted and without repeats
+pub fn find_files(packs: &[DataPack], rel_dir: &str, suffix: &str) -> Vec<String> {
+    let mut names: Vec<String> = packs.iter()
+                                      .filter_map(|pack| fs::read_dir(pack.dir.join(rel_dir)).ok())
+                                      .flat_map(|entries| entries.filter_map(|entry| entry.ok()))
+                                      .filter(|entry| entry.path().is_file())
+                                      .filter_map(|entry| entry.file_name().into_string().ok())
+                                      .filter(|name| name.ends_with(suffix))
+                                      .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Gather the entries of a [[table]] from every pack's copy of a file
+///
+/// Entries from later packs replace entries of earlier packs with the same
+/// id, and each replacement is reported back as a conflict.
+pub fn merge_tables<P: Proto>(packs: &[DataPack], rel_path: &str,
+                              table: &str) -> Result<(Vec<P>, PackConflicts), DefError> {
+    let mut merged: Vec<(String, P)> = Vec::new();
+    let mut conflicts = Vec::new();
+    let mut found = false;
+
+    for pack in packs {
+        let path = pack.dir.join(rel_path);
+        if !path.is_file() {
+            continue;
+        }
+        found = true;
+
+        // Packs only need to have the tables they change
+        let file = try!(read_toml(&path));
+        let protos: Vec<P> = match read_table(&file, table) {
+            Ok(protos) => protos,
+            Err(DefError::MissingTable(_)) => continue,
+            Err(err) => return Err(err),
+        };
+
+        let mut pack_ids = Vec::new();
+        for proto in protos {
+            if pack_ids.contains(&proto.id()) {
+                return Err(DefError::DuplicateID { entry: proto.name().to_owned(),
+                                                   id: proto.id().to_string() });
+            }
+            pack_ids.push(proto.id());
+
+            match merged.iter().position(|&(_, ref old)| old.id() == proto.id()) {
+                Some(index) => {
+                    let conflict = PackConflict {
+                        pack: pack.name.clone(),
+                        entry: proto.name().to_owned(),
+                        overridden: merged[index].0.clone(),
+                    };
+                    warn!("{} from pack {} overrides {} {} from pack {}", conflict.entry,
+                          conflict.pack, table, merged[index].1.name(), conflict.overridden);
+                    conflicts.push(conflict);
+                    merged[index] = (pack.name.clone(), proto);
+                },
+                None => merged.push((pack.name.clone(), proto)),
+            }
+        }
+    }
+
+    if !found {
+        return Err(DefError::Io(io::Error::new(io::ErrorKind::NotFound,
+                                               format!("No pack has {}", rel_path))));
+    }
+
+    Ok((merged.into_iter().map(|(_, proto)| proto).collect(), conflicts))
+}
+
+fn list_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), io::Error> {
+    for entry in try!(fs::read_dir(dir)) {
+        let path = try!(entry).path();
+        if path.is_dir() {
+            try!(list_files(&path, files));
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// 64 bit FNV-1a of some bytes, which unlike the std hashers is the same
+/// on every machine and build
+pub fn fnv1a_hash(bytes: &[u8]) -> u64 {
+    fnv1a(FNV_OFFSET, bytes)
+}
+
+/// 64 bit FNV-1a, continuing on from a previous hash
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+
+    hash
+}
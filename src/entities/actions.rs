@@ -3,46 +3,52 @@ use std::cmp::{min, max};
 
 use map::tiles::Map;
 use entities::entity::{Entity, EntID, EntIDs};
-use entities::creatures::{CreatureID, CreatureMap, attack_speed, dig_speed};
+use entities::creatures::{AttackID, CreatureID, CreatureMap, attack_speed, dig_speed};
+use entities::creatures::pick_attack;
 use entities::utils::*;
 use game::base::*;
 use io::base::TilesSelector;
+use objects::items::{ItemID, Items};
 
 
 pub type Actions = VecDeque<Action>;
 pub type Tasks = Vec<Task>;
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+// TODO Unhardcode how long it takes to pick up or put down an item
+const HANDLING_DURATION: Ticks = 10;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
 pub struct Action {
     pub atype: ActionType,
     pub duration: Ticks
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
 pub enum ActionType {
     Move(Pos),
     Dig(Pos),
-    Attack(AttackType, EntID),
+    Attack(AttackID, EntID),
+    // Pick up the item if it is on the same tile
+    PickUp(ItemID),
+    // Put down a carried item on the current tile
+    Drop(ItemID),
+    // Carry the item to the position. Only used for tasks, and
+    // broken down into moves, a pick up and a drop when scheduled
+    Haul(ItemID, Pos),
     #[allow(dead_code)]
     Wait,
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
 // TODO Move Move and Dig over here, make them be validated,
 // allow goals to have dependecies, make Dig and Attack 
 // have a move next to dependency. Should allow for cleaner
 // validation and recomputation
 pub enum Goal {
-    Attack(AttackType, EntID, Pos),
-}
-
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
-pub enum AttackType {
-    Bite,
-    Punch,
+    Attack(AttackID, EntID, Pos),
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
 /// Actions needing to be done
 pub struct Task {
     pub atype: ActionType,
@@ -63,16 +69,32 @@ impl Action {
                     dig_speed(creature_id, creature_types))
     }
 
-    // TODO Unhardcode attack duration
-    pub fn attack(id: EntID, pos: Pos, creature_id: CreatureID, 
-                  creature_types: &CreatureMap) -> (Action, Goal) {
-        let attack_type = AttackType::Bite;
+    pub fn pick_up(item_id: ItemID) -> Action {
+        Action::new(ActionType::PickUp(item_id), HANDLING_DURATION)
+    }
+
+    pub fn drop(item_id: ItemID) -> Action {
+        Action::new(ActionType::Drop(item_id), HANDLING_DURATION)
+    }
+
+    /// Attack the target at pos with whichever of the attacker's attacks
+    /// suits the distance between them best
+    ///
+    /// # Returns
+    /// * None if the creature has no attacks
+    pub fn attack(id: EntID, pos: Pos, from: Pos, creature_id: CreatureID, 
+                  creature_types: &CreatureMap) -> Option<(Action, Goal)> {
+        let attack = match pick_attack(&creature_id, creature_types, tile_dist(&from, &pos)) {
+            Some(attack) => attack,
+            None => return None,
+        };
+
         let action = Action::new(
-            ActionType::Attack(attack_type, id),
+            ActionType::Attack(attack.id, id),
             attack_speed(&creature_id, creature_types));
-        let goal = Goal::Attack(attack_type, id, pos);
+        let goal = Goal::Attack(attack.id, id, pos);
         
-        (action, goal)
+        Some((action, goal))
     }
 }
 
@@ -86,9 +108,19 @@ impl Task {
         Task { atype: ActionType::Dig(pos), owner: None }
     }
 
+    /// Schedule an item to be carried somewhere
+    ///
+    /// # Arguments
+    ///
+    /// * `item_id` - The item to carry
+    /// * `pos` - Where to drop the item off
+    pub fn haul(item_id: ItemID, pos: Pos) -> Task {
+        Task { atype: ActionType::Haul(item_id, pos), owner: None }
+    }
+
     pub fn priority(&self, ent: &Entity) -> i64 {
         match self.atype {
-            ActionType::Dig(pos) => dist(&ent.pos, &pos) as i64,
+            ActionType::Dig(pos) | ActionType::Haul(_, pos) => dist(&ent.pos, &pos) as i64,
             _ => 0,
         }
     }
@@ -129,6 +161,42 @@ pub fn add_dig_tasks(tasks: &mut Tasks, map: &mut Map, selector: TilesSelector)
     }
 }
 
+/// Drop haul tasks that are finished or can no longer be done
+///
+/// An owner that no longer has the item to pick up or put down queued is
+/// done with the task, either by finishing it or by being given other
+/// orders, so the task is freed up. A free haul task is finished once its
+/// item lies at the destination, and can not be done once its item is
+/// neither lying loose nor being carried, such as when it is used up.
+pub fn prune_haul_tasks(tasks: &mut Tasks, entities: &[Entity], items: &Items) {
+    for task in tasks.iter_mut() {
+        if let (ActionType::Haul(item_id, _), Some(owner)) = (task.atype, task.owner) {
+            let queued = entities.iter().any(|ent| {
+                ent.id == owner && ent.actions.iter().any(|act| match act.atype {
+                    ActionType::PickUp(id) | ActionType::Drop(id) => id == item_id,
+                    _ => false,
+                })
+            });
+            if !queued {
+                task.owner = None;
+            }
+        }
+    }
+
+    tasks.retain(|task| {
+        match (task.atype, task.owner) {
+            (ActionType::Haul(item_id, dest), None) => {
+                match items.iter().find(|item| item.id == item_id) {
+                    Some(item) => item.pos != dest,
+                    // Carried items are put down again if the carrier was interrupted
+                    None => entities.iter().any(|ent| ent.carries(item_id)),
+                }
+            },
+            _ => true,
+        }
+    });
+}
+
 // Make top left corner first element and bottom left corner second element
 /// Re-paramaterize the rectangular selection as the bottomost corner and topmost corner
 fn rotate_selector(selector: TilesSelector) -> TilesSelector {
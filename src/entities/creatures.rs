@@ -1,34 +1,49 @@
 use std::path::Path;
-use std::fs::File;
-use std::io::Read;
 use std::collections::HashMap;
 
+use defs::loader::{DefError, Proto, load_pack_defs, required};
+use defs::packs::{DataPack, PackConflicts, init_packs};
 use io::constants::*;
+use entities::entity::Health;
 use game::base::*;
-use toml;
+use map::tiles::PosUnit;
+use objects::items::ItemTypeID;
 
 
 pub type CreatureID = u16;
 pub type CreatureMap = HashMap<CreatureID, Creature>;
-type ProtoCreatures = Vec<ProtoCreature>;
-type ProtoCreatureMap = HashMap<String, ProtoCreature>;
+pub type AttackID = u16;
+pub type Strength = i32;
+pub type AttackMap = HashMap<String, Attack>;
 
-#[derive(Deserialize)]
-struct DeserializeStruct {
-    pub creatures: ProtoCreatures,
+const CREATURES_FILE: &'static str = "inc/creatures/creatures.toml";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+/// An entry in the attack catalogue that creatures pick their attacks from
+pub struct Attack {
+    pub name: String,
+    pub id: AttackID,
+    // Hit points taken off the target, before adding the attacker's strength
+    pub damage: Health,
+    // How many tiles away the target can be
+    pub reach: PosUnit,
 }
 
-// REFACTOR Macro this bit to dedup code
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Creature {
     pub name: String,
     pub id: CreatureID,
     pub texture: Option<String>,
+    pub health: Health,
+    pub strength: Strength,
+    pub attacks: Vec<Attack>,
     pub attack_speed: Ticks,
     pub dig_speed: Ticks,
     pub movement_speed: Ticks,
     pub color: [f32; 4],
     pub alt: CreatureID,
+    // The item left behind when the creature dies
+    pub corpse: Option<ItemTypeID>,
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize)]
@@ -37,102 +52,155 @@ pub struct ProtoCreature {
     pub id: CreatureID,
     pub template: Option<String>,
     pub texture: Option<String>,
+    pub health: Option<Health>,
+    pub strength: Option<Strength>,
+    pub attacks: Option<Vec<String>>,
     pub attack_speed: Option<Ticks>,
     pub dig_speed: Option<Ticks>,
     pub movement_speed: Option<Ticks>,
     pub color: Option<[f32; 4]>,
     pub alt: Option<CreatureID>,
+    pub corpse: Option<ItemTypeID>,
+}
+
+// Attacks have no templates, so are used as is
+impl Proto for Attack {
+    type ID = AttackID;
+    type Def = Attack;
+    type Context = ();
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn id(&self) -> AttackID {
+        self.id
+    }
+
+    fn template(&self) -> Option<&str> {
+        None
+    }
+
+    fn build(&self, _: &()) -> Result<Attack, DefError> {
+        Ok(self.clone())
+    }
+
+    fn inherit(&self, _: Attack, _: &()) -> Result<Attack, DefError> {
+        Ok(self.clone())
+    }
+}
+
+impl Proto for ProtoCreature {
+    type ID = CreatureID;
+    type Def = Creature;
+    // The attack catalogue, looked up by name
+    type Context = AttackMap;
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn id(&self) -> CreatureID {
+        self.id
+    }
+
+    fn template(&self) -> Option<&str> {
+        self.template.as_ref().map(|template| template.as_str())
+    }
+
+    fn build(&self, attack_map: &AttackMap) -> Result<Creature, DefError> {
+        let attacks = try!(required(&self.name, "attacks", &self.attacks));
+
+        Ok(Creature { 
+            name:           self.name.clone(),
+            id:             self.id,
+            texture:        None,  // FIXME
+            health:         try!(required(&self.name, "health", &self.health)),
+            strength:       try!(required(&self.name, "strength", &self.strength)),
+            attacks:        try!(lookup_attacks(&self.name, &attacks, attack_map)),
+            attack_speed:   try!(required(&self.name, "attack_speed", &self.attack_speed)),
+            dig_speed:      try!(required(&self.name, "dig_speed", &self.dig_speed)),
+            movement_speed: try!(required(&self.name, "movement_speed", &self.movement_speed)),
+            alt:            try!(required(&self.name, "alt", &self.alt)),
+            color:          try!(required(&self.name, "color", &self.color)), 
+            corpse:         self.corpse,
+        })
+    }
+
+    fn inherit(&self, mut new_creat: Creature,
+               attack_map: &AttackMap) -> Result<Creature, DefError> {
+        new_creat.name = self.name.clone();
+        new_creat.id = self.id;
+        if let Some(texture) = self.texture.clone() {
+            new_creat.texture = Some(texture);
+        }
+        if let Some(health) = self.health {
+            new_creat.health = health;
+        }
+        if let Some(strength) = self.strength {
+            new_creat.strength = strength;
+        }
+        if let Some(ref attacks) = self.attacks {
+            new_creat.attacks = try!(lookup_attacks(&self.name, attacks, attack_map));
+        }
+        if let Some(attack_speed) = self.attack_speed {
+            new_creat.attack_speed = attack_speed;
+        }
+        if let Some(dig_speed) = self.dig_speed {
+            new_creat.dig_speed = dig_speed;
+        }
+        if let Some(movement_speed) = self.movement_speed {
+            new_creat.movement_speed = movement_speed;
+        }
+        if let Some(alt) = self.alt {
+            new_creat.alt = alt;
+        }
+        if let Some(color) = self.color {
+            new_creat.color = color;
+        }
+        if let Some(corpse) = self.corpse {
+            new_creat.corpse = Some(corpse);
+        }
+
+        Ok(new_creat)
+    }
 }
 
 // ************************ 
 // Functions for Generating CreatureMap
 //
 // *********************** 
-//
-// TODO Genercize and dedup object, entity, and material 
-// Plan is to have a macro where one can derive the traits
-// for initializing the fields from the raw dumps
+
 pub fn init_creatures(root: &Path) -> CreatureMap {
     info!("Initializing creatures");
+    load_creatures(&init_packs(root))
+        .map(|(creature_types, _)| creature_types)
+        .unwrap_or_else(|err| panic!("creatures.toml invalid: {}", err))
+}
 
-    // Read creatures file to str
-    let creature_path = root.join("static/inc/creatures/creatures.toml");
-    let path_str = creature_path
-                   .to_str()
-                   .unwrap();
-    let mut file = File::open(&path_str).unwrap();
-    let mut contents = String::new(); 
-    file.read_to_string(&mut contents).unwrap();
-
-    let des_struct: DeserializeStruct = toml::from_str(&contents)
-                                              .expect("creatures.toml invalid");
-    let proto_creatures = des_struct.creatures.clone();
-            
-    let mut proto_map = HashMap::new();
-    for creat in &proto_creatures {
-        proto_map.insert(creat.name.clone(), creat.clone());
-    }
-
-    let mut creature_map = HashMap::new();
-    // Alternatively, one could topologically sort based on dependencies
-    // No current checking for circular dependencies
-    for creat in &proto_creatures {
-        resolve(creat, &proto_map, &mut creature_map);
-    }
-
-    creature_map
-}
-
-fn resolve(proto: &ProtoCreature, proto_map: &ProtoCreatureMap, 
-           mut creature_map: &mut CreatureMap) {
-    match proto.template.clone() {
-        None => {
-            let new_creat = Creature { 
-                name:           proto.name.clone(),
-                id:             proto.id,
-                texture:        None,  // FIXME
-                attack_speed:   proto.dig_speed.unwrap(),
-                dig_speed:      proto.dig_speed.unwrap(),
-                movement_speed: proto.movement_speed.unwrap(),
-                alt:            proto.alt.unwrap(),
-                color:          proto.color.unwrap(), 
-            };
-            creature_map.insert(new_creat.id, new_creat);
-        },
-        Some(template) => {
-            let template_proto = proto_map.get(&template)
-                                          .unwrap();
-            if !creature_map.contains_key(&template_proto.id) {
-                resolve(template_proto, proto_map, &mut creature_map);
-            }
-
-            let mut new_creat = creature_map.get(&template_proto.id)
-                                            .unwrap()
-                                            .clone();
-            new_creat.name = proto.name.clone();
-            new_creat.id = proto.id;
-            if let Some(texture) = proto.texture.clone() {
-                new_creat.texture = Some(texture);
-            }
-            if let Some(attack_speed) = proto.attack_speed {
-                new_creat.attack_speed = attack_speed;
-            }
-            if let Some(dig_speed) = proto.dig_speed {
-                new_creat.dig_speed = dig_speed;
-            }
-            if let Some(movement_speed) = proto.movement_speed {
-                new_creat.movement_speed = movement_speed;
-            }
-            if let Some(alt) = proto.alt {
-                new_creat.alt = alt;
-            }
-            if let Some(color) = proto.color {
-                new_creat.color = color;
-            }
-
-            creature_map.insert(new_creat.id, new_creat);
-        }   
-    }
+/// Load the creatures, along with the attacks and creatures that later packs overrode
+pub fn load_creatures(packs: &[DataPack]) -> Result<(CreatureMap, PackConflicts), DefError> {
+    let (attacks, mut conflicts) = try!(load_pack_defs::<Attack>(packs, CREATURES_FILE,
+                                                                 "attacks", &()));
+    let attack_map: AttackMap = attacks.into_iter()
+                                       .map(|(_, atk)| (atk.name.clone(), atk))
+                                       .collect();
+
+    let (creature_types, creature_conflicts) =
+        try!(load_pack_defs::<ProtoCreature>(packs, CREATURES_FILE, "creatures", &attack_map));
+    conflicts.extend(creature_conflicts);
+    Ok((creature_types, conflicts))
+}
+
+fn lookup_attacks(entry: &str, names: &[String],
+                  attack_map: &AttackMap) -> Result<Vec<Attack>, DefError> {
+    names.iter()
+         .map(|name| match attack_map.get(name) {
+             Some(attack) => Ok(attack.clone()),
+             None => Err(DefError::UnknownReference { entry: entry.to_owned(),
+                                                      reference: name.clone() }),
+         })
+         .collect()
 }
 
 // *************************
@@ -140,6 +208,51 @@ fn resolve(proto: &ProtoCreature, proto_map: &ProtoCreatureMap,
 //
 // ************************
 
+pub fn max_health(creature_id: &CreatureID, creature_types: &CreatureMap) -> Health {
+    if let Some(creature) = creature_types.get(creature_id) {
+        creature.health
+    } else {
+        0
+    }
+}
+
+/// The attack a creature should use on a target dist tiles away
+///
+/// The hardest hitting attack that can reach is preferred. When none
+/// can, the longest reaching one is picked so the attacker knows how
+/// close it has to get.
+pub fn pick_attack<'a>(creature_id: &CreatureID, creature_types: &'a CreatureMap,
+                       dist: PosUnit) -> Option<&'a Attack> {
+    let creature = match creature_types.get(creature_id) {
+        Some(creature) => creature,
+        None => return None,
+    };
+
+    let in_reach = creature.attacks.iter()
+                                   .filter(|atk| atk.reach >= dist)
+                                   .max_by_key(|atk| atk.damage);
+    in_reach.or_else(|| creature.attacks.iter().max_by_key(|atk| atk.reach))
+}
+
+/// Hit points an attack takes off, including the attacker's strength
+pub fn attack_damage(creature_id: &CreatureID, creature_types: &CreatureMap,
+                     attack_id: AttackID) -> Health {
+    if let Some(creature) = creature_types.get(creature_id) {
+        creature.attacks.iter()
+                        .find(|atk| atk.id == attack_id)
+                        .map_or(0, |atk| atk.damage + creature.strength)
+    } else {
+        0
+    }
+}
+
+pub fn attack_reach(creature_id: &CreatureID, creature_types: &CreatureMap,
+                    attack_id: AttackID) -> PosUnit {
+    creature_types.get(creature_id)
+                  .and_then(|creature| creature.attacks.iter().find(|atk| atk.id == attack_id))
+                  .map_or(0, |atk| atk.reach)
+}
+
 pub fn attack_speed(creature_id: &CreatureID, creature_types: &CreatureMap) -> Ticks {
     if let Some(creature) = creature_types.get(creature_id) {
         creature.attack_speed
@@ -164,6 +277,10 @@ pub fn movement_speed(creature_id: &CreatureID, creature_types: &CreatureMap) ->
     }
 }
 
+pub fn corpse(creature_id: &CreatureID, creature_types: &CreatureMap) -> Option<ItemTypeID> {
+    creature_types.get(creature_id).and_then(|creature| creature.corpse)
+}
+
 pub fn get_color(creature_id: &CreatureID, creature_types: &CreatureMap) -> Color {
     if let Some(creature) = creature_types.get(creature_id) {
         creature.color
@@ -1,11 +1,14 @@
 use std::path::Path;
 use std::mem;
 
-use entities::actions::{Action, Actions, ActionType, AttackType, Goal, Tasks};
-use entities::pathfind::path_next_to;
-use entities::creatures::{CreatureID, CreatureMap, init_creatures};
+use entities::actions::{Action, Actions, ActionType, Goal, Tasks};
+use entities::pathfind::{path_from, path_next_to};
+use entities::creatures::{AttackID, CreatureID, CreatureMap, init_creatures};
+use entities::creatures::{attack_damage, attack_reach, corpse, max_health};
+use entities::utils::tile_dist;
 use game::base::*;
 use map::tiles::Map;
+use objects::items::{Item, ItemID, Items};
 
 
 pub type EntID = i64;
@@ -14,7 +17,7 @@ pub type Entities = Vec<Entity>;
 pub type EntSnaps = Vec<EntSnap>;
 pub type Health = i32;
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub struct Entity {
     // Id unique to each entity
     pub id: EntID,
@@ -34,9 +37,11 @@ pub struct Entity {
     pub alive: bool,
     // Timer for waiting out the duration of an action
     pub timer: Ticks,
+    // Items being carried
+    pub inventory: Items,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 // A subset of the fields of Entity which is sent to clients for display
 pub struct EntSnap {
     pub id: EntID,
@@ -45,20 +50,39 @@ pub struct EntSnap {
     pub team_id: TeamID,
     pub health: Health,
     pub alive: bool,
+    pub inventory: Items,
 }
 
 impl Entity {
-    fn new(id: EntID, creature_id: CreatureID, pos: Pos, team_id: PlayerID) -> Entity {
+    pub fn new(id: EntID, creature_id: CreatureID, pos: Pos, team_id: TeamID,
+               creature_types: &CreatureMap) -> Entity {
         Entity { 
             id: id, 
             creature_id: creature_id,
             pos: pos, 
-            team_id: Some(team_id),
+            team_id: team_id,
             actions: Actions::new(), 
             goal: None,
-            health: 100,
+            health: max_health(&creature_id, creature_types),
             alive: true,
             timer: 0,
+            inventory: Items::new(),
+        }
+    }
+
+    /// Recreate an entity that a client has only seen snapshots of
+    pub fn from_snap(snap: &EntSnap) -> Entity {
+        Entity {
+            id: snap.id,
+            creature_id: snap.creature_id,
+            pos: snap.pos,
+            team_id: snap.team_id,
+            actions: Actions::new(),
+            goal: None,
+            health: snap.health,
+            alive: snap.alive,
+            timer: 0,
+            inventory: snap.inventory.clone(),
         }
     }
 
@@ -70,32 +94,51 @@ impl Entity {
             team_id: self.team_id,
             health: self.health,
             alive: self.alive,
+            inventory: self.inventory.clone(),
         }
     }
 
-    pub fn attack(&mut self, target_id: EntID, ents: &mut [Entity], attack_type: AttackType) {
-        // TODO Big changes. Use attack attributes from the creatures
-        // file instead of a hardcoded 40. Make attackers repath if the
-        // target moves. Check the the attacker is adjacent to the target
-
-        // TODO Figure out how to work this off of creature properties
-        let damage = match attack_type {
-            _ => 40,
-        };
+    pub fn attack(&mut self, target_id: EntID, ents: &mut [Entity], attack_id: AttackID,
+                  creature_types: &CreatureMap) {
+        let damage = attack_damage(&self.creature_id, creature_types, attack_id);
+        let reach = attack_reach(&self.creature_id, creature_types, attack_id);
 
         if let Some(target) = ents.iter_mut()
                                   .find(|ent| ent.id == target_id) {
-            if self.is_adjacent(target) {
+            if tile_dist(&self.pos, &target.pos) <= reach {
                 target.health -= damage;
             }
         }
     }
 
-    fn is_adjacent(&self, ent: &Entity) -> bool {
-        let (sx, sy, sz)  = self.pos;
-        let (ex, ey, ez) = ent.pos;
+    /// Move an item lying on the same tile into the inventory
+    fn pick_up(&mut self, item_id: ItemID, items: &mut Items) -> bool {
+        let pos = self.pos;
+        if let Some(index) = items.iter()
+                                  .position(|item| item.id == item_id && item.pos == pos) {
+            self.inventory.push(items.remove(index));
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether the item is in the inventory
+    pub fn carries(&self, item_id: ItemID) -> bool {
+        self.inventory.iter().any(|item| item.id == item_id)
+    }
 
-        (sx - ex).abs() <= 1 && (sy - ey).abs() <= 1 && (sz - ez).abs() <= 1 
+    /// Put a carried item down on the current tile
+    fn drop(&mut self, item_id: ItemID, items: &mut Items) -> bool {
+        if let Some(index) = self.inventory.iter()
+                                           .position(|item| item.id == item_id) {
+            let mut item = self.inventory.remove(index);
+            item.pos = self.pos;
+            items.push(item);
+            true
+        } else {
+            false
+        }
     }
 
     /// Enumerate the actions the a task requires, so that they can be 
@@ -105,7 +148,7 @@ impl Entity {
     /// * Actions
     /// * Can do action
     pub fn schedule_action(&self, map: &Map, creature_types: &CreatureMap, 
-                       atype: ActionType) -> Option<Actions> {
+                       items: &Items, atype: ActionType) -> Option<Actions> {
         let mut actions = Actions::new();
         match atype {
             ActionType::Dig(pos) => {
@@ -117,7 +160,37 @@ impl Entity {
                 } else {
                     None
                 }
-            }
+            },
+            ActionType::Haul(item_id, dest) => {
+                let item_pos = match items.iter().find(|item| item.id == item_id) {
+                    Some(item) => item.pos,
+                    None => return None,
+                };
+
+                // Walk to the item, unless already standing on it
+                if item_pos != self.pos {
+                    let path = path_from(map, self.pos, self.creature_id, creature_types,
+                                         item_pos, |&p| p == item_pos);
+                    if path.is_empty() {
+                        return None;
+                    }
+                    actions.extend(path);
+                }
+                actions.push_back(Action::pick_up(item_id));
+
+                // Then carry it over to the destination
+                if dest != item_pos {
+                    let path = path_from(map, item_pos, self.creature_id, creature_types,
+                                         dest, |&p| p == dest);
+                    if path.is_empty() {
+                        return None;
+                    }
+                    actions.extend(path);
+                }
+                actions.push_back(Action::drop(item_id));
+
+                Some(actions)
+            },
             _ => panic!("Not covered")
         }
     }
@@ -133,12 +206,12 @@ pub fn init_entities(root: &Path) -> (Entities, CreatureMap) {
     // TODO Get rid of this static shit and have
     // entities spawn via an actual process like
     // maybe whenever a player joins
-    let entity1 = Entity::new(-1, 1, (7, 7, 0), 1);
-    let entity2 = Entity::new(-2, 1, (3, 3, 0), 1);
-    let entity3 = Entity::new(-3, 1, (4, 4, 0), 1);
-    let entity4 = Entity::new(-4, 2, (80, 7, 0), 2);
-    let entity5 = Entity::new(-5, 2, (83, 3, 0), 2);
-    let entity6 = Entity::new(-6, 2, (85, 4, 0), 2);
+    let entity1 = Entity::new(-1, 1, (7, 7, 0), Some(1), &creature_types);
+    let entity2 = Entity::new(-2, 1, (3, 3, 0), Some(1), &creature_types);
+    let entity3 = Entity::new(-3, 1, (4, 4, 0), Some(1), &creature_types);
+    let entity4 = Entity::new(-4, 2, (80, 7, 0), Some(2), &creature_types);
+    let entity5 = Entity::new(-5, 2, (83, 3, 0), Some(2), &creature_types);
+    let entity6 = Entity::new(-6, 2, (85, 4, 0), Some(2), &creature_types);
 
     ents.push(entity1);
     ents.push(entity2);
@@ -150,23 +223,69 @@ pub fn init_entities(root: &Path) -> (Entities, CreatureMap) {
     (ents, creature_types)
 }
 
-pub fn resolve_dead(entities: &mut Entities) {
-    // For now just mark dead as not having a team
-    // and clear their pending actions
-    // TODO Turn the dead into items and drop their items
-    // and any other magic
+pub fn resolve_dead(entities: &mut Entities, items: &mut Items, cur_id: &mut EntID,
+                    creature_types: &CreatureMap) -> Vec<Change> {
+    // For now just mark dead as not having a team,
+    // clear their pending actions, drop what they
+    // were carrying and leave a corpse
+    // TODO Any other magic
+    let mut changes = Vec::<Change>::new();
 
     for ent in entities {
         if ent.health < 0 || !ent.alive {
+            // Only newly dead entities leave a corpse
+            if ent.alive {
+                changes.push(Change::EntChange(ent.id));
+                if let Some(type_id) = corpse(&ent.creature_id, creature_types) {
+                    let item = Item::new(next_id(cur_id), type_id, ent.pos);
+                    changes.push(Change::ItemChange(item.id));
+                    items.push(item);
+                }
+            }
+
+            for mut item in ent.inventory.drain(..) {
+                item.pos = ent.pos;
+                changes.push(Change::ItemChange(item.id));
+                items.push(item);
+            }
+
             ent.alive = false;
             ent.team_id = None;
             ent.goal = None;
             ent.actions.clear();
         }
     }
+
+    changes
+}
+
+/// Put down anything carried without a drop queued for it, such as when a
+/// haul is cut short by new orders, so it can be hauled again
+pub fn drop_stray_items(entities: &mut Entities, items: &mut Items) -> Vec<Change> {
+    let mut changes = Vec::<Change>::new();
+
+    for ent in entities.iter_mut().filter(|ent| !ent.inventory.is_empty()) {
+        let (pos, actions, inventory) = (ent.pos, &ent.actions, &mut ent.inventory);
+        let (kept, stray): (Items, Items) = inventory.drain(..).partition(|item| {
+            actions.iter().any(|act| act.atype == ActionType::Drop(item.id))
+        });
+        *inventory = kept;
+
+        if !stray.is_empty() {
+            changes.push(Change::EntChange(ent.id));
+        }
+        for mut item in stray {
+            item.pos = pos;
+            changes.push(Change::ItemChange(item.id));
+            items.push(item);
+        }
+    }
+
+    changes
 }
 
-pub fn do_actions(entities: &mut Entities, map: &mut Map) -> Vec<Change> {
+pub fn do_actions(entities: &mut Entities, map: &mut Map, items: &mut Items,
+                  cur_id: &mut EntID, creature_types: &CreatureMap) -> Vec<Change> {
     let mut temp_vec = Actions::new();
     // Changes keeps track of what was dug and who moved,
     // such that this can be used to selectively send 
@@ -198,16 +317,37 @@ pub fn do_actions(entities: &mut Entities, map: &mut Map) -> Vec<Change> {
                             // TODO Add validation testing
                             // Shouldn't move on to invalid tile
                             ent.pos = pos;
+                            changes.push(Change::EntChange(ent.id));
                         },
                         ActionType::Dig(pos) => {
-                            map.dig(pos);
+                            if let Some(type_id) = map.dig(pos) {
+                                let item = Item::new(next_id(cur_id), type_id, pos);
+                                changes.push(Change::ItemChange(item.id));
+                                items.push(item);
+                            }
                             changes.push(Change::TileChange(pos));
                         },
-                        ActionType::Attack(attack_type, ent_id) => {
+                        ActionType::Attack(attack_id, ent_id) => {
                             // As the ent list is split, we have have
                             // to search both for the target
-                            ent.attack(ent_id, front_ents, attack_type);
-                            ent.attack(ent_id, back_ents, attack_type);
+                            ent.attack(ent_id, front_ents, attack_id, creature_types);
+                            ent.attack(ent_id, back_ents, attack_id, creature_types);
+                            changes.push(Change::EntChange(ent_id));
+                        },
+                        ActionType::PickUp(item_id) => {
+                            if ent.pick_up(item_id, items) {
+                                changes.push(Change::ItemChange(item_id));
+                                changes.push(Change::EntChange(ent.id));
+                            } else {
+                                // The item is gone, so the rest of the haul is moot
+                                temp_vec.clear();
+                            }
+                        },
+                        ActionType::Drop(item_id) => {
+                            if ent.drop(item_id, items) {
+                                changes.push(Change::ItemChange(item_id));
+                                changes.push(Change::EntChange(ent.id));
+                            }
                         },
                         _ => {},
                     };
@@ -227,14 +367,14 @@ pub fn do_actions(entities: &mut Entities, map: &mut Map) -> Vec<Change> {
 }
 
 pub fn schedule_actions(entities: &mut Entities, tasks: &mut Tasks, map: &Map, 
-                        creature_types: &CreatureMap, team_id: TeamID) {
+                        creature_types: &CreatureMap, items: &Items, team_id: TeamID) {
     for ent in entities.iter_mut().filter(|ent| ent.actions.is_empty() &&
                                             ent.team_id == team_id) {
         tasks.sort_unstable_by_key(|task| task.priority(&ent));
         for task in tasks.iter_mut()
                          .filter(|task| task.owner.is_none()) {
             // If a task is not owned by anyone, assign it to some entitiy
-            if let Some(actions) = ent.schedule_action(map, creature_types, 
+            if let Some(actions) = ent.schedule_action(map, creature_types, items,
                                                        task.atype) {
                 task.owner = Some(ent.id);
                 ent.actions = actions;
@@ -1,9 +1,11 @@
+use std::collections::HashSet;
+
 use game::base::Pos;
 use map::constants::*;
-use map::tiles::Map;
+use map::tiles::{Map, PosUnit};
 use entities::actions::{Action, Actions, ActionType};
 use entities::entity::Entity;
-use entities::creatures::{CreatureMap, movement_speed};
+use entities::creatures::{CreatureID, CreatureMap, movement_speed};
 use entities::utils::*;
 
 use pathfinding::fringe;
@@ -27,10 +29,24 @@ pub fn path_next_to(map: &Map, ent: &Entity, creature_types: &CreatureMap,
                sucs.contains(&(p, DIAG_DIST))})
 }
 
+/// Path until the target is within reach, such as for attacks
+pub fn path_within(map: &Map, ent: &Entity, creature_types: &CreatureMap, 
+                   end_pos: Pos, reach: PosUnit) -> Actions {
+    path(map, ent, creature_types, end_pos, |p| tile_dist(p, &end_pos) <= reach)
+}
+
 pub fn path<F>(map: &Map, ent: &Entity, creature_types: &CreatureMap, 
                end_pos: Pos, end_det: F) -> Actions where 
     F: Fn(&Pos) -> bool {    
-    let pathing_result = fringe(&ent.pos,
+    path_from(map, ent.pos, ent.creature_id, creature_types, end_pos, end_det)
+}
+
+/// Path starting from somewhere other than where the entity currently is,
+/// such as for the later legs of a task with several stops
+pub fn path_from<F>(map: &Map, start_pos: Pos, creature_id: CreatureID, 
+                    creature_types: &CreatureMap, end_pos: Pos, end_det: F) -> Actions where 
+    F: Fn(&Pos) -> bool {    
+    let pathing_result = fringe(&start_pos,
                          |&p| succ(map, &p),
                          |&p| dist(&p, &end_pos),
                          end_det);
@@ -40,7 +56,7 @@ pub fn path<F>(map: &Map, ent: &Entity, creature_types: &CreatureMap,
         for coord in path {
             actions.push_back(
                 Action { atype: ActionType::Move(coord),
-                         duration: movement_speed(&ent.creature_id, creature_types) }
+                         duration: movement_speed(&creature_id, creature_types) }
             );
         }
     }
@@ -51,6 +67,21 @@ pub fn path<F>(map: &Map, ent: &Entity, creature_types: &CreatureMap,
     actions
 }
 
+/// Every tile that can be walked to from any of the starting tiles
+pub fn reachable(map: &Map, starts: &[Pos]) -> HashSet<Pos> {
+    let mut seen: HashSet<Pos> = starts.iter().cloned().collect();
+    let mut frontier = starts.to_vec();
+    while let Some(pos) = frontier.pop() {
+        for (next, _) in succ(map, &pos) {
+            if seen.insert(next) {
+                frontier.push(next);
+            }
+        }
+    }
+
+    seen
+}
+
 fn succ(map: &Map, pos: &Pos) -> Vec<(Pos, i32)> {
     let (x, y, z) = *pos;
 
@@ -1,3 +1,5 @@
+use std::cmp::max;
+
 use game::base::*;
 
 pub fn dist(pos1: &Pos, pos2: &Pos) -> i32 {
@@ -6,3 +8,10 @@ pub fn dist(pos1: &Pos, pos2: &Pos) -> i32 {
     let sqr_dist = (x1 - x2).pow(2) + (y1 - y2).pow(2) + (z1 - z2).pow(2);
     (sqr_dist as f64).sqrt() as i32
 }
+
+/// Distance counting diagonal steps as one, used for how far attacks reach
+pub fn tile_dist(pos1: &Pos, pos2: &Pos) -> i32 {
+    let (x1, y1, z1) = *pos1;
+    let (x2, y2, z2) = *pos2;
+    max(max((x1 - x2).abs(), (y1 - y2).abs()), (z1 - z2).abs())
+}
@@ -1,9 +1,17 @@
+
+use bincode::{serialize, Infinite};
+
+use defs::base::Defs;
+use defs::packs::fnv1a_hash;
+use net::base::JSON_SAFE_MAX;
 use entities::actions::{Action, Goal};
-use entities::creatures::CreatureMap;
-use entities::entity::{Entities, EntID};
-use entities::entity::{do_actions, resolve_dead};
-use entities::pathfind::{path_to, path_next_to};
+use entities::creatures::{CreatureMap, attack_reach};
+use entities::entity::{Entities, Entity, EntID};
+use entities::entity::{do_actions, drop_stray_items, resolve_dead};
+use entities::pathfind::{path_to, path_within};
 use map::tiles::{Map, PosUnit};
+use objects::buildings::{BuildingID, BuildingTypes, Buildings};
+use objects::items::{ItemID, ItemTypes, Items};
 
 
 pub const FRAME_RATE_NS: u32 = 16_666_667;
@@ -17,14 +25,20 @@ pub type Ticks = i32;
 pub enum Change {
     TileChange(Pos),
     EntChange(EntID),
+    ItemChange(ItemID),
+    BuildingChange(BuildingID),
 }
 
 pub struct GameState {
     pub map: Map,
     pub creature_types: CreatureMap,
+    pub item_types: ItemTypes,
+    pub building_types: BuildingTypes,
     pub entities: Entities,
+    // Items lying loose on the map
+    pub items: Items,
+    pub buildings: Buildings,
     pub ticks: Ticks,
-    #[allow(dead_code)]
     pub cur_id: EntID, // Global state for giving things ids
 }
 
@@ -37,16 +51,31 @@ pub enum NewGoalPoss {
 
 impl GameState {
     // Contains all state corresponding to a running game
-    pub fn new(map: Map, entities: Entities, creature_types: CreatureMap) -> GameState {
+    pub fn new(map: Map, entities: Entities, creature_types: CreatureMap,
+               item_types: ItemTypes, building_types: BuildingTypes) -> GameState {
         GameState {
             map: map,
             creature_types: creature_types,
+            item_types: item_types,
+            building_types: building_types,
             entities: entities,
+            items: Items::new(),
+            buildings: Buildings::new(),
             ticks: 0,
             cur_id: 0,
         }
     }
 
+    /// Copy out the definitions for sending to clients
+    pub fn defs(&self) -> Defs {
+        Defs {
+            materials: self.map.materials.clone(),
+            creature_types: self.creature_types.clone(),
+            item_types: self.item_types.clone(),
+            building_types: self.building_types.clone(),
+        }
+    }
+
     pub fn update(&mut self) -> Vec<Change> {
         self.ticks += 1;
 
@@ -55,8 +84,41 @@ impl GameState {
             self.validate_goals();
         }
         // Entity update and pathfinding
-        let changes = do_actions(&mut self.entities, &mut self.map);
-        resolve_dead(&mut self.entities);
+        let mut changes = do_actions(&mut self.entities, &mut self.map,
+                                     &mut self.items, &mut self.cur_id,
+                                     &self.creature_types);
+        changes.extend(drop_stray_items(&mut self.entities, &mut self.items));
+        changes.extend(resolve_dead(&mut self.entities, &mut self.items,
+                                    &mut self.cur_id, &self.creature_types));
+        changes.extend(self.update_buildings());
+
+        changes
+    }
+
+    /// Finish construction sites that have their materials and spawn trained units
+    fn update_buildings(&mut self) -> Vec<Change> {
+        let mut changes = Vec::<Change>::new();
+        let mut spawns = Vec::new();
+
+        for building in &mut self.buildings {
+            if !building.built {
+                let gathered = building.gather_materials(&mut self.items, &self.building_types);
+                if !gathered.is_empty() || building.built {
+                    changes.extend(gathered.into_iter().map(Change::ItemChange));
+                    changes.push(Change::BuildingChange(building.id));
+                }
+            } else if let Some(creature_id) = building.train(&self.building_types) {
+                spawns.push((creature_id, building.pos, building.team_id));
+                changes.push(Change::BuildingChange(building.id));
+            }
+        }
+
+        for (creature_id, pos, team_id) in spawns {
+            let id = self.give_id();
+            self.entities.push(Entity::new(id, creature_id, pos, team_id,
+                                           &self.creature_types));
+            changes.push(Change::EntChange(id));
+        }
 
         changes
     }
@@ -104,16 +166,25 @@ impl GameState {
         // Implement new goals
         for goal in new_goals.iter() {
             match *goal {
-                NewGoal(ent_id, Goal::Attack(attack_type, id, pos)) => {
+                NewGoal(ent_id, Goal::Attack(_, id, pos)) => {
                     let ent = self.entities.iter_mut()
                                            .find(|ent| ent.id == ent_id)
                                            .unwrap();
-                    ent.actions = path_next_to(&self.map, ent, 
-                                               &self.creature_types, pos);
-                    let (action, goal) = Action::attack(id, pos, ent.creature_id,
-                                                        &self.creature_types);
-                    ent.actions.push_back(action);
-                    ent.goal = Some(goal);
+                    // Pick the attack afresh, as the distance to the target has changed
+                    match Action::attack(id, pos, ent.pos, ent.creature_id, &self.creature_types) {
+                        Some((action, Goal::Attack(attack_id, _, _))) => {
+                            let reach = attack_reach(&ent.creature_id, &self.creature_types,
+                                                     attack_id);
+                            ent.actions = path_within(&self.map, ent, 
+                                                      &self.creature_types, pos, reach);
+                            ent.actions.push_back(action);
+                            ent.goal = Some(Goal::Attack(attack_id, id, pos));
+                        },
+                        None => {
+                            ent.goal = None;
+                            ent.actions.clear();
+                        },
+                    }
                 },
                 Delete(ent_id) => {
                     let ent = self.entities.iter_mut()
@@ -127,10 +198,24 @@ impl GameState {
         }
     }
 
-    #[allow(dead_code)]
+    /// A hash of everything that changes as the game runs, for checking that
+    /// peers running the same game have not drifted apart
+    pub fn checksum(&self) -> u64 {
+        let tiles = self.map.get_chunk((0, 0, 0), self.map.size());
+        let state = (&tiles, &self.entities, &self.items, &self.buildings,
+                     self.ticks, self.cur_id);
+        let bytes = serialize(&state, Infinite).expect("Could not serialize the game state");
+        fnv1a_hash(&bytes) & JSON_SAFE_MAX
+    }
+
     pub fn give_id(&mut self) -> EntID {
-        self.cur_id += 1;
-        self.cur_id
+        next_id(&mut self.cur_id)
     }
 }
 
+/// Advance an id counter such as GameState::cur_id and return the new id
+pub fn next_id(cur_id: &mut EntID) -> EntID {
+    *cur_id += 1;
+    *cur_id
+}
+
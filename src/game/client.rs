@@ -1,5 +1,5 @@
 // Std lib imports
-use std::net::Ipv4Addr;
+use std::collections::HashSet;
 use std::path::Path;
 
 // Crate imports
@@ -9,27 +9,42 @@ use piston::event_loop::*;
 use piston::input::*;
 
 // Local imports
+use config::DEFAULT_NAME;
+use defs::base::Defs;
+use defs::packs::{PackInfos, init_packs, pack_infos};
 use entities::creatures::CreatureMap;
 use entities::entity::*;
 use entities::actions::{select_entities};
 use game::base::*;
+use game::lobby::{LobbyPlayer, LobbyState};
+use game::lockstep::{CHECKSUM_PERIOD, Replica};
+use game::replay::{ReplayViewer, load_replay};
 use io::base::*;
 use io::constants::*;
 use io::utils::*;
 use io::textures::*;
 use io::tiles::{render, init_graphics};
+use map::material::Materials;
 use map::tiles::*;
-use net::base::{ServerMsg, PlayerJoin};
+use game::save::SaveGame;
+use net::base::{ClientMsg, EntUpdate, PlayerJoin, ResumeToken, ServerMsg};
 use net::client::*;
+use objects::buildings::{BuildingTypeID, BuildingTypes, Buildings};
+use objects::items::{ItemTypes, Items};
+use objects::stockpiles::Stockpiles;
 
 
 const CLICK_THRESH: f64 = 40.0;
+// TODO Add a menu for picking what to build
+const PLACED_BUILDING: BuildingTypeID = 1;
 
 pub struct Client {
     pub player_id: Option<PlayerID>,
     pub team_id: TeamID,
 
     pub ch: CameraHandle,
+    // Origins of the map chunks received from around the camera
+    loaded_chunks: HashSet<Pos>,
     mouse_pos: WinPos,
     pub selector: Option<Selector>,
     // Keep track of click down to detect if entity has been clicked
@@ -40,6 +55,22 @@ pub struct Client {
     sel_state: SelState,
     // Whether the client is finished or not, such as if it has been booted by the server
     pub done: bool,
+    // What we go by on the server
+    pub name: String,
+    // For getting our place back if the connection drops
+    resume_token: Option<ResumeToken>,
+    // Spectators watch the whole map and can not give orders
+    pub spectating: bool,
+    // In lockstep, our own copy of the game that the server's turns are run on
+    replica: Option<Replica>,
+    // Set when watching a recorded game instead of a live one
+    replay: Option<ReplayViewer>,
+    // Who is in the lobby and how the match is set up, until the match starts,
+    // along with our id there
+    pub lobby: Option<LobbyState>,
+    lobby_id: Option<PlayerID>,
+    // The local data packs, which have to match the server's
+    pub packs: PackInfos,
     
     // Graphics and IO
     events: Events,
@@ -50,33 +81,68 @@ pub struct Client {
 
     // State to sync from GameState
     pub creature_types: CreatureMap,
+    pub item_types: ItemTypes,
+    pub building_types: BuildingTypes,
     pub entities: Entities,
+    pub items: Items,
+    pub buildings: Buildings,
+    pub stockpiles: Stockpiles,
     pub map: Map,
     pub ticks: Ticks,
 }
 
-pub fn init_client(root: &Path, server_ip: Ipv4Addr) -> Client {
+/// Start a client on a connection to a server, such as from `init_network`
+pub fn init_client(root: &Path, comm: NetComm, name: &str, spectate: bool) -> Client {
     // The client starts with an unsized blank map that 
     // is then resized onced connected to a server and is 
-    // then populated with chunks downloaded from the server
-    let map = blank_map(root);
+    // then populated with chunks downloaded from the server.
+    // Likewise the definitions and entities all come from the server.
+    let map = Map::new((0, 0, 0), Materials::new());
 
     // Other initializations
-    let (entities, creature_types) = init_entities(root);
     let window = init_graphics();
-    let comm = init_network(server_ip);
+    let packs = pack_infos(&init_packs(root)).expect("Could not hash data packs");
+    if spectate {
+        comm.spectate(name, &packs);
+    } else {
+        comm.hello(name, &packs);
+    }
 
     // Must be done after window creation for OpenGL reasons
     let textures = load_textures(root);
 
     info!("Done initializing client");
-    Client::new(map, entities, creature_types, comm, window, textures)
+    let mut client = Client::new(map, Entities::new(), CreatureMap::new(), ItemTypes::new(),
+                                 BuildingTypes::new(), comm, window, textures);
+    client.name = name.to_owned();
+    client.packs = packs;
+    client
+}
+
+/// Watch a recorded game, with no server involved
+pub fn init_replay_viewer(root: &Path, replay_path: &Path) -> Client {
+    let replay = load_replay(replay_path).expect("Could not load replay");
+
+    let window = init_graphics();
+    let textures = load_textures(root);
+
+    info!("Done initializing replay viewer");
+    let mut client = Client::new(Map::new((0, 0, 0), Materials::new()), Entities::new(),
+                                 replay.defs.creature_types.clone(),
+                                 replay.defs.item_types.clone(),
+                                 replay.defs.building_types.clone(),
+                                 init_offline(), window, textures);
+    client.spectating = true;
+    client.replay = Some(ReplayViewer::new(replay));
+    client.sync_replay();
+    client
 }
 
 impl Client {
     // Top level global state
     pub fn new(map: Map, entities: Entities, creature_types: CreatureMap,
-               comm: NetComm, window: Window, textures: Textures) -> Client {
+               item_types: ItemTypes, building_types: BuildingTypes, comm: NetComm,
+               window: Window, textures: Textures) -> Client {
 
         // Initializations
         let mut events = Events::new(EventSettings::new());
@@ -88,12 +154,21 @@ impl Client {
             team_id: None,
 
             ch: CameraHandle {xlen: X_NUM_TILES, ylen: Y_NUM_TILES, x: 0, y: 0, z: 1},
+            loaded_chunks: HashSet::new(),
             mouse_pos: (0.0, 0.0),
             selector: None,
             selected_entities: Vec::new(),
             selector_start: None,
             sel_state: SelState::Ents,
             done: false,
+            name: DEFAULT_NAME.to_owned(),
+            resume_token: None,
+            spectating: false,
+            replica: None,
+            replay: None,
+            lobby: None,
+            lobby_id: None,
+            packs: PackInfos::new(),
 
             window: window,
             textures: textures,
@@ -103,7 +178,12 @@ impl Client {
 
             map: map,
             creature_types: creature_types,
+            item_types: item_types,
+            building_types: building_types,
             entities: entities,
+            items: Items::new(),
+            buildings: Buildings::new(),
+            stockpiles: Stockpiles::new(),
             ticks: 0,
         }
     }
@@ -126,16 +206,21 @@ impl Client {
                     self.release_button(button);
                 }
 
-                if let Some(_) = e.update_args() {
-                    // Network Updates
-                    while let Some(msg) = self.comm.get_incoming_msgs() {
-                        self.dispatch(msg);
+                if let Some(args) = e.update_args() {
+                    if self.replay.is_some() {
+                        self.play_replay(args.dt);
+                    } else {
+                        self.network_update();
                     }
                 }
 
                 if let Some(r) = e.render_args() {
                     self.render(&r);
                 }
+            } else {
+                // The window has been closed
+                self.comm.leave();
+                self.done = true;
             }
 
             if self.done {
@@ -144,6 +229,18 @@ impl Client {
         }
     }
 
+    fn network_update(&mut self) {
+        while let Some(msg) = self.comm.get_incoming_msgs() {
+            self.dispatch(msg);
+        }
+
+        if !self.done && !self.comm.connected() {
+            self.reconnect();
+        }
+        self.comm.keep_alive();
+        self.comm.send_turns(self.ticks);
+    }
+
     pub fn press_button(&mut self, button: Button) {
         if button == Button::Mouse(MouseButton::Left) {
             self.selector_start = Some(self.mouse_pos);
@@ -151,6 +248,20 @@ impl Client {
         }
 
         if let Button::Keyboard(key) = button {
+            if self.lobby.is_some() {
+                let func = match key {
+                    Key::R => Client::toggle_ready,
+                    Key::T => Client::next_team,
+                    Key::M => Client::next_map,
+                    Key::G => Client::start_match,
+                    Key::L => Client::toggle_lockstep,
+                    _      => Client::null,
+                };
+
+                func(self);
+                return;
+            }
+
             let func = match key {
                 Key::Right  | Key::L => Client::right,
                 Key::Left   | Key::H => Client::left, 
@@ -158,9 +269,17 @@ impl Client {
                 Key::Up     | Key::K => Client::forward, 
                 Key::Period | Key::O => Client::up,
                 Key::Comma  | Key::P => Client::down,
+                Key::Equals if self.replay.is_some() => Client::replay_faster,
+                Key::Minus  if self.replay.is_some() => Client::replay_slower,
+                Key::Space  if self.replay.is_some() => Client::replay_pause,
+                // Spectators only get to move the camera around
+                _ if self.spectating => Client::null,
                 Key::A      => Client::attack_mode,
                 Key::D      => Client::digging_mode,
+                Key::S      => Client::stockpile_mode,
                 Key::Y      => Client::move_to,
+                Key::B      => Client::place_building,
+                Key::Q      => Client::queue_unit,
                 _           => Client::null,
             };
 
@@ -177,7 +296,10 @@ impl Client {
     }
 
     pub fn release_button(&mut self, button: Button) {
-        if button == Button::Mouse(MouseButton::Left) {
+        if button == Button::Mouse(MouseButton::Left) && self.spectating {
+            self.selector_start = None;
+            self.selector = None;
+        } else if button == Button::Mouse(MouseButton::Left) {
 
             if let Some(selector) = self.selector {   
                 // Check for click on same spot
@@ -208,6 +330,11 @@ impl Client {
                             self.comm.mark_dig(tiles_selector);
                             self.sel_state = SelState::Ents;
                         },
+                        SelState::Stockpile => {
+                            // TODO Let the player pick which items go where
+                            self.comm.mark_stockpile(tiles_selector, Vec::new());
+                            self.sel_state = SelState::Ents;
+                        },
                         SelState::Attack => {
                             self.add_attack_goal(tiles_selector);
                             self.sel_state = SelState::Ents;
@@ -239,62 +366,121 @@ impl Client {
     pub fn dispatch(&mut self, msg: ServerMsg) {
         match msg {
             ServerMsg::ReplyJoin(player_join) => self.join(player_join),
-            ServerMsg::SendEnts(ent_snaps) => self.update_ents(ent_snaps),
-            ServerMsg::SendMapChunk(chunk) => self.map.apply_chunk(&chunk),
+            // The server has already checked them against ours
+            ServerMsg::SendPacks(packs) => info!("Server runs packs {:?}", packs),
+            ServerMsg::SendDefs(defs) => self.load_defs(defs),
+            ServerMsg::SendEnts(ent_update) => self.update_ents(ent_update),
+            ServerMsg::SendMapChunk(chunk) => self.load_chunk(&chunk),
             ServerMsg::UpdateTile(tile, pos) => self.map.update_tile(tile, pos),
-            ServerMsg::Boot() => {
-                warn!("Booted");
+            ServerMsg::SendItems(items) => self.items = items,
+            ServerMsg::SendStockpiles(stockpiles) => self.stockpiles = stockpiles,
+            ServerMsg::SendBuildings(buildings) => self.buildings = buildings,
+            ServerMsg::SendLobby(player_id, lobby) => self.update_lobby(player_id, lobby),
+            ServerMsg::SendSave(save) => self.start_lockstep(save),
+            ServerMsg::Turn(tick, turn) => self.run_turn(tick, &turn),
+            ServerMsg::PlayerLeft(player_id, reason) => info!("Player {} {}", player_id, reason),
+            ServerMsg::Heartbeat(stamp) => self.comm.ack(stamp),
+            ServerMsg::Ack(stamp) => self.comm.record_rtt(stamp),
+            ServerMsg::Boot(reason) => {
+                warn!("Booted: {}", reason);
                 self.done = true;
             }
-            _ =>  unimplemented!(),
         };
     }
 
     fn join(&mut self, player_join: PlayerJoin) {
         self.player_id = Some(player_join.player_id);
         self.team_id = player_join.team_id;
-        // Currently there is no mulitplayer
-        info!("Joined as Player {}", player_join.player_id);
+        self.resume_token = Some(player_join.resume_token);
+        self.spectating = player_join.spectator;
+        self.lobby = None;
+        if player_join.spectator {
+            info!("Spectating as Player {}", player_join.player_id);
+        } else {
+            info!("Joined as Player {}", player_join.player_id);
+        }
 
         self.map.resize(player_join.map_dim);
 
-        self.comm.request_map(((0, 0, 0), (0, 0, 0)));
+        self.follow_camera();
     }
 
-    fn update_ents(&mut self, ent_snaps: EntSnaps) {
-        for ent_snap in ent_snaps {
-            for ent in &mut self.entities {
-                if ent_snap.id == ent.id {
-                    ent.pos = ent_snap.pos;
-                    ent.health = ent_snap.health;
-                    ent.alive = ent_snap.alive;
-                }
-            }
+    /// Get back onto the server after the connection drops, checked on
+    /// each frame until it works or gives up. The server sends everything
+    /// again when we rejoin.
+    fn reconnect(&mut self) {
+        let resume_token = match self.resume_token {
+            Some(resume_token) => resume_token,
+            None => {
+                error!("Lost connection to the server before joining");
+                self.done = true;
+                return;
+            },
+        };
+
+        match self.comm.reconnect() {
+            Some(true) => {
+                self.loaded_chunks.clear();
+                self.comm.resume(&self.name, resume_token, &self.packs);
+            },
+            Some(false) => {
+                error!("Could not reconnect to the server");
+                self.done = true;
+            },
+            // Still trying
+            None => {},
+        }
+    }
+
+    /// Keep the server up to date with where the camera is
+    fn follow_camera(&mut self) {
+        // Anything but a hello before joining gets the client booted, and
+        // spectators and lockstep players have the whole map anyway
+        if self.player_id.is_some() && !self.spectating && self.replica.is_none() {
+            self.comm.follow_camera(&self.ch, &self.map, &mut self.loaded_chunks);
         }
     }
 
+    fn load_defs(&mut self, defs: Defs) {
+        self.map.materials = defs.materials;
+        self.creature_types = defs.creature_types;
+        self.item_types = defs.item_types;
+        self.building_types = defs.building_types;
+    }
+
+    fn update_ents(&mut self, ent_update: EntUpdate) {
+        self.selected_entities.retain(|ent_id| !ent_update.despawned.contains(ent_id));
+        ent_update.apply(&mut self.entities);
+    }
+
     pub fn forward(&mut self) {
         self.ch.y -= 1;
+        self.follow_camera();
     }
 
     pub fn back(&mut self) {
         self.ch.y += 1;
+        self.follow_camera();
     }
 
     pub fn left(&mut self) {
         self.ch.x -= 1;
+        self.follow_camera();
     }
 
     pub fn right(&mut self) {
         self.ch.x += 1;
+        self.follow_camera();
     }
 
     pub fn up(&mut self) {
         self.ch.z -= 1;
+        self.follow_camera();
     }
 
     pub fn down(&mut self) {
         self.ch.z += 1;
+        self.follow_camera();
     }
 
     pub fn null(&mut self) {}
@@ -303,6 +489,10 @@ impl Client {
         self.sel_state = SelState::Digging;
     }
 
+    pub fn stockpile_mode(&mut self) {
+        self.sel_state = SelState::Stockpile;
+    }
+
     pub fn attack_mode(&mut self) {
         self.sel_state = SelState::Attack;
     }
@@ -317,6 +507,189 @@ impl Client {
         self.selected_entities.clear();
     }
 
+    pub fn place_building(&mut self) {
+        let pos = win_pos_to_tile(self.mouse_pos, &self.ch);
+        self.comm.place_building(PLACED_BUILDING, pos);
+    }
+
+    /// Train the first unit on offer at the building under the cursor
+    pub fn queue_unit(&mut self) {
+        let pos = win_pos_to_tile(self.mouse_pos, &self.ch);
+        let building_types = &self.building_types;
+
+        if let Some(building) = self.buildings.iter()
+                                              .find(|bld| bld.contains(pos, building_types)) {
+            let spawn = building_types.get(&building.type_id)
+                                      .and_then(|bld_type| bld_type.spawns.first());
+            if let Some(creature_id) = spawn {
+                self.comm.queue_unit(building.id, *creature_id);
+            }
+        }
+    }
+
+    /// Lockstep runs its own copy of the map, which chunks from the server
+    /// would be out of step with
+    fn load_chunk(&mut self, chunk: &MapChunk) {
+        if self.replica.is_none() {
+            self.map.apply_chunk(chunk);
+        }
+    }
+
+    /// Start running the game ourselves from a snapshot of it
+    fn start_lockstep(&mut self, save: SaveGame) {
+        info!("Running the game in lockstep from tick {}", save.ticks);
+        let defs = Defs {
+            materials: self.map.materials.clone(),
+            creature_types: self.creature_types.clone(),
+            item_types: self.item_types.clone(),
+            building_types: self.building_types.clone(),
+        };
+        let replica = Replica::new(save, defs);
+
+        {
+            let g_state = replica.g_state();
+            self.map = g_state.map.clone();
+            self.entities = g_state.entities.clone();
+            self.items = g_state.items.clone();
+            self.buildings = g_state.buildings.clone();
+            self.ticks = g_state.ticks;
+        }
+        self.replica = Some(replica);
+        self.comm.start_turns(self.ticks);
+    }
+
+    /// Run a tick from the commands everyone gave for it
+    fn run_turn(&mut self, tick: Ticks, turn: &[(PlayerID, ClientMsg)]) {
+        let replica = match self.replica {
+            Some(ref mut replica) => replica,
+            None => {
+                warn!("Got a turn outside of lockstep");
+                return;
+            },
+        };
+
+        match replica.run_turn(tick, turn) {
+            Ok(changes) => {
+                let g_state = replica.g_state();
+                for change in changes {
+                    if let Change::TileChange(pos) = change {
+                        if let Some(tile) = g_state.map.get_tile(pos) {
+                            self.map.update_tile(tile, pos);
+                        }
+                    }
+                }
+                self.entities = g_state.entities.clone();
+                self.items = g_state.items.clone();
+                self.buildings = g_state.buildings.clone();
+                self.ticks = g_state.ticks;
+
+                if self.ticks % CHECKSUM_PERIOD == 0 {
+                    self.comm.checksum(self.ticks, g_state.checksum());
+                }
+            },
+            Err(expected) => warn!("Got the turn for tick {} while on tick {}", tick, expected),
+        }
+    }
+
+    /// Play the recording on by however many ticks fit in the time passed
+    fn play_replay(&mut self, dt: f64) {
+        let ran = match self.replay {
+            Some(ref mut viewer) => viewer.advance(dt),
+            None => false,
+        };
+        if ran {
+            self.sync_replay();
+        }
+    }
+
+    /// Show the replay's game as it is now
+    fn sync_replay(&mut self) {
+        if let Some(ref viewer) = self.replay {
+            let g_state = viewer.runner.g_state();
+            self.map = g_state.map.clone();
+            self.entities = g_state.entities.clone();
+            self.items = g_state.items.clone();
+            self.buildings = g_state.buildings.clone();
+            self.ticks = g_state.ticks;
+        }
+    }
+
+    pub fn replay_faster(&mut self) {
+        if let Some(ref mut viewer) = self.replay {
+            viewer.faster();
+            info!("Replay speed {}x", viewer.speed);
+        }
+    }
+
+    pub fn replay_slower(&mut self) {
+        if let Some(ref mut viewer) = self.replay {
+            viewer.slower();
+            info!("Replay speed {}x", viewer.speed);
+        }
+    }
+
+    pub fn replay_pause(&mut self) {
+        if let Some(ref mut viewer) = self.replay {
+            viewer.toggle_pause();
+        }
+    }
+
+    fn update_lobby(&mut self, player_id: PlayerID, lobby: LobbyState) {
+        info!("Lobby: {} players, map {}", lobby.players.len(), lobby.settings.map);
+        self.lobby_id = Some(player_id);
+        self.lobby = Some(lobby);
+    }
+
+    pub fn toggle_ready(&mut self) {
+        let ready = self.lobby_player().map(|player| player.ready);
+        if let Some(ready) = ready {
+            self.comm.set_ready(!ready);
+        }
+    }
+
+    /// Move to the team after ours, or the first team after the last one
+    pub fn next_team(&mut self) {
+        let team = self.lobby_player().map(|player| player.team);
+        if let (Some(team), Some(lobby)) = (team, self.lobby.as_ref()) {
+            let next = if team as usize > lobby.players.len() { 1 } else { team + 1 };
+            self.comm.set_team(next);
+        }
+    }
+
+    /// Pick the next map in the list, which only the host can do
+    pub fn next_map(&mut self) {
+        if let Some(ref lobby) = self.lobby {
+            let mut settings = lobby.settings.clone();
+            settings.map = lobby.next_map();
+            self.comm.set_match(settings);
+        }
+    }
+
+    /// Switch lockstep on or off for the match, which only the host can do
+    pub fn toggle_lockstep(&mut self) {
+        if let Some(ref lobby) = self.lobby {
+            let mut settings = lobby.settings.clone();
+            settings.lockstep = !settings.lockstep;
+            self.comm.set_match(settings);
+        }
+    }
+
+    pub fn start_match(&mut self) {
+        self.comm.start_match();
+    }
+
+    fn lobby_player(&self) -> Option<&LobbyPlayer> {
+        match (self.lobby.as_ref(), self.lobby_id) {
+            (Some(lobby), Some(player_id)) => lobby.player(player_id),
+            _ => None,
+        }
+    }
+
+    /// Round trip time to the server in milliseconds, for the HUD
+    pub fn rtt(&self) -> Option<u64> {
+        self.comm.rtt()
+    }
+
     pub fn get_snap(&self) -> MapSnapshot {
         handle_to_snapshot(&self.ch, &self.map)
     }
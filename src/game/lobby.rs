@@ -0,0 +1,307 @@
+use std::fmt;
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use rand;
+
+use defs::packs::{find_file, init_packs, find_files};
+use entities::creatures::CreatureID;
+use game::base::*;
+use gen::map::{Seed, generate_map};
+use map::material::Materials;
+use map::tiles::{Map, load_map};
+
+
+// Where maps live in each data pack, and the ending of the files load_map reads
+const MAPS_DIR: &'static str = "inc/maps";
+const MAP_SUFFIX: &'static str = ".sfm.csv";
+// The map the server ran before there was a choice
+const DEFAULT_MAP: &'static str = "arena.sfm.csv";
+pub const DEFAULT_GEN_SIZE: Pos = (90, 40, 3);
+// The largest map the host can have generated, which keeps it within memory
+pub const MAX_GEN_SIZE: Pos = (512, 512, 32);
+const DEFAULT_START_UNITS: u16 = 3;
+const DEFAULT_START_CREATURE: CreatureID = 1;
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+/// Where the map for a match comes from
+pub enum MapChoice {
+    // The name of a map file in the data packs, or the path to one the server
+    // was started with
+    File(String),
+    Generated { seed: Seed, size: Pos },
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+/// What the host picks before starting a match
+pub struct MatchSettings {
+    pub map: MapChoice,
+    // How many units each team starts with, and what creature they are
+    pub start_units: u16,
+    pub start_creature: CreatureID,
+    // Whether every player runs the game themselves from each other's commands
+    pub lockstep: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct LobbyPlayer {
+    pub player_id: PlayerID,
+    pub name: String,
+    pub team: u16,
+    pub ready: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+/// Everything the players in the lobby get to see
+pub struct LobbyState {
+    pub players: Vec<LobbyPlayer>,
+    // The player who picks the settings and starts the match
+    pub host: Option<PlayerID>,
+    pub settings: MatchSettings,
+    // The map files there is a choice of
+    pub maps: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LobbyError {
+    NotHost(PlayerID),
+    UnknownMap(String),
+    BadMapSize(Pos),
+    // The players who are not ready yet
+    NotReady(Vec<PlayerID>),
+}
+
+/// Players gathering on the server for a match, before the game starts
+pub struct Lobby {
+    root: PathBuf,
+    pub state: LobbyState,
+}
+
+pub fn init_lobby(root: &Path) -> Lobby {
+    let maps = find_files(&init_packs(root), MAPS_DIR, MAP_SUFFIX);
+    Lobby::new(root, maps)
+}
+
+impl Lobby {
+    pub fn new(root: &Path, maps: Vec<String>) -> Lobby {
+        let map = if maps.iter().any(|map| map == DEFAULT_MAP) {
+            MapChoice::File(DEFAULT_MAP.to_owned())
+        } else {
+            MapChoice::Generated { seed: rand::random(), size: DEFAULT_GEN_SIZE }
+        };
+
+        Lobby {
+            root: root.to_path_buf(),
+            state: LobbyState {
+                players: Vec::new(),
+                host: None,
+                settings: MatchSettings {
+                    map: map,
+                    start_units: DEFAULT_START_UNITS,
+                    start_creature: DEFAULT_START_CREATURE,
+                    lockstep: false,
+                },
+                maps: maps,
+            },
+        }
+    }
+
+    /// Add a player on a team of their own. The first player in hosts.
+    pub fn join(&mut self, player_id: PlayerID, name: &str) {
+        let team = (1..).find(|team| !self.state.players.iter().any(|player| player.team == *team))
+                        .unwrap();
+        self.state.players.push(LobbyPlayer {
+            player_id: player_id,
+            name: name.to_owned(),
+            team: team,
+            ready: false,
+        });
+
+        if self.state.host.is_none() {
+            self.state.host = Some(player_id);
+        }
+    }
+
+    /// Take a player out of the lobby, returning whether they were in it.
+    /// Hosting passes to whoever has been waiting longest.
+    pub fn leave(&mut self, player_id: PlayerID) -> bool {
+        let len = self.state.players.len();
+        self.state.players.retain(|player| player.player_id != player_id);
+
+        if self.state.host == Some(player_id) {
+            self.state.host = self.state.players.iter().map(|player| player.player_id).min();
+        }
+
+        self.state.players.len() != len
+    }
+
+    pub fn set_name(&mut self, player_id: PlayerID, name: &str) {
+        if let Some(player) = self.player_mut(player_id) {
+            player.name = name.to_owned();
+        }
+    }
+
+    pub fn set_team(&mut self, player_id: PlayerID, team: u16) {
+        if let Some(player) = self.player_mut(player_id) {
+            player.team = team;
+            player.ready = false;
+        }
+    }
+
+    pub fn set_ready(&mut self, player_id: PlayerID, ready: bool) {
+        if let Some(player) = self.player_mut(player_id) {
+            player.ready = ready;
+        }
+    }
+
+    /// Change the match settings, which only the host can do. Everyone has to
+    /// ready up again afterwards.
+    pub fn set_settings(&mut self, player_id: PlayerID,
+                        settings: MatchSettings) -> Result<(), LobbyError> {
+        try!(self.check_host(player_id));
+        try!(self.check_map(&settings.map));
+
+        self.state.settings = settings;
+        for player in &mut self.state.players {
+            player.ready = false;
+        }
+        Ok(())
+    }
+
+    /// Set the map the lobby offers, as the server is configured to, without
+    /// needing a host. Unlike the host, this can pick a map file outside the
+    /// data packs by its path.
+    pub fn pick_map(&mut self, map: MapChoice) -> Result<(), LobbyError> {
+        match map {
+            MapChoice::File(ref path) if Path::new(path).is_file() => {},
+            _ => try!(self.check_map(&map)),
+        }
+        self.state.settings.map = map;
+        Ok(())
+    }
+
+    /// Check that a player can start the match, which takes being the host
+    /// and everyone being ready
+    pub fn check_start(&self, player_id: PlayerID) -> Result<(), LobbyError> {
+        try!(self.check_host(player_id));
+
+        let not_ready: Vec<PlayerID> = self.state.players.iter()
+                                                         .filter(|player| !player.ready)
+                                                         .map(|player| player.player_id)
+                                                         .collect();
+        if !not_ready.is_empty() {
+            return Err(LobbyError::NotReady(not_ready));
+        }
+
+        Ok(())
+    }
+
+    /// The teams picked by the players, in order
+    pub fn teams(&self) -> Vec<u16> {
+        let mut teams: Vec<u16> = self.state.players.iter().map(|player| player.team).collect();
+        teams.sort();
+        teams.dedup();
+        teams
+    }
+
+    /// Load or generate the map picked for the match
+    pub fn build_map(&self, materials: Materials) -> Result<Map, io::Error> {
+        match self.state.settings.map {
+            MapChoice::File(ref name) => {
+                let rel_path = format!("{}/{}", MAPS_DIR, name);
+                let path = match find_file(&init_packs(&self.root), &rel_path) {
+                    Some(path) => path,
+                    None if Path::new(name).is_file() => PathBuf::from(name),
+                    None => {
+                        let msg = format!("No map called {}", name);
+                        return Err(io::Error::new(ErrorKind::NotFound, msg));
+                    },
+                };
+                let path_str = try!(path.to_str().ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidInput, "Unicode decode error")
+                }));
+                load_map(path_str, materials)
+            },
+            MapChoice::Generated { seed, size } => Ok(generate_map(seed, size, materials)),
+        }
+    }
+
+    fn check_host(&self, player_id: PlayerID) -> Result<(), LobbyError> {
+        if self.state.host == Some(player_id) {
+            Ok(())
+        } else {
+            Err(LobbyError::NotHost(player_id))
+        }
+    }
+
+    fn check_map(&self, map: &MapChoice) -> Result<(), LobbyError> {
+        match *map {
+            MapChoice::File(ref name) if !self.state.maps.contains(name) => {
+                Err(LobbyError::UnknownMap(name.clone()))
+            },
+            MapChoice::Generated { size, .. } if !can_generate(size) => {
+                Err(LobbyError::BadMapSize(size))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn player_mut(&mut self, player_id: PlayerID) -> Option<&mut LobbyPlayer> {
+        self.state.players.iter_mut().find(|player| player.player_id == player_id)
+    }
+}
+
+/// Whether a map of a size can be generated, which takes it being at least
+/// one tile and no bigger than MAX_GEN_SIZE
+fn can_generate(size: Pos) -> bool {
+    let (x, y, z) = size;
+    let (max_x, max_y, max_z) = MAX_GEN_SIZE;
+    x >= 1 && y >= 1 && z >= 1 && x <= max_x && y <= max_y && z <= max_z
+}
+
+impl LobbyState {
+    pub fn player(&self, player_id: PlayerID) -> Option<&LobbyPlayer> {
+        self.players.iter().find(|player| player.player_id == player_id)
+    }
+
+    /// The map after the one picked, going through the map files and then a
+    /// freshly generated map
+    pub fn next_map(&self) -> MapChoice {
+        let index = match self.settings.map {
+            MapChoice::File(ref name) => {
+                self.maps.iter().position(|map| map == name).map_or(0, |index| index + 1)
+            },
+            MapChoice::Generated { .. } => 0,
+        };
+
+        match self.maps.get(index) {
+            Some(name) => MapChoice::File(name.clone()),
+            None => MapChoice::Generated { seed: rand::random(), size: DEFAULT_GEN_SIZE },
+        }
+    }
+}
+
+impl fmt::Display for MapChoice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MapChoice::File(ref name) => write!(f, "{}", name),
+            MapChoice::Generated { seed, size: (x, y, z) } => {
+                write!(f, "generated {}x{}x{} with seed {}", x, y, z, seed)
+            },
+        }
+    }
+}
+
+impl fmt::Display for LobbyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LobbyError::NotHost(player_id) => write!(f, "Player {} is not the host", player_id),
+            LobbyError::UnknownMap(ref name) => write!(f, "There is no map called {}", name),
+            LobbyError::BadMapSize((x, y, z)) => write!(f, "Can not make a {}x{}x{} map", x, y, z),
+            LobbyError::NotReady(ref player_ids) => {
+                write!(f, "Players {:?} are not ready", player_ids)
+            },
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use defs::base::Defs;
+use game::base::*;
+use game::save::SaveGame;
+use game::server::{Server, init_offline_server};
+use net::base::ClientMsg;
+
+
+// Ticks between a command being given and it running, so that every peer
+// has the turn in hand by then
+pub const INPUT_DELAY: Ticks = 4;
+// Ticks between comparing the state of the game across peers
+pub const CHECKSUM_PERIOD: Ticks = 60;
+// Frames the server waits on missing turns before running the tick without them
+const STALL_LIMIT: u32 = 120;
+// Checksums kept for peers that are running behind
+const CHECKSUM_HISTORY: usize = 8;
+
+/// The commands run on a tick and who gave them
+pub type Turn = Vec<(PlayerID, ClientMsg)>;
+
+/// The server's side of lockstep, where every peer runs the game from the
+/// same turns and the server only gathers and hands them out
+///
+/// Each player sends a turn for every tick, even an empty one. A tick runs
+/// once everyone has sent their turn for it, or once the wait gets too long.
+/// Players who kept it waiting that long are not waited on again until they
+/// send another turn.
+pub struct Lockstep {
+    // Commands waiting for their tick, in the order they came in
+    turns: BTreeMap<Ticks, Turn>,
+    // The last tick each player has sent a turn for
+    submitted: HashMap<PlayerID, Ticks>,
+    checksums: BTreeMap<Ticks, u64>,
+    // Frames spent waiting on the next tick
+    stalled: u32,
+    // Players who hit the stall limit and have not sent a turn since
+    lagging: HashSet<PlayerID>,
+}
+
+impl Lockstep {
+    pub fn new() -> Lockstep {
+        Lockstep {
+            turns: BTreeMap::new(),
+            submitted: HashMap::new(),
+            checksums: BTreeMap::new(),
+            stalled: 0,
+            lagging: HashSet::new(),
+        }
+    }
+
+    /// Take a player's turn for a tick. Turns that arrive after their tick has
+    /// run are put on the next one to run.
+    pub fn submit(&mut self, player_id: PlayerID, tick: Ticks, commands: Vec<ClientMsg>,
+                  next_tick: Ticks) {
+        let submitted = self.submitted.entry(player_id).or_insert(tick);
+        *submitted = (*submitted).max(tick);
+        self.lagging.remove(&player_id);
+
+        let turn = self.turns.entry(tick.max(next_tick)).or_insert_with(Vec::new);
+        turn.extend(commands.into_iter().map(|command| (player_id, command)));
+    }
+
+    /// Put a command of our own on a tick, under a player who need not be
+    /// taking turns any more
+    pub fn add(&mut self, tick: Ticks, player_id: PlayerID, command: ClientMsg) {
+        self.turns.entry(tick).or_insert_with(Vec::new).push((player_id, command));
+    }
+
+    /// The commands to run on a tick, once the players have sent their turns
+    /// for it or have kept the game waiting too long
+    pub fn take_turn(&mut self, tick: Ticks, player_ids: &[PlayerID]) -> Option<Turn> {
+        let missing: Vec<PlayerID> = player_ids.iter()
+                                               .filter(|player_id| {
+                                                   !self.lagging.contains(player_id) &&
+                                                   self.submitted.get(player_id)
+                                                                 .map_or(true, |sent| *sent < tick)
+                                               })
+                                               .cloned()
+                                               .collect();
+        if !missing.is_empty() {
+            if self.stalled < STALL_LIMIT {
+                self.stalled += 1;
+                return None;
+            }
+            warn!("Running tick {} without turns from players {:?}", tick, missing);
+            self.lagging.extend(missing);
+        }
+        self.stalled = 0;
+
+        // Put in player order, so the order turns arrived in makes no difference
+        let mut turn = self.turns.remove(&tick).unwrap_or_else(Vec::new);
+        turn.sort_by_key(|&(player_id, _)| player_id);
+        Some(turn)
+    }
+
+    pub fn record_checksum(&mut self, tick: Ticks, checksum: u64) {
+        self.checksums.insert(tick, checksum);
+        while self.checksums.len() > CHECKSUM_HISTORY {
+            let oldest = *self.checksums.keys().next().unwrap();
+            self.checksums.remove(&oldest);
+        }
+    }
+
+    /// Whether a peer's checksum for a tick matches ours, or None if the tick
+    /// is too long ago or yet to come
+    pub fn check(&self, tick: Ticks, checksum: u64) -> Option<bool> {
+        self.checksums.get(&tick).map(|ours| *ours == checksum)
+    }
+}
+
+/// A peer's own copy of the game, run from the turns the server hands out
+pub struct Replica {
+    server: Server,
+}
+
+impl Replica {
+    /// Start from a snapshot of the game, such as the one sent on joining
+    pub fn new(save: SaveGame, defs: Defs) -> Replica {
+        Replica { server: init_offline_server(save, defs) }
+    }
+
+    /// Run the next tick, which has to be the one the turn is for
+    pub fn run_turn(&mut self, tick: Ticks, turn: &[(PlayerID, ClientMsg)])
+                    -> Result<Vec<Change>, Ticks> {
+        let expected = self.server.g_state.ticks + 1;
+        if tick != expected {
+            return Err(expected);
+        }
+
+        Ok(self.server.run_turn(turn))
+    }
+
+    pub fn g_state(&self) -> &GameState {
+        &self.server.g_state
+    }
+}
@@ -2,4 +2,8 @@ pub mod base;
 pub mod client;
 #[cfg(feature = "term")]
 pub mod term_client;
+pub mod lobby;
+pub mod lockstep;
+pub mod replay;
+pub mod save;
 pub mod server;
@@ -0,0 +1,198 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+
+use bincode::{deserialize_from, serialize_into, Infinite};
+
+use defs::base::Defs;
+use game::base::*;
+use game::lockstep::Lockstep;
+use game::save::{SaveGame, invalid_data};
+use game::server::{Server, init_offline_server};
+use net::base::{ClientMsg, LeaveReason};
+
+
+// Every replay file starts with these bytes, followed by the format version
+pub const REPLAY_MAGIC: [u8; 4] = *b"SFRP";
+// Bump whenever the layout of a replay changes
+pub const REPLAY_VERSION: u32 = 1;
+// Fastest and slowest a replay can be played back at
+const MAX_SPEED: f64 = 16.0;
+const MIN_SPEED: f64 = 0.125;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+/// Something that came in from a player, as the server saw it
+pub enum ReplayEvent {
+    Connected(PlayerID),
+    Message(PlayerID, ClientMsg),
+    Dropped(PlayerID),
+}
+
+/// Writes a replay as the game goes: the magic bytes, the format version,
+/// the definitions and starting state, whether the match runs in lockstep,
+/// and then each event with its tick
+pub struct Recorder {
+    writer: BufWriter<File>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path, defs: &Defs, start: &SaveGame,
+                  lockstep: bool) -> Result<Recorder, io::Error> {
+        let f = try!(File::create(path));
+        let mut writer = BufWriter::new(f);
+
+        try!(writer.write_all(&REPLAY_MAGIC));
+        try!(serialize_into(&mut writer, &REPLAY_VERSION, Infinite).map_err(invalid_data));
+        try!(serialize_into(&mut writer, defs, Infinite).map_err(invalid_data));
+        try!(serialize_into(&mut writer, start, Infinite).map_err(invalid_data));
+        try!(serialize_into(&mut writer, &lockstep, Infinite).map_err(invalid_data));
+        try!(writer.flush());
+
+        Ok(Recorder { writer: writer })
+    }
+
+    /// Add an event, flushed straight away so a crash leaves a usable replay
+    pub fn record(&mut self, tick: Ticks, event: &ReplayEvent) -> Result<(), io::Error> {
+        try!(serialize_into(&mut self.writer, &(tick, event), Infinite).map_err(invalid_data));
+        self.writer.flush()
+    }
+}
+
+/// A recorded game, which is everything needed to play it again
+pub struct Replay {
+    pub defs: Defs,
+    pub start: SaveGame,
+    // Whether the turns the players sent are what ran the game
+    pub lockstep: bool,
+    pub events: Vec<(Ticks, ReplayEvent)>,
+}
+
+/// Read a replay file, rejecting files from other versions of the format
+pub fn load_replay(path: &Path) -> Result<Replay, io::Error> {
+    let f = try!(File::open(path));
+    let mut reader = BufReader::new(f);
+
+    let mut magic = [0u8; 4];
+    try!(reader.read_exact(&mut magic));
+    if magic != REPLAY_MAGIC {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Not a SpaceFort replay file"));
+    }
+
+    let version: u32 = try!(deserialize_from(&mut reader, Infinite).map_err(invalid_data));
+    if version != REPLAY_VERSION {
+        return Err(io::Error::new(ErrorKind::InvalidData,
+                                  format!("Unsupported replay version {}, expected {}",
+                                          version, REPLAY_VERSION)));
+    }
+
+    let defs = try!(deserialize_from(&mut reader, Infinite).map_err(invalid_data));
+    let start = try!(deserialize_from(&mut reader, Infinite).map_err(invalid_data));
+    let lockstep = try!(deserialize_from(&mut reader, Infinite).map_err(invalid_data));
+    let mut events = Vec::new();
+    while !try!(reader.fill_buf()).is_empty() {
+        events.push(try!(deserialize_from(&mut reader, Infinite).map_err(invalid_data)));
+    }
+
+    Ok(Replay { defs: defs, start: start, lockstep: lockstep, events: events })
+}
+
+/// Plays a replay by handing its events to a server, tick by tick, just as
+/// they reached the one that recorded it
+pub struct ReplayRunner {
+    pub server: Server,
+    events: VecDeque<(Ticks, ReplayEvent)>,
+    // The tick of the last event
+    end: Ticks,
+}
+
+impl ReplayRunner {
+    pub fn new(replay: Replay) -> ReplayRunner {
+        let mut server = init_offline_server(replay.start, replay.defs);
+        // The recorded turns only run the game as they did in lockstep
+        if replay.lockstep {
+            server.lockstep = Some(Lockstep::new());
+        }
+        let ticks = server.g_state.ticks;
+        let end = replay.events.last().map_or(ticks, |&(tick, _)| tick);
+        ReplayRunner {
+            server: server,
+            events: replay.events.into_iter().collect(),
+            end: end,
+        }
+    }
+
+    /// Hand over the events for the current tick and run it
+    pub fn step(&mut self) {
+        let ticks = self.server.g_state.ticks;
+        while self.events.front().map_or(false, |&(tick, _)| tick <= ticks) {
+            match self.events.pop_front().unwrap().1 {
+                ReplayEvent::Connected(player_id) => { self.server.pending.insert(player_id); },
+                ReplayEvent::Message(player_id, msg) => self.server.dispatch(msg, player_id),
+                ReplayEvent::Dropped(player_id) => {
+                    self.server.disconnect(player_id, LeaveReason::Dropped)
+                },
+            }
+        }
+        self.server.update();
+    }
+
+    /// Whether every event has been played
+    pub fn done(&self) -> bool {
+        self.events.is_empty() && self.server.g_state.ticks >= self.end
+    }
+
+    pub fn g_state(&self) -> &GameState {
+        &self.server.g_state
+    }
+}
+
+/// Plays a replay in real time, or faster or slower
+pub struct ReplayViewer {
+    pub runner: ReplayRunner,
+    pub speed: f64,
+    pub paused: bool,
+    // Ticks owed from frames that did not add up to a whole one
+    owed: f64,
+}
+
+impl ReplayViewer {
+    pub fn new(replay: Replay) -> ReplayViewer {
+        ReplayViewer {
+            runner: ReplayRunner::new(replay),
+            speed: 1.0,
+            paused: false,
+            owed: 0.0,
+        }
+    }
+
+    pub fn faster(&mut self) {
+        self.speed = (self.speed * 2.0).min(MAX_SPEED);
+    }
+
+    pub fn slower(&mut self) {
+        self.speed = (self.speed / 2.0).max(MIN_SPEED);
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+    }
+
+    /// Run the ticks that fall in a stretch of real time, returning whether
+    /// any were run
+    pub fn advance(&mut self, dt: f64) -> bool {
+        if self.paused || self.runner.done() {
+            return false;
+        }
+
+        self.owed += dt * 1e9 / FRAME_RATE_NS as f64 * self.speed;
+        let mut ran = false;
+        while self.owed >= 1.0 && !self.runner.done() {
+            self.runner.step();
+            self.owed -= 1.0;
+            ran = true;
+        }
+        ran
+    }
+}
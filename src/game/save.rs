@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+
+use bincode;
+use bincode::{deserialize_from, serialize_into, Infinite};
+
+use entities::actions::Tasks;
+use entities::entity::{Entities, EntID};
+use game::base::*;
+use game::server::ServerPlayer;
+use net::base::ResumeToken;
+use map::tiles::{Map, MapChunk};
+use objects::buildings::Buildings;
+use objects::items::Items;
+use objects::stockpiles::Stockpiles;
+
+
+// Every save file starts with these bytes, followed by the format version
+pub const SAVE_MAGIC: [u8; 4] = *b"SFSV";
+// Bump whenever the layout of SaveGame changes
+pub const SAVE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+/// Everything needed to resume a server mid-game
+///
+/// Definitions such as materials and creatures are not saved,
+/// and are instead reloaded from the static files when restoring.
+pub struct SaveGame {
+    // The whole map as a single chunk, including tile modes and marks
+    pub map: MapChunk,
+    pub entities: Entities,
+    pub items: Items,
+    pub buildings: Buildings,
+    pub cur_id: EntID,
+    pub ticks: Ticks,
+    pub players: Vec<PlayerSave>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+/// The persistent part of a ServerPlayer
+pub struct PlayerSave {
+    pub player_id: PlayerID,
+    pub name: String,
+    pub team_id: TeamID,
+    pub tasks: Tasks,
+    pub stockpiles: Stockpiles,
+    // So the player can come back to the restored game
+    pub resume_token: ResumeToken,
+}
+
+impl SaveGame {
+    pub fn new(g_state: &GameState, players: &HashMap<PlayerID, ServerPlayer>) -> SaveGame {
+        let map = &g_state.map;
+        // Spectators have nothing to save
+        let mut player_saves: Vec<PlayerSave> = players.values()
+                                                       .filter(|player| !player.spectator)
+                                                       .map(PlayerSave::new)
+                                                       .collect();
+        // Keep the output stable regardless of hashing order
+        player_saves.sort_by_key(|player| player.player_id);
+
+        SaveGame {
+            map: map.get_chunk((0, 0, 0), map.size()),
+            entities: g_state.entities.clone(),
+            items: g_state.items.clone(),
+            buildings: g_state.buildings.clone(),
+            cur_id: g_state.cur_id,
+            ticks: g_state.ticks,
+            players: player_saves,
+        }
+    }
+
+    /// Fill a map, which provides the materials, with the saved tiles
+    pub fn restore_map(&self, mut map: Map) -> Map {
+        map.resize((self.map.xlen, self.map.ylen, self.map.zlen));
+        map.apply_chunk(&self.map);
+        map
+    }
+
+    /// The save as sent to players, who must not learn each other's resume tokens
+    pub fn without_tokens(mut self) -> SaveGame {
+        for player in &mut self.players {
+            player.resume_token = 0;
+        }
+        self
+    }
+}
+
+impl PlayerSave {
+    pub fn new(player: &ServerPlayer) -> PlayerSave {
+        PlayerSave {
+            player_id: player.player_id,
+            name: player.name.clone(),
+            team_id: player.team_id,
+            tasks: player.tasks.clone(),
+            stockpiles: player.stockpiles.clone(),
+            resume_token: player.resume_token,
+        }
+    }
+}
+
+/// Write a save file as the magic bytes, the format version and then the game
+pub fn save_game(path: &Path, save: &SaveGame) -> Result<(), io::Error> {
+    let f = try!(File::create(path));
+    let mut writer = BufWriter::new(f);
+
+    try!(writer.write_all(&SAVE_MAGIC));
+    try!(serialize_into(&mut writer, &SAVE_VERSION, Infinite).map_err(invalid_data));
+    try!(serialize_into(&mut writer, save, Infinite).map_err(invalid_data));
+    try!(writer.flush());
+
+    Ok(())
+}
+
+/// Read a save file, rejecting files from other versions of the format
+pub fn load_game(path: &Path) -> Result<SaveGame, io::Error> {
+    let f = try!(File::open(path));
+    let mut reader = BufReader::new(f);
+
+    let mut magic = [0u8; 4];
+    try!(reader.read_exact(&mut magic));
+    if magic != SAVE_MAGIC {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Not a SpaceFort save file"));
+    }
+
+    let version: u32 = try!(deserialize_from(&mut reader, Infinite).map_err(invalid_data));
+    if version != SAVE_VERSION {
+        return Err(io::Error::new(ErrorKind::InvalidData,
+                                  format!("Unsupported save version {}, expected {}",
+                                          version, SAVE_VERSION)));
+    }
+
+    deserialize_from(&mut reader, Infinite).map_err(invalid_data)
+}
+
+pub fn invalid_data(err: bincode::Error) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, err)
+}
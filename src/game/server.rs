@@ -1,81 +1,309 @@
-use std::net::Ipv4Addr;
-use std::path::Path;
-
-use std::net::TcpStream;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::thread;
 
+use config::Config;
+use defs::base::Defs;
+use defs::packs::{PackInfos, init_packs, pack_infos, pack_mismatch};
 use game::base::*;
-use entities::creatures::CreatureMap;
-use entities::actions::{Action, Tasks, add_dig_tasks};
-use entities::entity::{Entity, Entities, EntSnaps, EntID};
+use entities::creatures::{CreatureID, CreatureMap};
+use entities::actions::{Action, ActionType, Task, Tasks, add_dig_tasks, prune_haul_tasks};
+use entities::entity::{Entity, Entities, EntID};
 use entities::entity::schedule_actions;
-use map::tiles::Map;
-use net::base::{ClientMsg, PlayerJoin};
-use net::server::NetComm;
-use map::tiles::init_map;
-use entities::entity::init_entities;
-use net::server::init_network;
+use entities::creatures::init_creatures;
+use entities::pathfind::reachable;
+use game::lobby::{Lobby, LobbyError, MatchSettings, init_lobby};
+use game::lockstep::{CHECKSUM_PERIOD, Lockstep};
+use game::replay::{Recorder, ReplayEvent};
+use game::save::{SaveGame, load_game, save_game};
+use map::tiles::{Map, PosUnit, blank_map, chunk_origin};
+use objects::buildings::{Building, BuildingID, BuildingTypeID, BuildingTypes};
+use objects::buildings::{add_construction_tasks, can_place, init_buildings};
+use objects::items::{ItemID, ItemTypeID, ItemTypes, init_items};
+use objects::stockpiles::{Stockpile, Stockpiles, add_haul_tasks, claimed_items};
+use net::base::{BootReason, ClientMsg, Conn, ConnEvent, EntBaseline, GAME_VERSION, Hello};
+use net::base::{LeaveReason, PlayerJoin, PROTOCOL_VERSION, ResumeToken, VIEW_MARGIN, View};
+use net::base::{Stamp, new_resume_token, smooth_rtt, stamp_since};
+use net::server::{NetComm, init_offline};
+
 
+// Ticks between sending every entity to every player
+const KEYFRAME_PERIOD: Ticks = 300;
+// Ticks between bandwidth measurements
+const BANDWIDTH_PERIOD: Ticks = 60;
+// Ticks between looking for loose items to stockpile
+const HAUL_PERIOD: Ticks = 30;
+// Ticks a dropped player has to reconnect before their things are handed on
+pub const RESUME_PERIOD: Ticks = 3600;
+// Milliseconds between heartbeats to each player
+const HEARTBEAT_PERIOD_MS: u64 = 1000;
+// Milliseconds a player can go quiet before their connection is taken as dropped
+pub const TIMEOUT_MS: u64 = 10_000;
+// How far from their team's spot starting units can be placed
+const SPAWN_RADIUS: PosUnit = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// What becomes of a player's units, buildings and tasks once they are gone
+pub enum DisconnectPolicy {
+    /// Everything goes neutral, units drop what they were doing and digging is unmarked
+    Release,
+    /// Everything goes to whoever has been in the game longest, or is released
+    /// if nobody is left
+    Reassign,
+    // Either way, a team with players left in it stays theirs
+}
 
 pub struct Server {
     pub g_state: GameState,
     pub players: HashMap<PlayerID, ServerPlayer>,
     pub comm: NetComm,
+    // Sent to joining players, who leave if theirs do not match
+    pub packs: PackInfos,
+    // Players that have connected but not yet said hello
+    pub pending: HashSet<PlayerID>,
+    // Players whose connection dropped, and the tick it happened on
+    pub dropped: HashMap<PlayerID, (ServerPlayer, Ticks)>,
+    // Connections that resumed players came back on, and who they are
+    conn_players: HashMap<PlayerID, PlayerID>,
+    // In lockstep, players gone for good whose things are handed on in the next turn
+    leaving: HashMap<PlayerID, ServerPlayer>,
+    pub disconnect_policy: DisconnectPolicy,
+    // For stamping heartbeats
+    started: Instant,
+    // When heartbeats last went out, or None if they are yet to
+    last_heartbeat: Option<Instant>,
+    // Players waiting for the match to start, or None once it has
+    pub lobby: Option<Lobby>,
+    // Set when every player runs the game themselves from the turns we hand out
+    pub lockstep: Option<Lockstep>,
+    // Where to record the match to once it starts
+    pub record_to: Option<PathBuf>,
+    recorder: Option<Recorder>,
+    // Length of a tick
+    pub frame_ns: u32,
 }
 
 pub struct ServerPlayer {
     pub player_id: PlayerID,
+    pub name: String,
     pub team_id: TeamID,
     pub tasks: Tasks,
+    pub stockpiles: Stockpiles,
+    // What the player has been sent of the entities
+    pub ent_baseline: EntBaseline,
+    // The tiles the player gets updates for, or None for everywhere
+    pub view: Option<View>,
+    // Bytes a second sent to the player, as of the last measurement
+    pub bandwidth: u64,
+    last_bytes_sent: u64,
+    pub resume_token: ResumeToken,
+    // Round trip time to the player in milliseconds, once they have answered a heartbeat
+    pub rtt: Option<u64>,
+    // When the player was last heard from, or None for players restored
+    // from a save that have not joined yet
+    pub last_heard: Option<Instant>,
+    // Spectators watch without a team, tasks or any say in the game
+    pub spectator: bool,
 }
 
-pub fn init_server(root: &Path, server_ip: Ipv4Addr) -> Server {
-    let map = init_map(root);
-    let (entities, creature_types) = init_entities(root);
-    let comm = init_network(server_ip);
+/// Start a server in the lobby, where the map is picked once players have joined.
+/// Players reach it however the comm lets them, over the network or in memory.
+pub fn init_server(root: &Path, comm: NetComm) -> Server {
+    let creature_types = init_creatures(root);
+    let item_types = init_items(root);
+    let building_types = init_buildings(root);
+
+    let mut server = Server::new(blank_map(root), Entities::new(), creature_types, item_types,
+                                 building_types, comm);
+    server.packs = pack_infos(&init_packs(root)).expect("Could not hash data packs");
+    server.lobby = Some(init_lobby(root));
+    server
+}
+
+/// Resume a game from a save file written by `Server::save`
+pub fn init_server_from_save(root: &Path, save_path: &Path, comm: NetComm) -> Server {
+    let save = load_game(save_path).expect("Could not load save");
+    let creature_types = init_creatures(root);
+    let item_types = init_items(root);
+    let building_types = init_buildings(root);
+
+    let mut server = Server::from_save(save, blank_map(root), creature_types, item_types,
+                                       building_types, comm);
+    server.packs = pack_infos(&init_packs(root)).expect("Could not hash data packs");
+    server
+}
 
-    Server::new(map, entities, creature_types, comm)
+/// Run a game from a snapshot with nobody connected, for peers and replays
+/// that only need the simulation
+pub fn init_offline_server(save: SaveGame, defs: Defs) -> Server {
+    let map = Map::new((0, 0, 0), defs.materials);
+    Server::from_save(save, map, defs.creature_types, defs.item_types, defs.building_types,
+                      init_offline())
 }
 
 impl Server {
     // Top level global state
     pub fn new(map: Map, entities: Entities, creature_types: CreatureMap,
-               comm: NetComm) -> Server {
+               item_types: ItemTypes, building_types: BuildingTypes, comm: NetComm) -> Server {
         Server {
-            g_state: GameState::new(map, entities, creature_types),
+            g_state: GameState::new(map, entities, creature_types, item_types, building_types),
             players: HashMap::new(),
             comm: comm,
+            packs: PackInfos::new(),
+            pending: HashSet::new(),
+            dropped: HashMap::new(),
+            leaving: HashMap::new(),
+            conn_players: HashMap::new(),
+            disconnect_policy: DisconnectPolicy::Release,
+            started: Instant::now(),
+            last_heartbeat: None,
+            lobby: None,
+            lockstep: None,
+            record_to: None,
+            recorder: None,
+            frame_ns: FRAME_RATE_NS,
         }
     }
 
+    /// Restore a game from a save. The map supplies the materials
+    /// that the saved tiles refer to.
+    pub fn from_save(save: SaveGame, map: Map, creature_types: CreatureMap,
+                     item_types: ItemTypes, building_types: BuildingTypes,
+                     comm: NetComm) -> Server {
+        let map = save.restore_map(map);
+        let mut server = Server::new(map, save.entities, creature_types, item_types,
+                                     building_types, comm);
+        server.g_state.items = save.items;
+        server.g_state.buildings = save.buildings;
+        server.g_state.ticks = save.ticks;
+        server.g_state.cur_id = save.cur_id;
+
+        for player in save.players {
+            server.players.insert(player.player_id, ServerPlayer {
+                player_id: player.player_id,
+                name: player.name,
+                team_id: player.team_id,
+                tasks: player.tasks,
+                stockpiles: player.stockpiles,
+                ent_baseline: EntBaseline::new(),
+                view: None,
+                bandwidth: 0,
+                last_bytes_sent: 0,
+                resume_token: player.resume_token,
+                rtt: None,
+                last_heard: None,
+                spectator: false,
+            });
+        }
+
+        server
+    }
+
+    /// Apply the settings the server was started with, before it starts. A
+    /// map that can not be found is an error, rather than starting on another.
+    pub fn configure(&mut self, config: &Config) -> Result<(), LobbyError> {
+        self.frame_ns = 1_000_000_000 / config.tick_rate;
+        self.record_to = config.record.clone();
+        if let (Some(map), Some(lobby)) = (config.map_choice(), self.lobby.as_mut()) {
+            try!(lobby.pick_map(map));
+        }
+        Ok(())
+    }
+
+    /// Write the running game to disk
+    pub fn save(&self, path: &Path) -> Result<(), io::Error> {
+        save_game(path, &SaveGame::new(&self.g_state, &self.players))
+    }
+
     pub fn start(&mut self) {
         info!("Server started");
+        if self.lobby.is_some() {
+            info!("Waiting for players in the lobby");
+        } else {
+            self.begin_recording();
+        }
 
         // Game loop
         let mut now = Instant::now();
         loop {
             // Frame Rate Handler
             let time_elapsed = now.elapsed();
-            let frame_dur = Duration::new(0, FRAME_RATE_NS);
+            let frame_dur = Duration::new(0, self.frame_ns);
             if time_elapsed < frame_dur {
                 thread::sleep(frame_dur - time_elapsed);
             }
             now = Instant::now();
 
-            self.update();
+            // The game only ticks once the match has started. Players are
+            // checked on every frame, however long the tick keeps them waiting.
+            if self.lobby.is_some() {
+                self.network_update();
+            } else {
+                self.update();
+                self.heartbeats();
+            }
         }
     }
 
-    pub fn update(&mut self) {
-        // Network Updates
-        while let Some((stream, player_id)) = self.comm.check_incoming_streams() {
-            self.add_player(player_id, stream);
+    /// Start recording to `record_to`, if set
+    fn begin_recording(&mut self) {
+        let path = match self.record_to.clone() {
+            Some(path) => path,
+            None => return,
+        };
+        match self.start_recording(&path) {
+            Ok(()) => info!("Recording the match to {}", path.display()),
+            Err(err) => error!("Could not record to {}: {}", path.display(), err),
         }
+    }
 
-        while let Some((msg, player_id)) = self.comm.check_incoming_msgs() {
-            self.dispatch(msg, player_id);
+    /// Record the game from here on, starting with a snapshot of it
+    pub fn start_recording(&mut self, path: &Path) -> Result<(), io::Error> {
+        let start = SaveGame::new(&self.g_state, &self.players);
+        let lockstep = self.lockstep.is_some();
+        self.recorder = Some(try!(Recorder::create(path, &self.g_state.defs(), &start,
+                                                   lockstep)));
+
+        // The snapshot leaves out connections yet to say hello, and spectators
+        let mut conn_ids: Vec<PlayerID> = self.pending.iter().cloned().collect();
+        conn_ids.sort();
+        for conn_id in conn_ids {
+            self.record(ReplayEvent::Connected(conn_id));
+        }
+        let mut spectators: Vec<(PlayerID, String)> =
+            self.players.values()
+                        .filter(|player| player.spectator)
+                        .map(|player| (player.player_id, player.name.clone()))
+                        .collect();
+        spectators.sort();
+        for (player_id, name) in spectators {
+            self.record(ReplayEvent::Connected(player_id));
+            self.record(ReplayEvent::Message(player_id, ClientMsg::Hello(Hello::spectate(&name))));
+        }
+        Ok(())
+    }
+
+    /// Add an event to the recording, if there is one
+    fn record(&mut self, event: ReplayEvent) {
+        let ticks = self.g_state.ticks;
+        let failed = match self.recorder {
+            Some(ref mut recorder) => recorder.record(ticks, &event).err(),
+            None => None,
+        };
+        if let Some(err) = failed {
+            error!("Stopped recording: {}", err);
+            self.recorder = None;
+        }
+    }
+
+    pub fn update(&mut self) {
+        self.network_update();
+
+        if self.lockstep.is_some() {
+            self.lockstep_update();
+            return;
         }
 
         // Player Updates
@@ -84,52 +312,731 @@ impl Server {
         // World Updates
         let mut changes = self.world_update();
 
+        let mut items_changed = false;
+        let mut buildings_changed = false;
+        let mut ents_changed = HashSet::new();
         for change in changes.drain(..) {
             match change {
                 Change::TileChange(pos) => self.tile_update(pos),
-                Change::EntChange(_) => {},
+                Change::EntChange(ent_id) => { ents_changed.insert(ent_id); },
+                Change::ItemChange(_) => items_changed = true,
+                Change::BuildingChange(_) => buildings_changed = true,
             };
         }
 
+        if items_changed {
+            self.item_updates();
+        }
+
+        if buildings_changed {
+            self.building_updates();
+        }
+
+        // Keyframes put right anything the deltas missed
+        let keyframe = self.g_state.ticks % KEYFRAME_PERIOD == 0;
+        let player_ids: Vec<PlayerID> = self.players.keys().cloned().collect();
+        for player_id in player_ids {
+            self.ent_updates(player_id, &ents_changed, keyframe);
+        }
+
+        if self.g_state.ticks % BANDWIDTH_PERIOD == 0 {
+            self.measure_bandwidth();
+        }
+    }
+
+    /// Run the next tick once its turn is ready and pass the turn on, which is
+    /// all the players need to run the tick themselves
+    fn lockstep_update(&mut self) {
+        let tick = self.g_state.ticks + 1;
+        let turn_takers = self.turn_takers();
+        let turn = match self.lockstep {
+            Some(ref mut lockstep) => lockstep.take_turn(tick, &turn_takers),
+            None => None,
+        };
+        let turn = match turn {
+            Some(turn) => turn,
+            None => return,
+        };
+
+        for player_id in self.players.keys() {
+            self.comm.send_turn(*player_id, tick, turn.clone());
+        }
+        self.run_turn(&turn);
+
+        if tick % CHECKSUM_PERIOD == 0 {
+            let checksum = self.g_state.checksum();
+            if let Some(ref mut lockstep) = self.lockstep {
+                lockstep.record_checksum(tick, checksum);
+            }
+        }
+    }
+
+    /// Run a tick with the commands given for it, the same way on every peer
+    pub fn run_turn(&mut self, turn: &[(PlayerID, ClientMsg)]) -> Vec<Change> {
+        for &(player_id, ref msg) in turn {
+            self.command(msg.clone(), player_id);
+        }
+
+        self.player_update();
+        self.world_update()
+    }
+
+    /// The players that lockstep waits on for turns, in order
+    fn turn_takers(&self) -> Vec<PlayerID> {
+        let mut player_ids: Vec<PlayerID> = self.players.values()
+                                                        .filter(|player| !player.spectator)
+                                                        .map(|player| player.player_id)
+                                                        .collect();
+        player_ids.sort();
+        player_ids
+    }
+
+    fn submit_turn(&mut self, player_id: PlayerID, tick: Ticks, commands: Vec<ClientMsg>) {
+        if self.players.get(&player_id).map_or(true, |player| player.spectator) {
+            warn!("Rejecting a turn from spectator {}", player_id);
+            return;
+        }
+
+        let (commands, rejected): (Vec<ClientMsg>, Vec<ClientMsg>) =
+            commands.into_iter().partition(|msg| msg.is_command());
+        if !rejected.is_empty() {
+            warn!("Player {} put {:?} in a turn", player_id, rejected);
+        }
+
+        let next_tick = self.g_state.ticks + 1;
+        match self.lockstep {
+            Some(ref mut lockstep) => lockstep.submit(player_id, tick, commands, next_tick),
+            None => warn!("Player {} sent a turn outside of lockstep", player_id),
+        }
+    }
+
+    /// Compare a player's game with ours, sending the whole game again if
+    /// they have drifted from it
+    fn check_sync(&mut self, player_id: PlayerID, tick: Ticks, checksum: u64) {
+        let in_sync = self.lockstep.as_ref().and_then(|lockstep| lockstep.check(tick, checksum));
+        if in_sync == Some(false) {
+            error!("Player {} is out of sync as of tick {}", player_id, tick);
+            let save = SaveGame::new(&self.g_state, &self.players).without_tokens();
+            self.comm.send_save(player_id, save);
+        }
+    }
+
+    /// Take in new connections and messages, and see to players that have left
+    pub fn network_update(&mut self) {
+        let mut dropped = Vec::new();
+        while let Some(event) = self.comm.check_conn_events() {
+            match event {
+                ConnEvent::Opened(conn, player_id) => self.connect(player_id, conn),
+                // Dealt with once the player's last messages are in
+                ConnEvent::Closed(conn_id) => dropped.push(conn_id),
+                // Only ever sent to the outgoing thread
+                ConnEvent::Renamed(_, _) | ConnEvent::Answer(_, _) => {},
+                ConnEvent::Unreadable(conn_id, reason) => self.unreadable(conn_id, reason),
+            }
+        }
+
+        while let Some((msg, conn_id)) = self.comm.check_incoming_msgs() {
+            let player_id = self.player_for(conn_id);
+            self.dispatch(msg, player_id);
+        }
+
+        for conn_id in dropped {
+            let player_id = self.player_for(conn_id);
+            // A player that has already come back on another connection stays
+            if self.conn_of(player_id) == conn_id {
+                self.record(ReplayEvent::Dropped(player_id));
+                self.disconnect(player_id, LeaveReason::Dropped);
+            }
+        }
+        self.expire_dropped();
+    }
+
+    /// Check on every player, dropping those that have gone quiet, and send
+    /// heartbeats when they are due. Goes by the clock rather than by ticks.
+    pub fn heartbeats(&mut self) {
+        let timeout = Duration::from_millis(TIMEOUT_MS);
+        let timed_out: Vec<PlayerID> = self.players.values()
+                                                   .filter(|player| {
+                                                       player.last_heard.map_or(false, |heard| {
+                                                           heard.elapsed() >= timeout
+                                                       })
+                                                   })
+                                                   .map(|player| player.player_id)
+                                                   .collect();
+
+        for player_id in timed_out {
+            warn!("Player {} timed out", player_id);
+            // Replays do not keep time, so are told who timed out
+            self.record(ReplayEvent::Dropped(player_id));
+            self.disconnect(player_id, LeaveReason::Dropped);
+        }
+
+        let due = self.last_heartbeat.map_or(true, |sent| {
+            sent.elapsed() >= Duration::from_millis(HEARTBEAT_PERIOD_MS)
+        });
+        if !due {
+            return;
+        }
+        self.last_heartbeat = Some(Instant::now());
+
+        let stamp = stamp_since(self.started);
+        for player_id in self.players.keys() {
+            self.comm.heartbeat(*player_id, stamp);
+        }
+    }
+
+    /// Time how long a player took to answer a heartbeat
+    fn record_rtt(&mut self, player_id: PlayerID, stamp: Stamp) {
+        let sample = stamp_since(self.started).saturating_sub(stamp);
+        if let Some(player) = self.players.get_mut(&player_id) {
+            player.rtt = Some(smooth_rtt(player.rtt, sample));
+            debug!("Player {} has a round trip time of {} ms", player_id, sample);
+        }
+    }
+
+    fn ent_updates(&mut self, player_id: PlayerID, ents_changed: &HashSet<EntID>,
+                   keyframe: bool) {
+        if let Some(player) = self.players.get_mut(&player_id) {
+            let ent_update = player.ent_baseline.update(&self.g_state.entities, ents_changed,
+                                                        keyframe, player.view);
+            if !ent_update.is_empty() {
+                self.comm.send_ents(player_id, ent_update);
+            }
+        }
+    }
+
+    /// Have every entity sent to the player afresh on the next update
+    fn resend_ents(&mut self, player_id: PlayerID) {
+        if let Some(player) = self.players.get_mut(&player_id) {
+            player.ent_baseline.request_keyframe();
+        }
+    }
+
+    /// Work out how many bytes a second each player has been sent since the last measurement
+    fn measure_bandwidth(&mut self) {
+        let seconds = BANDWIDTH_PERIOD as f64 * self.frame_ns as f64 / 1e9;
+        for (player_id, player) in &mut self.players {
+            let bytes_sent = self.comm.bytes_sent(*player_id);
+            player.bandwidth = ((bytes_sent - player.last_bytes_sent) as f64 / seconds) as u64;
+            player.last_bytes_sent = bytes_sent;
+            debug!("Sending player {} {} B/s", player_id, player.bandwidth);
+        }
+    }
+
+    fn item_updates(&mut self) {
         let player_ids: Vec<PlayerID> = self.players.keys().cloned().collect();
         for player_id in player_ids {
-            self.ent_updates(player_id);
+            self.comm.send_items(player_id, self.g_state.items.clone());
         }
     }
 
-    fn ent_updates(&mut self, player_id: PlayerID) {
-        let ent_snaps: EntSnaps = self.g_state.entities.iter_mut()
-                                              .map(|ent| ent.snap())
-                                              .collect();
-        
-        self.comm.send_ents(player_id, ent_snaps);
+    fn building_updates(&mut self) {
+        let player_ids: Vec<PlayerID> = self.players.keys().cloned().collect();
+        for player_id in player_ids {
+            self.comm.send_buildings(player_id, self.g_state.buildings.clone());
+        }
     }
 
     fn tile_update(&mut self, pos: Pos) {
         let tile_snap = self.g_state.map.get_tile(pos).unwrap();
         let player_ids: Vec<PlayerID> = self.players.keys().cloned().collect();
 
+        let origin = chunk_origin(pos);
+
         for player_id in player_ids {
-            self.comm.update_tile(player_id, tile_snap, pos);
+            if self.players[&player_id].view.map_or(true, |view| view.overlaps_chunk(origin)) {
+                self.comm.update_tile(player_id, tile_snap, pos);
+            }
+        }
+    }
+
+    /// Hook up a new connection, which joins once it has said hello
+    pub fn connect(&mut self, player_id: PlayerID, conn: Conn) {
+        info!("Player {} connected on {:?}", player_id, conn);
+        self.comm.setup_out_stream((conn, player_id));
+        self.pending.insert(player_id);
+        self.record(ReplayEvent::Connected(player_id));
+    }
+
+    /// Boot a connection that opened with a message we could not read, such as
+    /// a hello from another protocol version
+    fn unreadable(&mut self, conn_id: PlayerID, reason: BootReason) {
+        if self.pending.remove(&conn_id) {
+            warn!("Rejecting player {}: {}", conn_id, reason);
+            self.comm.boot(conn_id, reason);
+        }
+    }
+
+    /// Check that a connecting player speaks our protocol before letting them join
+    pub fn hello(&mut self, player_id: PlayerID, hello: Hello) {
+        if !self.pending.remove(&player_id) {
+            warn!("Player {} is not waiting to join", player_id);
+            return;
+        }
+
+        if hello.protocol_version != PROTOCOL_VERSION {
+            let reason = BootReason::ProtocolMismatch { server: PROTOCOL_VERSION,
+                                                        client: hello.protocol_version };
+            warn!("Rejecting player {}: {}", player_id, reason);
+            self.comm.boot(player_id, reason);
+            return;
+        }
+
+        // Servers replaying a recording run no packs of their own to compare against
+        if !self.packs.is_empty() {
+            if let Some(reason) = pack_mismatch(&hello.packs, &self.packs) {
+                warn!("Rejecting player {}: {}", player_id, reason);
+                self.comm.boot(player_id, BootReason::PackMismatch(reason));
+                return;
+            }
+        }
+
+        if hello.game_version != GAME_VERSION {
+            warn!("Player {} runs game version {} against our {}", player_id,
+                  hello.game_version, GAME_VERSION);
+        }
+
+        if let Some(resume_token) = hello.resume_token {
+            if self.resume(player_id, resume_token, &hello.name) {
+                return;
+            }
+            warn!("Player {} could not resume, so is joining afresh", player_id);
+        }
+
+        if hello.spectate {
+            self.add_spectator(player_id, &hello.name);
+            return;
+        }
+
+        if let Some(ref mut lobby) = self.lobby {
+            info!("Player {} joined the lobby as {}", player_id, hello.name);
+            lobby.join(player_id, &hello.name);
+            self.comm.send_packs(player_id, self.packs.clone());
+        }
+        if self.lobby.is_some() {
+            self.lobby_updates();
+            return;
+        }
+
+        self.add_player(player_id, &hello.name);
+    }
+
+    /// Let someone watch the game. Spectators that turn up before the match
+    /// starts watch the lobby until it does.
+    fn add_spectator(&mut self, player_id: PlayerID, name: &str) {
+        info!("Player {} is spectating as {}", player_id, name);
+        let mut spectator = ServerPlayer::spectator(player_id);
+        spectator.name = name.to_owned();
+        self.players.insert(player_id, spectator);
+
+        if self.lobby.is_some() {
+            self.comm.send_packs(player_id, self.packs.clone());
+            self.lobby_updates();
+        } else {
+            self.add_player(player_id, name);
+        }
+    }
+
+    /// Send everyone in the lobby, and those watching it, how it looks now
+    fn lobby_updates(&mut self) {
+        if let Some(ref lobby) = self.lobby {
+            let spectator_ids = self.players.values()
+                                            .filter(|player| player.spectator)
+                                            .map(|player| player.player_id);
+            for player_id in lobby.state.players.iter().map(|player| player.player_id)
+                                                       .chain(spectator_ids) {
+                self.comm.send_lobby(player_id, lobby.state.clone());
+            }
+        }
+    }
+
+    fn lobby_dispatch(&mut self, msg: ClientMsg, player_id: PlayerID) {
+        {
+            let lobby = self.lobby.as_mut().unwrap();
+            let result = match msg {
+                ClientMsg::SetName(ref name) => {
+                    lobby.set_name(player_id, name);
+                    Ok(())
+                },
+                ClientMsg::SetTeam(team) => {
+                    lobby.set_team(player_id, team);
+                    Ok(())
+                },
+                ClientMsg::SetReady(ready) => {
+                    lobby.set_ready(player_id, ready);
+                    Ok(())
+                },
+                ClientMsg::SetMatch(ref settings) => lobby.set_settings(player_id, settings.clone()),
+                ClientMsg::StartMatch() => lobby.check_start(player_id),
+                _ => Ok(()),
+            };
+
+            if let Err(err) = result {
+                warn!("Lobby: {}", err);
+                return;
+            }
+        }
+
+        match msg {
+            ClientMsg::StartMatch() => self.start_match(),
+            ClientMsg::Heartbeat(stamp) => self.comm.ack(player_id, stamp),
+            ClientMsg::Leave() => self.disconnect(player_id, LeaveReason::Quit),
+            ClientMsg::SetName(_) | ClientMsg::SetTeam(_) | ClientMsg::SetReady(_) |
+            ClientMsg::SetMatch(_) => self.lobby_updates(),
+            ClientMsg::Ack(_) => {},
+            msg => warn!("Player {} sent {:?} before the match started", player_id, msg),
         }
     }
 
-    pub fn add_player(&mut self, player_id: PlayerID, stream: TcpStream) {
-        self.players.insert(player_id, ServerPlayer::new(player_id, Some(player_id)));
+    /// Leave the lobby for the game, setting up the map and each team's units
+    pub fn start_match(&mut self) {
+        let lobby = match self.lobby.take() {
+            Some(lobby) => lobby,
+            None => return,
+        };
 
-        let player_join = PlayerJoin::new(player_id, Some(player_id), self.g_state.map.size());
+        match lobby.build_map(self.g_state.map.materials.clone()) {
+            Ok(map) => self.g_state.map = map,
+            Err(err) => {
+                error!("Could not make the map {}: {}", lobby.state.settings.map, err);
+                self.lobby = Some(lobby);
+                return;
+            },
+        }
+        info!("Starting the match on {}", lobby.state.settings.map);
+
+        self.g_state.entities = Entities::new();
+        let teams = lobby.teams();
+        for (index, team) in teams.iter().enumerate() {
+            self.spawn_team(*team, index, teams.len(), &lobby.state.settings);
+        }
 
-        info!("Adding Player {} at addr {:?}", player_id, stream);
-        self.comm.setup_out_stream((stream, player_id));
+        let spectators: Vec<(PlayerID, String)> =
+            self.players.values()
+                        .filter(|player| player.spectator)
+                        .map(|player| (player.player_id, player.name.clone()))
+                        .collect();
+        // Everyone is in before anyone is sent the game, so lockstep players
+        // all start from the same snapshot
+        for player in &lobby.state.players {
+            self.players.insert(player.player_id,
+                                ServerPlayer::new(player.player_id, Some(player.team)));
+        }
+        if lobby.state.settings.lockstep {
+            self.lockstep = Some(Lockstep::new());
+        }
+        for player in &lobby.state.players {
+            self.add_player(player.player_id, &player.name);
+        }
+        for (player_id, name) in spectators {
+            self.add_player(player_id, &name);
+        }
+        self.begin_recording();
+    }
+
+    /// Place a team's starting units around a spot on the surface, with the
+    /// teams spread out across the map
+    fn spawn_team(&mut self, team: u16, index: usize, num_teams: usize,
+                  settings: &MatchSettings) {
+        let (xlen, ylen, _) = self.g_state.map.size();
+        let x = xlen * (index as PosUnit + 1) / (num_teams as PosUnit + 1);
+        let spot = (x, ylen / 2, 0);
+
+        let mut placed = 0;
+        for radius in 0..SPAWN_RADIUS {
+            for dx in -radius..(radius + 1) {
+                for dy in -radius..(radius + 1) {
+                    let pos = (spot.0 + dx, spot.1 + dy, spot.2);
+                    let taken = self.g_state.entities.iter().any(|ent| ent.pos == pos);
+                    let on_edge = dx.abs() == radius || dy.abs() == radius;
+                    if placed < settings.start_units && on_edge && !taken &&
+                       self.g_state.map.passable(pos) {
+                        let id = self.g_state.give_id();
+                        let ent = Entity::new(id, settings.start_creature, pos, Some(team),
+                                              &self.g_state.creature_types);
+                        self.g_state.entities.push(ent);
+                        placed += 1;
+                    }
+                }
+            }
+        }
+
+        if placed < settings.start_units {
+            warn!("Only found room for {} of team {}'s units", placed, team);
+        }
+    }
+
+    /// Put a player that dropped back in their place, on their new connection,
+    /// returning whether there was anyone with the token to resume
+    fn resume(&mut self, conn_id: PlayerID, resume_token: ResumeToken, name: &str) -> bool {
+        let dropped_id = self.dropped.iter()
+                                     .find(|&(_, &(ref player, _))| {
+                                         player.resume_token == resume_token
+                                     })
+                                     .map(|(player_id, _)| *player_id);
+        // The old connection may not have been noticed as dead yet
+        let connected_id = self.players.values()
+                                       .find(|player| player.resume_token == resume_token)
+                                       .map(|player| player.player_id);
+
+        let player_id = match (dropped_id, connected_id) {
+            (Some(player_id), _) => {
+                let (player, _) = self.dropped.remove(&player_id).unwrap();
+                self.players.insert(player_id, player);
+                player_id
+            },
+            (None, Some(player_id)) => player_id,
+            (None, None) => return false,
+        };
+
+        info!("Player {} is back as player {}", conn_id, player_id);
+        self.conn_players.retain(|_, id| *id != player_id);
+        if conn_id != player_id {
+            self.conn_players.insert(conn_id, player_id);
+            self.comm.rename(conn_id, player_id);
+        }
+
+        self.add_player(player_id, name);
+        true
+    }
+
+    pub fn add_player(&mut self, player_id: PlayerID, name: &str) {
+        // Players restored from a save keep their pending tasks, and those
+        // joining after the match started get a team of their own
+        let free_team = self.free_team();
+        {
+            let player = self.players.entry(player_id)
+                                     .or_insert_with(|| ServerPlayer::new(player_id, free_team));
+            player.name = name.to_owned();
+            // Everything is sent afresh, as to a new player
+            player.ent_baseline = EntBaseline::new();
+            player.view = None;
+            player.last_heard = Some(Instant::now());
+            // The count of bytes sent starts over with each connection
+            player.last_bytes_sent = self.comm.bytes_sent(player_id);
+        }
+
+        let (team_id, resume_token, spectator) = {
+            let player = &self.players[&player_id];
+            (player.team_id, player.resume_token, player.spectator)
+        };
+        let player_join = PlayerJoin::new(player_id, team_id, self.g_state.map.size(),
+                                          resume_token, spectator);
+
+        info!("Adding Player {} as {}", player_id, name);
         self.comm.reply_join(player_id, player_join);
+        self.comm.send_packs(player_id, self.packs.clone());
+        self.comm.send_defs(player_id, self.g_state.defs());
+
+        self.comm.send_items(player_id, self.g_state.items.clone());
+        self.comm.send_buildings(player_id, self.g_state.buildings.clone());
+        self.send_stockpiles(player_id);
+
+        // Lockstep players run the game themselves, so need all of it
+        if self.lockstep.is_some() {
+            let save = SaveGame::new(&self.g_state, &self.players).without_tokens();
+            self.comm.send_save(player_id, save);
+        } else if spectator {
+            // Spectators see everything, so get the whole map up front
+            let (xlen, ylen, zlen) = self.g_state.map.size();
+            self.send_map(player_id, ((0, 0, 0), (xlen - 1, ylen - 1, zlen - 1)));
+        }
+    }
+
+    /// The lowest team that no player, even a dropped one, is on
+    fn free_team(&self) -> TeamID {
+        let taken: HashSet<TeamID> = self.players.values()
+                                                 .chain(self.dropped.values()
+                                                                    .map(|&(ref player, _)| player))
+                                                 .map(|player| player.team_id)
+                                                 .collect();
+        (1..).map(Some).find(|team| !taken.contains(team)).unwrap()
+    }
+
+    /// The player a connection speaks for, which is only ever not the
+    /// connection's own id for resumed players
+    fn player_for(&self, conn_id: PlayerID) -> PlayerID {
+        self.conn_players.get(&conn_id).cloned().unwrap_or(conn_id)
+    }
+
+    /// The connection a player is currently on
+    fn conn_of(&self, player_id: PlayerID) -> PlayerID {
+        self.conn_players.iter()
+                         .find(|&(_, id)| *id == player_id)
+                         .map_or(player_id, |(conn_id, _)| *conn_id)
+    }
+
+    /// Take a player out of the game and let everyone else know. Players that
+    /// quit have their things handed on as the policy says, while those whose
+    /// connection dropped are held onto for a while in case they come back.
+    pub fn disconnect(&mut self, player_id: PlayerID, reason: LeaveReason) {
+        self.pending.remove(&player_id);
+        self.comm.hang_up(player_id);
+
+        let left_lobby = self.lobby.as_mut().map_or(false, |lobby| lobby.leave(player_id));
+        if left_lobby {
+            info!("Player {} left the lobby", player_id);
+            self.lobby_updates();
+        }
+
+        let player = match self.players.remove(&player_id) {
+            Some(player) => player,
+            None => return,
+        };
+        info!("Player {} {}", player_id, reason);
+
+        match reason {
+            LeaveReason::Quit => {
+                self.conn_players.retain(|_, id| *id != player_id);
+                self.hand_on(player);
+            },
+            LeaveReason::Dropped => {
+                self.dropped.insert(player_id, (player, self.g_state.ticks));
+            },
+        }
+
+        let player_ids: Vec<PlayerID> = self.players.keys().cloned().collect();
+        for other_id in player_ids {
+            self.comm.player_left(other_id, player_id, reason);
+        }
+    }
+
+    /// Give up on dropped players that have not come back in time
+    fn expire_dropped(&mut self) {
+        let ticks = self.g_state.ticks;
+        let expired: Vec<PlayerID> = self.dropped.iter()
+                                                 .filter(|&(_, &(_, since))| {
+                                                     ticks - since >= RESUME_PERIOD
+                                                 })
+                                                 .map(|(player_id, _)| *player_id)
+                                                 .collect();
+
+        for player_id in expired {
+            info!("Player {} did not come back", player_id);
+            let (player, _) = self.dropped.remove(&player_id).unwrap();
+            self.conn_players.retain(|_, id| *id != player_id);
+            self.hand_on(player);
+        }
+    }
+
+    /// Hand a player's things on as the policy says, once they are gone for good.
+    /// In lockstep that changes the game, so it waits for the next turn.
+    fn hand_on(&mut self, player: ServerPlayer) {
+        if player.spectator {
+            return;
+        }
+
+        // Player ids are handed out in order, so the lowest has been around longest.
+        // Teammates still in the game carry on with the team's things whatever
+        // the policy, taking over the player's tasks and stockpiles.
+        let teammate_id = self.players.values()
+                                      .filter(|other| !other.spectator &&
+                                                      other.team_id == player.team_id)
+                                      .map(|other| other.player_id)
+                                      .min();
+        let heir_id = match self.disconnect_policy {
+            DisconnectPolicy::Reassign => self.players.values()
+                                                      .filter(|player| !player.spectator)
+                                                      .map(|player| player.player_id)
+                                                      .min(),
+            DisconnectPolicy::Release => None,
+        };
+
+        let next_tick = self.g_state.ticks + 1;
+        if let Some(ref mut lockstep) = self.lockstep {
+            lockstep.add(next_tick, player.player_id, ClientMsg::HandOn(teammate_id.or(heir_id)));
+            self.leaving.insert(player.player_id, player);
+            return;
+        }
+
+        self.hand_over(player, teammate_id.or(heir_id));
+    }
+
+    /// Give a player's things to their heir, or release them if there is none
+    fn hand_over(&mut self, player: ServerPlayer, heir_id: Option<PlayerID>) {
+        match heir_id {
+            Some(heir_id) if self.players.contains_key(&heir_id) => self.reassign(player, heir_id),
+            _ => self.release(player),
+        }
+
+        // Entities may have changed teams
+        let player_ids: Vec<PlayerID> = self.players.keys().cloned().collect();
+        for player_id in player_ids {
+            self.resend_ents(player_id);
+        }
+        self.building_updates();
+    }
+
+    fn release(&mut self, player: ServerPlayer) {
+        for ent in self.g_state.entities.iter_mut().filter(|ent| ent.team_id == player.team_id) {
+            ent.team_id = None;
+            ent.actions.clear();
+            ent.goal = None;
+        }
+
+        for building in self.g_state.buildings.iter_mut()
+                                              .filter(|bld| bld.team_id == player.team_id) {
+            building.team_id = None;
+            building.queue.clear();
+            building.timer = 0;
+        }
 
-        self.send_map(player_id);
+        for task in &player.tasks {
+            if let ActionType::Dig(pos) = task.atype {
+                self.g_state.map.unmark(pos);
+                self.tile_update(pos);
+            }
+        }
     }
 
-    pub fn send_map(&mut self, player_id: PlayerID) {
-        let chunks = self.g_state.map.to_chunks(); 
-        for chunk in &chunks {
-            self.comm.send_map_chunk(player_id, chunk);
+    fn reassign(&mut self, player: ServerPlayer, heir_id: PlayerID) {
+        let heir = self.players.get_mut(&heir_id).unwrap();
+        info!("Handing player {}'s game to player {}", player.player_id, heir_id);
+
+        for ent in self.g_state.entities.iter_mut().filter(|ent| ent.team_id == player.team_id) {
+            ent.team_id = heir.team_id;
+        }
+
+        for building in self.g_state.buildings.iter_mut()
+                                              .filter(|bld| bld.team_id == player.team_id) {
+            building.team_id = heir.team_id;
+        }
+
+        // Tasks keep their owners, who carry on with them
+        heir.tasks.extend(player.tasks);
+        heir.stockpiles.extend(player.stockpiles);
+        self.comm.send_stockpiles(heir_id, heir.stockpiles.clone());
+    }
+
+    fn send_stockpiles(&mut self, player_id: PlayerID) {
+        if let Some(player) = self.players.get(&player_id) {
+            self.comm.send_stockpiles(player_id, player.stockpiles.clone());
+        }
+    }
+
+    /// Send the chunks that overlap a block of the map
+    pub fn send_map(&mut self, player_id: PlayerID, selection: (Pos, Pos)) {
+        let View { start, end } = View::new(selection.0, selection.1);
+        for origin in self.g_state.map.chunks_in(start, end) {
+            let chunk = self.g_state.map.get_chunk_at(origin);
+            self.comm.send_map_chunk(player_id, &chunk);
+        }
+    }
+
+    /// Subscribe a player to updates around their camera
+    fn set_view(&mut self, player_id: PlayerID, view: View) {
+        if let Some(player) = self.players.get_mut(&player_id) {
+            // Spectators keep getting updates from everywhere
+            if player.spectator {
+                return;
+            }
+
+            let view = Some(view.grow(VIEW_MARGIN));
+            if player.view != view {
+                player.view = view;
+                // Entities coming into view need spawning, and those leaving despawning
+                player.ent_baseline.move_view();
+            }
         }
     }
 
@@ -139,10 +1046,53 @@ impl Server {
     }
 
     pub fn player_update(&mut self) {
-        for player in self.players.values_mut() {
+        // Stockpiling looks over every item, so only every so often
+        let stockpiling = self.g_state.ticks % HAUL_PERIOD == 0;
+        // Peers only hear of players dropping once they are handed on, so in
+        // lockstep those players' units keep at their tasks until then
+        let lockstep = self.lockstep.is_some();
+        let dropped = self.dropped.values_mut()
+                                  .filter(|_| lockstep)
+                                  .map(|&mut (ref mut player, _)| player);
+        let mut players: Vec<&mut ServerPlayer> = self.players.values_mut()
+                                                              .chain(dropped)
+                                                              .filter(|player| !player.spectator)
+                                                              .collect();
+        let items = &self.g_state.items;
+        let claims: Vec<(PlayerID, HashSet<ItemID>)> = if stockpiling {
+            players.iter()
+                   .map(|player| (player.player_id,
+                                  claimed_items(&player.tasks, &player.stockpiles, items)))
+                   .collect()
+        } else {
+            Vec::new()
+        };
+
+        // Go in order so every lockstep peer schedules the same way
+        players.sort_by_key(|player| player.player_id);
+        for player in players {
+            prune_haul_tasks(&mut player.tasks, &self.g_state.entities, &self.g_state.items);
+            // Construction comes first so materials are not stockpiled out from under it
+            add_construction_tasks(&mut player.tasks, &self.g_state.buildings,
+                                   &self.g_state.building_types, &self.g_state.items,
+                                   &self.g_state.entities, &self.g_state.map, player.team_id);
+            if stockpiling && !player.stockpiles.is_empty() {
+                let starts: Vec<Pos> = self.g_state.entities.iter()
+                                                            .filter(|ent| ent.alive &&
+                                                                    ent.team_id == player.team_id)
+                                                            .map(|ent| ent.pos)
+                                                            .collect();
+                let claimed: HashSet<ItemID> = claims.iter()
+                                                     .filter(|&&(id, _)| id != player.player_id)
+                                                     .flat_map(|&(_, ref ids)| ids.iter().cloned())
+                                                     .collect();
+                add_haul_tasks(&mut player.tasks, &player.stockpiles, &self.g_state.items,
+                               &self.g_state.map, &reachable(&self.g_state.map, &starts),
+                               &claimed);
+            }
             schedule_actions(&mut self.g_state.entities, &mut player.tasks,
                              &self.g_state.map, &self.g_state.creature_types,
-                             Some(player.player_id))
+                             &self.g_state.items, player.team_id)
         }
     }
 
@@ -156,6 +1106,66 @@ impl Server {
         }
     }
 
+    /// Designate a zone that loose items matching the filter get hauled to
+    pub fn mark_stockpile(&mut self, player_id: PlayerID, selection: (Pos, Pos),
+                          filter: Vec<ItemTypeID>) {
+        if let Some(player) = self.players.get_mut(&player_id) {
+            player.stockpiles.push(Stockpile::new(selection, filter));
+        }
+        self.send_stockpiles(player_id);
+    }
+
+    /// Lay down a construction site, which is built once its materials are hauled over
+    pub fn place_building(&mut self, player_id: PlayerID, type_id: BuildingTypeID, pos: Pos) {
+        let team_id = match self.players.get(&player_id) {
+            Some(player) => player.team_id,
+            None => return,
+        };
+
+        if can_place(type_id, pos, &self.g_state.map, &self.g_state.buildings,
+                     &self.g_state.building_types) {
+            let id = self.g_state.give_id();
+            self.g_state.buildings.push(Building::new(id, type_id, pos, team_id));
+            self.building_updates();
+        }
+    }
+
+    /// Add a unit to the back of a building's training queue
+    pub fn queue_unit(&mut self, player_id: PlayerID, building_id: BuildingID,
+                      creature_id: CreatureID) {
+        let team_id = match self.players.get(&player_id) {
+            Some(player) => player.team_id,
+            None => return,
+        };
+
+        let building_types = &self.g_state.building_types;
+        let queued = match self.g_state.buildings.iter_mut()
+                                                 .find(|bld| bld.id == building_id) {
+            Some(ref mut building) if building.team_id == team_id => {
+                let can_train = building_types.get(&building.type_id)
+                                              .map_or(false, |bld_type| {
+                                                  bld_type.spawns.contains(&creature_id)
+                                              });
+                if can_train {
+                    building.queue.push_back(creature_id);
+                }
+                can_train
+            },
+            _ => false,
+        };
+
+        if queued {
+            self.building_updates();
+        }
+    }
+
+    /// Have one of the player's entities carry an item to dest
+    pub fn haul(&mut self, player_id: PlayerID, item_id: ItemID, dest: Pos) {
+        if let Some(player) = self.players.get_mut(&player_id) {
+            player.tasks.push(Task::haul(item_id, dest));
+        }
+    }
+
     pub fn attack(&mut self, player_id: PlayerID, attacker_id: EntID, target_id: EntID) {
         if let Some(player) = self.players.get(&player_id) {
             let (mut attackers, mut defenders): (Vec<&mut Entity>, Vec<&mut Entity>) =
@@ -166,9 +1176,11 @@ impl Server {
                                            .find(|ent| ent.id == target_id) {
                 if let Some(mut attacker) = attackers.iter_mut()
                                                      .find(|ent| ent.id == attacker_id) {
-                    let (_, goal) = Action::attack(target.id, target.pos, attacker.creature_id,
-                                                   &self.g_state.creature_types);
-                    attacker.goal = Some(goal);
+                    if let Some((_, goal)) = Action::attack(target.id, target.pos, attacker.pos,
+                                                            attacker.creature_id,
+                                                            &self.g_state.creature_types) {
+                        attacker.goal = Some(goal);
+                    }
                 }
             }
         }
@@ -176,14 +1188,88 @@ impl Server {
 
     pub fn dispatch(&mut self, msg: ClientMsg, player_id: PlayerID) {
         debug!("Msg: {:?}", msg);
+        if self.recorder.is_some() {
+            self.record(ReplayEvent::Message(player_id, msg.clone()));
+        }
+
+        // Nothing but hello is accepted until the handshake is done
+        if self.pending.contains(&player_id) {
+            match msg {
+                ClientMsg::Hello(hello) => self.hello(player_id, hello),
+                ClientMsg::Leave() => self.disconnect(player_id, LeaveReason::Quit),
+                _ => {
+                    self.pending.remove(&player_id);
+                    self.comm.boot(player_id, BootReason::NoHello);
+                },
+            }
+            return;
+        }
+
+        if self.lobby.is_some() {
+            self.lobby_dispatch(msg, player_id);
+            return;
+        }
+
+        let spectator = match self.players.get_mut(&player_id) {
+            Some(player) => {
+                player.last_heard = Some(Instant::now());
+                player.spectator
+            },
+            None => false,
+        };
+
+        if spectator && msg.is_command() {
+            warn!("Rejecting {:?} from spectator {}", msg, player_id);
+            return;
+        }
+
+        // Commands only run as part of a turn in lockstep
+        if self.lockstep.is_some() && msg.is_command() {
+            warn!("Player {} sent {:?} outside of a turn", player_id, msg);
+            return;
+        }
+
+        match msg {
+            ClientMsg::Hello(_) => warn!("Player {} said hello twice", player_id),
+            ClientMsg::Heartbeat(stamp) => self.comm.ack(player_id, stamp),
+            ClientMsg::Ack(stamp) => self.record_rtt(player_id, stamp),
+            ClientMsg::RequestMap(selection) => self.send_map(player_id, selection),
+            ClientMsg::SetView(view) => self.set_view(player_id, view),
+            ClientMsg::RequestEnts() => self.resend_ents(player_id),
+            ClientMsg::Turn(tick, commands) => self.submit_turn(player_id, tick, commands),
+            ClientMsg::Checksum(tick, checksum) => self.check_sync(player_id, tick, checksum),
+            ClientMsg::Leave() => self.disconnect(player_id, LeaveReason::Quit),
+            ClientMsg::SetName(_) | ClientMsg::SetTeam(_) | ClientMsg::SetReady(_) |
+            ClientMsg::SetMatch(_) | ClientMsg::StartMatch() => {
+                warn!("Player {} sent {:?} after the match started", player_id, msg)
+            },
+            ClientMsg::HandOn(_) => {
+                warn!("Player {} sent {:?}, which only we give", player_id, msg)
+            },
+            msg => self.command(msg, player_id),
+        }
+    }
+
+    /// Carry out an order from a player
+    fn command(&mut self, msg: ClientMsg, player_id: PlayerID) {
         match msg {
-            ClientMsg::RequestMap(_) => self.send_map(0),
-            ClientMsg::RequestEnts() => {},
             ClientMsg::MarkDig(sel) => self.dig(player_id, sel),
+            ClientMsg::MarkStockpile(sel, filter) => self.mark_stockpile(player_id, sel, filter),
+            ClientMsg::PlaceBuilding(type_id, pos) => self.place_building(player_id, type_id, pos),
+            ClientMsg::QueueUnit(building_id, creature_id) => {
+                self.queue_unit(player_id, building_id, creature_id)
+            },
             ClientMsg::EntAttack(attacker, target) => self.attack(player_id, attacker, target),
             ClientMsg::EntMove(ent_id, pos) => self.ent_move(ent_id, pos),
-            ClientMsg::Leave() => {}, 
-            _ => unimplemented!(),
+            ClientMsg::HandOn(heir_id) => {
+                // Peers still have the player, while we took them out on their leaving
+                let player = self.players.remove(&player_id)
+                                         .or_else(|| self.leaving.remove(&player_id));
+                if let Some(player) = player {
+                    self.hand_over(player, heir_id);
+                }
+            },
+            msg => warn!("{:?} from player {} is not a command", msg, player_id),
         }
     }
 }
@@ -192,8 +1278,22 @@ impl ServerPlayer {
     pub fn new(player_id: PlayerID, team_id: TeamID) -> ServerPlayer {
         ServerPlayer {
             player_id: player_id,
+            name: String::new(),
             team_id: team_id,
             tasks: Vec::new(),
+            stockpiles: Stockpiles::new(),
+            ent_baseline: EntBaseline::new(),
+            view: None,
+            bandwidth: 0,
+            last_bytes_sent: 0,
+            resume_token: new_resume_token(),
+            rtt: None,
+            last_heard: None,
+            spectator: false,
         }
     }
+
+    pub fn spectator(player_id: PlayerID) -> ServerPlayer {
+        ServerPlayer { spectator: true, ..ServerPlayer::new(player_id, None) }
+    }
 }
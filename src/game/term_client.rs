@@ -1,16 +1,29 @@
+use std::collections::HashSet;
+use std::path::Path;
+
 use ncurses::*;
 
+use config::DEFAULT_NAME;
+use defs::base::Defs;
+use defs::packs::{PackInfos, init_packs, pack_infos};
 use entities::creatures::CreatureMap;
-use entities::entity::{Entities, EntID, EntSnaps};
+use entities::entity::{Entities, EntID};
 use entities::actions::{select_entities};
 use game::base::*;
+use game::lobby::{LobbyPlayer, LobbyState};
+use game::lockstep::{CHECKSUM_PERIOD, Replica};
 use io::base::*;
 use io::constants::*;
 use io::utils::*;
 use io::term::*;
-use map::tiles::{Map, MapSnapshot, handle_to_snapshot};
-use net::base::{ServerMsg, PlayerJoin};
+use map::material::Materials;
+use map::tiles::{Map, MapChunk, MapSnapshot, handle_to_snapshot};
+use game::save::SaveGame;
+use net::base::{ClientMsg, EntUpdate, PlayerJoin, ResumeToken, ServerMsg};
 use net::client::NetComm;
+use objects::buildings::{BuildingTypes, Buildings};
+use objects::items::{ItemTypes, Items};
+use objects::stockpiles::Stockpiles;
 
 
 pub struct TermClient {
@@ -18,6 +31,8 @@ pub struct TermClient {
     pub team_id: TeamID,
 
     pub ch: CameraHandle,
+    // Origins of the map chunks received from around the camera
+    loaded_chunks: HashSet<Pos>,
     mouse_pos: WinPos,
     pub selector: Option<Selector>,
     // Keep track of click down to detect if entity has been clicked
@@ -28,44 +43,88 @@ pub struct TermClient {
     sel_state: SelState,
     // Whether the client is finished or not, such as if it has been booted by the server
     pub done: bool,
+    // What we go by on the server
+    pub name: String,
+    // The local data packs, which have to match the server's
+    pub packs: PackInfos,
+    // For getting our place back if the connection drops
+    resume_token: Option<ResumeToken>,
+    // Spectators watch the whole map and can not give orders
+    pub spectating: bool,
+    // In lockstep, our own copy of the game that the server's turns are run on
+    replica: Option<Replica>,
+    // Who is in the lobby and how the match is set up, until the match starts,
+    // along with our id there
+    pub lobby: Option<LobbyState>,
+    lobby_id: Option<PlayerID>,
     
     // Graphics and IO
     comm: NetComm,
 
     // State to sync from GameState
     pub creature_types: CreatureMap,
+    pub item_types: ItemTypes,
+    pub building_types: BuildingTypes,
     pub entities: Entities,
+    pub items: Items,
+    pub buildings: Buildings,
+    pub stockpiles: Stockpiles,
     pub map: Map,
     pub ticks: Ticks,
 }
 
-pub fn init_client(map: Map, entities: Entities, creature_types: CreatureMap,
-                   comm: NetComm) -> TermClient {
-    TermClient::new(map, entities, creature_types, comm)
+/// Start a client on a connection to a server. As with the graphical client,
+/// the map, definitions and entities all come from the server.
+pub fn init_client(root: &Path, comm: NetComm, name: &str, spectate: bool) -> TermClient {
+    let packs = pack_infos(&init_packs(root)).expect("Could not hash data packs");
+    if spectate {
+        comm.spectate(name, &packs);
+    } else {
+        comm.hello(name, &packs);
+    }
+    let mut client = TermClient::new(Map::new((0, 0, 0), Materials::new()), Entities::new(),
+                                     CreatureMap::new(), ItemTypes::new(), comm);
+    client.name = name.to_owned();
+    client.packs = packs;
+    client
 }
 
 impl TermClient {
     // Top level global state
 pub fn new(map: Map,  entities: Entities, 
-               creature_types: CreatureMap, comm: NetComm) -> TermClient {
+               creature_types: CreatureMap, item_types: ItemTypes,
+               comm: NetComm) -> TermClient {
         TermClient {
             // Start with none and initialize when connected
             player_id: None,
             team_id: None,
 
             ch: CameraHandle::new(80, 40, 0, 0, 1),
+            loaded_chunks: HashSet::new(),
             mouse_pos: (0.0, 0.0),
             selector: None,
             selected_entities: Vec::new(),
             selector_start: None,
             sel_state: SelState::Ents,
             done: false,
+            name: DEFAULT_NAME.to_owned(),
+            packs: PackInfos::new(),
+            resume_token: None,
+            spectating: false,
+            replica: None,
+            lobby: None,
+            lobby_id: None,
 
             comm: comm,
 
             map: map,
             creature_types: creature_types,
+            item_types: item_types,
+            building_types: BuildingTypes::new(),
             entities: entities,
+            items: Items::new(),
+            buildings: Buildings::new(),
+            stockpiles: Stockpiles::new(),
             ticks: 0,
         }
     }
@@ -84,6 +143,12 @@ pub fn new(map: Map,  entities: Entities,
                 self.dispatch(msg);
             }
 
+            if !self.done && !self.comm.connected() {
+                self.reconnect();
+            }
+            self.comm.keep_alive();
+            self.comm.send_turns(self.ticks);
+
             if self.done {
                 end_term();      
                 break;
@@ -96,6 +161,21 @@ pub fn new(map: Map,  entities: Entities,
         //TODO Enable key bindings
         //TODO Allow char instead of raw ascii 
 		let ch = getch();
+        if self.lobby.is_some() {
+            let func = match ch {
+              114       => TermClient::toggle_ready,
+              116       => TermClient::next_team,
+              109       => TermClient::next_map,
+              103       => TermClient::start_match,
+              108       => TermClient::toggle_lockstep,
+              81        => TermClient::exit,
+              _         => TermClient::null,
+            };
+
+            func(self);
+            return;
+        }
+
         let func = match ch {
           KEY_LEFT  => TermClient::left,
           KEY_RIGHT => TermClient::right,
@@ -132,67 +212,128 @@ pub fn new(map: Map,  entities: Entities,
     pub fn dispatch(&mut self, msg: ServerMsg) {
         match msg {
             ServerMsg::ReplyJoin(player_join) => self.join(player_join),
-            ServerMsg::SendEnts(ent_snaps) => self.update_ents(ent_snaps),
-            ServerMsg::SendMapChunk(chunk) => self.map.apply_chunk(&chunk),
+            // The server has already checked them against ours
+            ServerMsg::SendPacks(packs) => info!("Server runs packs {:?}", packs),
+            ServerMsg::SendDefs(defs) => self.load_defs(defs),
+            ServerMsg::SendEnts(ent_update) => self.update_ents(ent_update),
+            ServerMsg::SendMapChunk(chunk) => self.load_chunk(&chunk),
             ServerMsg::UpdateTile(tile, pos) => self.map.update_tile(tile, pos),
-            ServerMsg::Boot() => {
-                warn!("Booted");
+            ServerMsg::SendItems(items) => self.items = items,
+            ServerMsg::SendStockpiles(stockpiles) => self.stockpiles = stockpiles,
+            ServerMsg::SendBuildings(buildings) => self.buildings = buildings,
+            ServerMsg::SendLobby(player_id, lobby) => self.update_lobby(player_id, lobby),
+            ServerMsg::SendSave(save) => self.start_lockstep(save),
+            ServerMsg::Turn(tick, turn) => self.run_turn(tick, &turn),
+            ServerMsg::PlayerLeft(player_id, reason) => info!("Player {} {}", player_id, reason),
+            ServerMsg::Heartbeat(stamp) => self.comm.ack(stamp),
+            ServerMsg::Ack(stamp) => self.comm.record_rtt(stamp),
+            ServerMsg::Boot(reason) => {
+                warn!("Booted: {}", reason);
                 self.done = true;
             }
-            _ =>  unimplemented!(),
         };
     }
 
     fn join(&mut self, player_join: PlayerJoin) {
         self.player_id = Some(player_join.player_id);
         self.team_id = player_join.team_id;
-        // Currently there is no mulitplayer
-        info!("Joined as Player {}", player_join.player_id);
+        self.resume_token = Some(player_join.resume_token);
+        self.spectating = player_join.spectator;
+        self.lobby = None;
+        clear();
+        if player_join.spectator {
+            info!("Spectating as Player {}", player_join.player_id);
+        } else {
+            info!("Joined as Player {}", player_join.player_id);
+        }
 
         self.map.resize(player_join.map_dim);
 
-        self.comm.request_map(((0, 0, 0), (0, 0, 0)));
+        self.follow_camera();
     }
 
-    fn update_ents(&mut self, ent_snaps: EntSnaps) {
-        for ent_snap in ent_snaps {
-            for ent in &mut self.entities {
-                if ent_snap.id == ent.id {
-                    ent.pos = ent_snap.pos;
-                    ent.health = ent_snap.health;
-                    ent.alive = ent_snap.alive;
-                }
-            }
+    /// Get back onto the server after the connection drops, checked on
+    /// each frame until it works or gives up. The server sends everything
+    /// again when we rejoin.
+    fn reconnect(&mut self) {
+        let resume_token = match self.resume_token {
+            Some(resume_token) => resume_token,
+            None => {
+                error!("Lost connection to the server before joining");
+                self.done = true;
+                return;
+            },
+        };
+
+        match self.comm.reconnect() {
+            Some(true) => {
+                self.loaded_chunks.clear();
+                self.comm.resume(&self.name, resume_token, &self.packs);
+            },
+            Some(false) => {
+                error!("Could not reconnect to the server");
+                self.done = true;
+            },
+            // Still trying
+            None => {},
+        }
+    }
+
+    /// Keep the server up to date with where the camera is
+    fn follow_camera(&mut self) {
+        // Anything but a hello before joining gets the client booted, and
+        // spectators and lockstep players have the whole map anyway
+        if self.player_id.is_some() && !self.spectating && self.replica.is_none() {
+            self.comm.follow_camera(&self.ch, &self.map, &mut self.loaded_chunks);
         }
     }
 
+    fn load_defs(&mut self, defs: Defs) {
+        self.map.materials = defs.materials;
+        self.creature_types = defs.creature_types;
+        self.item_types = defs.item_types;
+        self.building_types = defs.building_types;
+    }
+
+    fn update_ents(&mut self, ent_update: EntUpdate) {
+        self.selected_entities.retain(|ent_id| !ent_update.despawned.contains(ent_id));
+        ent_update.apply(&mut self.entities);
+    }
+
     pub fn forward(&mut self) {
         self.ch.y -= 1;
+        self.follow_camera();
     }
 
     pub fn back(&mut self) {
         self.ch.y += 1;
+        self.follow_camera();
     }
 
     pub fn left(&mut self) {
         self.ch.x -= 1;
+        self.follow_camera();
     }
 
     pub fn right(&mut self) {
         self.ch.x += 1;
+        self.follow_camera();
     }
 
     pub fn up(&mut self) {
         self.ch.z -= 1;
+        self.follow_camera();
     }
 
     pub fn down(&mut self) {
         self.ch.z += 1;
+        self.follow_camera();
     }
 
     pub fn null(&mut self) {}
 
     pub fn exit(&mut self) {
+        self.comm.leave();
         self.done = true; 
     }
 
@@ -214,10 +355,131 @@ pub fn new(map: Map,  entities: Entities,
         self.selected_entities.clear();
     }
 
+    /// Lockstep runs its own copy of the map, which chunks from the server
+    /// would be out of step with
+    fn load_chunk(&mut self, chunk: &MapChunk) {
+        if self.replica.is_none() {
+            self.map.apply_chunk(chunk);
+        }
+    }
+
+    /// Start running the game ourselves from a snapshot of it
+    fn start_lockstep(&mut self, save: SaveGame) {
+        info!("Running the game in lockstep from tick {}", save.ticks);
+        let defs = Defs {
+            materials: self.map.materials.clone(),
+            creature_types: self.creature_types.clone(),
+            item_types: self.item_types.clone(),
+            building_types: self.building_types.clone(),
+        };
+        let replica = Replica::new(save, defs);
+
+        {
+            let g_state = replica.g_state();
+            self.map = g_state.map.clone();
+            self.entities = g_state.entities.clone();
+            self.items = g_state.items.clone();
+            self.buildings = g_state.buildings.clone();
+            self.ticks = g_state.ticks;
+        }
+        self.replica = Some(replica);
+        self.comm.start_turns(self.ticks);
+    }
+
+    /// Run a tick from the commands everyone gave for it
+    fn run_turn(&mut self, tick: Ticks, turn: &[(PlayerID, ClientMsg)]) {
+        let replica = match self.replica {
+            Some(ref mut replica) => replica,
+            None => {
+                warn!("Got a turn outside of lockstep");
+                return;
+            },
+        };
+
+        match replica.run_turn(tick, turn) {
+            Ok(changes) => {
+                let g_state = replica.g_state();
+                for change in changes {
+                    if let Change::TileChange(pos) = change {
+                        if let Some(tile) = g_state.map.get_tile(pos) {
+                            self.map.update_tile(tile, pos);
+                        }
+                    }
+                }
+                self.entities = g_state.entities.clone();
+                self.items = g_state.items.clone();
+                self.buildings = g_state.buildings.clone();
+                self.ticks = g_state.ticks;
+
+                if self.ticks % CHECKSUM_PERIOD == 0 {
+                    self.comm.checksum(self.ticks, g_state.checksum());
+                }
+            },
+            Err(expected) => warn!("Got the turn for tick {} while on tick {}", tick, expected),
+        }
+    }
+
+    fn update_lobby(&mut self, player_id: PlayerID, lobby: LobbyState) {
+        info!("Lobby: {} players, map {}", lobby.players.len(), lobby.settings.map);
+        self.lobby_id = Some(player_id);
+        self.lobby = Some(lobby);
+        clear();
+    }
+
+    pub fn toggle_ready(&mut self) {
+        let ready = self.lobby_player().map(|player| player.ready);
+        if let Some(ready) = ready {
+            self.comm.set_ready(!ready);
+        }
+    }
+
+    /// Move to the team after ours, or the first team after the last one
+    pub fn next_team(&mut self) {
+        let team = self.lobby_player().map(|player| player.team);
+        if let (Some(team), Some(lobby)) = (team, self.lobby.as_ref()) {
+            let next = if team as usize > lobby.players.len() { 1 } else { team + 1 };
+            self.comm.set_team(next);
+        }
+    }
+
+    /// Pick the next map in the list, which only the host can do
+    pub fn next_map(&mut self) {
+        if let Some(ref lobby) = self.lobby {
+            let mut settings = lobby.settings.clone();
+            settings.map = lobby.next_map();
+            self.comm.set_match(settings);
+        }
+    }
+
+    /// Switch lockstep on or off for the match, which only the host can do
+    pub fn toggle_lockstep(&mut self) {
+        if let Some(ref lobby) = self.lobby {
+            let mut settings = lobby.settings.clone();
+            settings.lockstep = !settings.lockstep;
+            self.comm.set_match(settings);
+        }
+    }
+
+    pub fn start_match(&mut self) {
+        self.comm.start_match();
+    }
+
+    pub fn lobby_player(&self) -> Option<&LobbyPlayer> {
+        match (self.lobby.as_ref(), self.lobby_id) {
+            (Some(lobby), Some(player_id)) => lobby.player(player_id),
+            _ => None,
+        }
+    }
+
     pub fn render(&mut self) {
         render(self);
     }
 
+    /// Round trip time to the server in milliseconds, for the HUD
+    pub fn rtt(&self) -> Option<u64> {
+        self.comm.rtt()
+    }
+
     pub fn get_snap(&self) -> MapSnapshot {
         handle_to_snapshot(&self.ch, &self.map)
     }
@@ -1 +1,305 @@
+use std::path::Path;
 
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use game::base::*;
+use map::constants::*;
+use map::material::{MaterialID, Materials, init_materials, material_id};
+use map::tiles::{Map, PosUnit, Tile};
+
+
+pub type Seed = u32;
+
+// Chance for an underground tile to start out as open cave
+const CAVE_FILL: f64 = 0.42;
+// Number of cellular automaton passes used to smooth caves
+const CAVE_SMOOTHING: u32 = 4;
+// Surface noise above this becomes a grass mound
+const MOUND_THRESHOLD: f64 = 0.62;
+const MAX_SOIL_DEPTH: PosUnit = 3;
+const STAIRS_PER_LEVEL: u32 = 2;
+// Half width of the room carved out around each staircase
+const STAIR_ROOM: PosUnit = 1;
+
+/// The materials the generator builds with, looked up by name
+struct Palette {
+    grass: MaterialID,
+    grass_floor: MaterialID,
+    dirt: MaterialID,
+    dirt_floor: MaterialID,
+    stone: MaterialID,
+    stone_floor: MaterialID,
+    upstairs: MaterialID,
+    downstairs: MaterialID,
+}
+
+impl Palette {
+    fn new(materials: &Materials) -> Palette {
+        let find = |name| material_id(materials, name)
+                              .unwrap_or_else(|| panic!("No {} in materials.toml", name));
+
+        Palette {
+            grass: find("grass"),
+            grass_floor: find("grass_floor"),
+            dirt: find("dirt"),
+            dirt_floor: find("dirt_floor"),
+            stone: find("stone"),
+            stone_floor: find("stone_floor"),
+            upstairs: find("upstairs"),
+            downstairs: find("downstairs"),
+        }
+    }
+}
+
+/// Generate a map with a seed, loading the materials from the static files
+pub fn init_gen_map(root: &Path, seed: Seed, size: Pos) -> Map {
+    info!("Generating map with seed {}", seed);
+    generate_map(seed, size, init_materials(root))
+}
+
+/// Generate a map of the given size. The same seed always produces the same map.
+///
+/// The top level (z = 0) is a grass surface dotted with mounds. Below it
+/// lies a layer of dirt of varying depth and then stone. Caves are carved
+/// through every underground level, and each level is joined to the one
+/// below by staircases.
+pub fn generate_map(seed: Seed, size: Pos, materials: Materials) -> Map {
+    let mut rng = seed_rng(seed);
+    let palette = Palette::new(&materials);
+    let mut map = Map::new(size, materials);
+    let (xlen, ylen, zlen) = size;
+
+    // Soil depth for every column
+    let soil = smooth_noise(&mut rng, xlen, ylen)
+                   .iter()
+                   .map(|noise| 1 + (noise * MAX_SOIL_DEPTH as f64) as PosUnit)
+                   .map(|depth| depth.min(MAX_SOIL_DEPTH))
+                   .collect::<Vec<PosUnit>>();
+
+    // Surface
+    let mounds = smooth_noise(&mut rng, xlen, ylen);
+    for y in 0..ylen {
+        for x in 0..xlen {
+            let tile = if mounds[(x + y * xlen) as usize] > MOUND_THRESHOLD {
+                Tile::new(palette.grass, Mode::Block)
+            } else {
+                Tile::new(palette.grass_floor, Mode::Empty)
+            };
+            map.set_tile(tile, (x, y, 0));
+        }
+    }
+
+    // Underground strata and caves
+    for z in 1..zlen {
+        let caves = gen_caves(&mut rng, xlen, ylen);
+        for y in 0..ylen {
+            for x in 0..xlen {
+                let depth = soil[(x + y * xlen) as usize];
+                let tile = if caves[(x + y * xlen) as usize] {
+                    Tile::new(floor_at(&palette, depth, z), Mode::Empty)
+                } else {
+                    Tile::new(wall_at(&palette, depth, z), Mode::Block)
+                };
+                map.set_tile(tile, (x, y, z));
+            }
+        }
+    }
+
+    // Stairs between each pair of levels
+    for z in 0..(zlen - 1) {
+        for _ in 0..STAIRS_PER_LEVEL {
+            if let Some((x, y)) = pick_stairs(&mut rng, &map, z) {
+                carve_stairs(&mut map, &palette, &soil, (x, y, z));
+            }
+        }
+    }
+
+    map
+}
+
+fn seed_rng(seed: Seed) -> XorShiftRng {
+    // XorShift can not be seeded with all zeros, so mix in some constants
+    XorShiftRng::from_seed([seed ^ 0x9E37_79B9,
+                            seed.rotate_left(8) ^ 0x85EB_CA6B,
+                            seed.rotate_left(16) ^ 0xC2B2_AE35,
+                            seed.rotate_left(24) ^ 0x27D4_EB2F])
+}
+
+/// Random values in [0, 1) for each column, blurred so neighbours are similar
+fn smooth_noise(rng: &mut XorShiftRng, xlen: PosUnit, ylen: PosUnit) -> Vec<f64> {
+    let raw: Vec<f64> = (0..xlen * ylen).map(|_| rng.gen::<f64>()).collect();
+
+    let mut smooth = Vec::with_capacity(raw.len());
+    for y in 0..ylen {
+        for x in 0..xlen {
+            let mut total = 0.0;
+            let mut count = 0.0;
+            for ny in (y - 2)..(y + 3) {
+                for nx in (x - 2)..(x + 3) {
+                    if 0 <= nx && nx < xlen && 0 <= ny && ny < ylen {
+                        total += raw[(nx + ny * xlen) as usize];
+                        count += 1.0;
+                    }
+                }
+            }
+            smooth.push(total / count);
+        }
+    }
+
+    // Blurring squashes values towards the middle, so stretch them back out
+    let min = smooth.iter().cloned().fold(1.0, f64::min);
+    let max = smooth.iter().cloned().fold(0.0, f64::max);
+    let range = if max > min { max - min } else { 1.0 };
+    smooth.iter()
+          .map(|val| ((val - min) / range).min(0.999))
+          .collect()
+}
+
+/// Cave layout for one level, true where the level is open
+fn gen_caves(rng: &mut XorShiftRng, xlen: PosUnit, ylen: PosUnit) -> Vec<bool> {
+    let on_edge = |x, y| x == 0 || y == 0 || x == xlen - 1 || y == ylen - 1;
+
+    let mut open: Vec<bool> = (0..xlen * ylen).map(|_| rng.gen::<f64>() < CAVE_FILL)
+                                              .collect();
+
+    for _ in 0..CAVE_SMOOTHING {
+        let mut next = open.clone();
+        for y in 0..ylen {
+            for x in 0..xlen {
+                let i = (x + y * xlen) as usize;
+                if on_edge(x, y) {
+                    next[i] = false;
+                    continue;
+                }
+
+                // Count solid neighbours, treating the map edge as solid
+                let mut walls = 0;
+                for ny in (y - 1)..(y + 2) {
+                    for nx in (x - 1)..(x + 2) {
+                        if (nx, ny) != (x, y) && !open[(nx + ny * xlen) as usize] {
+                            walls += 1;
+                        }
+                    }
+                }
+                next[i] = if walls > 4 { false } else if walls < 4 { true } else { open[i] };
+            }
+        }
+        open = next;
+    }
+
+    open
+}
+
+fn wall_at(palette: &Palette, soil_depth: PosUnit, z: PosUnit) -> MaterialID {
+    if z <= soil_depth { palette.dirt } else { palette.stone }
+}
+
+fn floor_at(palette: &Palette, soil_depth: PosUnit, z: PosUnit) -> MaterialID {
+    match z {
+        0 => palette.grass_floor,
+        _ if z <= soil_depth => palette.dirt_floor,
+        _ => palette.stone_floor,
+    }
+}
+
+/// Choose where stairs down from level z go, on open ground so they never
+/// land on the stairs coming up from the level above
+fn pick_stairs(rng: &mut XorShiftRng, map: &Map, z: PosUnit) -> Option<(PosUnit, PosUnit)> {
+    let (xlen, ylen, _) = map.size();
+    let margin = STAIR_ROOM + 1;
+    if xlen <= 2 * margin || ylen <= 2 * margin {
+        return None;
+    }
+
+    let mut open = Vec::new();
+    for y in margin..(ylen - margin) {
+        for x in margin..(xlen - margin) {
+            if let Some(tile) = map.get_tile((x, y, z)) {
+                if tile.mode == Mode::Empty {
+                    open.push((x, y));
+                }
+            }
+        }
+    }
+
+    if open.is_empty() {
+        None
+    } else {
+        let i = rng.gen_range(0, open.len());
+        Some(open[i])
+    }
+}
+
+/// Place a staircase going down from pos, carving out a room at the bottom
+/// and a tunnel from that room to the nearest cave
+fn carve_stairs(map: &mut Map, palette: &Palette, soil: &[PosUnit], pos: Pos) {
+    let (x0, y0, z) = pos;
+    let (xlen, _, _) = map.size();
+    let floor = |x: PosUnit, y: PosUnit, z| {
+        Tile::new(floor_at(palette, soil[(x + y * xlen) as usize], z), Mode::Empty)
+    };
+
+    let below = z + 1;
+    let nearest = nearest_open(map, (x0, y0, below));
+
+    for y in (y0 - STAIR_ROOM)..(y0 + STAIR_ROOM + 1) {
+        for x in (x0 - STAIR_ROOM)..(x0 + STAIR_ROOM + 1) {
+            if is_plain(map, (x, y, below)) {
+                map.set_tile(floor(x, y, below), (x, y, below));
+            }
+        }
+    }
+
+    if let Some((tx, ty)) = nearest {
+        let (mut x, mut y) = (x0, y0);
+        while (x, y) != (tx, ty) {
+            if x != tx {
+                x += (tx - x).signum();
+            } else {
+                y += (ty - y).signum();
+            }
+            if is_plain(map, (x, y, below)) {
+                map.set_tile(floor(x, y, below), (x, y, below));
+            }
+        }
+    }
+
+    map.set_tile(Tile::new(palette.downstairs, Mode::DownStairs), (x0, y0, z));
+    map.set_tile(Tile::new(palette.upstairs, Mode::UpStairs), (x0, y0, below));
+}
+
+/// Closest open tile on a level outside of the room around pos
+fn nearest_open(map: &Map, pos: Pos) -> Option<(PosUnit, PosUnit)> {
+    let (x0, y0, z) = pos;
+    let (xlen, ylen, _) = map.size();
+
+    let mut nearest = None;
+    let mut best_dist = PosUnit::max_value();
+    for y in 0..ylen {
+        for x in 0..xlen {
+            let dist = (x - x0).abs() + (y - y0).abs();
+            let in_room = (x - x0).abs() <= STAIR_ROOM && (y - y0).abs() <= STAIR_ROOM;
+            if !in_room && dist < best_dist && is_open(map, (x, y, z)) {
+                nearest = Some((x, y));
+                best_dist = dist;
+            }
+        }
+    }
+
+    nearest
+}
+
+fn is_open(map: &Map, pos: Pos) -> bool {
+    match map.get_tile(pos) {
+        Some(tile) => tile.mode == Mode::Empty,
+        None => false,
+    }
+}
+
+/// Whether a tile is plain ground or wall, so not part of a staircase
+fn is_plain(map: &Map, pos: Pos) -> bool {
+    match map.get_tile(pos) {
+        Some(tile) => tile.mode == Mode::Empty || tile.mode == Mode::Block,
+        None => false,
+    }
+}
@@ -4,6 +4,7 @@ use game::base::Pos;
 pub enum SelState {
     Ents,
     Digging,
+    Stockpile,
     Attack,
 }
 
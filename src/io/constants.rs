@@ -7,13 +7,25 @@ pub const BLACK:   Color = [0.0, 0.0, 0.0, 1.0];
 #[allow(dead_code)]
 pub const WHITE:   Color = [1.0, 1.0, 1.0, 1.0];
 pub const YELLOW:   Color = [0.5, 0.5, 1.0, 1.0];
-#[allow(dead_code)]
 pub const RED:   Color = [1.0, 0.0, 0.0, 1.0];
 #[allow(dead_code)]
 pub const BLUE:   Color = [0.0, 0.0, 1.0, 1.0];
-#[allow(dead_code)]
 pub const GREEN:   Color = [0.0, 1.0, 0.0, 1.0];
+pub const GREY:   Color = [0.5, 0.5, 0.5, 1.0];
 pub const SELECTOR_COLOR: Color = [0.54, 0.69, 0.93, 0.5];
+pub const STOCKPILE_COLOR: Color = [0.85, 0.75, 0.3, 0.25];
+
+// The round trip time meter, which is full at LATENCY_MAX_MS
+pub const LATENCY_OK_MS: u64 = 100;
+pub const LATENCY_BAD_MS: u64 = 250;
+pub const LATENCY_MAX_MS: u64 = 500;
+pub const LATENCY_BAR_WIDTH: f64 = 100.0;
+pub const LATENCY_BAR_HEIGHT: f64 = 8.0;
+
+// The rows of players shown in the lobby
+pub const LOBBY_MARGIN: f64 = 20.0;
+pub const LOBBY_ROW_HEIGHT: f64 = 40.0;
+pub const LOBBY_SQUARE: f64 = 30.0;
 
 pub const X_WIN_SIZE: u32 = 800; 
 pub const Y_WIN_SIZE: u32 = 800;
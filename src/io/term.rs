@@ -26,8 +26,16 @@ pub fn end_term() {
 
 /// Update screen based off changes to map, creatures, and such
 pub fn render(term: &mut TermClient) {
+    if term.lobby.is_some() {
+        draw_lobby(term);
+        refresh();
+        return;
+    }
+
     draw_tiles(term);
+    draw_items(term);
     draw_ents(term);
+    draw_hud(term);
     refresh();
 }
 
@@ -41,8 +49,18 @@ fn draw_tiles(term: &mut TermClient) {
     }
 }
 
+fn draw_items(term: &mut TermClient) {
+    for item in term.items.iter() {
+        if term.ch.in_bounds(&item.pos) {
+            let (ox, oy, _) = item.pos;
+            let (x, y) = (ox - term.ch.x, oy - term.ch.y);
+            mvaddch(y, x, 42);
+        }
+    }
+}
+
 fn draw_ents(term: &mut TermClient) {
-    for ent in term.entities.iter() {
+    for ent in term.entities.iter().filter(|ent| ent.alive) {
         if term.ch.in_bounds(&ent.pos) {
             // TODO Add back in color
             let (ox, oy, _) = ent.pos;
@@ -52,6 +70,35 @@ fn draw_ents(term: &mut TermClient) {
     }
 }
 
+/// List who is in the lobby and how the match is set up
+fn draw_lobby(term: &mut TermClient) {
+    let lobby = match term.lobby {
+        Some(ref lobby) => lobby,
+        None => return,
+    };
+
+    mvprintw(0, 0, &format!("Map: {:<60}", lobby.settings.map));
+    mvprintw(1, 0, &format!("Starting units: {}  Lockstep: {:<3}", lobby.settings.start_units,
+                            if lobby.settings.lockstep { "on" } else { "off" }));
+    for (row, player) in lobby.players.iter().enumerate() {
+        let host = if lobby.host == Some(player.player_id) { "host" } else { "" };
+        let ready = if player.ready { "ready" } else { "" };
+        mvprintw(3 + row as i32, 0, &format!("{:<20} team {:<3} {:<5} {:<4}",
+                                             player.name, player.team, ready, host));
+    }
+    mvprintw(4 + lobby.players.len() as i32, 0,
+             "r: ready  t: team  m: map  l: lockstep  g: start  Q: quit");
+}
+
+/// Show the round trip time to the server below the map
+fn draw_hud(term: &mut TermClient) {
+    let rtt = match term.rtt() {
+        Some(rtt) => format!("{} ms", rtt),
+        None => "--".to_owned(),
+    };
+    mvprintw(term.ch.ylen, 0, &format!("ping {:<8}", rtt));
+}
+
 /// Write tile to screen handling color and character
 fn update_tile(x: i32, y: i32, tile: Tile) {
     if tile.material == 10 {
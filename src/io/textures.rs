@@ -4,6 +4,7 @@ use std::collections::HashMap;
 use opengl_graphics::Texture;
 use piston_window::TextureSettings;
 
+use defs::packs::{find_file, init_packs};
 use map::material::*;
 
 
@@ -11,7 +12,8 @@ pub type Textures = HashMap<MaterialID, Texture>;
 
 pub fn load_textures(root: &Path) -> Textures {
     let mut textures = HashMap::new();
-    let path = root.join("static/inc/textures/materials/grass.png");
+    let path = find_file(&init_packs(root), "inc/textures/materials/grass.png")
+                   .expect("No pack has the grass texture");
     let settings = TextureSettings::new();
     let texture = Texture::from_path(path, &settings).unwrap();
     textures.insert(7, texture);
@@ -12,9 +12,15 @@ use io::textures::*;
 use io::utils::*;
 use game::base::*;
 use game::client::Client;
+use game::lobby::LobbyState;
 use map::tiles::{Map, MapSnapshot};
 use entities::creatures::{CreatureMap, get_color};
 use entities::entity::{Entity, EntID, EntIDs};
+use objects::buildings;
+use objects::buildings::{Building, BuildingTypes};
+use objects::items;
+use objects::items::{Item, ItemTypes};
+use objects::stockpiles::Stockpile;
 
 
 pub fn init_graphics() -> Window {
@@ -32,12 +38,27 @@ pub fn render(player: &mut Client, args: &RenderArgs) {
     // TODO Keep track of FPS 
     // TODO Dynamically resize window bounds
 
+    if let Some(ref lobby) = player.lobby {
+        let gl = &mut player.gl;
+        gl.draw(args.viewport(), |c, gl| {
+            clear(BLACK, gl);
+            draw_lobby(c, gl, lobby);
+        });
+        return;
+    }
+
     let snap = player.get_snap();
+    let rtt = player.rtt();
     let map = &player.map;
     let entities = &mut player.entities;
     let ch = &player.ch;
     let selector = player.selector;
     let creature_types = &player.creature_types;
+    let items = &player.items;
+    let stockpiles = &player.stockpiles;
+    let buildings = &player.buildings;
+    let building_types = &player.building_types;
+    let item_types = &player.item_types;
     let gl = &mut player.gl;
     let selected_ents = &player.selected_entities;
     let textures = &player.textures;
@@ -47,11 +68,52 @@ pub fn render(player: &mut Client, args: &RenderArgs) {
         clear(BLACK, gl);
 
         draw_tiles(c, gl, &snap, map, textures);
-        draw_entities(c, gl, ch, entities, creature_types, selected_ents);
+        draw_stockpiles(c, gl, ch, stockpiles);
+        draw_buildings(c, gl, ch, buildings, building_types);
+        draw_items(c, gl, ch, items, item_types);
+        draw_entities(c, gl, ch, entities, creature_types, item_types, selected_ents);
         draw_selector(c, gl, selector);
+        draw_latency(c, gl, rtt);
     });
 }
 
+/// A row for each player in the lobby with their team's color, marked green
+/// once they are ready, with the host's row set apart
+fn draw_lobby(c: Context, gl: &mut GlGraphics, lobby: &LobbyState) {
+    for (row, player) in lobby.players.iter().enumerate() {
+        let y = LOBBY_MARGIN + LOBBY_ROW_HEIGHT * row as f64;
+        let x = if lobby.host == Some(player.player_id) { 0.0 } else { LOBBY_MARGIN };
+        let team_square = rectangle::square(x + LOBBY_MARGIN, y, LOBBY_SQUARE);
+        rectangle(team_color(Some(player.team)), team_square, c.transform, gl);
+
+        let ready_color = if player.ready { GREEN } else { GREY };
+        let ready_square = rectangle::square(x + LOBBY_MARGIN * 2.0 + LOBBY_SQUARE, y,
+                                             LOBBY_SQUARE);
+        rectangle(ready_color, ready_square, c.transform, gl);
+    }
+}
+
+/// A bar in the top right corner that grows and reddens with the round trip
+/// time to the server, and is empty until the server first answers
+fn draw_latency(c: Context, gl: &mut GlGraphics, rtt: Option<u64>) {
+    if let Some(rtt) = rtt {
+        let color = if rtt < LATENCY_OK_MS {
+            GREEN
+        } else if rtt < LATENCY_BAD_MS {
+            YELLOW
+        } else {
+            RED
+        };
+
+        let fraction = rtt.min(LATENCY_MAX_MS) as f64 / LATENCY_MAX_MS as f64;
+        let width = (LATENCY_BAR_WIDTH * fraction).max(2.0);
+        let x = f64::from(X_WIN_SIZE) - LATENCY_BAR_WIDTH - LATENCY_BAR_HEIGHT;
+        rectangle(BLACK, [x, LATENCY_BAR_HEIGHT, LATENCY_BAR_WIDTH, LATENCY_BAR_HEIGHT],
+                  c.transform, gl);
+        rectangle(color, [x, LATENCY_BAR_HEIGHT, width, LATENCY_BAR_HEIGHT], c.transform, gl);
+    }
+}
+
 fn draw_tiles(c: Context, gl: &mut GlGraphics, snap: &MapSnapshot, 
               map: &Map, textures: &Textures) {
     let square = rectangle::square(0.0, 0.0, X_PIXELS);
@@ -78,17 +140,70 @@ fn draw_tiles(c: Context, gl: &mut GlGraphics, snap: &MapSnapshot,
     }
 }
 
+/// Shade the tiles of each stockpile zone
+fn draw_stockpiles(c: Context, gl: &mut GlGraphics, ch: &CameraHandle,
+                   stockpiles: &[Stockpile]) {
+    let square = rectangle::square(0.0, 0.0, X_PIXELS);
+
+    for pos in stockpiles.iter().flat_map(|pile| pile.tiles()) {
+        if ch.in_bounds(&pos) {
+            let (winx, winy) = tile_pos_to_win(pos, ch);
+            let transform = c.transform.trans(winx, winy);
+            rectangle(STOCKPILE_COLOR, square, transform, gl);
+        }
+    }
+}
+
+/// Fill in the footprint of each building, faded out while under construction
+fn draw_buildings(c: Context, gl: &mut GlGraphics, ch: &CameraHandle,
+                  buildings: &[Building], building_types: &BuildingTypes) {
+    let square = rectangle::square(0.0, 0.0, X_PIXELS);
+
+    for building in buildings {
+        let mut color = buildings::get_color(&building.type_id, building_types);
+        if !building.built {
+            color[3] = 0.4;
+        }
+
+        for pos in building.tiles(building_types) {
+            if ch.in_bounds(&pos) {
+                let (winx, winy) = tile_pos_to_win(pos, ch);
+                let transform = c.transform.trans(winx, winy);
+                rectangle(color, square, transform, gl);
+            }
+        }
+    }
+}
+
+fn draw_items(c: Context, gl: &mut GlGraphics, ch: &CameraHandle,
+              items: &[Item], item_types: &ItemTypes) {
+    // Items are drawn as small squares in the middle of their tile
+    let item_square = rectangle::square(0.0, 0.0, X_PIXELS * 0.4);
+
+    for item in items {
+        if ch.in_bounds(&item.pos) {
+            let (winx, winy) = tile_pos_to_win(item.pos, ch);
+            let transform = c.transform.trans(winx + X_PIXELS * 0.3, 
+                                              winy + X_PIXELS * 0.3);
+            let color = items::get_color(&item.type_id, item_types);
+            rectangle(color, item_square, transform, gl);
+        }
+    }
+}
+
 fn draw_entities(c: Context, gl: &mut GlGraphics, ch: &CameraHandle, 
                  entities: &[Entity], creature_types: &CreatureMap, 
-                 selected_ents: &EntIDs) {
+                 item_types: &ItemTypes, selected_ents: &EntIDs) {
     // Scale entities slightly smaller than a square for now
     let inner_square = rectangle::square(0.0, 0.0, X_PIXELS * 0.85);
     let outer_square = rectangle::square(0.0, 0.0, X_PIXELS * 1.0);
+    let carry_square = rectangle::square(0.0, 0.0, X_PIXELS * 0.3);
 
     // Transform EntIDs into map
     let sel_ents_set: HashSet<EntID> = selected_ents.iter().cloned().collect();
 
-    for ent in entities {
+    // The dead are left as corpse items, so are not drawn
+    for ent in entities.iter().filter(|ent| ent.alive) {
         if ch.in_bounds(&ent.pos) {
             let (winx, winy) = tile_pos_to_win(ent.pos, ch);
             let inner_transform = c.transform.trans(winx + X_PIXELS * 0.075, 
@@ -101,12 +216,16 @@ fn draw_entities(c: Context, gl: &mut GlGraphics, ch: &CameraHandle,
             rectangle(outline_color, outer_square, outer_transform, gl);
 
             // Draw color of ent
-            let ent_color = if ent.alive { 
-                get_color(&ent.creature_id, creature_types)
-            } else {
-                BLACK
-            };
+            let ent_color = get_color(&ent.creature_id, creature_types);
             rectangle(ent_color, inner_square, inner_transform, gl);
+
+            // Mark entities carrying something with the item in the corner
+            if let Some(item) = ent.inventory.first() {
+                let carry_transform = c.transform.trans(winx + X_PIXELS * 0.6, 
+                                                        winy + X_PIXELS * 0.6);
+                let item_color = items::get_color(&item.type_id, item_types);
+                rectangle(item_color, carry_square, carry_transform, gl);
+            }
         }
     }
 }
@@ -1,6 +1,7 @@
 #![cfg_attr(feature="clippy", feature(plugin))]
 #![cfg_attr(feature="clippy", plugin(clippy))]
 
+extern crate base64;
 extern crate bincode;
 extern crate glutin_window;
 extern crate graphics;
@@ -13,10 +14,15 @@ extern crate pathfinding;
 extern crate piston;
 extern crate piston_window;
 extern crate rand;
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
+extern crate sha1;
 extern crate toml;
 
+pub mod config;
+pub mod defs;
 pub mod entities;
 pub mod game;
 pub mod gen;
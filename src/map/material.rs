@@ -1,15 +1,15 @@
 use std::path::Path;
-use std::fs::File;
-use std::io::Read;
 use std::collections::HashMap;
-use toml;
+
+use defs::loader::{DefError, Proto, load_pack_defs, required};
+use defs::packs::{DataPack, PackConflicts, init_packs};
+use objects::items::ItemTypeID;
 
 
 pub type MaterialID = u16;
 pub type Materials = HashMap<MaterialID, Material>;
-type ProtoMap = HashMap<String, ProtoMaterial>;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Material {
     pub name: String,
     pub id: MaterialID,
@@ -18,11 +18,8 @@ pub struct Material {
     pub passable: bool,
     pub color: [f32; 4],
     pub alt: MaterialID,
-}
-
-#[derive(Deserialize)]
-struct DesMat {
-    pub materials: Vec<ProtoMaterial>,
+    // The item left behind when the material is dug out
+    pub item: Option<ItemTypeID>,
 }
 
 #[derive(Clone, PartialEq, Deserialize)]
@@ -35,74 +32,79 @@ struct ProtoMaterial {
     pub passable: Option<bool>,
     pub color: Option<[f32; 4]>,
     pub alt: Option<MaterialID>,
+    pub item: Option<ItemTypeID>,
 }
 
-// TODO Genercize and dedup object, entity, and material 
-pub fn init_materials(root: &Path) -> Materials {
-    // Read materials file to str
-    let material_path = root.join("static/inc/materials/materials.toml");
-    let path_str = material_path.to_str().unwrap();
-    let mut file = File::open(&path_str).unwrap();
-    let mut contents = String::new(); 
-    file.read_to_string(&mut contents).unwrap();
-
-    // Deserialize
-    let des_mat: DesMat = toml::from_str(&contents).unwrap();
-    let proto_materials = des_mat.materials;
-    let proto_map: ProtoMap = proto_materials.iter()
-                                             .map(|mat| (mat.name.clone(), mat.clone()))
-                                             .collect();
+impl Proto for ProtoMaterial {
+    type ID = MaterialID;
+    type Def = Material;
+    type Context = ();
 
-    let mut material_map = HashMap::new();
-    for mat in &proto_materials {
-        resolve(mat, &proto_map, &mut material_map)
+    fn name(&self) -> &str {
+        &self.name
     }
-    material_map
-}
 
-fn resolve(proto: &ProtoMaterial, proto_map: &ProtoMap, mut material_map: &mut Materials) {
-    let mat = match proto.template {
-        None => {
-            Material { 
-                name:     proto.name.clone(),
-                id:       proto.id,
-                texture:   None,  // FIXME
-                diggable: proto.diggable.unwrap(),
-                passable: proto.passable.unwrap(),
-                alt:      proto.alt.unwrap(),
-                color:    proto.color.unwrap() 
-            }
-        },
-        Some(ref template) => {
-            let template_proto = proto_map.get(template).unwrap();
-            if !material_map.contains_key(&template_proto.id) {
-                resolve(template_proto, proto_map, &mut material_map);
-            }
+    fn id(&self) -> MaterialID {
+        self.id
+    }
 
-            // Initialize with prototype 
-            let mut mat = material_map.get(&template_proto.id).unwrap().clone();
+    fn template(&self) -> Option<&str> {
+        self.template.as_ref().map(|template| template.as_str())
+    }
 
-            // Overwrite applicable fields
-            mat.name = proto.name.clone();
-            mat.id = proto.id;
-            if let Some(texture) = proto.texture.clone() {
-                mat.texture = Some(texture);
-            }
-            if let Some(diggable) = proto.diggable {
-                mat.diggable = diggable;
-            }
-            if let Some(passable) = proto.passable {
-                mat.passable = passable;
-            }
-            if let Some(color) = proto.color {
-                mat.color = color;
-            }
-            if let Some(alt) = proto.alt {
-                mat.alt = alt;
-            }
+    fn build(&self, _: &()) -> Result<Material, DefError> {
+        Ok(Material { 
+            name:     self.name.clone(),
+            id:       self.id,
+            texture:  None,  // FIXME
+            diggable: try!(required(&self.name, "diggable", &self.diggable)),
+            passable: try!(required(&self.name, "passable", &self.passable)),
+            alt:      try!(required(&self.name, "alt", &self.alt)),
+            color:    try!(required(&self.name, "color", &self.color)),
+            item:     self.item,
+        })
+    }
 
-            mat
+    fn inherit(&self, mut mat: Material, _: &()) -> Result<Material, DefError> {
+        // Overwrite applicable fields
+        mat.name = self.name.clone();
+        mat.id = self.id;
+        if let Some(texture) = self.texture.clone() {
+            mat.texture = Some(texture);
+        }
+        if let Some(diggable) = self.diggable {
+            mat.diggable = diggable;
+        }
+        if let Some(passable) = self.passable {
+            mat.passable = passable;
+        }
+        if let Some(color) = self.color {
+            mat.color = color;
         }
-    };
-    material_map.insert(mat.id, mat);
+        if let Some(alt) = self.alt {
+            mat.alt = alt;
+        }
+        if let Some(item) = self.item {
+            mat.item = Some(item);
+        }
+
+        Ok(mat)
+    }
+}
+
+pub fn init_materials(root: &Path) -> Materials {
+    load_materials(&init_packs(root))
+        .map(|(materials, _)| materials)
+        .unwrap_or_else(|err| panic!("materials.toml invalid: {}", err))
+}
+
+pub fn load_materials(packs: &[DataPack]) -> Result<(Materials, PackConflicts), DefError> {
+    load_pack_defs::<ProtoMaterial>(packs, "inc/materials/materials.toml", "materials", &())
+}
+
+/// Look up the id of a material by its name in materials.toml
+pub fn material_id(materials: &Materials, name: &str) -> Option<MaterialID> {
+    materials.values()
+             .find(|mat| mat.name == name)
+             .map(|mat| mat.id)
 }
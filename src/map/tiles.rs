@@ -1,20 +1,25 @@
-use std::cmp::min;
+use std::cmp::{max, min};
 use std::fs::File;
 use std::path::Path;
-use std::io::{Read, Write, BufWriter, Error};
+use std::io::{Read, Write, BufWriter, Error, ErrorKind};
+use std::str::FromStr;
 
+use defs::packs::{find_file, init_packs};
 use game::base::*;
 use io::base::*;
 use map::constants::*;
 use map::material::*;
+use objects::items::ItemTypeID;
 
 
 pub type Tiles = Vec<Tile>;
 
 pub type PosUnit = i32;
-const CHUNK_TILES_X: PosUnit = 8;
-const CHUNK_TILES_Y: PosUnit = 8;
-const CHUNK_TILES_Z: PosUnit = 1;
+pub const CHUNK_TILES_X: PosUnit = 8;
+pub const CHUNK_TILES_Y: PosUnit = 8;
+pub const CHUNK_TILES_Z: PosUnit = 1;
+// Version written in the header of map files
+const MAP_VERSION: i32 = 1;
 
 //TODO Clean up unwraps
 
@@ -26,7 +31,7 @@ pub struct Tile {
     pub marked: bool,
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Map {
     //Holds the terrain info as a vector of tiles
     tiles: Tiles, 
@@ -56,7 +61,8 @@ pub struct MapChunk {
 
 pub fn init_map(root: &Path) -> Map {
     info!("Initializing map");
-    let test_path = root.join("static/inc/maps/smol_map_excel.sfm.csv");
+    let test_path = find_file(&init_packs(root), "inc/maps/arena.sfm.csv")
+                        .unwrap_or_else(|| root.join("static/inc/maps/arena.sfm.csv"));
     let path_str = test_path
                    .to_str()
                    .expect("Unicode decode error");
@@ -68,7 +74,7 @@ pub fn init_map(root: &Path) -> Map {
 }
 
 impl Tile {
-    fn new(material: MaterialID, mode: Mode) -> Tile {
+    pub fn new(material: MaterialID, mode: Mode) -> Tile {
         Tile { 
             material: material,
             mode: mode, 
@@ -78,6 +84,20 @@ impl Tile {
 }
 
 impl Map {
+    /// Create a map of the given size filled with air
+    pub fn new(size: Pos, materials: Materials) -> Map {
+        let mut map = Map {
+            tiles: Tiles::new(), 
+            materials: materials, 
+            xlen: 0,
+            ylen: 0,
+            zlen: 0,
+        };
+        map.resize(size);
+
+        map
+    }
+
     #[allow(dead_code)]
     pub fn print(&self) {
         //Debug print method
@@ -135,39 +155,46 @@ impl Map {
 
     // TODO Add duplication factor
     pub fn to_chunks(&self) -> Vec<MapChunk> {
-        let mut chunks = Vec::<MapChunk>::new();
-        
-        let x_chunks = Map::get_num_chunks(self.xlen, CHUNK_TILES_X);
-        let y_chunks = Map::get_num_chunks(self.ylen, CHUNK_TILES_Y);
-        let z_chunks = Map::get_num_chunks(self.zlen, CHUNK_TILES_Z);
-
-        for dx in 0..x_chunks {
-            for dy in 0..y_chunks {
-                for dz in 0..z_chunks {
-                    let x = dx * CHUNK_TILES_X;
-                    let y = dy * CHUNK_TILES_Y;
-                    let z = dz * CHUNK_TILES_Z;
-                    let pos = (x, y, z);
-
-                    let xlen = min(CHUNK_TILES_X, self.xlen - dx * CHUNK_TILES_X);
-                    let ylen = min(CHUNK_TILES_Y, self.ylen - dy * CHUNK_TILES_Y);
-                    let zlen = min(CHUNK_TILES_Z, self.zlen - dz * CHUNK_TILES_Z);
-                    let size = (xlen, ylen, zlen);
-
-                    chunks.push(self.get_chunk(pos, size))
+        self.chunks_in((0, 0, 0), (self.xlen - 1, self.ylen - 1, self.zlen - 1))
+            .into_iter()
+            .map(|origin| self.get_chunk_at(origin))
+            .collect()
+    }
+
+    /// The origins of the chunks that overlap the block between two
+    /// corners, leaving out any past the edge of the map
+    pub fn chunks_in(&self, start: Pos, end: Pos) -> Vec<Pos> {
+        let (x0, y0, z0) = chunk_origin((max(start.0, 0), max(start.1, 0), max(start.2, 0)));
+        let (x1, y1, z1) = (min(end.0, self.xlen - 1),
+                            min(end.1, self.ylen - 1),
+                            min(end.2, self.zlen - 1));
+
+        let mut origins = Vec::new();
+        let mut x = x0;
+        while x <= x1 {
+            let mut y = y0;
+            while y <= y1 {
+                let mut z = z0;
+                while z <= z1 {
+                    origins.push((x, y, z));
+                    z += CHUNK_TILES_Z;
                 }
+                y += CHUNK_TILES_Y;
             }
+            x += CHUNK_TILES_X;
         }
 
-        chunks
+        origins
     }
 
-    fn get_num_chunks(map_len: PosUnit, chunk_len: PosUnit) -> PosUnit {
-        if map_len % chunk_len == 0 {
-            map_len / chunk_len
-        } else {
-            map_len / chunk_len + 1
-        }
+    /// The chunk starting at a chunk origin, cut short at the edge of the map
+    pub fn get_chunk_at(&self, origin: Pos) -> MapChunk {
+        let (x, y, z) = origin;
+        let size = (min(CHUNK_TILES_X, self.xlen - x),
+                    min(CHUNK_TILES_Y, self.ylen - y),
+                    min(CHUNK_TILES_Z, self.zlen - z));
+
+        self.get_chunk(origin, size)
     }
 
     pub fn apply_chunk(&mut self, chunk: &MapChunk) {
@@ -218,23 +245,43 @@ impl Map {
         (x + y * self.xlen + z * self.xlen * self.ylen) as usize
     }
 
+    /// Overwrite a tile entirely, including its mode
+    pub fn set_tile(&mut self, tile: Tile, pos: Pos) {
+        self.apply_tile_func(pos, |old_tile| *old_tile = tile);
+    }
+
     pub fn update_tile(&mut self, new_tile: Tile, pos: Pos) {
         self.apply_tile_func(pos, |tile| {
             tile.material = new_tile.material;
+            tile.mode = new_tile.mode;
             tile.marked = false;
         });
     }
 
-    pub fn dig(&mut self, pos: Pos) {
+    /// Replace a tile with its alt material, opening it up if the alt is passable
+    ///
+    /// # Returns
+    /// * The type of item left behind by the dug out material
+    pub fn dig(&mut self, pos: Pos) -> Option<ItemTypeID> {
+        let item = self.grab_material(pos).and_then(|material| material.item);
         let alt = self.get_alt(pos);
-        self.apply_tile_func(pos, |tile| tile.material = alt);
+        let mode = match self.materials.get(&alt) {
+            Some(material) if material.passable => Mode::Empty,
+            _ => Mode::Block,
+        };
+
+        self.apply_tile_func(pos, |tile| {
+            tile.material = alt;
+            tile.mode = mode;
+        });
+
+        item
     }
 
     pub fn mark(&mut self, pos: Pos) {
         self.apply_tile_func(pos, |tile| tile.marked = true);
     }
 
-    #[allow(dead_code)]
     pub fn unmark(&mut self, pos: Pos) {
         self.apply_tile_func(pos, |tile| tile.marked = false);
     }
@@ -282,24 +329,29 @@ impl Map {
         }
     }
 
-    #[allow(dead_code)]
+    /// Save the map in the same comma separated format read by `load_map`
+    ///
+    /// Each tile is written as `material;mode`. Tile marks are not
+    /// kept, as they are part of the game state rather than the map.
     pub fn save(&self, path: &str) -> Result<(), Error> {
-        //Saves map as file. Currently unversioned, so take heed.
         let f = try!(File::create(&path)); 
         let mut writer = BufWriter::new(&f);
 
-        try!(write!(&mut writer, "{} {} {}\n", self.xlen, self.ylen, self.zlen));
+        try!(write!(&mut writer, "{},{},{},{}\n", 
+                    MAP_VERSION, self.xlen, self.ylen, self.zlen));
 
         for z in 0..self.zlen {
             for y in 0..self.ylen {
                 for x in 0..self.xlen {
-                    try!(write!(&mut writer, "{} ", 
-                                self.get_tile((x, y, z)).expect("Malformed map").material));
+                    let tile = self.get_tile((x, y, z)).expect("Malformed map");
+                    try!(write!(&mut writer, "{};{},", tile.material, tile.mode as u32));
                 }
                 try!(write!(&mut writer, "\n"));
             }
             try!(write!(&mut writer, "\n"));
         }
+        try!(writer.flush());
+
         Ok(())
     }
 }
@@ -343,53 +395,79 @@ pub fn handle_to_snapshot(handle: &CameraHandle, map: &Map) -> MapSnapshot {
     MapSnapshot {tiles: tiles, xlen: handle.xlen, ylen: handle.ylen}
 }
 
+/// The origin of the chunk that a tile belongs to
+pub fn chunk_origin(pos: Pos) -> Pos {
+    let (x, y, z) = pos;
+    (round_down(x, CHUNK_TILES_X), round_down(y, CHUNK_TILES_Y), round_down(z, CHUNK_TILES_Z))
+}
+
+// Down to a multiple of the chunk length, which may be 1
+fn round_down(n: PosUnit, chunk_len: PosUnit) -> PosUnit {
+    n - n % chunk_len
+}
+
 pub fn blank_map(root: &Path) -> Map {
     // Load materials properties file
     let materials = init_materials(root);
 
-    Map {
-        tiles: Tiles::new(), 
-        materials: materials, 
-        xlen: 0,
-        ylen: 0,
-        zlen: 0,
-    }
+    Map::new((0, 0, 0), materials)
 }
 
 pub fn load_map(path: &str, materials: Materials) -> Result<Map, Error> {
     // Load map from file. Currently unversioned so take heed.
-    // Map validation is not performed.
+    // A malformed file is an error, but tiles are not checked against the materials.
     let mut f = try!(File::open(&path));
     let mut contents = String::new(); 
     try!(f.read_to_string(&mut contents));
 
+    let mut lines = contents.lines();
+    let mut header = try!(lines.next().ok_or_else(|| bad_map("Empty map file".to_owned())))
+                         .split(",");
+    let version: i32 = try!(parse_field(header.next(), "version"));
+    if version < 1 {
+        return Err(bad_map(format!("Bad map version {}", version)));
+    }
+    let xlen: PosUnit = try!(parse_field(header.next(), "x length"));
+    let ylen: PosUnit = try!(parse_field(header.next(), "y length"));
+    let zlen: PosUnit = try!(parse_field(header.next(), "z length"));
+    if xlen < 1 || ylen < 1 || zlen < 1 {
+        return Err(bad_map(format!("Bad map size {}x{}x{}", xlen, ylen, zlen)));
+    }
+
     let mut tiles = Vec::new();
-    let (mut xlen, mut ylen, mut zlen) = (0i32, 0i32, 0i32); 
-    for (i, line) in contents.lines().enumerate() {
-        if i == 0 {
-            let mut split_line = line.split(",");
-            let version: i32 = split_line.next().unwrap().parse().unwrap();
-            assert!(version >= 1);
-            xlen = split_line.next().unwrap().parse().unwrap();
-            ylen = split_line.next().unwrap().parse().unwrap();
-            zlen = split_line.next().unwrap().parse().unwrap();
-        } else {
-            for word in line.split(",") {
-                let mut word_parts = word.split(";");
-                if let Some(material_str) = word_parts.next() {
-                    if material_str.len() > 0 {
-                        let material: u16 = material_str.parse().unwrap();
-                        let mode: Mode = match word_parts.next() { 
-                            Some(mode_str) => to_mode(mode_str.parse().unwrap()).unwrap(),
-                            None => Mode::Block,
-                        };
-                        tiles.push(Tile::new(material, mode));
-                    }
-                }
-            }
+    for line in lines {
+        for word in line.split(",").map(|word| word.trim()).filter(|word| !word.is_empty()) {
+            let mut word_parts = word.split(";");
+            let material: MaterialID = try!(parse_field(word_parts.next(), "material"));
+            let mode: Mode = match word_parts.next() { 
+                Some(mode_str) => {
+                    let mode_num = try!(parse_field(Some(mode_str), "mode"));
+                    try!(to_mode(mode_num).ok_or_else(|| {
+                        bad_map(format!("Bad mode {} in map", mode_num))
+                    }))
+                },
+                None => Mode::Block,
+            };
+            tiles.push(Tile::new(material, mode));
         }
     }
 
+    let size = (xlen as usize).checked_mul(ylen as usize)
+                              .and_then(|area| area.checked_mul(zlen as usize));
+    if size != Some(tiles.len()) {
+        return Err(bad_map(format!("Map is {}x{}x{} but has {} tiles",
+                                   xlen, ylen, zlen, tiles.len())));
+    }
 
     Ok(Map {tiles: tiles, materials: materials, xlen: xlen, ylen: ylen, zlen: zlen})
 }
+
+fn bad_map(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+// One comma or semicolon separated field of a map file
+fn parse_field<T: FromStr>(field: Option<&str>, what: &str) -> Result<T, Error> {
+    let field = try!(field.ok_or_else(|| bad_map(format!("Map is missing its {}", what))));
+    field.trim().parse().map_err(|_| bad_map(format!("Bad {} {} in map", what, field)))
+}
@@ -1,63 +1,580 @@
+use std::cmp::{max, min};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
 use std::sync::mpsc::{Receiver, Sender, SyncSender};
 use std::net::{TcpStream};
+use std::time::Instant;
+
+use bincode;
+use bincode::{deserialize, serialize, serialized_size, Infinite};
+use rand;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
 
 use game::base::*;
-use entities::entity::{EntID, EntSnaps};
-use map::tiles::{Tile, MapChunk};
+use game::lobby::{LobbyState, MatchSettings};
+use game::save::SaveGame;
+use entities::entity::{Entities, Entity, EntID, EntIDs, EntSnap, EntSnaps};
+use map::tiles::{CHUNK_TILES_X, CHUNK_TILES_Y, CHUNK_TILES_Z, MapChunk, PosUnit, Tile};
+use defs::base::Defs;
+use defs::packs::PackInfos;
+use entities::creatures::CreatureID;
+use objects::buildings::{BuildingID, BuildingTypeID, Buildings};
+use objects::items::{ItemTypeID, Items};
+use objects::stockpiles::Stockpiles;
+use net::web::Encoding;
+
+// Bump whenever a change to the messages stops older peers from reading them
+pub const PROTOCOL_VERSION: ProtocolVersion = 1;
+pub const GAME_VERSION: &'static str = env!("CARGO_PKG_VERSION");
+
+// Tiles around the edge of a player's camera that they still get updates for
+pub const VIEW_MARGIN: PosUnit = 8;
 
 pub const SERVER_PORT: u16 = 9999;
+// For browsers, which can only speak WebSocket
+pub const WEB_PORT: u16 = 9998;
 pub const CLIENT_PORT: u16 = 0;
-pub const MSG_BUF_SIZE: usize = 4096;
+// Largest message accepted by default, which comfortably fits the biggest map chunk
+pub const MAX_MSG_SIZE: usize = 16 * 1024 * 1024;
+// Bytes taken up by the length prefix in front of each message
+pub const FRAME_HEADER_SIZE: usize = 4;
 
+pub type ProtocolVersion = u32;
+// Handed to a joining player so they can take their place back after a dropped connection
+pub type ResumeToken = u64;
+// Milliseconds since the sender started, echoed back in the ack to a heartbeat,
+// which stays under JSON_SAFE_MAX for a few hundred thousand years
+pub type Stamp = u64;
+// Largest whole number that browsers read a JSON number as exactly. Tokens,
+// checksums and hashes are cut down to fit, so they come back as they went.
+pub const JSON_SAFE_MAX: u64 = (1 << 53) - 1;
 pub type SyncClientMsgSend = SyncSender<(ClientMsg, PlayerID)>;
 pub type ClientMsgSend = Sender<(ClientMsg, PlayerID)>;
 pub type ClientMsgRecv = Receiver<(ClientMsg, PlayerID)>;
 pub type ServerMsgSend = Sender<(ServerMsg, PlayerID)>;
 pub type ServerMsgRecv = Receiver<(ServerMsg, PlayerID)>;
-pub type SendStream = Sender<(TcpStream, PlayerID)>;
-pub type RecvStream = Receiver<(TcpStream, PlayerID)>;
+pub type SendConn = Sender<ConnEvent>;
+pub type RecvConn = Receiver<ConnEvent>;
 
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub enum ClientMsg {
-    Heartbeat(),
-    Ack(),
+    // Kept first so that it decodes the same under every protocol version
+    Hello(Hello),
+    Heartbeat(Stamp),
+    Ack(Stamp),
     RequestMap((Pos, Pos)),
     RequestEnts(),
+    SetView(View),
     MarkDig((Pos, Pos)),
+    MarkStockpile((Pos, Pos), Vec<ItemTypeID>),
+    PlaceBuilding(BuildingTypeID, Pos),
+    QueueUnit(BuildingID, CreatureID),
     EntAttack(EntID, EntID),
     EntMove(EntID, Pos),
     Leave(),
+    // Lobby
+    SetName(String),
+    SetTeam(u16),
+    SetReady(bool),
+    SetMatch(MatchSettings),
+    StartMatch(),
+    // Lockstep, with the commands to run on a tick and the checksum after a tick
+    Turn(Ticks, Vec<ClientMsg>),
+    Checksum(Ticks, u64),
+    // Only ever put in a turn by the server, under a player who is gone for
+    // good, giving their things to the heir or releasing them if None
+    HandOn(Option<PlayerID>),
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub enum ServerMsg {
-    Heartbeat(),
-    Ack(),
+    // Kept first for the same reason as ClientMsg::Hello
+    Boot(BootReason),
+    Heartbeat(Stamp),
+    Ack(Stamp),
     ReplyJoin(PlayerJoin),
+    // The data packs, in load order, that the server runs
+    SendPacks(PackInfos),
+    SendDefs(Defs),
     SendMapChunk(MapChunk),
     UpdateTile(Tile, Pos),
-    SendEnts(EntSnaps),
-    Boot(),
+    SendEnts(EntUpdate),
+    SendItems(Items),
+    SendStockpiles(Stockpiles),
+    SendBuildings(Buildings),
+    PlayerLeft(PlayerID, LeaveReason),
+    // The lobby, along with the id of the player it is sent to
+    SendLobby(PlayerID, LobbyState),
+    // Lockstep, with the whole game to run from and then the turns for each tick
+    SendSave(SaveGame),
+    Turn(Ticks, Vec<(PlayerID, ClientMsg)>),
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+/// The first message a client sends, which the server answers with either
+/// ReplyJoin or Boot
+pub struct Hello {
+    // Kept first so that servers can read it from a hello they can not
+    // otherwise make sense of, see unreadable_hello
+    pub protocol_version: ProtocolVersion,
+    pub game_version: String,
+    pub name: String,
+    // Set when reconnecting to pick up where a dropped connection left off
+    pub resume_token: Option<ResumeToken>,
+    // Watch the whole game without playing in it
+    pub spectate: bool,
+    // The client's data packs, in load order, which have to match the server's
+    pub packs: PackInfos,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+/// Why the server dropped a player
+pub enum BootReason {
+    ProtocolMismatch { server: ProtocolVersion, client: ProtocolVersion },
+    // Anything sent before Hello
+    NoHello,
+    // The client runs different data packs, for the reason given
+    PackMismatch(String),
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+/// Why a player is no longer in the game
+pub enum LeaveReason {
+    // The player left of their own accord
+    Quit,
+    // The connection was lost
+    Dropped,
+}
+
+#[derive(Debug)]
+/// Connections opening and closing, passed between the network threads and the game
+pub enum ConnEvent {
+    Opened(Conn, PlayerID),
+    Closed(PlayerID),
+    // A connection taken over by a resuming player, from its own id to theirs
+    Renamed(PlayerID, PlayerID),
+    // A connection whose first message could not be read, and why to boot it
+    Unreadable(PlayerID, BootReason),
+    // A WebSocket frame answering a ping or close, for the outgoing thread to
+    // write so that it can not split one of its own frames
+    Answer(TcpStream, Vec<u8>),
+}
+
+#[derive(Debug)]
+/// A player's connection, as the outgoing thread writes to it
+pub enum Conn {
+    /// A socket, and how messages go over it
+    Socket(TcpStream, Wire),
+    /// A client in the same process, which is handed its messages as they are
+    Local(ServerMsgSend),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Wire {
+    /// Length prefixed bincode, as FrameCodec writes it
+    Frames,
+    /// WebSocket frames, as WebCodec writes them
+    WebSocket(Encoding),
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+/// A block of tiles, such as what a player's camera covers, including both corners
+pub struct View {
+    pub start: Pos,
+    pub end: Pos,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug, Default)]
+/// How the entities have changed since the last update sent to a player
+pub struct EntUpdate {
+    // Whether every entity is included, rather than just those that changed
+    pub keyframe: bool,
+    // Entities the player has not been told about yet, in full
+    pub spawned: EntSnaps,
+    // Entities the player already knows about
+    pub changed: EntSnaps,
+    // Tombstones for entities that have died or been removed
+    pub despawned: EntIDs,
+}
+
+#[derive(Debug, Clone)]
+/// The entity state last sent to a player, which updates are worked out
+/// against. Messages arrive in order over TCP, so this is also what the
+/// player's client holds.
+pub struct EntBaseline {
+    sent: HashMap<EntID, EntSnap>,
+    keyframe_due: bool,
+    // The view has moved since the last update, so what is in it needs checking
+    view_moved: bool,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
 pub struct PlayerJoin {
     pub player_id: PlayerID,
     pub team_id: TeamID,
-    pub map_dim: Pos
+    pub map_dim: Pos,
+    pub resume_token: ResumeToken,
+    // Spectators are sent the whole map and every entity, but can not give orders
+    pub spectator: bool,
 }
 
 impl PlayerJoin {
-    pub fn new(player_id: PlayerID, team_id: TeamID, map_dim: Pos) -> PlayerJoin {
+    pub fn new(player_id: PlayerID, team_id: TeamID, map_dim: Pos,
+               resume_token: ResumeToken, spectator: bool) -> PlayerJoin {
         PlayerJoin { 
             player_id: player_id, 
             team_id: team_id,
             map_dim: map_dim,
+            resume_token: resume_token,
+            spectator: spectator,
+        }
+    }
+}
+
+impl ClientMsg {
+    /// Whether the message orders a team about, which spectators can not do
+    pub fn is_command(&self) -> bool {
+        match *self {
+            ClientMsg::MarkDig(_) | ClientMsg::MarkStockpile(_, _) |
+            ClientMsg::PlaceBuilding(_, _) | ClientMsg::QueueUnit(_, _) |
+            ClientMsg::EntAttack(_, _) | ClientMsg::EntMove(_, _) => true,
+            _ => false,
         }
     }
 }
 
+/// A token for a player to resume with, small enough to go through JSON
+pub fn new_resume_token() -> ResumeToken {
+    rand::random::<u64>() & JSON_SAFE_MAX
+}
+
+/// The stamp for a heartbeat sent now
+pub fn stamp_since(start: Instant) -> Stamp {
+    let elapsed = start.elapsed();
+    elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_nanos() / 1_000_000)
+}
+
+/// Work a new round trip time, in milliseconds, into the running average
+pub fn smooth_rtt(rtt: Option<u64>, sample: u64) -> u64 {
+    match rtt {
+        Some(rtt) => (rtt * 7 + sample) / 8,
+        None => sample,
+    }
+}
+
+impl Hello {
+    pub fn new(name: &str) -> Hello {
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            game_version: GAME_VERSION.to_owned(),
+            name: name.to_owned(),
+            resume_token: None,
+            spectate: false,
+            packs: PackInfos::new(),
+        }
+    }
+
+    pub fn spectate(name: &str) -> Hello {
+        Hello { spectate: true, ..Hello::new(name) }
+    }
+
+    pub fn resume(name: &str, resume_token: ResumeToken) -> Hello {
+        Hello { resume_token: Some(resume_token), ..Hello::new(name) }
+    }
+}
+
+impl fmt::Display for BootReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BootReason::ProtocolMismatch { server, client } => {
+                write!(f, "Server speaks protocol version {} but the client speaks {}",
+                       server, client)
+            },
+            BootReason::NoHello => write!(f, "Client did not say hello"),
+            BootReason::PackMismatch(ref reason) => {
+                write!(f, "Data packs do not match the server: {}", reason)
+            },
+        }
+    }
+}
+
+impl fmt::Display for LeaveReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LeaveReason::Quit => write!(f, "quit"),
+            LeaveReason::Dropped => write!(f, "lost connection"),
+        }
+    }
+}
+
+impl View {
+    pub fn new(corner1: Pos, corner2: Pos) -> View {
+        let (x1, y1, z1) = corner1;
+        let (x2, y2, z2) = corner2;
+
+        View {
+            start: (min(x1, x2), min(y1, y2), min(z1, z2)),
+            end: (max(x1, x2), max(y1, y2), max(z1, z2)),
+        }
+    }
+
+    /// The view widened by margin tiles to each side, and a level up and down
+    pub fn grow(&self, margin: PosUnit) -> View {
+        let (x0, y0, z0) = self.start;
+        let (x1, y1, z1) = self.end;
+
+        View {
+            start: (x0 - margin, y0 - margin, z0 - 1),
+            end: (x1 + margin, y1 + margin, z1 + 1),
+        }
+    }
+
+    pub fn contains(&self, pos: Pos) -> bool {
+        let (x, y, z) = pos;
+        self.start.0 <= x && x <= self.end.0 &&
+        self.start.1 <= y && y <= self.end.1 &&
+        self.start.2 <= z && z <= self.end.2
+    }
+
+    /// Whether any of the chunk starting at origin is in view
+    pub fn overlaps_chunk(&self, origin: Pos) -> bool {
+        let (x, y, z) = origin;
+        x <= self.end.0 && self.start.0 < x + CHUNK_TILES_X &&
+        y <= self.end.1 && self.start.1 < y + CHUNK_TILES_Y &&
+        z <= self.end.2 && self.start.2 < z + CHUNK_TILES_Z
+    }
+}
+
+impl EntBaseline {
+    pub fn new() -> EntBaseline {
+        EntBaseline {
+            sent: HashMap::new(),
+            // A player starts out knowing of nothing
+            keyframe_due: true,
+            view_moved: false,
+        }
+    }
+
+    /// Have the next update send every entity
+    pub fn request_keyframe(&mut self) {
+        self.keyframe_due = true;
+    }
+
+    /// Have the next update spawn whatever came into view and despawn whatever
+    /// left it, without resending the entities that stayed in view
+    pub fn move_view(&mut self) {
+        self.view_moved = true;
+    }
+
+    /// Work out the next update for the player, and take it as sent
+    ///
+    /// # Arguments
+    ///
+    /// * `changed` - Entities that may have changed, from Change::EntChange
+    /// * `keyframe` - Whether to send every entity, whether changed or not
+    /// * `view` - Where the player can see, or None to send everything
+    pub fn update(&mut self, entities: &[Entity], changed: &HashSet<EntID>,
+                  keyframe: bool, view: Option<View>) -> EntUpdate {
+        let keyframe = keyframe || self.keyframe_due;
+        // Every entity has to be looked at, though only the differences are sent
+        let recheck = keyframe || self.view_moved;
+        self.keyframe_due = false;
+        self.view_moved = false;
+
+        let visible = |ent: &Entity| ent.alive && view.map_or(true, |view| view.contains(ent.pos));
+
+        let mut update = EntUpdate { keyframe: keyframe, ..EntUpdate::default() };
+        for ent in entities.iter().filter(|ent| visible(ent) &&
+                                                (recheck || changed.contains(&ent.id))) {
+            let snap = ent.snap();
+            match self.sent.get(&ent.id) {
+                None => update.spawned.push(snap.clone()),
+                Some(old_snap) if keyframe || *old_snap != snap => update.changed.push(snap.clone()),
+                Some(_) => continue,
+            }
+            self.sent.insert(ent.id, snap);
+        }
+
+        // Dead entities, and those out of view, are gone as far as clients are concerned
+        let gone: EntIDs = self.sent.keys()
+                                    .filter(|id| recheck || changed.contains(id))
+                                    .filter(|id| !entities.iter().any(|ent| ent.id == **id &&
+                                                                            visible(ent)))
+                                    .cloned()
+                                    .collect();
+        for ent_id in gone {
+            self.sent.remove(&ent_id);
+            update.despawned.push(ent_id);
+        }
+
+        update
+    }
+}
+
+impl EntUpdate {
+    pub fn is_empty(&self) -> bool {
+        !self.keyframe &&
+        self.spawned.is_empty() && self.changed.is_empty() && self.despawned.is_empty()
+    }
+
+    /// Bring a client's copy of the entities up to date
+    pub fn apply(self, entities: &mut Entities) {
+        entities.retain(|ent| !self.despawned.contains(&ent.id));
+        // Anything left out of a keyframe should not be there
+        if self.keyframe {
+            entities.retain(|ent| {
+                self.changed.iter().chain(self.spawned.iter()).any(|snap| snap.id == ent.id)
+            });
+        }
+
+        for ent_snap in self.changed.iter().chain(self.spawned.iter()) {
+            match entities.iter_mut().find(|ent| ent.id == ent_snap.id) {
+                Some(ent) => {
+                    ent.pos = ent_snap.pos;
+                    ent.team_id = ent_snap.team_id;
+                    ent.health = ent_snap.health;
+                    ent.alive = ent_snap.alive;
+                    ent.inventory = ent_snap.inventory.clone();
+                },
+                // A spawn, or a change the client missed the spawn of
+                None => entities.push(Entity::from_snap(ent_snap)),
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    Io(io::Error),
+    // A message over the maximum size, with the size it claimed to be
+    TooLarge(usize),
+    Encode(bincode::Error),
+    // The payload was read in full but is not a valid message, so the
+    // stream can carry on with the next frame
+    Decode(bincode::Error),
+    // A JSON message that could not be encoded or decoded, which like Decode
+    // leaves the stream fine to carry on
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FrameError::Io(ref err) => write!(f, "{}", err),
+            FrameError::TooLarge(size) => write!(f, "Message of {} bytes is too large", size),
+            FrameError::Encode(ref err) => write!(f, "Could not encode message: {}", err),
+            FrameError::Decode(ref err) => write!(f, "Could not decode message: {}", err),
+            FrameError::Json(ref err) => write!(f, "Bad JSON message: {}", err),
+        }
+    }
+}
+
+impl From<io::Error> for FrameError {
+    fn from(err: io::Error) -> FrameError {
+        FrameError::Io(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// Writes and reads messages as a 4 byte big endian length followed by the
+/// bincode encoded message
+pub struct FrameCodec {
+    pub max_size: usize,
+}
+
+impl FrameCodec {
+    pub fn new(max_size: usize) -> FrameCodec {
+        FrameCodec {
+            max_size: max_size,
+        }
+    }
+
+    pub fn write<W: Write, T: Serialize>(&self, stream: &mut W, msg: &T) -> Result<(), FrameError> {
+        let encoded = match serialize(msg, Infinite) {
+            Ok(encoded) => encoded,
+            Err(err) => return Err(FrameError::Encode(err)),
+        };
+        if encoded.len() > self.max_size {
+            return Err(FrameError::TooLarge(encoded.len()));
+        }
+
+        // Write in one go so that frames from different threads cannot interleave
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + encoded.len());
+        frame.extend_from_slice(&usize_to_u8_array(encoded.len()));
+        frame.extend_from_slice(&encoded);
+        try!(stream.write_all(&frame));
+
+        Ok(())
+    }
+
+    /// Read the next message, blocking until all of it has arrived
+    ///
+    /// After an Io or TooLarge error the stream is no longer lined up
+    /// with the start of a frame and should be dropped.
+    pub fn read<R: Read, T: DeserializeOwned>(&self, stream: &mut R) -> Result<T, FrameError> {
+        let payload = try!(self.read_payload(stream));
+        decode(Encoding::Bincode, &payload)
+    }
+
+    /// Bytes that writing a message takes, length prefix and all
+    pub fn frame_size<T: Serialize>(&self, msg: &T) -> u64 {
+        FRAME_HEADER_SIZE as u64 + serialized_size(msg)
+    }
+
+    /// Read the next frame without decoding it, failing as read does
+    pub fn read_payload<R: Read>(&self, stream: &mut R) -> Result<Vec<u8>, FrameError> {
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        try!(stream.read_exact(&mut header));
+
+        let size = u8_array_to_usize(&header, 0);
+        if size > self.max_size {
+            return Err(FrameError::TooLarge(size));
+        }
+
+        let mut payload = vec![0u8; size];
+        try!(stream.read_exact(&mut payload));
+
+        Ok(payload)
+    }
+}
+
+#[derive(Deserialize)]
+/// The start of ClientMsg::Hello, which every protocol version lays out the same
+enum HelloStart {
+    Hello { protocol_version: ProtocolVersion },
+}
+
+/// Decode a message read off the wire in either encoding
+pub fn decode<T: DeserializeOwned>(encoding: Encoding, payload: &[u8]) -> Result<T, FrameError> {
+    match encoding {
+        Encoding::Json => serde_json::from_slice(payload).map_err(FrameError::Json),
+        Encoding::Bincode => deserialize(payload).map_err(FrameError::Decode),
+    }
+}
+
+/// Why to boot a connection whose first message could not be decoded.
+/// A hello from another protocol version still starts with the version,
+/// so the client can be told it does not match.
+pub fn unreadable_hello(encoding: Encoding, payload: &[u8]) -> BootReason {
+    match decode(encoding, payload) {
+        Ok(HelloStart::Hello { protocol_version }) if protocol_version != PROTOCOL_VERSION => {
+            BootReason::ProtocolMismatch { server: PROTOCOL_VERSION, client: protocol_version }
+        },
+        _ => BootReason::NoHello,
+    }
+}
+
+impl Default for FrameCodec {
+    fn default() -> FrameCodec {
+        FrameCodec::new(MAX_MSG_SIZE)
+    }
+}
+
 pub fn usize_to_u8_array(x: usize) -> [u8;4] {
     let b1 : u8 = ((x >> 24) & 0xff) as u8;
     let b2 : u8 = ((x >> 16) & 0xff) as u8;
@@ -1,143 +1,299 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
 use std::io;
-use std::io::{Read, Write};
-use std::net::{Ipv4Addr, SocketAddrV4, TcpStream};
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4, TcpStream};
 use std::thread;
-use std::sync::mpsc::channel;
-
-use bincode::{deserialize, serialize, Infinite};
+use std::time::{Duration, Instant};
+use std::sync::mpsc::{Receiver, TryRecvError, channel};
 
+use defs::packs::PackInfos;
 use game::base::*;
+use game::lobby::MatchSettings;
+use game::lockstep::INPUT_DELAY;
+use entities::creatures::CreatureID;
 use entities::entity::EntID;
+use io::base::CameraHandle;
+use map::tiles::Map;
+use objects::buildings::{BuildingID, BuildingTypeID};
+use objects::items::ItemTypeID;
 use net::base::*;
+use net::transport::{TcpTransport, Transport};
 
 
 pub struct ClientNetIn {
     stream: TcpStream,
+    codec: FrameCodec,
     send_incoming: ServerMsgSend,
 }
 
 pub struct ClientNetOut {
     stream: TcpStream,
+    codec: FrameCodec,
     recv_outgoing: ClientMsgRecv,
 }
 
+// What a reconnect in the background hands back: the transport, and the new
+// connection if it got one
+type Reconnected = (Box<Transport>, Option<(ClientMsgSend, ServerMsgRecv)>);
+
 pub struct NetComm {
+    // How to reach the server again if the connection drops, which is away
+    // on another thread while reconnecting
+    transport: Option<Box<Transport>>,
+    reconnecting: Option<Receiver<Reconnected>>,
     send_outgoing: ClientMsgSend,
     recv_incoming: ServerMsgRecv,
+    connected: bool,
+    // For stamping heartbeats
+    started: Instant,
+    last_heartbeat: Instant,
+    last_heard: Instant,
+    // Round trip time to the server in milliseconds
+    rtt: Option<u64>,
+    // In lockstep, the commands held back for the next turn and the tick it is for
+    turn: RefCell<Option<Vec<ClientMsg>>>,
+    next_turn: Ticks,
 }
 
-pub fn init_network(server_ip: Ipv4Addr) -> NetComm {
-    let server = SocketAddrV4::new(server_ip, SERVER_PORT);
+// Tries at getting back onto the server after losing the connection
+const RECONNECT_ATTEMPTS: u32 = 5;
+const RECONNECT_DELAY_MS: u64 = 1000;
+const HEARTBEAT_INTERVAL_MS: u64 = 1000;
+// How long the server can go quiet before the connection is taken as dropped
+const TIMEOUT_MS: u64 = 10_000;
+
+/// Connect to a server over TCP, sending and taking messages of up to max_msg_size bytes
+pub fn init_network(server_ip: Ipv4Addr, port: u16,
+                    max_msg_size: usize) -> Result<NetComm, io::Error> {
+    let server = SocketAddrV4::new(server_ip, port);
+    init_network_over(Box::new(TcpTransport::new(server, FrameCodec::new(max_msg_size))))
+}
 
-    info!("Connecting to {}", server);
-    let stream = TcpStream::connect(server).unwrap();
-    stream.set_nodelay(true).unwrap();
+/// Connect to a server however it can be reached, such as in memory
+pub fn init_network_over(transport: Box<Transport>) -> Result<NetComm, io::Error> {
+    let (send_outgoing, recv_incoming) = try!(transport.connect());
+    Ok(NetComm::new(transport, send_outgoing, recv_incoming))
+}
 
+/// A connection to nowhere, for watching replays, where nothing is sent or heard
+pub fn init_offline() -> NetComm {
+    let server = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), SERVER_PORT);
     let (send_outgoing, recv_outgoing) = channel();
-    let (send_incoming, recv_incoming) = channel();
-
-    let mut net_out = ClientNetOut::new(stream.try_clone().unwrap(), recv_outgoing);
-    let mut net_in = ClientNetIn::new(stream, send_incoming);
+    let (_send_incoming, recv_incoming) = channel();
 
-    // Outgoing message handler
-    thread::spawn(move|| {
-        net_out.outgoing();
-    });
-            
-    // Incoming message handler
-    thread::spawn(move|| {
-        net_in.incoming();
+    thread::spawn(move || {
+        while recv_outgoing.recv().is_ok() {}
     });
 
-    NetComm::new(send_outgoing, recv_incoming)
+    NetComm::new(Box::new(TcpTransport::new(server, FrameCodec::default())), send_outgoing,
+                 recv_incoming)
 }
 
 impl ClientNetOut {
 
-    pub fn new(stream: TcpStream, recv_outgoing: ClientMsgRecv) -> ClientNetOut {
+    pub fn new(stream: TcpStream, codec: FrameCodec, recv_outgoing: ClientMsgRecv) -> ClientNetOut {
         ClientNetOut { 
             stream: stream,
+            codec: codec,
             recv_outgoing: recv_outgoing,
         }
     }
 
     pub fn outgoing(&mut self) {
-        loop {
-            if let Ok((msg, _)) = self.recv_outgoing.recv() {
-                self.snd(&msg);
-            };
-        };
+        // Ends once the comm has hung up, taking the incoming side down with it
+        while let Ok((msg, _)) = self.recv_outgoing.recv() {
+            self.snd(&msg);
+        }
+        let _ = self.stream.shutdown(Shutdown::Both);
     }
 
     fn snd(&mut self, msg: &ClientMsg) {
-
-        let mut buf = [0u8; MSG_BUF_SIZE];
-        let encoded: Vec<u8> = serialize(&msg, Infinite).unwrap();
-        let enc_size_u8s = usize_to_u8_array(encoded.len());
-        let buf_len = encoded.len() + 4;
-
-        buf[..4].clone_from_slice(&enc_size_u8s);
-        buf[4..buf_len].clone_from_slice(&encoded);
-        let _amt = self.stream.write(&buf[..buf_len]);
+        if let Err(err) = self.codec.write(&mut self.stream, msg) {
+            warn!("Could not send to server: {}", err);
+        }
     }
 }
 
 impl ClientNetIn {
 
-    pub fn new(stream: TcpStream, send_incoming: ServerMsgSend) -> ClientNetIn {
+    pub fn new(stream: TcpStream, codec: FrameCodec, send_incoming: ServerMsgSend) -> ClientNetIn {
         ClientNetIn { 
             stream: stream,
+            codec: codec,
             send_incoming: send_incoming,
         }
     }
 
     pub fn incoming(&mut self) {
         loop {
-            if let Ok(msg) = self.rcv() {
-                self.send_incoming.send((msg, 0)).unwrap();
+            match self.codec.read(&mut self.stream) {
+                Ok(msg) => {
+                    if self.send_incoming.send((msg, 0)).is_err() {
+                        break;
+                    }
+                },
+                Err(FrameError::Decode(err)) => warn!("Bad message from server: {}", err),
+                Err(err) => {
+                    warn!("Server stream err: {}", err);
+                    break;
+                },
             }
         }
     }
-
-    fn rcv(&mut self) -> Result<ServerMsg, io::Error> {
-        let mut n_buf = [0u8; 4];
-        let mut buf = [0u8; MSG_BUF_SIZE];
-
-        try!(self.stream.read_exact(&mut n_buf));
-        let n = u8_array_to_usize(&n_buf[..], 0);
-        try!(self.stream.read_exact(&mut buf[..n]));
-
-        let decoded: ServerMsg = deserialize(&buf[..n]).unwrap();
-
-        Ok(decoded)
-    }
 }
 
 impl NetComm {
 
-    pub fn new(send_outgoing: ClientMsgSend, recv_incoming: ServerMsgRecv) -> NetComm {
+    pub fn new(transport: Box<Transport>, send_outgoing: ClientMsgSend,
+               recv_incoming: ServerMsgRecv) -> NetComm {
         NetComm { 
+            transport: Some(transport),
+            reconnecting: None,
             send_outgoing: send_outgoing,
             recv_incoming: recv_incoming,
+            connected: true,
+            started: Instant::now(),
+            last_heartbeat: Instant::now(),
+            last_heard: Instant::now(),
+            rtt: None,
+            turn: RefCell::new(None),
+            next_turn: 0,
         }
     }
 
     pub fn get_incoming_msgs(&mut self) -> Option<ServerMsg> {
-        self.recv_incoming.try_recv().ok().map(|(msg, _)| msg)
+        match self.recv_incoming.try_recv() {
+            Ok((msg, _)) => {
+                self.last_heard = Instant::now();
+                Some(msg)
+            },
+            Err(TryRecvError::Empty) => None,
+            // The incoming thread has hung up
+            Err(TryRecvError::Disconnected) => {
+                self.connected = false;
+                None
+            },
+        }
+    }
+
+    /// Whether the connection to the server is still up, as of the last
+    /// check for incoming messages
+    pub fn connected(&self) -> bool {
+        self.connected && self.last_heard.elapsed() < Duration::from_millis(TIMEOUT_MS)
+    }
+
+    /// Send a heartbeat if one is due, so the server knows we are still here
+    pub fn keep_alive(&mut self) {
+        if self.last_heartbeat.elapsed() >= Duration::from_millis(HEARTBEAT_INTERVAL_MS) {
+            self.heartbeat();
+        }
+    }
+
+    /// Round trip time to the server in milliseconds, once it has answered a heartbeat
+    pub fn rtt(&self) -> Option<u64> {
+        self.rtt
+    }
+
+    /// Time how long the server took to answer a heartbeat
+    pub fn record_rtt(&mut self, stamp: Stamp) {
+        let sample = stamp_since(self.started).saturating_sub(stamp);
+        self.rtt = Some(smooth_rtt(self.rtt, sample));
+    }
+
+    /// Get back onto the server after losing the connection. The old one is
+    /// hung up on, and a few tries at a new one are made in the background,
+    /// so this is called every frame until it gives back whether we got one.
+    pub fn reconnect(&mut self) -> Option<bool> {
+        if let Some(transport) = self.transport.take() {
+            warn!("Lost connection to the server, reconnecting");
+            self.hang_up();
+
+            let (send_reconnected, recv_reconnected) = channel();
+            self.reconnecting = Some(recv_reconnected);
+            thread::spawn(move|| {
+                let connection = try_connect(&*transport);
+                let _ = send_reconnected.send((transport, connection));
+            });
+        }
+
+        let (transport, connection) = match self.reconnecting.as_ref().map(|recv| recv.try_recv()) {
+            Some(Ok(reconnected)) => reconnected,
+            Some(Err(TryRecvError::Empty)) | None => return None,
+            Some(Err(TryRecvError::Disconnected)) => {
+                self.reconnecting = None;
+                return Some(false);
+            },
+        };
+        self.transport = Some(transport);
+        self.reconnecting = None;
+
+        match connection {
+            Some((send_outgoing, recv_incoming)) => {
+                self.send_outgoing = send_outgoing;
+                self.recv_incoming = recv_incoming;
+                self.connected = true;
+                self.last_heard = Instant::now();
+                self.rtt = None;
+                Some(true)
+            },
+            None => Some(false),
+        }
+    }
+
+    /// Drop the channels to the connection, which shuts its stream down.
+    /// Anything sent until there is a new one goes nowhere.
+    fn hang_up(&mut self) {
+        let (send_outgoing, _) = channel();
+        let (_, recv_incoming) = channel();
+        self.send_outgoing = send_outgoing;
+        self.recv_incoming = recv_incoming;
+        self.connected = false;
+    }
+
+    pub fn hello(&self, name: &str, packs: &PackInfos) {
+        self.snd_msg(ClientMsg::Hello(Hello { packs: packs.clone(), ..Hello::new(name) }));
+    }
+
+    /// Ask to watch the game rather than play in it
+    pub fn spectate(&self, name: &str, packs: &PackInfos) {
+        self.snd_msg(ClientMsg::Hello(Hello { packs: packs.clone(), ..Hello::spectate(name) }));
     }
 
-    pub fn heartbeat(&self) {
-        self.snd_msg(ClientMsg::Heartbeat());
+    /// Say hello on a new connection, asking for our old place back
+    pub fn resume(&self, name: &str, resume_token: ResumeToken, packs: &PackInfos) {
+        let hello = Hello { packs: packs.clone(), ..Hello::resume(name, resume_token) };
+        self.snd_msg(ClientMsg::Hello(hello));
     }
 
-    pub fn ack(&self) {
-        self.snd_msg(ClientMsg::Ack());
+    pub fn heartbeat(&mut self) {
+        self.last_heartbeat = Instant::now();
+        self.snd_msg(ClientMsg::Heartbeat(stamp_since(self.started)));
+    }
+
+    pub fn ack(&self, stamp: Stamp) {
+        self.snd_msg(ClientMsg::Ack(stamp));
     }
 
     pub fn request_map(&self, selection: (Pos, Pos)) {
         self.snd_msg(ClientMsg::RequestMap(selection));
     }
 
+    /// Tell the server where the camera is, and ask for any chunks around it
+    /// that are not loaded yet. Chunks that drop out of view stop getting
+    /// updates, so they are forgotten and fetched again when back in view.
+    pub fn follow_camera(&self, ch: &CameraHandle, map: &Map, loaded_chunks: &mut HashSet<Pos>) {
+        let view = View::new((ch.x, ch.y, ch.z), (ch.x + ch.xlen - 1, ch.y + ch.ylen - 1, ch.z));
+        self.snd_msg(ClientMsg::SetView(view));
+
+        let subscribed = view.grow(VIEW_MARGIN);
+        loaded_chunks.retain(|origin| subscribed.overlaps_chunk(*origin));
+        for origin in map.chunks_in(subscribed.start, subscribed.end) {
+            if loaded_chunks.insert(origin) {
+                self.request_map((origin, origin));
+            }
+        }
+    }
+
     pub fn request_ents(&self) {
         self.snd_msg(ClientMsg::RequestEnts());
     }
@@ -146,6 +302,18 @@ impl NetComm {
         self.snd_msg(ClientMsg::MarkDig(selection));
     }
 
+    pub fn mark_stockpile(&self, selection: (Pos, Pos), filter: Vec<ItemTypeID>) {
+        self.snd_msg(ClientMsg::MarkStockpile(selection, filter));
+    }
+
+    pub fn place_building(&self, type_id: BuildingTypeID, pos: Pos) {
+        self.snd_msg(ClientMsg::PlaceBuilding(type_id, pos));
+    }
+
+    pub fn queue_unit(&self, building_id: BuildingID, creature_id: CreatureID) {
+        self.snd_msg(ClientMsg::QueueUnit(building_id, creature_id));
+    }
+
     pub fn ent_attack(&self, attacker: EntID, defender: EntID) {
         self.snd_msg(ClientMsg::EntAttack(attacker, defender));
     }
@@ -154,11 +322,78 @@ impl NetComm {
         self.snd_msg(ClientMsg::EntMove(ent_id, pos));
     }
 
+    /// Hold commands back to send as turns, starting with the tick after the
+    /// one the game is on
+    pub fn start_turns(&mut self, ticks: Ticks) {
+        *self.turn.borrow_mut() = Some(Vec::new());
+        self.next_turn = ticks + 1;
+    }
+
+    /// Send the turns up to INPUT_DELAY ticks ahead of the game, which are
+    /// empty unless orders were given. Waiting on the game to catch up keeps
+    /// us from getting ahead of the other players.
+    pub fn send_turns(&mut self, ticks: Ticks) {
+        while self.next_turn <= ticks + INPUT_DELAY {
+            let commands = match *self.turn.borrow_mut() {
+                Some(ref mut commands) => commands.drain(..).collect(),
+                None => return,
+            };
+            self.snd_msg(ClientMsg::Turn(self.next_turn, commands));
+            self.next_turn += 1;
+        }
+    }
+
+    pub fn checksum(&self, tick: Ticks, checksum: u64) {
+        self.snd_msg(ClientMsg::Checksum(tick, checksum));
+    }
+
     pub fn leave(&self) {
         self.snd_msg(ClientMsg::Leave());
     }
 
+    pub fn set_name(&self, name: &str) {
+        self.snd_msg(ClientMsg::SetName(name.to_owned()));
+    }
+
+    pub fn set_team(&self, team: u16) {
+        self.snd_msg(ClientMsg::SetTeam(team));
+    }
+
+    pub fn set_ready(&self, ready: bool) {
+        self.snd_msg(ClientMsg::SetReady(ready));
+    }
+
+    pub fn set_match(&self, settings: MatchSettings) {
+        self.snd_msg(ClientMsg::SetMatch(settings));
+    }
+
+    pub fn start_match(&self) {
+        self.snd_msg(ClientMsg::StartMatch());
+    }
+
     fn snd_msg(&self, msg: ClientMsg) {
-        self.send_outgoing.send((msg, 0)).unwrap();
+        if msg.is_command() {
+            if let Some(ref mut commands) = *self.turn.borrow_mut() {
+                commands.push(msg);
+                return;
+            }
+        }
+
+        // Lost along with the connection, if it is down
+        let _ = self.send_outgoing.send((msg, 0));
     }
 }
+
+/// Open a new connection, trying a few times before giving up
+fn try_connect(transport: &Transport) -> Option<(ClientMsgSend, ServerMsgRecv)> {
+    for attempt in 0..RECONNECT_ATTEMPTS {
+        thread::sleep(Duration::from_millis(RECONNECT_DELAY_MS));
+
+        match transport.connect() {
+            Ok(connection) => return Some(connection),
+            Err(err) => warn!("Could not reconnect, attempt {}: {}", attempt + 1, err),
+        }
+    }
+
+    None
+}
@@ -1,3 +1,5 @@
 pub mod base;
 pub mod server;
 pub mod client;
+pub mod transport;
+pub mod web;
@@ -1,144 +1,321 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::io;
-use std::io::{Read, Write};
-use std::net::{Ipv4Addr, SocketAddrV4, TcpListener, TcpStream};
-use std::sync::mpsc::{channel, sync_channel};
+use std::io::{ErrorKind, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{RecvTimeoutError, channel, sync_channel};
 use std::thread;
-
-use bincode::{deserialize, serialize, Infinite};
+use std::time::Duration;
 
 use game::base::*;
-use entities::entity::{EntSnaps};
+use defs::base::Defs;
+use defs::packs::PackInfos;
+use game::lobby::LobbyState;
+use game::save::SaveGame;
 use map::tiles::{Tile, MapChunk};
+use objects::buildings::Buildings;
+use objects::items::Items;
+use objects::stockpiles::Stockpiles;
 use net::base::*;
+use net::web;
+use net::web::{Encoding, WebCodec};
+use net::transport::MemoryTransport;
+
 
+// Longest the outgoing thread goes without seeing to connection events, such
+// as WebSocket pings to answer, while there are no messages to send
+const CONN_EVENT_WAIT_MS: u64 = 50;
 
 pub struct ServerNetOut {
-    player_conns: HashMap<PlayerID, TcpStream>,
+    player_conns: HashMap<PlayerID, Conn>,
+    codec: FrameCodec,
     recv_outgoing: ServerMsgRecv,
-    recv_stream_from_game: RecvStream,
+    recv_conn_from_game: RecvConn,
 }
 
 pub struct NetComm {
     send_outgoing: ServerMsgSend,
     recv_incoming: ClientMsgRecv,
-    recv_stream_to_game: RecvStream,
-    send_stream_from_game: SendStream,
+    recv_conn_to_game: RecvConn,
+    send_conn_from_game: SendConn,
+    // Bytes sent to each player so far
+    bytes_sent: RefCell<HashMap<PlayerID, u64>>,
+    // How each player's messages go over the network, which decides their size
+    wires: HashMap<PlayerID, Wire>,
 }
 
-pub fn init_network(server_ip: Ipv4Addr) -> NetComm {
+/// Listen for players over TCP, and for browsers over WebSocket, dropping
+/// anyone who sends a message bigger than max_msg_size bytes
+pub fn init_network(server_ip: Ipv4Addr, port: u16, web_port: u16,
+                    max_msg_size: usize) -> NetComm {
+    let codec = FrameCodec::new(max_msg_size);
 
-    let conn = SocketAddrV4::new(server_ip, SERVER_PORT);
-    let listener = TcpListener::bind(conn).unwrap();
+    let listener = TcpListener::bind(SocketAddrV4::new(server_ip, port)).unwrap();
+    let web_listener = TcpListener::bind(SocketAddrV4::new(server_ip, web_port)).unwrap();
 
     let (send_outgoing, recv_outgoing) = channel();
     let (send_incoming, recv_incoming) = sync_channel(1024);
 
     // New Stream -> NetIn -> Game -> NetOut
-    let (send_stream_to_game, recv_stream_to_game) = channel();
-    let (send_stream_from_game, recv_stream_from_game) = channel();
+    let (send_conn_to_game, recv_conn_to_game) = channel();
+    let (send_conn_from_game, recv_conn_from_game) = channel();
 
-    // Listener thread
-    thread::spawn(move|| {
-        let mut next_player_id = 1;
+    // Both listeners hand out ids from the one count
+    let next_player_id = Arc::new(AtomicUsize::new(1));
+    listen(listener, false, codec, next_player_id.clone(), send_incoming.clone(),
+           send_conn_to_game.clone(), send_conn_from_game.clone());
+    listen(web_listener, true, codec, next_player_id, send_incoming, send_conn_to_game,
+           send_conn_from_game.clone());
+
+    let mut net = ServerNetOut::new(recv_outgoing, recv_conn_from_game, codec);
+    thread::spawn(move || { net.outgoing() });
+
+    NetComm::new(send_outgoing, recv_incoming,
+                 send_conn_from_game, recv_conn_to_game)
+}
+
+/// A network only clients in the same process can join, by connecting
+/// through the transport that comes with it
+pub fn init_local_network() -> (NetComm, MemoryTransport) {
+    let (send_outgoing, recv_outgoing) = channel();
+    let (send_incoming, recv_incoming) = sync_channel(1024);
+    let (send_conn_to_game, recv_conn_to_game) = channel();
+    let (send_conn_from_game, recv_conn_from_game) = channel();
 
+    let mut net = ServerNetOut::new(recv_outgoing, recv_conn_from_game, FrameCodec::default());
+    thread::spawn(move || { net.outgoing() });
+
+    let comm = NetComm::new(send_outgoing, recv_incoming,
+                            send_conn_from_game, recv_conn_to_game);
+    (comm, MemoryTransport::new(send_incoming, send_conn_to_game))
+}
+
+/// A comm with no one on the other end, for running a game that only
+/// talks to itself
+pub fn init_offline() -> NetComm {
+    init_local_network().0
+}
+
+/// Start a thread taking connections on a listener, each served on its own thread
+fn listen(listener: TcpListener, web: bool, codec: FrameCodec, next_player_id: Arc<AtomicUsize>,
+          send_incoming: SyncClientMsgSend, send_conn_to_game: SendConn,
+          send_conn_to_net_out: SendConn) {
+    thread::spawn(move|| {
         for _stream in listener.incoming() {
             if let Ok(stream) = _stream {
                 info!("New client at {:?}", stream);
                 stream.set_nodelay(true).unwrap();
 
-                let player_id = next_player_id;
-                next_player_id += 1;
-
-                // Send copy of stream to outgoing
-                let stream_copy = stream.try_clone().unwrap();
-                send_stream_to_game.send((stream_copy, player_id)).unwrap();
-
+                let player_id = next_player_id.fetch_add(1, Ordering::SeqCst) as PlayerID;
                 let send_in_clone = send_incoming.clone();
+                let send_conn_clone = send_conn_to_game.clone();
+                let send_out_clone = send_conn_to_net_out.clone();
                 thread::spawn(move|| {
-                    // Tell the game that  a new player has joined
-                    handle_client(stream, send_in_clone, player_id);
+                    serve(stream, web, codec, send_in_clone, send_conn_clone, send_out_clone,
+                          player_id);
                 });
             }
         }
     });
-
-    let mut net = ServerNetOut::new(recv_outgoing, recv_stream_from_game);
-    thread::spawn(move || { net.outgoing() });
-
-    NetComm::new(send_outgoing, recv_incoming,
-                 send_stream_from_game, recv_stream_to_game)
 }
 
-pub fn handle_client(mut stream: TcpStream, send_incoming: SyncClientMsgSend, 
-                     player_id: PlayerID) {
-    loop {
-        match rcv(&mut stream) {
-            Ok(msg) => { 
-                send_incoming.send((msg, player_id)).unwrap();
+/// See a connection through from the handshake, if any, to it closing
+fn serve(mut stream: TcpStream, web: bool, codec: FrameCodec, send_incoming: SyncClientMsgSend,
+         send_conn_to_game: SendConn, send_conn_to_net_out: SendConn, player_id: PlayerID) {
+    let wire = if web {
+        match web::accept(&mut stream) {
+            Ok(encoding) => Wire::WebSocket(encoding),
+            Err(err) => {
+                warn!("WebSocket handshake with player {} failed: {}", player_id, err);
+                return;
             },
-            Err(err) => { 
-                warn!("Client stream err: {}", err);
-                break ;
-            },            
         }
+    } else {
+        Wire::Frames
+    };
+
+    // Send copy of stream to outgoing
+    let conn = Conn::Socket(stream.try_clone().unwrap(), wire);
+    if send_conn_to_game.send(ConnEvent::Opened(conn, player_id)).is_err() {
+        return;
     }
+
+    match wire {
+        Wire::Frames => {
+            handle_client(stream, codec, send_incoming, send_conn_to_game.clone(), player_id)
+        },
+        Wire::WebSocket(encoding) => {
+            let mut web_codec = WebCodec::server(encoding);
+            web_codec.max_size = codec.max_size;
+            handle_web_client(stream, web_codec, send_incoming, send_conn_to_game.clone(),
+                              send_conn_to_net_out, player_id)
+        },
+    }
+    // Tell the game once the player is gone, after their last message
+    let _ = send_conn_to_game.send(ConnEvent::Closed(player_id));
 }
 
-fn rcv(stream: &mut TcpStream) -> Result<ClientMsg, io::Error> {
-    let mut n_buf = [0u8; 4];
-    let mut buf = [0u8; MSG_BUF_SIZE];
+pub fn handle_client(mut stream: TcpStream, codec: FrameCodec, send_incoming: SyncClientMsgSend,
+                     send_conn_to_game: SendConn, player_id: PlayerID) {
+    pass_on(&mut stream, send_incoming, send_conn_to_game, player_id, |stream| {
+        codec.read_payload(stream).map(|payload| (Encoding::Bincode, payload))
+    });
+
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+/// Like handle_client, except that pings and closes are answered through the
+/// outgoing thread, which does all the writing to the stream
+pub fn handle_web_client(mut stream: TcpStream, codec: WebCodec, send_incoming: SyncClientMsgSend,
+                         send_conn_to_game: SendConn, send_conn_to_net_out: SendConn,
+                         player_id: PlayerID) {
+    let writer = stream.try_clone().unwrap();
+    pass_on(&mut stream, send_incoming, send_conn_to_game, player_id, |stream| {
+        codec.read_message(stream, |answer| {
+            if let Ok(writer) = writer.try_clone() {
+                let _ = send_conn_to_net_out.send(ConnEvent::Answer(writer, answer));
+            }
+        })
+    });
 
-    try!(stream.read_exact(&mut n_buf));
-    let n = u8_array_to_usize(&n_buf[..], 0);
-    let amt = try!(stream.read(&mut buf[..n]));
+    let _ = stream.shutdown(Shutdown::Both);
+}
 
-    let decoded: ClientMsg = deserialize(&buf[..amt]).unwrap();
+/// Hand a player's messages to the game until their stream closes
+///
+/// Bad messages are skipped, unless the first message is bad. Then the game
+/// is asked to boot the connection, and nothing more from it is passed on
+/// while the boot goes out and hangs up on it.
+fn pass_on<F>(stream: &mut TcpStream, send_incoming: SyncClientMsgSend, send_conn_to_game: SendConn,
+              player_id: PlayerID, mut read: F)
+    where F: FnMut(&mut TcpStream) -> Result<(Encoding, Vec<u8>), FrameError> {
+    let mut greeted = false;
+    let mut booted = false;
+    loop {
+        let (encoding, payload) = match read(stream) {
+            Ok(message) => message,
+            Err(err) => {
+                warn!("Client stream err: {}", err);
+                break;
+            },
+        };
+        if booted {
+            continue;
+        }
 
-    Ok(decoded)
+        match decode(encoding, &payload) {
+            Ok(msg) => {
+                greeted = true;
+                if send_incoming.send((msg, player_id)).is_err() {
+                    break;
+                }
+            },
+            Err(err) => {
+                warn!("Bad message from player {}: {}", player_id, err);
+                if !greeted {
+                    booted = true;
+                    let reason = unreadable_hello(encoding, &payload);
+                    if send_conn_to_game.send(ConnEvent::Unreadable(player_id, reason)).is_err() {
+                        break;
+                    }
+                }
+            },
+        }
+    }
 }
 
 impl ServerNetOut {
 
-    pub fn new(recv_outgoing: ServerMsgRecv, 
-               recv_stream_from_game: RecvStream) -> ServerNetOut {
+    pub fn new(recv_outgoing: ServerMsgRecv, recv_conn_from_game: RecvConn,
+               codec: FrameCodec) -> ServerNetOut {
         ServerNetOut {
             player_conns: HashMap::new(),
+            codec: codec,
             recv_outgoing: recv_outgoing,
-            recv_stream_from_game: recv_stream_from_game,
+            recv_conn_from_game: recv_conn_from_game,
         }
     }
 
     pub fn outgoing(&mut self) {
+        // Ends once the game is gone
         loop {
-            if let Ok((msg, player_id)) = self.recv_outgoing.recv() {
-                self.snd(&msg, player_id);
-            };
-        };
+            match self.recv_outgoing.recv_timeout(Duration::from_millis(CONN_EVENT_WAIT_MS)) {
+                Ok((msg, player_id)) => self.snd(msg, player_id),
+                Err(RecvTimeoutError::Timeout) => self.conn_events(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
     }
 
-    fn snd(&mut self, msg: &ServerMsg, player_id: PlayerID) {
-        // Iterate over new streams until the stream for this joining can be added
-        // TODO Think about whether this checking is neccessary
-        if let ServerMsg::ReplyJoin(reply) = *msg {
-            while !self.player_conns.contains_key(&reply.player_id) {
-                match self.recv_stream_from_game.recv() {
-                    Ok((stream, player_id)) => { self.player_conns.insert(player_id, stream); },
-                    Err(_) => error!("Stream was not available for adding."),
-                }
+    /// See to streams opening and closing, and anything to write back on them
+    fn conn_events(&mut self) {
+        while let Ok(event) = self.recv_conn_from_game.try_recv() {
+            match event {
+                ConnEvent::Opened(conn, new_id) => { self.player_conns.insert(new_id, conn); },
+                ConnEvent::Closed(old_id) => self.hang_up(old_id),
+                ConnEvent::Renamed(conn_id, new_id) => {
+                    if let Some(conn) = self.player_conns.remove(&conn_id) {
+                        self.hang_up(new_id);
+                        self.player_conns.insert(new_id, conn);
+                    }
+                },
+                ConnEvent::Answer(mut stream, answer) => {
+                    // The stream is likely gone if this fails, which the incoming thread sees
+                    let _ = stream.write_all(&answer);
+                },
+                // Only ever sent to the game
+                ConnEvent::Unreadable(_, _) => {},
             }
         }
+    }
 
-        if let Some(mut conn) = self.player_conns.get(&player_id) {
+    fn snd(&mut self, msg: ServerMsg, player_id: PlayerID) {
+        // The game hands over a player's stream before sending them anything,
+        // so any new streams will already be waiting
+        self.conn_events();
 
-            let mut buf = [0u8; MSG_BUF_SIZE];
-            let encoded: Vec<u8> = serialize(msg, Infinite).unwrap();
-            let enc_size_u8s = usize_to_u8_array(encoded.len());
-            let buf_len = encoded.len() + 4;
+        let boot = match msg {
+            ServerMsg::Boot(_) => true,
+            _ => false,
+        };
+        let sent = match self.player_conns.get_mut(&player_id) {
+            Some(&mut Conn::Socket(ref mut stream, Wire::Frames)) => self.codec.write(stream, &msg),
+            Some(&mut Conn::Socket(ref mut stream, Wire::WebSocket(encoding))) => {
+                let mut web_codec = WebCodec::server(encoding);
+                web_codec.max_size = self.codec.max_size;
+                web_codec.write(stream, &msg)
+            },
+            // A local client that has gone away is the same as a dead stream
+            Some(&mut Conn::Local(ref send_to_client)) => {
+                send_to_client.send((msg, player_id)).map_err(|_| {
+                    FrameError::Io(io::Error::new(ErrorKind::BrokenPipe, "Client has gone"))
+                })
+            },
+            None => return,
+        };
 
-            buf[..4].clone_from_slice(&enc_size_u8s);
-            buf[4..buf_len].clone_from_slice(&encoded);
-            let _amt = conn.write(&buf[..buf_len]);
+        match sent {
+            // A stream that can not be written to is dead
+            Err(FrameError::Io(err)) => {
+                warn!("Lost connection to player {}: {}", player_id, err);
+                self.hang_up(player_id);
+            },
+            Err(err) => warn!("Could not send to player {}: {}", player_id, err),
+            Ok(()) => {},
+        }
+
+        if boot {
+            self.hang_up(player_id);
+        }
+    }
+
+    /// Forget a player's stream, which also ends their incoming thread
+    fn hang_up(&mut self, player_id: PlayerID) {
+        // Dropping a local client's channel is enough to hang up on it
+        if let Some(Conn::Socket(stream, _)) = self.player_conns.remove(&player_id) {
+            let _ = stream.shutdown(Shutdown::Both);
         }
     }
 }
@@ -146,22 +323,50 @@ impl ServerNetOut {
 impl NetComm {
 
     pub fn new(send_outgoing: ServerMsgSend, recv_incoming: ClientMsgRecv,
-               send_stream_from_game: SendStream, recv_stream_to_game: RecvStream)
+               send_conn_from_game: SendConn, recv_conn_to_game: RecvConn)
                -> NetComm {
         NetComm {
             send_outgoing: send_outgoing,
             recv_incoming: recv_incoming,
-            recv_stream_to_game: recv_stream_to_game,
-            send_stream_from_game: send_stream_from_game,
+            recv_conn_to_game: recv_conn_to_game,
+            send_conn_from_game: send_conn_from_game,
+            bytes_sent: RefCell::new(HashMap::new()),
+            wires: HashMap::new(),
         }
     }
 
-    pub fn check_incoming_streams(&mut self) -> Option<(TcpStream, PlayerID)> {
-        self.recv_stream_to_game.try_recv().ok()
+    /// Bytes sent to a player so far, including framing
+    pub fn bytes_sent(&self, player_id: PlayerID) -> u64 {
+        self.bytes_sent.borrow().get(&player_id).cloned().unwrap_or(0)
     }
 
-    pub fn setup_out_stream(&mut self, msg: (TcpStream, PlayerID)) {
-        self.send_stream_from_game.send(msg).unwrap();
+    pub fn check_conn_events(&mut self) -> Option<ConnEvent> {
+        self.recv_conn_to_game.try_recv().ok()
+    }
+
+    pub fn setup_out_stream(&mut self, msg: (Conn, PlayerID)) {
+        let (conn, player_id) = msg;
+        if let Conn::Socket(_, wire) = conn {
+            self.wires.insert(player_id, wire);
+        }
+        self.send_conn_from_game.send(ConnEvent::Opened(conn, player_id)).unwrap();
+    }
+
+    /// Send what comes in and goes out on a connection as a different player
+    pub fn rename(&mut self, conn_id: PlayerID, player_id: PlayerID) {
+        self.wires.remove(&player_id);
+        if let Some(wire) = self.wires.remove(&conn_id) {
+            self.wires.insert(player_id, wire);
+        }
+        let _ = self.send_conn_from_game.send(ConnEvent::Renamed(conn_id, player_id));
+    }
+
+    /// Close the connection to a player that has left
+    pub fn hang_up(&mut self, player_id: PlayerID) {
+        self.bytes_sent.borrow_mut().remove(&player_id);
+        self.wires.remove(&player_id);
+        // Nothing to do when the outgoing thread is gone too
+        let _ = self.send_conn_from_game.send(ConnEvent::Closed(player_id));
     }
 
     pub fn check_incoming_msgs(&mut self) -> Option<(ClientMsg, PlayerID)> {
@@ -172,12 +377,12 @@ impl NetComm {
         self.snd_msg(player_id, ServerMsg::ReplyJoin(player_join));
     }
 
-    pub fn heartbeat(&self, player_id: PlayerID) {
-        self.snd_msg(player_id, ServerMsg::Heartbeat());
+    pub fn heartbeat(&self, player_id: PlayerID, stamp: Stamp) {
+        self.snd_msg(player_id, ServerMsg::Heartbeat(stamp));
     }
 
-    pub fn ack(&self, player_id: PlayerID) {
-        self.snd_msg(player_id, ServerMsg::Ack());
+    pub fn ack(&self, player_id: PlayerID, stamp: Stamp) {
+        self.snd_msg(player_id, ServerMsg::Ack(stamp));
     }
 
     pub fn send_map_chunk(&self, player_id: PlayerID, map_chunk: &MapChunk) {
@@ -188,15 +393,61 @@ impl NetComm {
         self.snd_msg(player_id, ServerMsg::UpdateTile(tile_snap, pos));
     }
 
-    pub fn send_ents(&self, player_id: PlayerID, ent_snaps: EntSnaps) {
-        self.snd_msg(player_id, ServerMsg::SendEnts(ent_snaps));
+    pub fn send_ents(&self, player_id: PlayerID, ent_update: EntUpdate) {
+        self.snd_msg(player_id, ServerMsg::SendEnts(ent_update));
+    }
+
+    pub fn send_items(&self, player_id: PlayerID, items: Items) {
+        self.snd_msg(player_id, ServerMsg::SendItems(items));
+    }
+
+    pub fn send_stockpiles(&self, player_id: PlayerID, stockpiles: Stockpiles) {
+        self.snd_msg(player_id, ServerMsg::SendStockpiles(stockpiles));
+    }
+
+    pub fn send_packs(&self, player_id: PlayerID, packs: PackInfos) {
+        self.snd_msg(player_id, ServerMsg::SendPacks(packs));
+    }
+
+    pub fn send_defs(&self, player_id: PlayerID, defs: Defs) {
+        self.snd_msg(player_id, ServerMsg::SendDefs(defs));
+    }
+
+    pub fn send_buildings(&self, player_id: PlayerID, buildings: Buildings) {
+        self.snd_msg(player_id, ServerMsg::SendBuildings(buildings));
     }
 
-    pub fn boot(&self, player_id: PlayerID) {
-        self.snd_msg(player_id, ServerMsg::Boot());
+    pub fn player_left(&self, player_id: PlayerID, left_id: PlayerID, reason: LeaveReason) {
+        self.snd_msg(player_id, ServerMsg::PlayerLeft(left_id, reason));
+    }
+
+    pub fn send_save(&self, player_id: PlayerID, save: SaveGame) {
+        self.snd_msg(player_id, ServerMsg::SendSave(save));
+    }
+
+    pub fn send_turn(&self, player_id: PlayerID, tick: Ticks, turn: Vec<(PlayerID, ClientMsg)>) {
+        self.snd_msg(player_id, ServerMsg::Turn(tick, turn));
+    }
+
+    pub fn send_lobby(&self, player_id: PlayerID, lobby: LobbyState) {
+        self.snd_msg(player_id, ServerMsg::SendLobby(player_id, lobby));
+    }
+
+    pub fn boot(&self, player_id: PlayerID, reason: BootReason) {
+        self.snd_msg(player_id, ServerMsg::Boot(reason));
     }
 
     fn snd_msg(&self, player_id: PlayerID, msg: ServerMsg) {
+        // Counted as the player's own codec writes it, with players in memory
+        // counted as if they were sent frames
+        let size = match self.wires.get(&player_id) {
+            Some(&Wire::WebSocket(encoding)) => {
+                WebCodec::server(encoding).frame_size(&msg).unwrap_or(0)
+            },
+            _ => FrameCodec::default().frame_size(&msg),
+        };
+        *self.bytes_sent.borrow_mut().entry(player_id).or_insert(0) += size;
+
         self.send_outgoing.send((msg, player_id)).unwrap();
     }
 }
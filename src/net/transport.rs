@@ -0,0 +1,109 @@
+use std::io;
+use std::io::ErrorKind;
+use std::net::{SocketAddrV4, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::thread;
+
+use game::base::PlayerID;
+use net::base::*;
+use net::client::{ClientNetIn, ClientNetOut};
+
+
+/// A way for a client to reach a server, which can be handed to another
+/// thread to reconnect on
+pub trait Transport: Send {
+    /// Open a new connection, giving the channel for messages to the server
+    /// and the one its messages come back on
+    fn connect(&self) -> Result<(ClientMsgSend, ServerMsgRecv), io::Error>;
+}
+
+/// Over the network, to a server listening with `net::server::init_network`
+pub struct TcpTransport {
+    pub server: SocketAddrV4,
+    pub codec: FrameCodec,
+}
+
+#[derive(Clone)]
+/// Straight to a server in the same process, without opening any ports.
+/// Made along with the server's comm by `net::server::init_local_network`.
+pub struct MemoryTransport {
+    send_incoming: SyncClientMsgSend,
+    send_conn_to_game: SendConn,
+    next_player_id: Arc<AtomicUsize>,
+}
+
+impl TcpTransport {
+    pub fn new(server: SocketAddrV4, codec: FrameCodec) -> TcpTransport {
+        TcpTransport {
+            server: server,
+            codec: codec,
+        }
+    }
+}
+
+impl Transport for TcpTransport {
+    /// Open a socket, with threads for sending and receiving over it
+    fn connect(&self) -> Result<(ClientMsgSend, ServerMsgRecv), io::Error> {
+        info!("Connecting to {}", self.server);
+        let stream = try!(TcpStream::connect(self.server));
+        try!(stream.set_nodelay(true));
+
+        let (send_outgoing, recv_outgoing) = channel();
+        let (send_incoming, recv_incoming) = channel();
+
+        let mut net_out = ClientNetOut::new(try!(stream.try_clone()), self.codec, recv_outgoing);
+        let mut net_in = ClientNetIn::new(stream, self.codec, send_incoming);
+
+        // Outgoing message handler
+        thread::spawn(move|| {
+            net_out.outgoing();
+        });
+
+        // Incoming message handler
+        thread::spawn(move|| {
+            net_in.incoming();
+        });
+
+        Ok((send_outgoing, recv_incoming))
+    }
+}
+
+impl MemoryTransport {
+    pub fn new(send_incoming: SyncClientMsgSend, send_conn_to_game: SendConn) -> MemoryTransport {
+        MemoryTransport {
+            send_incoming: send_incoming,
+            send_conn_to_game: send_conn_to_game,
+            next_player_id: Arc::new(AtomicUsize::new(1)),
+        }
+    }
+}
+
+impl Transport for MemoryTransport {
+    /// Hand the server a channel to us, and pass what we send on to it
+    /// under our id until we hang up
+    fn connect(&self) -> Result<(ClientMsgSend, ServerMsgRecv), io::Error> {
+        let player_id = self.next_player_id.fetch_add(1, Ordering::SeqCst) as PlayerID;
+        let (send_to_client, recv_incoming) = channel();
+        let opened = ConnEvent::Opened(Conn::Local(send_to_client), player_id);
+        if self.send_conn_to_game.send(opened).is_err() {
+            return Err(io::Error::new(ErrorKind::ConnectionRefused, "The server has stopped"));
+        }
+
+        let (send_outgoing, recv_outgoing) = channel();
+        let send_incoming = self.send_incoming.clone();
+        let send_conn_to_game = self.send_conn_to_game.clone();
+        thread::spawn(move|| {
+            while let Ok((msg, _)) = recv_outgoing.recv() {
+                if send_incoming.send((msg, player_id)).is_err() {
+                    break;
+                }
+            }
+            // Tell the game once the player is gone, after their last message
+            let _ = send_conn_to_game.send(ConnEvent::Closed(player_id));
+        });
+
+        Ok((send_outgoing, recv_incoming))
+    }
+}
@@ -0,0 +1,329 @@
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+
+use base64;
+use bincode::{serialize, Infinite};
+use rand;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+use sha1::Sha1;
+
+use net::base::{FrameError, MAX_MSG_SIZE, decode};
+
+
+// Appended to the client's key to make the accept key, as RFC 6455 sets out
+const ACCEPT_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC11B85";
+// Subprotocols a client can ask for to pick how messages are encoded
+pub const JSON_PROTOCOL: &'static str = "spacefort.json";
+pub const BINCODE_PROTOCOL: &'static str = "spacefort.bincode";
+// Longest handshake read before giving up on the connection
+const MAX_HEAD_SIZE: usize = 8192;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// How messages are written on a WebSocket
+pub enum Encoding {
+    /// Text frames of JSON, which a browser can read without any help
+    Json,
+    /// Binary frames of the same bincode that goes over plain TCP
+    Bincode,
+}
+
+impl Encoding {
+    pub fn protocol(&self) -> &'static str {
+        match *self {
+            Encoding::Json => JSON_PROTOCOL,
+            Encoding::Bincode => BINCODE_PROTOCOL,
+        }
+    }
+
+    fn from_protocol(protocol: &str) -> Option<Encoding> {
+        match protocol {
+            JSON_PROTOCOL => Some(Encoding::Json),
+            BINCODE_PROTOCOL => Some(Encoding::Bincode),
+            _ => None,
+        }
+    }
+}
+
+/// Answer a client's opening handshake, returning the encoding it asked for.
+/// Clients that ask for neither of our subprotocols get JSON.
+pub fn accept<S: Read + Write>(stream: &mut S) -> Result<Encoding, FrameError> {
+    let request = try!(read_head(stream));
+    let key = match header(&request, "sec-websocket-key") {
+        Some(key) if request.starts_with("GET ") => key,
+        _ => {
+            let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+            return Err(protocol_error("Not a WebSocket handshake"));
+        },
+    };
+
+    let asked_for = header(&request, "sec-websocket-protocol").and_then(|protocols| {
+        protocols.split(',').filter_map(|protocol| Encoding::from_protocol(protocol.trim())).next()
+    });
+    let mut response = format!("HTTP/1.1 101 Switching Protocols\r\n\
+                                Upgrade: websocket\r\n\
+                                Connection: Upgrade\r\n\
+                                Sec-WebSocket-Accept: {}\r\n",
+                               accept_key(&key));
+    // Naming a subprotocol the client did not offer would fail the connection
+    if let Some(encoding) = asked_for {
+        response.push_str(&format!("Sec-WebSocket-Protocol: {}\r\n", encoding.protocol()));
+    }
+    response.push_str("\r\n");
+    try!(stream.write_all(response.as_bytes()));
+
+    Ok(asked_for.unwrap_or(Encoding::Json))
+}
+
+/// Open a WebSocket over a stream from the client's end, asking for an encoding
+pub fn connect<S: Read + Write>(stream: &mut S, host: &str, encoding: Encoding)
+                                -> Result<(), FrameError> {
+    let key = base64::encode(&rand::random::<[u8; 16]>());
+    let request = format!("GET / HTTP/1.1\r\n\
+                           Host: {}\r\n\
+                           Upgrade: websocket\r\n\
+                           Connection: Upgrade\r\n\
+                           Sec-WebSocket-Key: {}\r\n\
+                           Sec-WebSocket-Version: 13\r\n\
+                           Sec-WebSocket-Protocol: {}\r\n\r\n",
+                          host, key, encoding.protocol());
+    try!(stream.write_all(request.as_bytes()));
+
+    let response = try!(read_head(stream));
+    if !response.starts_with("HTTP/1.1 101") {
+        return Err(protocol_error("Server refused the WebSocket"));
+    }
+    if header(&response, "sec-websocket-accept") != Some(accept_key(&key)) {
+        return Err(protocol_error("Server gave the wrong accept key"));
+    }
+
+    Ok(())
+}
+
+/// Read an HTTP request or response up to the blank line after its headers
+fn read_head<R: Read>(stream: &mut R) -> Result<String, FrameError> {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD_SIZE {
+            return Err(protocol_error("Handshake is too long"));
+        }
+        try!(stream.read_exact(&mut byte));
+        head.push(byte[0]);
+    }
+
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+/// The value of a header, looked up regardless of case
+fn header(head: &str, name: &str) -> Option<String> {
+    head.lines().skip(1).filter_map(|line| {
+        let mut parts = line.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) if key.trim().to_lowercase() == name => {
+                Some(value.trim().to_owned())
+            },
+            _ => None,
+        }
+    }).next()
+}
+
+fn accept_key(key: &str) -> String {
+    let mut sha = Sha1::new();
+    sha.update(key.as_bytes());
+    sha.update(ACCEPT_GUID.as_bytes());
+    base64::encode(&sha.digest().bytes())
+}
+
+fn protocol_error(reason: &str) -> FrameError {
+    FrameError::Io(io::Error::new(ErrorKind::InvalidData, reason))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// Writes and reads messages as WebSocket frames, in place of the length
+/// prefix that FrameCodec uses
+pub struct WebCodec {
+    pub max_size: usize,
+    pub encoding: Encoding,
+    // Frames from a client have to be masked, and frames from a server must not be
+    mask: bool,
+}
+
+impl WebCodec {
+    pub fn server(encoding: Encoding) -> WebCodec {
+        WebCodec { max_size: MAX_MSG_SIZE, encoding: encoding, mask: false }
+    }
+
+    pub fn client(encoding: Encoding) -> WebCodec {
+        WebCodec { max_size: MAX_MSG_SIZE, encoding: encoding, mask: true }
+    }
+
+    pub fn write<W: Write, T: Serialize>(&self, stream: &mut W, msg: &T) -> Result<(), FrameError> {
+        let (opcode, payload) = try!(self.encode(msg));
+        if payload.len() > self.max_size {
+            return Err(FrameError::TooLarge(payload.len()));
+        }
+
+        self.write_frame(stream, opcode, payload)
+    }
+
+    /// Bytes that writing a message takes, frame and all
+    pub fn frame_size<T: Serialize>(&self, msg: &T) -> Result<u64, FrameError> {
+        let (_, payload) = try!(self.encode(msg));
+        let len = payload.len();
+        let extended_len = if len < 126 { 0 } else if len <= 0xFFFF { 2 } else { 8 };
+        let mask_len = if self.mask { 4 } else { 0 };
+        Ok((2 + extended_len + mask_len + len) as u64)
+    }
+
+    fn encode<T: Serialize>(&self, msg: &T) -> Result<(u8, Vec<u8>), FrameError> {
+        Ok(match self.encoding {
+            Encoding::Json => (OP_TEXT, try!(serde_json::to_vec(msg).map_err(FrameError::Json))),
+            Encoding::Bincode => {
+                (OP_BINARY, try!(serialize(msg, Infinite).map_err(FrameError::Encode)))
+            },
+        })
+    }
+
+    /// Read the next message, answering pings along the way, on a stream that
+    /// nothing else writes to. Text frames are read as JSON and binary ones as
+    /// bincode, whichever we write in.
+    pub fn read<S: Read + Write, T: DeserializeOwned>(&self, stream: &mut S)
+                                                      -> Result<T, FrameError> {
+        let mut answers = Vec::new();
+        let read = self.read_message(stream, |answer| answers.push(answer));
+        for answer in answers {
+            try!(stream.write_all(&answer));
+        }
+
+        let (encoding, message) = try!(read);
+        decode(encoding, &message)
+    }
+
+    /// Read the next message without decoding it, along with the encoding it
+    /// is in. The frames answering pings and closes are handed to answer, for
+    /// whoever writes to the stream to send.
+    pub fn read_message<R: Read, F>(&self, stream: &mut R, mut answer: F)
+                                    -> Result<(Encoding, Vec<u8>), FrameError>
+        where F: FnMut(Vec<u8>) {
+        let mut message = Vec::new();
+        let mut message_op = None;
+        loop {
+            let (fin, opcode, payload) = try!(self.read_frame(stream, message.len()));
+            match opcode {
+                OP_PING => {
+                    answer(self.frame(OP_PONG, payload));
+                    continue;
+                },
+                OP_PONG => continue,
+                OP_CLOSE => {
+                    answer(self.frame(OP_CLOSE, Vec::new()));
+                    return Err(FrameError::Io(io::Error::new(ErrorKind::ConnectionAborted,
+                                                             "WebSocket closed")));
+                },
+                OP_CONTINUATION if message_op.is_some() => {},
+                OP_TEXT | OP_BINARY if message_op.is_none() => message_op = Some(opcode),
+                _ => return Err(protocol_error("Unexpected WebSocket frame")),
+            }
+
+            message.extend_from_slice(&payload);
+            if fin {
+                break;
+            }
+        }
+
+        if message_op == Some(OP_TEXT) {
+            Ok((Encoding::Json, message))
+        } else {
+            Ok((Encoding::Bincode, message))
+        }
+    }
+
+    fn write_frame<W: Write>(&self, stream: &mut W, opcode: u8, payload: Vec<u8>)
+                             -> Result<(), FrameError> {
+        // Write in one go so that frames from different threads cannot interleave
+        try!(stream.write_all(&self.frame(opcode, payload)));
+        try!(stream.flush());
+        Ok(())
+    }
+
+    /// A whole frame, ready to write
+    fn frame(&self, opcode: u8, mut payload: Vec<u8>) -> Vec<u8> {
+        let mask_bit = if self.mask { 0x80 } else { 0 };
+        let len = payload.len();
+
+        let mut frame = Vec::with_capacity(len + 14);
+        frame.push(0x80 | opcode);
+        if len < 126 {
+            frame.push(mask_bit | len as u8);
+        } else if len <= 0xFFFF {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&[(len >> 8) as u8, len as u8]);
+        } else {
+            frame.push(mask_bit | 127);
+            for shift in (0..8).rev() {
+                frame.push((len as u64 >> (shift * 8)) as u8);
+            }
+        }
+        if self.mask {
+            let key: [u8; 4] = rand::random();
+            frame.extend_from_slice(&key);
+            apply_mask(&mut payload, key);
+        }
+        frame.extend_from_slice(&payload);
+        frame
+    }
+
+    /// Read one frame, given how much of the message has already been read
+    fn read_frame<R: Read>(&self, stream: &mut R, so_far: usize)
+                           -> Result<(bool, u8, Vec<u8>), FrameError> {
+        let mut head = [0u8; 2];
+        try!(stream.read_exact(&mut head));
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0F;
+        let masked = head[1] & 0x80 != 0;
+
+        let len = match head[1] & 0x7F {
+            126 => try!(read_len(stream, 2)),
+            127 => try!(read_len(stream, 8)),
+            len => len as u64,
+        };
+        if so_far as u64 + len > self.max_size as u64 {
+            return Err(FrameError::TooLarge(so_far + len as usize));
+        }
+
+        let mut key = [0u8; 4];
+        if masked {
+            try!(stream.read_exact(&mut key));
+        }
+        let mut payload = vec![0u8; len as usize];
+        try!(stream.read_exact(&mut payload));
+        if masked {
+            apply_mask(&mut payload, key);
+        }
+
+        Ok((fin, opcode, payload))
+    }
+}
+
+/// Read a big endian length of some number of bytes
+fn read_len<R: Read>(stream: &mut R, bytes: usize) -> Result<u64, io::Error> {
+    let mut buf = [0u8; 8];
+    try!(stream.read_exact(&mut buf[..bytes]));
+    Ok(buf[..bytes].iter().fold(0, |len, byte| len << 8 | *byte as u64))
+}
+
+/// Masking and unmasking are the same XOR with the key
+fn apply_mask(payload: &mut [u8], key: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= key[i % 4];
+    }
+}
@@ -0,0 +1,336 @@
+use std::path::Path;
+use std::collections::{HashMap, VecDeque};
+
+use defs::loader::{DefError, Proto, load_pack_defs, required};
+use defs::packs::{DataPack, PackConflicts, init_packs};
+use entities::actions::{ActionType, Task, Tasks};
+use entities::creatures::CreatureID;
+use entities::entity::{Entity, EntID};
+use game::base::*;
+use io::constants::*;
+use map::tiles::{Map, PosUnit};
+use objects::items::{Item, ItemID, ItemTypeID, Items};
+
+
+pub type BuildingTypeID = u16;
+pub type BuildingTypes = HashMap<BuildingTypeID, BuildingType>;
+// Buildings share the id space of entities, see GameState::give_id
+pub type BuildingID = EntID;
+pub type Buildings = Vec<Building>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+/// The properties shared by every building of a kind
+pub struct BuildingType {
+    pub name: String,
+    pub id: BuildingTypeID,
+    pub texture: Option<String>,
+    pub color: [f32; 4],
+    // Width and depth in tiles
+    pub footprint: (PosUnit, PosUnit),
+    // Items that have to be brought to the site to finish construction
+    pub cost: Vec<ItemTypeID>,
+    // Creatures that can be trained here
+    pub spawns: Vec<CreatureID>,
+    // Ticks it takes to train a single creature
+    pub spawn_time: Ticks,
+    // Whether players can put it down, which templates like default can't
+    pub placeable: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+/// A building placed on the map, either finished or still a construction site
+pub struct Building {
+    pub id: BuildingID,
+    pub type_id: BuildingTypeID,
+    // Top left corner of the footprint
+    pub pos: Pos,
+    pub team_id: TeamID,
+    // Materials delivered to the construction site so far
+    pub materials: Items,
+    pub built: bool,
+    // Creatures waiting to be trained, with the one in training at the front
+    pub queue: VecDeque<CreatureID>,
+    // Time spent training the creature at the front of the queue
+    pub timer: Ticks,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct ProtoBuilding {
+    pub name: String,
+    pub id: BuildingTypeID,
+    pub template: Option<String>,
+    pub texture: Option<String>,
+    pub color: Option<[f32; 4]>,
+    pub footprint: Option<(PosUnit, PosUnit)>,
+    pub cost: Option<Vec<ItemTypeID>>,
+    pub spawns: Option<Vec<CreatureID>>,
+    pub spawn_time: Option<Ticks>,
+    pub placeable: Option<bool>,
+}
+
+impl Building {
+    pub fn new(id: BuildingID, type_id: BuildingTypeID, pos: Pos, team_id: TeamID) -> Building {
+        Building {
+            id: id,
+            type_id: type_id,
+            pos: pos,
+            team_id: team_id,
+            materials: Items::new(),
+            built: false,
+            queue: VecDeque::new(),
+            timer: 0,
+        }
+    }
+
+    /// Every tile the building covers, row by row
+    pub fn tiles(&self, building_types: &BuildingTypes) -> Vec<Pos> {
+        footprint_tiles(self.type_id, self.pos, building_types)
+    }
+
+    pub fn contains(&self, pos: Pos, building_types: &BuildingTypes) -> bool {
+        self.tiles(building_types).contains(&pos)
+    }
+
+    /// The materials still missing before construction can finish
+    pub fn remaining_cost(&self, building_types: &BuildingTypes) -> Vec<ItemTypeID> {
+        let mut remaining = match building_types.get(&self.type_id) {
+            Some(building_type) => building_type.cost.clone(),
+            None => Vec::new(),
+        };
+
+        for item in &self.materials {
+            if let Some(index) = remaining.iter().position(|type_id| *type_id == item.type_id) {
+                remaining.remove(index);
+            }
+        }
+
+        remaining
+    }
+
+    /// Take in any missing materials lying on the site, finishing
+    /// construction once they have all arrived
+    ///
+    /// # Returns
+    /// * The ids of the items that were used up
+    pub fn gather_materials(&mut self, items: &mut Items,
+                            building_types: &BuildingTypes) -> Vec<ItemID> {
+        let mut gathered = Vec::new();
+        let mut remaining = self.remaining_cost(building_types);
+        let tiles = self.tiles(building_types);
+
+        let mut i = 0;
+        while i < items.len() {
+            let needed = remaining.iter().position(|type_id| *type_id == items[i].type_id);
+            match needed {
+                Some(index) if tiles.contains(&items[i].pos) => {
+                    remaining.remove(index);
+                    gathered.push(items[i].id);
+                    self.materials.push(items.remove(i));
+                },
+                _ => i += 1,
+            }
+        }
+
+        if remaining.is_empty() {
+            self.built = true;
+            self.materials.clear();
+        }
+
+        gathered
+    }
+
+    /// Advance the unit being trained
+    ///
+    /// # Returns
+    /// * The creature to spawn, if training has just finished
+    pub fn train(&mut self, building_types: &BuildingTypes) -> Option<CreatureID> {
+        if !self.built || self.queue.is_empty() {
+            return None;
+        }
+
+        let spawn_time = match building_types.get(&self.type_id) {
+            Some(building_type) => building_type.spawn_time,
+            None => return None,
+        };
+
+        self.timer += 1;
+        if self.timer >= spawn_time {
+            self.timer = 0;
+            self.queue.pop_front()
+        } else {
+            None
+        }
+    }
+}
+
+impl Proto for ProtoBuilding {
+    type ID = BuildingTypeID;
+    type Def = BuildingType;
+    type Context = ();
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn id(&self) -> BuildingTypeID {
+        self.id
+    }
+
+    fn template(&self) -> Option<&str> {
+        self.template.as_ref().map(|template| template.as_str())
+    }
+
+    fn build(&self, _: &()) -> Result<BuildingType, DefError> {
+        Ok(BuildingType {
+            name:       self.name.clone(),
+            id:         self.id,
+            texture:    None,  // FIXME
+            color:      try!(required(&self.name, "color", &self.color)),
+            footprint:  try!(required(&self.name, "footprint", &self.footprint)),
+            cost:       try!(required(&self.name, "cost", &self.cost)),
+            spawns:     try!(required(&self.name, "spawns", &self.spawns)),
+            spawn_time: try!(required(&self.name, "spawn_time", &self.spawn_time)),
+            placeable:  try!(required(&self.name, "placeable", &self.placeable)),
+        })
+    }
+
+    fn inherit(&self, mut building_type: BuildingType,
+               _: &()) -> Result<BuildingType, DefError> {
+        // Overwrite applicable fields
+        building_type.name = self.name.clone();
+        building_type.id = self.id;
+        if let Some(texture) = self.texture.clone() {
+            building_type.texture = Some(texture);
+        }
+        if let Some(color) = self.color {
+            building_type.color = color;
+        }
+        if let Some(footprint) = self.footprint {
+            building_type.footprint = footprint;
+        }
+        if let Some(cost) = self.cost.clone() {
+            building_type.cost = cost;
+        }
+        if let Some(spawns) = self.spawns.clone() {
+            building_type.spawns = spawns;
+        }
+        if let Some(spawn_time) = self.spawn_time {
+            building_type.spawn_time = spawn_time;
+        }
+        if let Some(placeable) = self.placeable {
+            building_type.placeable = placeable;
+        }
+
+        Ok(building_type)
+    }
+}
+
+pub fn init_buildings(root: &Path) -> BuildingTypes {
+    info!("Initializing buildings");
+    load_buildings(&init_packs(root))
+        .map(|(building_types, _)| building_types)
+        .unwrap_or_else(|err| panic!("buildings.toml invalid: {}", err))
+}
+
+pub fn load_buildings(packs: &[DataPack]) -> Result<(BuildingTypes, PackConflicts), DefError> {
+    load_pack_defs::<ProtoBuilding>(packs, "inc/buildings/buildings.toml", "buildings", &())
+}
+
+fn footprint_tiles(type_id: BuildingTypeID, pos: Pos,
+                   building_types: &BuildingTypes) -> Vec<Pos> {
+    let (x0, y0, z) = pos;
+    let (xlen, ylen) = match building_types.get(&type_id) {
+        Some(building_type) => building_type.footprint,
+        None => (0, 0),
+    };
+
+    let mut tiles = Vec::new();
+    for y in y0..(y0 + ylen) {
+        for x in x0..(x0 + xlen) {
+            tiles.push((x, y, z));
+        }
+    }
+
+    tiles
+}
+
+/// Whether a placeable building fits at pos, needing open ground that no other building covers
+pub fn can_place(type_id: BuildingTypeID, pos: Pos, map: &Map, buildings: &[Building],
+                 building_types: &BuildingTypes) -> bool {
+    let placeable = building_types.get(&type_id).map_or(false, |bld_type| bld_type.placeable);
+    let tiles = footprint_tiles(type_id, pos, building_types);
+
+    placeable && !tiles.is_empty() &&
+    tiles.iter().all(|tile| {
+        map.passable(*tile) &&
+        !buildings.iter().any(|building| building.contains(*tile, building_types))
+    })
+}
+
+/// Queue up haul tasks that bring materials to a team's construction sites
+///
+/// Materials already on their way, whether lying loose or being
+/// carried, are counted so that each site only gets what it still needs.
+pub fn add_construction_tasks(tasks: &mut Tasks, buildings: &[Building],
+                              building_types: &BuildingTypes, items: &Items,
+                              entities: &[Entity], map: &Map, team_id: TeamID) {
+    let mut hauling: Vec<ItemID> = tasks.iter()
+                                        .filter_map(|task| match task.atype {
+                                            ActionType::Haul(item_id, _) => Some(item_id),
+                                            _ => None,
+                                        })
+                                        .collect();
+
+    for building in buildings.iter().filter(|bld| !bld.built && bld.team_id == team_id) {
+        let tiles = building.tiles(building_types);
+        let dest = match tiles.iter().find(|tile| map.passable(**tile)) {
+            Some(tile) => *tile,
+            None => continue,
+        };
+
+        // Take out what is already being hauled here
+        let mut needed = building.remaining_cost(building_types);
+        for task in tasks.iter() {
+            if let ActionType::Haul(item_id, pos) = task.atype {
+                if !tiles.contains(&pos) {
+                    continue;
+                }
+                if let Some(item) = find_item(item_id, items, entities) {
+                    if let Some(index) = needed.iter().position(|id| *id == item.type_id) {
+                        needed.remove(index);
+                    }
+                }
+            }
+        }
+
+        let mut new_tasks = Vec::new();
+        for type_id in needed {
+            let material = items.iter().find(|item| {
+                item.type_id == type_id &&
+                !hauling.contains(&item.id) &&
+                !buildings.iter().any(|bld| !bld.built && bld.contains(item.pos, building_types))
+            });
+
+            if let Some(item) = material {
+                hauling.push(item.id);
+                new_tasks.push(Task::haul(item.id, dest));
+            }
+        }
+        tasks.extend(new_tasks);
+    }
+}
+
+/// Look for an item either lying loose or being carried
+fn find_item<'a>(item_id: ItemID, items: &'a [Item], entities: &'a [Entity]) -> Option<&'a Item> {
+    items.iter()
+         .chain(entities.iter().flat_map(|ent| ent.inventory.iter()))
+         .find(|item| item.id == item_id)
+}
+
+pub fn get_color(type_id: &BuildingTypeID, building_types: &BuildingTypes) -> Color {
+    if let Some(building_type) = building_types.get(type_id) {
+        building_type.color
+    } else {
+        WHITE
+    }
+}
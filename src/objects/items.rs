@@ -0,0 +1,116 @@
+use std::path::Path;
+use std::collections::HashMap;
+
+use defs::loader::{DefError, Proto, load_pack_defs, required};
+use defs::packs::{DataPack, PackConflicts, init_packs};
+use entities::entity::EntID;
+use game::base::*;
+use io::constants::*;
+
+
+pub type ItemTypeID = u16;
+pub type ItemTypes = HashMap<ItemTypeID, ItemType>;
+// Items share the id space of entities, see GameState::give_id
+pub type ItemID = EntID;
+pub type Items = Vec<Item>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+/// The properties shared by every item of a kind
+pub struct ItemType {
+    pub name: String,
+    pub id: ItemTypeID,
+    pub texture: Option<String>,
+    pub color: [f32; 4],
+    pub weight: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+/// A single item lying somewhere on the map
+pub struct Item {
+    pub id: ItemID,
+    pub type_id: ItemTypeID,
+    pub pos: Pos,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct ProtoItem {
+    pub name: String,
+    pub id: ItemTypeID,
+    pub template: Option<String>,
+    pub texture: Option<String>,
+    pub color: Option<[f32; 4]>,
+    pub weight: Option<i32>,
+}
+
+impl Item {
+    pub fn new(id: ItemID, type_id: ItemTypeID, pos: Pos) -> Item {
+        Item {
+            id: id,
+            type_id: type_id,
+            pos: pos,
+        }
+    }
+}
+
+impl Proto for ProtoItem {
+    type ID = ItemTypeID;
+    type Def = ItemType;
+    type Context = ();
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn id(&self) -> ItemTypeID {
+        self.id
+    }
+
+    fn template(&self) -> Option<&str> {
+        self.template.as_ref().map(|template| template.as_str())
+    }
+
+    fn build(&self, _: &()) -> Result<ItemType, DefError> {
+        Ok(ItemType {
+            name:    self.name.clone(),
+            id:      self.id,
+            texture: self.texture.clone(),
+            color:   try!(required(&self.name, "color", &self.color)),
+            weight:  try!(required(&self.name, "weight", &self.weight)),
+        })
+    }
+
+    fn inherit(&self, mut item_type: ItemType, _: &()) -> Result<ItemType, DefError> {
+        // Overwrite applicable fields
+        item_type.name = self.name.clone();
+        item_type.id = self.id;
+        if let Some(texture) = self.texture.clone() {
+            item_type.texture = Some(texture);
+        }
+        if let Some(color) = self.color {
+            item_type.color = color;
+        }
+        if let Some(weight) = self.weight {
+            item_type.weight = weight;
+        }
+
+        Ok(item_type)
+    }
+}
+
+pub fn init_items(root: &Path) -> ItemTypes {
+    info!("Initializing items");
+    load_items(&init_packs(root)).map(|(item_types, _)| item_types)
+                                 .unwrap_or_else(|err| panic!("items.toml invalid: {}", err))
+}
+
+pub fn load_items(packs: &[DataPack]) -> Result<(ItemTypes, PackConflicts), DefError> {
+    load_pack_defs::<ProtoItem>(packs, "inc/items/items.toml", "items", &())
+}
+
+pub fn get_color(type_id: &ItemTypeID, item_types: &ItemTypes) -> Color {
+    if let Some(item_type) = item_types.get(type_id) {
+        item_type.color
+    } else {
+        WHITE
+    }
+}
@@ -0,0 +1,3 @@
+pub mod buildings;
+pub mod items;
+pub mod stockpiles;
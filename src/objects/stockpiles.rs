@@ -0,0 +1,150 @@
+use std::cmp::{min, max};
+use std::collections::HashSet;
+
+use entities::actions::{ActionType, Task, Tasks};
+use game::base::*;
+use io::base::TilesSelector;
+use map::tiles::Map;
+use objects::items::{ItemID, ItemTypeID, Items};
+
+
+pub type Stockpiles = Vec<Stockpile>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+/// A zone of tiles that loose items get hauled to
+pub struct Stockpile {
+    // Lowest corner of the zone
+    pub start: Pos,
+    // Highest corner of the zone
+    pub end: Pos,
+    // The item types that belong here. Empty accepts everything.
+    pub filter: Vec<ItemTypeID>,
+}
+
+impl Stockpile {
+    pub fn new(selector: TilesSelector, filter: Vec<ItemTypeID>) -> Stockpile {
+        let ((x1, y1, z1), (x2, y2, z2)) = selector;
+
+        Stockpile {
+            start: (min(x1, x2), min(y1, y2), min(z1, z2)),
+            end: (max(x1, x2), max(y1, y2), max(z1, z2)),
+            filter: filter,
+        }
+    }
+
+    pub fn accepts(&self, type_id: ItemTypeID) -> bool {
+        self.filter.is_empty() || self.filter.contains(&type_id)
+    }
+
+    pub fn contains(&self, pos: Pos) -> bool {
+        let (x, y, z) = pos;
+        let ((sx, sy, sz), (ex, ey, ez)) = (self.start, self.end);
+
+        sx <= x && x <= ex && sy <= y && y <= ey && sz <= z && z <= ez
+    }
+
+    /// Every tile in the zone, top level first and then row by row
+    pub fn tiles(&self) -> Vec<Pos> {
+        let ((sx, sy, sz), (ex, ey, ez)) = (self.start, self.end);
+
+        let mut tiles = Vec::new();
+        for z in sz..(ez + 1) {
+            for y in sy..(ey + 1) {
+                for x in sx..(ex + 1) {
+                    tiles.push((x, y, z));
+                }
+            }
+        }
+
+        tiles
+    }
+}
+
+/// The items a player has a claim on, by having them stored in their
+/// stockpiles or being hauled for them, which other players leave alone
+pub fn claimed_items(tasks: &[Task], stockpiles: &[Stockpile], items: &Items) -> HashSet<ItemID> {
+    let mut claimed: HashSet<ItemID> = items.iter()
+                                            .filter(|item| is_stored(stockpiles, item.pos,
+                                                                     item.type_id))
+                                            .map(|item| item.id)
+                                            .collect();
+    for task in tasks {
+        if let ActionType::Haul(item_id, _) = task.atype {
+            claimed.insert(item_id);
+        }
+    }
+
+    claimed
+}
+
+/// Queue up haul tasks for loose items that belong in a stockpile
+///
+/// Each item gets its own tile, so tiles that already hold an item or that
+/// an item is being hauled to are skipped. Items already stored in a
+/// stockpile that accepts them, claimed by another player, or out of reach
+/// are left where they are. Queued hauls to the stockpiles
+/// whose items have gone out of reach are given up on.
+pub fn add_haul_tasks(tasks: &mut Tasks, stockpiles: &[Stockpile], items: &Items, map: &Map,
+                      reachable: &HashSet<Pos>, claimed: &HashSet<ItemID>) {
+    tasks.retain(|task| match (task.atype, task.owner) {
+        (ActionType::Haul(item_id, dest), None) => {
+            !stockpiles.iter().any(|pile| pile.contains(dest)) ||
+            items.iter().any(|item| item.id == item_id && reachable.contains(&item.pos))
+        },
+        _ => true,
+    });
+
+    let mut reserved: HashSet<Pos> = items.iter().map(|item| item.pos).collect();
+    let mut hauling = HashSet::new();
+    for task in tasks.iter() {
+        if let ActionType::Haul(item_id, dest) = task.atype {
+            hauling.insert(item_id);
+            reserved.insert(dest);
+        }
+    }
+
+    // The tiles of each stockpile that could take an item, worked out once
+    // for the pass and in reverse so they fill up in order
+    let mut free: Vec<Vec<Pos>> = stockpiles.iter().map(|pile| {
+        let mut tiles: Vec<Pos> = pile.tiles().into_iter()
+                                      .filter(|pos| map.passable(*pos) &&
+                                                    reachable.contains(pos) &&
+                                                    !reserved.contains(pos))
+                                      .collect();
+        tiles.reverse();
+        tiles
+    }).collect();
+
+    for item in items {
+        if hauling.contains(&item.id) || claimed.contains(&item.id) ||
+           !reachable.contains(&item.pos) || is_stored(stockpiles, item.pos, item.type_id) {
+            continue;
+        }
+
+        let dest = stockpiles.iter()
+                             .zip(free.iter_mut())
+                             .filter(|&(pile, _)| pile.accepts(item.type_id))
+                             .filter_map(|(_, tiles)| pop_free(tiles, &reserved))
+                             .next();
+        if let Some(dest) = dest {
+            tasks.push(Task::haul(item.id, dest));
+            reserved.insert(dest);
+        }
+    }
+}
+
+/// Take the next of a stockpile's free tiles that no other stockpile has
+/// handed out, for where stockpiles overlap
+fn pop_free(tiles: &mut Vec<Pos>, reserved: &HashSet<Pos>) -> Option<Pos> {
+    while let Some(pos) = tiles.pop() {
+        if !reserved.contains(&pos) {
+            return Some(pos);
+        }
+    }
+
+    None
+}
+
+fn is_stored(stockpiles: &[Stockpile], pos: Pos, type_id: ItemTypeID) -> bool {
+    stockpiles.iter().any(|pile| pile.contains(pos) && pile.accepts(type_id))
+}
@@ -0,0 +1,80 @@
+extern crate spacefort;
+
+mod common;
+
+// Local imports
+use spacefort::*;
+use common::{open_map, root, server_on};
+use game::server;
+use objects::{buildings, items};
+
+
+fn setup() -> server::Server {
+    let mut server = server_on(open_map());
+    server.players.insert(1, server::ServerPlayer::new(1, Some(1)));
+    server
+}
+
+#[test]
+fn test_building_templates() {
+    let building_types = buildings::init_buildings(root());
+
+    let barracks = &building_types[&1];
+    let kennel = &building_types[&2];
+    assert_eq!(barracks.footprint, (3, 3));
+    assert_eq!(barracks.spawns, vec![1]);
+    assert_eq!(kennel.spawn_time, building_types[&0].spawn_time);
+}
+
+#[test]
+fn test_overlapping_placement_rejected() {
+    let mut server = setup();
+    server.place_building(1, 1, (20, 2, 0));
+    server.place_building(1, 2, (22, 4, 0));
+    server.place_building(1, 1, (89, 2, 0));
+
+    assert_eq!(server.g_state.buildings.len(), 1);
+}
+
+#[test]
+fn test_template_not_placeable() {
+    let mut server = setup();
+    assert!(!server.g_state.building_types[&0].placeable);
+    assert!(server.g_state.building_types[&2].placeable);
+
+    // The default template costs nothing, so it would otherwise be built for free
+    server.place_building(1, 0, (20, 2, 0));
+    server.place_building(1, 99, (24, 2, 0));
+    assert!(server.g_state.buildings.is_empty());
+}
+
+#[test]
+fn test_build_and_spawn() {
+    let mut server = setup();
+    for i in 0..4 {
+        server.g_state.items.push(items::Item::new(100 + i as i64, 1, (10 + i, 8, 0)));
+    }
+    server.place_building(1, 1, (20, 2, 0));
+    let building_id = server.g_state.buildings[0].id;
+
+    // The stone chunks get hauled over and used up
+    for _ in 0..3000 {
+        server.update();
+    }
+    assert!(server.g_state.buildings[0].built);
+    assert!(server.g_state.items.is_empty());
+
+    let ent_count = server.g_state.entities.len();
+    server.queue_unit(1, building_id, 1);
+    // Barracks can not train dogs
+    server.queue_unit(1, building_id, 2);
+    for _ in 0..1000 {
+        server.update();
+    }
+
+    assert_eq!(server.g_state.entities.len(), ent_count + 1);
+    let recruit = server.g_state.entities.last().unwrap();
+    assert_eq!(recruit.creature_id, 1);
+    assert_eq!(recruit.team_id, Some(1));
+    assert!(server.g_state.buildings[0].queue.is_empty());
+}
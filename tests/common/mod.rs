@@ -0,0 +1,113 @@
+// Fixtures shared by the integration tests, not all of which use every one
+#![allow(dead_code)]
+
+// Std lib imports
+use std::collections::HashMap;
+use std::path::Path;
+
+// Local imports
+use spacefort::entities::entity::init_entities;
+use spacefort::game::base::PlayerID;
+use spacefort::game::server::Server;
+use spacefort::map::tiles::{Map, blank_map};
+use spacefort::net::base::{ClientMsgSend, ConnEvent, ServerMsg, ServerMsgRecv};
+use spacefort::net::server::{NetComm, init_local_network};
+use spacefort::net::transport::Transport;
+use spacefort::objects::{buildings, items};
+
+
+// Players connected in memory by capture_comm, from 1 up
+pub const CAPTURED_PLAYERS: PlayerID = 8;
+
+/// What the server sends to players 1 to CAPTURED_PLAYERS, who are connected
+/// but have not said anything, so tests can speak for them with dispatch
+pub struct Captured {
+    recv_sent: HashMap<PlayerID, ServerMsgRecv>,
+    // Only ever sent the acks that show everything before them has arrived
+    watcher_id: PlayerID,
+    // Held so that the connections stay open
+    _send_outgoing: Vec<ClientMsgSend>,
+}
+
+
+/// Root points to the directory containing static where assets are loaded from
+pub fn root() -> &'static Path {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+}
+
+/// A comm that nobody is connected to, so what is sent goes nowhere
+pub fn stub_comm() -> NetComm {
+    init_local_network().0
+}
+
+/// A comm with players connected in memory, whose messages can be checked
+pub fn capture_comm() -> (NetComm, Captured) {
+    let (mut comm, transport) = init_local_network();
+    let mut recv_sent = HashMap::new();
+    let mut send_outgoing = Vec::new();
+    for _ in 0..(CAPTURED_PLAYERS + 1) {
+        let (send, recv) = transport.connect().unwrap();
+        // Set up to be written to, without the game seeing them connect
+        match comm.check_conn_events() {
+            Some(ConnEvent::Opened(conn, player_id)) => {
+                comm.setup_out_stream((conn, player_id));
+                recv_sent.insert(player_id, recv);
+            },
+            event => panic!("Expected a connection, got {:?}", event),
+        }
+        send_outgoing.push(send);
+    }
+
+    let captured = Captured {
+        recv_sent: recv_sent,
+        watcher_id: CAPTURED_PLAYERS + 1,
+        _send_outgoing: send_outgoing,
+    };
+    (comm, captured)
+}
+
+/// A server like server_on an open map, with its messages captured
+pub fn capture_server() -> (Server, Captured) {
+    let (comm, captured) = capture_comm();
+    (server_with(open_map(), comm), captured)
+}
+
+/// Every message sent to a player since the last look, which for a resumed
+/// player arrives on the connection they came back on
+pub fn sent_to(server: &Server, captured: &Captured, conn_id: PlayerID) -> Vec<ServerMsg> {
+    wait_for_sent(server, captured);
+    captured.recv_sent[&conn_id].try_iter().map(|(msg, _)| msg).collect()
+}
+
+/// Forget everything sent so far
+pub fn clear_sent(server: &Server, captured: &Captured) {
+    wait_for_sent(server, captured);
+    for recv in captured.recv_sent.values() {
+        recv.try_iter().count();
+    }
+}
+
+/// Messages go out in order on another thread, so once an ack sent now has
+/// arrived, so has everything sent before it
+fn wait_for_sent(server: &Server, captured: &Captured) {
+    server.comm.ack(captured.watcher_id, 0);
+    let recv = &captured.recv_sent[&captured.watcher_id];
+    while recv.recv().expect("The outgoing thread is gone").0 != ServerMsg::Ack(0) {}
+}
+
+/// Open field big enough to hold the starting entities
+pub fn open_map() -> Map {
+    Map::new((90, 10, 1), blank_map(root()).materials)
+}
+
+/// A server on a map with the starting entities, and nobody connected
+pub fn server_on(map: Map) -> Server {
+    server_with(map, stub_comm())
+}
+
+/// A server on a map with the starting entities, reached through a comm
+pub fn server_with(map: Map, comm: NetComm) -> Server {
+    let (entities, creature_types) = init_entities(root());
+    Server::new(map, entities, creature_types, items::init_items(root()),
+                buildings::init_buildings(root()), comm)
+}
@@ -0,0 +1,170 @@
+extern crate spacefort;
+
+// Std lib imports
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+
+// Local imports
+use spacefort::*;
+use config::{Config, ConfigError, parse_config};
+use game::lobby::{DEFAULT_GEN_SIZE, Lobby, LobbyError, MapChoice};
+use game::server::init_server;
+use net::base::{MAX_MSG_SIZE, SERVER_PORT};
+use net::server::init_local_network;
+
+
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+/// Write a config file to the temp directory
+fn config_file(name: &str, contents: &str) -> PathBuf {
+    let path = env::temp_dir().join(name);
+    File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+    path
+}
+
+#[test]
+fn test_config_defaults() {
+    let config = parse_config(&[]).unwrap();
+    assert_eq!(config, Config::default());
+    assert_eq!(config.port, SERVER_PORT);
+    assert_eq!(config.max_msg_size, MAX_MSG_SIZE);
+    assert_eq!(config.server, Ipv4Addr::new(127, 0, 0, 1));
+    assert_eq!(config.map_choice(), None);
+}
+
+#[test]
+fn test_config_command_line() {
+    let config = parse_config(&args(&["--bind", "127.0.0.1", "--port", "7777",
+                                      "--server", "10.0.0.2", "--seed", "42",
+                                      "--tick-rate", "30", "--root", "/srv/spacefort",
+                                      "--max-msg-size", "65536",
+                                      "--name", "urist", "--spectate"])).unwrap();
+    assert_eq!(config.bind, Ipv4Addr::new(127, 0, 0, 1));
+    assert_eq!(config.port, 7777);
+    assert_eq!(config.server, Ipv4Addr::new(10, 0, 0, 2));
+    assert_eq!(config.tick_rate, 30);
+    assert_eq!(config.max_msg_size, 65536);
+    assert_eq!(config.root, Path::new("/srv/spacefort"));
+    assert_eq!(config.name, "urist");
+    assert!(config.spectate);
+    assert_eq!(config.map_choice(),
+               Some(MapChoice::Generated { seed: 42, size: DEFAULT_GEN_SIZE }));
+}
+
+#[test]
+fn test_config_file() {
+    let path = config_file("spacefort_test_config.toml", "
+        port = 8000
+        map = \"arena.sfm.csv\"
+        name = \"urist\"
+        record = \"match.sfr\"
+    ");
+
+    let config = parse_config(&args(&["--config", path.to_str().unwrap()])).unwrap();
+    assert_eq!(config.port, 8000);
+    assert_eq!(config.name, "urist");
+    assert_eq!(config.record, Some(PathBuf::from("match.sfr")));
+    assert_eq!(config.map_choice(), Some(MapChoice::File("arena.sfm.csv".to_owned())));
+    // Anything left out stays at the default
+    assert_eq!(config.tick_rate, Config::default().tick_rate);
+
+    // The command line wins over the file, including picking the other kind of map
+    let config = parse_config(&args(&["--port", "8001", "--config", path.to_str().unwrap(),
+                                      "--seed", "7"])).unwrap();
+    assert_eq!(config.port, 8001);
+    assert_eq!(config.name, "urist");
+    assert_eq!(config.map_choice(), Some(MapChoice::Generated { seed: 7, size: DEFAULT_GEN_SIZE }));
+}
+
+#[test]
+fn test_config_errors() {
+    match parse_config(&args(&["--prot", "8000"])) {
+        Err(ConfigError::UnknownOption(ref option)) if option == "--prot" => {},
+        result => panic!("Expected an unknown option, got {:?}", result),
+    }
+    match parse_config(&args(&["--port"])) {
+        Err(ConfigError::MissingValue(ref option)) if option == "--port" => {},
+        result => panic!("Expected a missing value, got {:?}", result),
+    }
+    match parse_config(&args(&["--port", "99999"])) {
+        Err(ConfigError::BadValue { .. }) => {},
+        result => panic!("Expected a bad value, got {:?}", result),
+    }
+    match parse_config(&args(&["--tick-rate", "0"])) {
+        Err(ConfigError::BadValue { .. }) => {},
+        result => panic!("Expected a bad tick rate, got {:?}", result),
+    }
+    match parse_config(&args(&["--max-msg-size", "0"])) {
+        Err(ConfigError::BadValue { .. }) => {},
+        result => panic!("Expected a bad message size, got {:?}", result),
+    }
+    match parse_config(&args(&["--config", "/nonexistent/spacefort.toml"])) {
+        Err(ConfigError::Io(..)) => {},
+        result => panic!("Expected the file to be missing, got {:?}", result),
+    }
+
+    // Misspelt settings in a file are caught too
+    let path = config_file("spacefort_test_typo.toml", "tick_rat = 30\n");
+    match parse_config(&args(&["--config", path.to_str().unwrap()])) {
+        Err(ConfigError::Parse(..)) => {},
+        result => panic!("Expected a parse error, got {:?}", result),
+    }
+
+    let path = config_file("spacefort_test_both_maps.toml", "map = \"arena.sfm.csv\"\nseed = 3\n");
+    match parse_config(&args(&["--config", path.to_str().unwrap()])) {
+        Err(ConfigError::Conflict(..)) => {},
+        result => panic!("Expected conflicting maps, got {:?}", result),
+    }
+}
+
+#[test]
+fn test_lobby_pick_map() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut lobby = Lobby::new(root, vec!["arena.sfm.csv".to_owned()]);
+
+    // Picking a map takes no host, but the map still has to exist
+    lobby.pick_map(MapChoice::File("arena.sfm.csv".to_owned())).unwrap();
+    assert_eq!(lobby.state.settings.map, MapChoice::File("arena.sfm.csv".to_owned()));
+    match lobby.pick_map(MapChoice::File("missing.sfm.csv".to_owned())) {
+        Err(LobbyError::UnknownMap(_)) => {},
+        result => panic!("Expected an unknown map, got {:?}", result),
+    }
+    assert_eq!(lobby.state.settings.map, MapChoice::File("arena.sfm.csv".to_owned()));
+
+    // The server can be started on a map file anywhere, but the host can only
+    // pick from the data packs
+    let path = config_file("spacefort_test_outside.sfm.csv", "");
+    let path = path.to_str().unwrap().to_owned();
+    lobby.pick_map(MapChoice::File(path.clone())).unwrap();
+    assert_eq!(lobby.state.settings.map, MapChoice::File(path.clone()));
+    lobby.join(1, "urist");
+    let mut settings = lobby.state.settings.clone();
+    settings.map = MapChoice::File(path.clone());
+    match lobby.set_settings(1, settings) {
+        Err(LobbyError::UnknownMap(ref name)) if *name == path => {},
+        result => panic!("Expected an unknown map, got {:?}", result),
+    }
+}
+
+#[test]
+fn test_configure_bad_map() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut server = init_server(root, init_local_network().0);
+
+    // Starting on a map that is nowhere fails, rather than starting on another
+    let config = parse_config(&args(&["--map", "/nonexistent/missing.sfm.csv"])).unwrap();
+    match server.configure(&config) {
+        Err(LobbyError::UnknownMap(_)) => {},
+        result => panic!("Expected an unknown map, got {:?}", result),
+    }
+
+    let config = parse_config(&args(&["--seed", "5"])).unwrap();
+    server.configure(&config).unwrap();
+    assert_eq!(server.lobby.unwrap().state.settings.map,
+               MapChoice::Generated { seed: 5, size: DEFAULT_GEN_SIZE });
+}
@@ -0,0 +1,63 @@
+extern crate spacefort;
+
+mod common;
+
+// Local imports
+use spacefort::*;
+use common::{open_map, root, server_on};
+use entities::creatures::{init_creatures, pick_attack};
+use game::server;
+
+
+#[test]
+fn test_creature_stats() {
+    let creature_types = init_creatures(root());
+
+    let dwarf = &creature_types[&1];
+    assert_eq!(dwarf.health, 100);
+    assert_eq!(dwarf.strength, 10);
+    assert_eq!(dwarf.attack_speed, creature_types[&0].attack_speed);
+    assert!(dwarf.attack_speed != dwarf.dig_speed);
+    let names: Vec<&str> = dwarf.attacks.iter().map(|atk| atk.name.as_str()).collect();
+    assert_eq!(names, vec!["punch", "bite"]);
+
+    let troll = &creature_types[&3];
+    assert_eq!(troll.health, 200);
+    assert_eq!(troll.attacks.len(), 3);
+}
+
+#[test]
+fn test_pick_attack() {
+    let creature_types = init_creatures(root());
+
+    // Up close the hardest hitter is used, from afar the one that reaches
+    assert_eq!(pick_attack(&3, &creature_types, 1).unwrap().name, "kick");
+    assert_eq!(pick_attack(&3, &creature_types, 3).unwrap().name, "rock_throw");
+    assert_eq!(pick_attack(&3, &creature_types, 10).unwrap().name, "rock_throw");
+    assert_eq!(pick_attack(&1, &creature_types, 10).unwrap().reach, 1);
+    assert!(pick_attack(&0, &creature_types, 1).is_none());
+}
+
+#[test]
+fn test_fight_to_the_death() {
+    let mut server = server_on(open_map());
+    server.players.insert(1, server::ServerPlayer::new(1, Some(1)));
+
+    // Entities start out with their creature's health
+    let dog_health = server.g_state.creature_types[&2].health;
+    assert!(server.g_state.entities.iter()
+                                   .filter(|ent| ent.creature_id == 2)
+                                   .all(|ent| ent.health == dog_health));
+
+    server.attack(1, -1, -4);
+    for _ in 0..6000 {
+        server.update();
+    }
+
+    let dog = server.g_state.entities.iter().find(|ent| ent.id == -4).unwrap();
+    let dwarf = server.g_state.entities.iter().find(|ent| ent.id == -1).unwrap();
+    assert!(!dog.alive);
+    assert!(dwarf.alive);
+    // Dwarves bite for 30 plus 10 strength
+    assert_eq!((dog_health - dog.health) % 40, 0);
+}
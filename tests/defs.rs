@@ -0,0 +1,219 @@
+extern crate bincode;
+extern crate spacefort;
+#[macro_use]
+extern crate serde_derive;
+
+// Std lib imports
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// Crate imports
+use bincode::{deserialize, serialize, Infinite};
+
+// Local imports
+use spacefort::*;
+use defs::base::Defs;
+use defs::loader::{DefError, Proto, load_defs, required};
+use defs::packs::load_packs;
+use entities::creatures::load_creatures;
+use entities::entity::{Entities, init_entities};
+use game::base::GameState;
+use map::material::load_materials;
+use map::tiles::blank_map;
+use objects::buildings::{init_buildings, load_buildings};
+use objects::items::{init_items, load_items};
+
+
+#[derive(Clone, Deserialize)]
+struct ProtoThing {
+    name: String,
+    id: u16,
+    template: Option<String>,
+    size: Option<i32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Thing {
+    name: String,
+    size: i32,
+}
+
+impl Proto for ProtoThing {
+    type ID = u16;
+    type Def = Thing;
+    type Context = ();
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn id(&self) -> u16 {
+        self.id
+    }
+
+    fn template(&self) -> Option<&str> {
+        self.template.as_ref().map(|template| template.as_str())
+    }
+
+    fn build(&self, _: &()) -> Result<Thing, DefError> {
+        Ok(Thing { name: self.name.clone(), size: try!(required(&self.name, "size", &self.size)) })
+    }
+
+    fn inherit(&self, mut thing: Thing, _: &()) -> Result<Thing, DefError> {
+        thing.name = self.name.clone();
+        if let Some(size) = self.size {
+            thing.size = size;
+        }
+        Ok(thing)
+    }
+}
+
+fn root() -> &'static Path {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+}
+
+fn write_defs(name: &str, contents: &str) -> PathBuf {
+    let path = env::temp_dir().join(name);
+    let mut file = File::create(&path).unwrap();
+    file.write_all(contents.as_bytes()).unwrap();
+    path
+}
+
+fn load(name: &str, contents: &str) -> Result<Vec<(u16, Thing)>, DefError> {
+    let path = write_defs(name, contents);
+    let defs = try!(load_defs::<ProtoThing>(&path, "things", &()));
+    let mut defs: Vec<(u16, Thing)> = defs.into_iter().collect();
+    defs.sort_by_key(|&(id, _)| id);
+    Ok(defs)
+}
+
+#[test]
+fn test_static_defs_load() {
+    let packs = load_packs(root()).unwrap();
+    assert!(load_materials(&packs).is_ok());
+    assert!(load_creatures(&packs).is_ok());
+    assert!(load_items(&packs).is_ok());
+    assert!(load_buildings(&packs).is_ok());
+}
+
+#[test]
+fn test_templates() {
+    // Templates may come after the entries that use them
+    let defs = load("spacefort_defs_templates.toml", r#"
+        [[things]]
+        name = "big"
+        id = 2
+        template = "small"
+        size = 10
+
+        [[things]]
+        name = "also_small"
+        id = 3
+        template = "small"
+
+        [[things]]
+        name = "small"
+        id = 1
+        size = 1
+    "#).unwrap();
+
+    let sizes: Vec<(u16, i32)> = defs.iter().map(|&(id, ref thing)| (id, thing.size)).collect();
+    assert_eq!(sizes, vec![(1, 1), (2, 10), (3, 1)]);
+    assert_eq!(defs[2].1.name, "also_small");
+}
+
+#[test]
+fn test_cycle() {
+    let err = load("spacefort_defs_cycle.toml", r#"
+        [[things]]
+        name = "a"
+        id = 1
+        template = "b"
+
+        [[things]]
+        name = "b"
+        id = 2
+        template = "a"
+    "#).unwrap_err();
+
+    match err {
+        DefError::Cycle(chain) => assert_eq!(chain, vec!["a", "b", "a"]),
+        err => panic!("Wrong error {:?}", err),
+    }
+}
+
+#[test]
+fn test_missing_template() {
+    let err = load("spacefort_defs_missing_template.toml", r#"
+        [[things]]
+        name = "a"
+        id = 1
+        template = "nope"
+    "#).unwrap_err();
+
+    match err {
+        DefError::MissingTemplate { entry, template } => {
+            assert_eq!(entry, "a");
+            assert_eq!(template, "nope");
+        },
+        err => panic!("Wrong error {:?}", err),
+    }
+}
+
+#[test]
+fn test_duplicate_id() {
+    let err = load("spacefort_defs_duplicate_id.toml", r#"
+        [[things]]
+        name = "a"
+        id = 1
+        size = 1
+
+        [[things]]
+        name = "b"
+        id = 1
+        size = 2
+    "#).unwrap_err();
+
+    match err {
+        DefError::DuplicateID { entry, id } => {
+            assert_eq!(entry, "b");
+            assert_eq!(id, "1");
+        },
+        err => panic!("Wrong error {:?}", err),
+    }
+}
+
+#[test]
+fn test_missing_field() {
+    let err = load("spacefort_defs_missing_field.toml", r#"
+        [[things]]
+        name = "a"
+        id = 1
+    "#).unwrap_err();
+
+    match err {
+        DefError::MissingField { entry, field } => {
+            assert_eq!(entry, "a");
+            assert_eq!(field, "size");
+        },
+        err => panic!("Wrong error {:?}", err),
+    }
+}
+
+#[test]
+fn test_defs_sent_to_clients() {
+    let (_, creature_types) = init_entities(root());
+    let g_state = GameState::new(blank_map(root()), Entities::new(), creature_types,
+                                 init_items(root()), init_buildings(root()));
+
+    let defs = g_state.defs();
+    let bytes = serialize(&defs, Infinite).unwrap();
+    let received: Defs = deserialize(&bytes).unwrap();
+
+    assert_eq!(received, defs);
+    assert_eq!(received.materials, g_state.map.materials);
+    assert_eq!(received.creature_types, g_state.creature_types);
+    assert!(!received.building_types.is_empty());
+}
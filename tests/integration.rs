@@ -2,15 +2,14 @@ extern crate spacefort;
 
 // Std lib imports
 use std::path::Path;
-use std::sync::mpsc::{channel, sync_channel};
-use std::thread;
 
 // Local imports
 use spacefort::*;
 use entities::entity;
 use game::server;
 use map::tiles;
-use net::server::NetComm;
+use net::server::init_local_network;
+use objects::{buildings, items};
 
 
 fn setup() -> server::Server {
@@ -20,26 +19,12 @@ fn setup() -> server::Server {
 
     let map = tiles::init_map(root);
     let (entities, creature_types) = entity::init_entities(root);
-    let comm = stub_comm();
+    let item_types = items::init_items(root);
+    let building_types = buildings::init_buildings(root);
+    // Nobody connects, so everything sent goes nowhere
+    let (comm, _) = init_local_network();
 
-    server::Server::new(map, entities, creature_types, comm)
-}
-
-fn stub_comm() -> NetComm {
-    let (send_outgoing, recv_outgoing) = channel();
-    let (send_incoming, recv_incoming) = sync_channel(1024);
-
-    let (send_stream_to_game, recv_stream_to_game) = channel();
-    let (send_stream_from_game, recv_stream_from_game) = channel();
-    let comm = NetComm::new(send_outgoing, recv_incoming,
-                            send_stream_from_game, recv_stream_to_game);
-    thread::spawn(move|| {
-        loop {
-            let _ = recv_outgoing.recv();
-        }
-    });
-
-    comm
+    server::Server::new(map, entities, creature_types, item_types, building_types, comm)
 }
 
 #[test]
@@ -133,34 +118,37 @@ fn test_dig_mutiple_blocks() {
 #[test]
 fn test_attack_stationary_unit() {
     let mut server = setup();
-    let team_id = 1;
+    let player_id = 1;
     let attacker_id = -1;
     let defender_id = -4;
+    server.players.insert(player_id, server::ServerPlayer::new(player_id, Some(1)));
     {
         let ent = server.g_state.entities.iter()
                                          .find(|ent| ent.id == defender_id)
                                          .unwrap();
         assert!(ent.alive);
     }
-    server.attack(team_id, attacker_id, defender_id) ;
+    server.attack(player_id, attacker_id, defender_id);
     for _ in 0..6000 {
         server.update()
     }
 
+    // Bites of 30 plus a strength of 10 are enough to kill a 50 health dog
     {
         let ent = server.g_state.entities.iter()
                                          .find(|ent| ent.id == defender_id)
                                          .unwrap();
-        assert!(ent.alive);
+        assert!(!ent.alive);
     }
 }
 
 #[test]
 fn test_attack_moving_unit() {
     let mut server = setup();
-    let team_id = 1;
+    let player_id = 1;
     let attacker_id = -1;
     let defender_id = -4;
+    server.players.insert(player_id, server::ServerPlayer::new(player_id, Some(1)));
     {
         let ent = server.g_state.entities.iter()
                                          .find(|ent| ent.id == defender_id)
@@ -168,25 +156,27 @@ fn test_attack_moving_unit() {
         assert!(ent.alive);
     }
 
-    server.attack(team_id, attacker_id, defender_id) ;
+    server.attack(player_id, attacker_id, defender_id);
     server.ent_move(-4, (43, 13, 1));
 
     for _ in 0..6000 {
         server.update()
     }
 
+    // The attacker chases the defender down
     let ent = server.g_state.entities.iter()
                                      .find(|ent| ent.id == defender_id)
                                      .unwrap();
-    assert!(ent.alive);
+    assert!(!ent.alive);
 }
 
 #[test]
 fn test_attack_unit_then_move() {
     let mut server = setup();
-    let team_id = 1;
+    let player_id = 1;
     let attacker_id = -1;
     let defender_id = -4;
+    server.players.insert(player_id, server::ServerPlayer::new(player_id, Some(1)));
     {
         let ent = server.g_state.entities.iter()
                                          .find(|ent| ent.id == defender_id)
@@ -194,7 +184,7 @@ fn test_attack_unit_then_move() {
         assert!(ent.alive);
     }
 
-    server.attack(team_id, attacker_id, defender_id) ;
+    server.attack(player_id, attacker_id, defender_id);
 
     for _ in 0..6000 {
         server.update()
@@ -210,7 +200,7 @@ fn test_attack_unit_then_move() {
         let defender = server.g_state.entities.iter()
                                          .find(|ent| ent.id == defender_id)
                                          .unwrap();
-        assert!(defender.alive);
+        assert!(!defender.alive);
         let attacker = server.g_state.entities.iter()
                                          .find(|ent| ent.id == attacker_id)
                                          .unwrap();
@@ -0,0 +1,187 @@
+extern crate spacefort;
+
+mod common;
+
+// Local imports
+use spacefort::*;
+use common::{open_map, root, server_on};
+use game::server;
+use map::constants::{AIR_TILE, Mode};
+use map::tiles::Tile;
+use objects::items;
+
+
+/// Open field with a stone block, big enough to hold the starting entities
+fn setup() -> server::Server {
+    let mut map = open_map();
+    map.set_tile(Tile { material: 2, ..AIR_TILE }, (10, 7, 0));
+
+    let mut server = server_on(map);
+    server.players.insert(1, server::ServerPlayer::new(1, Some(1)));
+    server
+}
+
+#[test]
+fn test_item_templates() {
+    let item_types = items::init_items(root());
+
+    let corpse = &item_types[&10];
+    let dwarf_corpse = &item_types[&11];
+    assert_eq!(dwarf_corpse.name, "dwarf_corpse");
+    assert_eq!(dwarf_corpse.weight, corpse.weight);
+    assert!(dwarf_corpse.color != corpse.color);
+}
+
+#[test]
+fn test_dig_drops_item() {
+    let mut server = setup();
+    let pos = (10, 7, 0);
+    server.dig(1, (pos, pos));
+    for _ in 0..2000 {
+        server.update();
+    }
+
+    assert!(server.g_state.map.passable(pos));
+    let items: Vec<_> = server.g_state.items.iter()
+                                            .filter(|item| item.pos == pos)
+                                            .collect();
+    assert_eq!(items.len(), 1);
+    assert_eq!(server.g_state.item_types[&items[0].type_id].name, "stone_chunk");
+}
+
+#[test]
+fn test_dead_leave_corpse() {
+    let mut server = setup();
+    let pos = {
+        let ent = server.g_state.entities.iter_mut()
+                                         .find(|ent| ent.id == -1)
+                                         .unwrap();
+        ent.health = -1;
+        ent.pos
+    };
+
+    // Corpses are only left once, no matter how long the dead lie around
+    for _ in 0..10 {
+        server.update();
+    }
+
+    assert_eq!(server.g_state.items.len(), 1);
+    let corpse = server.g_state.items[0];
+    assert_eq!(corpse.pos, pos);
+    assert_eq!(server.g_state.item_types[&corpse.type_id].name, "dwarf_corpse");
+}
+
+#[test]
+fn test_haul() {
+    let mut server = setup();
+    let dest = (30, 2, 0);
+    server.g_state.items.push(items::Item::new(100, 1, (20, 7, 0)));
+    server.haul(1, 100, dest);
+
+    // Partway there the item should be carried rather than lying around
+    let mut carried = false;
+    for _ in 0..2000 {
+        server.update();
+        carried |= server.g_state.entities.iter()
+                                          .any(|ent| ent.inventory.iter().any(|item| item.id == 100));
+    }
+
+    assert!(carried);
+    assert_eq!(server.g_state.items.len(), 1);
+    assert_eq!(server.g_state.items[0].pos, dest);
+    assert!(server.g_state.entities.iter().all(|ent| ent.inventory.is_empty()));
+    assert!(server.players[&1].tasks.is_empty());
+}
+
+#[test]
+fn test_stockpile_collects_items() {
+    let mut server = setup();
+    let pile = ((30, 1, 0), (31, 2, 0));
+    server.g_state.items.push(items::Item::new(100, 1, (20, 7, 0)));
+    server.g_state.items.push(items::Item::new(101, 2, (22, 7, 0)));
+    server.g_state.items.push(items::Item::new(102, 3, (24, 7, 0)));
+
+    // Only stone chunks and dirt clods belong in this pile
+    server.mark_stockpile(1, pile, vec![1, 2]);
+    for _ in 0..3000 {
+        server.update();
+    }
+
+    let stockpile = &server.players[&1].stockpiles[0];
+    let stored: Vec<_> = server.g_state.items.iter()
+                                             .filter(|item| stockpile.contains(item.pos))
+                                             .collect();
+    assert_eq!(stored.len(), 2);
+    assert!(stored[0].pos != stored[1].pos);
+    assert!(stored.iter().all(|item| item.type_id != 3));
+    assert!(server.players[&1].tasks.is_empty());
+}
+
+#[test]
+fn test_interrupted_haul() {
+    let mut server = setup();
+    let dest = (30, 2, 0);
+    server.g_state.items.push(items::Item::new(100, 1, (20, 7, 0)));
+    server.haul(1, 100, dest);
+
+    let carrier = (0..2000).filter_map(|_| {
+        server.update();
+        server.g_state.entities.iter().find(|ent| ent.carries(100)).map(|ent| ent.id)
+    }).next().expect("Nobody picked the item up");
+
+    // Sending the carrier elsewhere puts the item down where it stands
+    server.ent_move(carrier, (50, 8, 0));
+    server.update();
+    assert!(server.g_state.entities.iter().all(|ent| ent.inventory.is_empty()));
+    assert_eq!(server.g_state.items.len(), 1);
+    assert!(server.players[&1].tasks.iter().all(|task| task.owner != Some(carrier)));
+
+    // And someone picks the haul back up
+    for _ in 0..3000 {
+        server.update();
+    }
+    assert_eq!(server.g_state.items[0].pos, dest);
+    assert!(server.players[&1].tasks.is_empty());
+}
+
+#[test]
+fn test_stockpile_skips_unreachable_items() {
+    let mut server = setup();
+    server.g_state.items.push(items::Item::new(100, 1, (60, 5, 0)));
+    for x in 59..62 {
+        for y in 4..7 {
+            if (x, y) != (60, 5) {
+                server.g_state.map.set_tile(Tile::new(2, Mode::Block), (x, y, 0));
+            }
+        }
+    }
+
+    // Walled in items are not worth queueing, and are picked up once freed
+    server.mark_stockpile(1, ((30, 1, 0), (31, 2, 0)), vec![]);
+    for _ in 0..100 {
+        server.update();
+    }
+    assert!(server.players[&1].tasks.is_empty());
+
+    server.g_state.map.set_tile(AIR_TILE, (59, 5, 0));
+    for _ in 0..3000 {
+        server.update();
+    }
+    assert!(server.players[&1].stockpiles[0].contains(server.g_state.items[0].pos));
+}
+
+#[test]
+fn test_stockpiles_leave_others_items() {
+    let mut server = setup();
+    server.players.insert(2, server::ServerPlayer::new(2, Some(2)));
+    server.g_state.items.push(items::Item::new(100, 1, (50, 2, 0)));
+
+    // The item is already in the other player's stockpile, so stays put
+    server.mark_stockpile(2, ((50, 2, 0), (50, 2, 0)), vec![]);
+    server.mark_stockpile(1, ((30, 1, 0), (31, 2, 0)), vec![]);
+    for _ in 0..1000 {
+        server.update();
+    }
+    assert_eq!(server.g_state.items[0].pos, (50, 2, 0));
+    assert!(server.players[&1].tasks.is_empty());
+}
@@ -1,14 +1,113 @@
-/*
-extern crate ncurses;
-mod io;
-mod map;
+extern crate spacefort;
 
-use map::tiles::*;
+mod common;
+
+// Std lib imports
+use std::env;
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+
+// Local imports
+use spacefort::*;
+use common::{root, server_on, stub_comm};
+use game::save;
+use game::server;
+use map::constants::AIR_TILE;
+use map::tiles;
+use map::tiles::Map;
+
+
+/// Small open map with a couple of diggable stone blocks
+fn test_map() -> Map {
+    let mut map = tiles::blank_map(root());
+    map.resize((90, 10, 2));
+
+    let stone = tiles::Tile { material: 2, ..AIR_TILE };
+    for x in 10..13 {
+        map.update_tile(stone, (x, 5, 0));
+    }
+
+    map
+}
+
+#[test]
+fn test_map_round_trip() {
+    let path = env::temp_dir().join("spacefort_test_map.sfm");
+    let path_str = path.to_str().unwrap();
 
-fn main() {
     let save_map = test_map();
-    save_map.save(".unit_test.sfm").expect("Map save failure");
-    let load_map = load_map(".unit_test.sfm").expect("Map load failure");
-    assert!(save_map == load_map);
+    save_map.save(path_str).expect("Map save failure");
+    let load_map = tiles::load_map(path_str, save_map.materials.clone())
+                         .expect("Map load failure");
+
+    assert_eq!(save_map, load_map);
+}
+
+#[test]
+fn test_game_round_trip() {
+    let path = env::temp_dir().join("spacefort_test_game.sav");
+
+    let mut server = server_on(test_map());
+    server.players.insert(1, server::ServerPlayer::new(1, Some(1)));
+    server.dig(1, ((10, 5, 0), (12, 5, 0)));
+    server.ent_move(-4, (60, 2, 0));
+    server.place_building(1, 1, (40, 2, 0));
+
+    // Stop part way through so that there are queued actions to save
+    for _ in 0..50 {
+        server.update();
+    }
+    server.save(&path).expect("Game save failure");
+
+    let loaded = save::load_game(&path).expect("Game load failure");
+    let mut restored = server::Server::from_save(loaded, tiles::blank_map(root()),
+                                                 server.g_state.creature_types.clone(),
+                                                 server.g_state.item_types.clone(),
+                                                 server.g_state.building_types.clone(),
+                                                 stub_comm());
+
+    assert_eq!(server.g_state.map, restored.g_state.map);
+    assert_eq!(server.g_state.entities, restored.g_state.entities);
+    assert_eq!(server.g_state.items, restored.g_state.items);
+    assert_eq!(server.g_state.buildings, restored.g_state.buildings);
+    assert_eq!(server.g_state.ticks, restored.g_state.ticks);
+    assert_eq!(server.g_state.cur_id, restored.g_state.cur_id);
+    assert_eq!(server.players[&1].tasks, restored.players[&1].tasks);
+
+    // Both games should carry on identically from the save point
+    for _ in 0..500 {
+        server.update();
+        restored.update();
+    }
+    assert_eq!(server.g_state.map, restored.g_state.map);
+    assert_eq!(server.g_state.entities, restored.g_state.entities);
+}
+
+#[test]
+fn test_load_rejects_garbage() {
+    let path = env::temp_dir().join("spacefort_test_garbage.sav");
+    tiles::blank_map(root()).save(path.to_str().unwrap()).unwrap();
+
+    assert!(save::load_game(&path).is_err());
+}
+
+#[test]
+fn test_load_map_rejects_malformed() {
+    let path = env::temp_dir().join("spacefort_test_malformed.sfm.csv");
+    let path_str = path.to_str().unwrap();
+    let materials = tiles::blank_map(root()).materials;
+
+    let malformed = ["", "1,2,1\n", "1,x,1,1\n2;0,\n", "0,1,1,1\n2;0,\n", "1,0,1,1\n",
+                     "1,2,1,1\n2;0,\n", "1,1,1,1\nstone;0,\n", "1,1,1,1\n2;9,\n"];
+    for contents in &malformed {
+        File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+        match tiles::load_map(path_str, materials.clone()) {
+            Err(ref err) if err.kind() == ErrorKind::InvalidData => {},
+            Err(err) => panic!("Expected {:?} to be malformed, got {}", contents, err),
+            Ok(_) => panic!("Loaded {:?} as a map", contents),
+        }
+    }
+
+    File::create(&path).unwrap().write_all(b"1,2,1,1\n2;0,0\n").unwrap();
+    assert_eq!(tiles::load_map(path_str, materials).unwrap().size(), (2, 1, 1));
 }
-*/
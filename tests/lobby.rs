@@ -0,0 +1,160 @@
+extern crate spacefort;
+
+mod common;
+
+// Local imports
+use spacefort::*;
+use common::{Captured, capture_server, root, sent_to};
+use game::lobby::{DEFAULT_GEN_SIZE, Lobby, LobbyError, MAX_GEN_SIZE, MapChoice, MatchSettings};
+use game::server::Server;
+use net::base::{ClientMsg, Hello, ServerMsg};
+
+
+fn generated(size: (i32, i32, i32)) -> MatchSettings {
+    MatchSettings {
+        map: MapChoice::Generated { seed: 7, size: size },
+        start_units: 2,
+        start_creature: 1,
+        lockstep: false,
+    }
+}
+
+/// A lobby with players 1, 2 and 3 in it
+fn three_player_lobby() -> Lobby {
+    let mut lobby = Lobby::new(root(), vec!["arena.sfm.csv".to_owned()]);
+    lobby.join(1, "urist");
+    lobby.join(2, "cog");
+    lobby.join(3, "bomrek");
+    lobby
+}
+
+/// A server still waiting in its lobby, with outgoing messages captured
+fn lobby_server() -> (Server, Captured) {
+    let (mut server, captured) = capture_server();
+    server.lobby = Some(Lobby::new(root(), Vec::new()));
+    (server, captured)
+}
+
+#[test]
+fn test_join_and_leave() {
+    let mut lobby = three_player_lobby();
+    assert_eq!(lobby.state.host, Some(1));
+    assert_eq!(lobby.teams(), vec![1, 2, 3]);
+
+    // The host leaving passes hosting on, and their team is free again
+    assert!(lobby.leave(1));
+    assert!(!lobby.leave(1));
+    assert_eq!(lobby.state.host, Some(2));
+    lobby.join(4, "kadol");
+    assert_eq!(lobby.state.player(4).unwrap().team, 1);
+
+    lobby.set_team(3, 2);
+    assert_eq!(lobby.teams(), vec![1, 2]);
+}
+
+#[test]
+fn test_settings_and_start() {
+    let mut lobby = three_player_lobby();
+    let mut settings = generated((30, 10, 1));
+
+    assert_eq!(lobby.set_settings(2, settings.clone()), Err(LobbyError::NotHost(2)));
+    settings.map = MapChoice::File("nowhere.sfm.csv".to_owned());
+    assert_eq!(lobby.set_settings(1, settings.clone()),
+               Err(LobbyError::UnknownMap("nowhere.sfm.csv".to_owned())));
+    for &size in &[(0, 10, 1), (100000, 100000, 100), (MAX_GEN_SIZE.0 + 1, 10, 1)] {
+        assert_eq!(lobby.set_settings(1, generated(size)), Err(LobbyError::BadMapSize(size)));
+    }
+    assert!(lobby.set_settings(1, generated(MAX_GEN_SIZE)).is_ok());
+    settings.map = MapChoice::File("arena.sfm.csv".to_owned());
+    assert!(lobby.set_settings(1, settings).is_ok());
+
+    // Everyone has to be ready, and changing teams takes that back
+    lobby.set_ready(1, true);
+    lobby.set_ready(2, true);
+    assert_eq!(lobby.check_start(1), Err(LobbyError::NotReady(vec![3])));
+    lobby.set_ready(3, true);
+    lobby.set_team(2, 1);
+    assert_eq!(lobby.check_start(1), Err(LobbyError::NotReady(vec![2])));
+    lobby.set_ready(2, true);
+    assert_eq!(lobby.check_start(2), Err(LobbyError::NotHost(2)));
+    assert!(lobby.check_start(1).is_ok());
+
+    // Going through the maps ends with a generated one
+    match lobby.state.next_map() {
+        MapChoice::Generated { size, .. } => assert_eq!(size, DEFAULT_GEN_SIZE),
+        map => panic!("Expected a generated map after the last file, got {}", map),
+    }
+}
+
+#[test]
+fn test_match_starts_from_lobby() {
+    let (mut server, captured) = lobby_server();
+    for player_id in 1..3 {
+        server.pending.insert(player_id);
+        server.dispatch(ClientMsg::Hello(Hello::new("urist")), player_id);
+    }
+
+    // Joining only gets a player into the lobby
+    assert!(server.players.is_empty());
+    let (first, second) = (sent_to(&server, &captured, 1), sent_to(&server, &captured, 2));
+    assert!(second.iter().any(|msg| match *msg {
+        ServerMsg::SendLobby(2, ref lobby) => lobby.players.len() == 2,
+        _ => false,
+    }));
+    assert!(first.iter().chain(second.iter()).all(|msg| match *msg {
+        ServerMsg::ReplyJoin(_) => false,
+        _ => true,
+    }));
+
+    server.dispatch(ClientMsg::SetMatch(generated((30, 10, 1))), 1);
+    server.dispatch(ClientMsg::SetReady(true), 1);
+    server.dispatch(ClientMsg::StartMatch(), 1);
+    assert!(server.lobby.is_some());
+
+    server.dispatch(ClientMsg::SetReady(true), 2);
+    server.dispatch(ClientMsg::StartMatch(), 2);
+    assert!(server.lobby.is_some());
+    server.dispatch(ClientMsg::StartMatch(), 1);
+    assert!(server.lobby.is_none());
+
+    // Each player is on their lobby team with their starting units
+    assert_eq!(server.g_state.map.size(), (30, 10, 1));
+    for player_id in 1..3 {
+        let team = Some(player_id as u16);
+        assert_eq!(server.players[&player_id].team_id, team);
+        assert_eq!(server.g_state.entities.iter().filter(|ent| ent.team_id == team).count(), 2);
+    }
+    for player_id in 1..3 {
+        let joins = sent_to(&server, &captured, player_id).into_iter().filter(|msg| match *msg {
+            ServerMsg::ReplyJoin(ref join) => join.team_id == Some(player_id as u16),
+            _ => false,
+        }).count();
+        assert_eq!(joins, 1);
+    }
+}
+
+#[test]
+fn test_teammate_leaving_and_late_joiner() {
+    let (mut server, _captured) = lobby_server();
+    for player_id in 1..4 {
+        server.pending.insert(player_id);
+        server.dispatch(ClientMsg::Hello(Hello::new("urist")), player_id);
+    }
+    // Players 1 and 3 share team 1
+    server.dispatch(ClientMsg::SetTeam(1), 3);
+    server.dispatch(ClientMsg::SetMatch(generated((30, 10, 1))), 1);
+    for player_id in 1..4 {
+        server.dispatch(ClientMsg::SetReady(true), player_id);
+    }
+    server.dispatch(ClientMsg::StartMatch(), 1);
+    assert!(server.lobby.is_none());
+
+    // A teammate leaving takes nothing from the team
+    server.dispatch(ClientMsg::Leave(), 3);
+    assert_eq!(server.g_state.entities.iter().filter(|ent| ent.team_id == Some(1)).count(), 2);
+
+    // Someone joining now gets a team nobody is on, not their player id
+    server.pending.insert(5);
+    server.dispatch(ClientMsg::Hello(Hello::new("kadol")), 5);
+    assert_eq!(server.players[&5].team_id, Some(3));
+}
@@ -0,0 +1,183 @@
+extern crate spacefort;
+
+mod common;
+
+// Local imports
+use spacefort::*;
+use common::{Captured, capture_server, clear_sent, open_map, sent_to, server_on};
+use entities::entity::{Entity, EntID};
+use game::base::Ticks;
+use game::lockstep::{CHECKSUM_PERIOD, Lockstep, Replica};
+use game::save::SaveGame;
+use game::server::Server;
+use net::base::{ClientMsg, Hello, ServerMsg};
+
+
+/// A lockstep server with two players who each have a unit
+fn lockstep_server() -> (Server, Captured) {
+    let (mut server, captured) = capture_server();
+    server.lockstep = Some(Lockstep::new());
+    for player_id in 1..3 {
+        let ent = Entity::new(100 + player_id as EntID, 1, (player_id as i32 * 10, 1, 0),
+                              Some(player_id), &server.g_state.creature_types);
+        server.g_state.entities.push(ent);
+    }
+    for player_id in 1..3 {
+        server.pending.insert(player_id);
+        server.dispatch(ClientMsg::Hello(Hello::new("urist")), player_id);
+    }
+
+    (server, captured)
+}
+
+/// Have both players send their turn for a tick and run it
+fn run_tick(server: &mut Server, tick: Ticks, commands: Vec<ClientMsg>) {
+    server.dispatch(ClientMsg::Turn(tick, commands), 1);
+    server.dispatch(ClientMsg::Turn(tick, Vec::new()), 2);
+    server.update();
+}
+
+#[test]
+fn test_checksum() {
+    let mut first = server_on(open_map());
+    let mut second = server_on(open_map());
+    assert_eq!(first.g_state.checksum(), second.g_state.checksum());
+
+    let turn = vec![(1, ClientMsg::EntMove(-1, (5, 5, 0)))];
+    for _ in 0..10 {
+        first.run_turn(&turn);
+        second.run_turn(&turn);
+    }
+    assert_eq!(first.g_state.checksum(), second.g_state.checksum());
+
+    first.g_state.map.mark((30, 1, 0));
+    assert!(first.g_state.checksum() != second.g_state.checksum());
+}
+
+#[test]
+fn test_turns_wait_for_everyone() {
+    let (mut server, captured) = lockstep_server();
+    clear_sent(&server, &captured);
+
+    // Commands only count as part of a turn
+    let stockpile = ClientMsg::MarkStockpile(((30, 1, 0), (31, 2, 0)), Vec::new());
+    server.dispatch(stockpile.clone(), 1);
+    server.dispatch(ClientMsg::Turn(1, vec![stockpile.clone()]), 1);
+    server.update();
+    assert_eq!(server.g_state.ticks, 0);
+
+    server.dispatch(ClientMsg::Turn(1, Vec::new()), 2);
+    server.update();
+    assert_eq!(server.g_state.ticks, 1);
+    assert_eq!(server.players[&1].stockpiles.len(), 1);
+
+    // The turn is all the players are sent
+    let turn = vec![(1, stockpile.clone())];
+    assert_eq!(sent_to(&server, &captured, 2), vec![ServerMsg::Turn(1, turn)]);
+
+    // A turn sent too late goes on the next tick, and a quiet player is only
+    // waited on for so long
+    server.dispatch(ClientMsg::Turn(1, vec![stockpile]), 2);
+    server.dispatch(ClientMsg::Turn(2, Vec::new()), 2);
+    let mut updates = 0;
+    while server.g_state.ticks == 1 {
+        server.update();
+        updates += 1;
+    }
+    assert!(updates > 1);
+    assert_eq!(server.players[&2].stockpiles.len(), 1);
+
+    // Having kept the game waiting once, the quiet player is not waited on
+    // again until they send another turn
+    server.dispatch(ClientMsg::Turn(3, Vec::new()), 2);
+    server.update();
+    assert_eq!(server.g_state.ticks, 3);
+    run_tick(&mut server, 4, Vec::new());
+    assert_eq!(server.g_state.ticks, 4);
+    server.dispatch(ClientMsg::Turn(5, Vec::new()), 2);
+    server.update();
+    assert_eq!(server.g_state.ticks, 4);
+}
+
+#[test]
+fn test_replica_stays_in_sync() {
+    let (mut server, captured) = lockstep_server();
+    let save = sent_to(&server, &captured, 1).into_iter().filter_map(|msg| match msg {
+        ServerMsg::SendSave(save) => Some(save),
+        _ => None,
+    }).last().expect("No save sent");
+    let mut replica = Replica::new(save, server.g_state.defs());
+
+    run_tick(&mut server, 1, vec![ClientMsg::EntMove(101, (40, 5, 0))]);
+    run_tick(&mut server, 2, vec![ClientMsg::EntAttack(101, 102)]);
+    for tick in 3..30 {
+        run_tick(&mut server, tick, Vec::new());
+    }
+
+    for msg in sent_to(&server, &captured, 1) {
+        if let ServerMsg::Turn(tick, turn) = msg {
+            replica.run_turn(tick, &turn).unwrap();
+        }
+    }
+    assert_eq!(replica.g_state().ticks, 29);
+    assert_eq!(replica.g_state().checksum(), server.g_state.checksum());
+
+    // Turns have to be run in order
+    assert_eq!(replica.run_turn(31, &[]).err(), Some(30));
+}
+
+#[test]
+fn test_desync_sends_game_again() {
+    let (mut server, captured) = lockstep_server();
+    for tick in 1..(CHECKSUM_PERIOD + 1) {
+        run_tick(&mut server, tick, Vec::new());
+    }
+    clear_sent(&server, &captured);
+
+    let checksum = server.g_state.checksum();
+    server.dispatch(ClientMsg::Checksum(CHECKSUM_PERIOD, checksum), 1);
+    assert!(sent_to(&server, &captured, 1).is_empty());
+
+    server.dispatch(ClientMsg::Checksum(CHECKSUM_PERIOD, checksum + 1), 1);
+    // Everything but the other players' resume tokens
+    let expected = SaveGame::new(&server.g_state, &server.players).without_tokens();
+    assert!(expected.players.iter().all(|player| player.resume_token == 0));
+    assert_eq!(sent_to(&server, &captured, 1), vec![ServerMsg::SendSave(expected)]);
+}
+
+#[test]
+fn test_leaving_hands_on_in_a_turn() {
+    let (mut server, captured) = lockstep_server();
+    // The last to join is sent a game with everyone in it
+    let save = sent_to(&server, &captured, 2).into_iter().filter_map(|msg| match msg {
+        ServerMsg::SendSave(save) => Some(save),
+        _ => None,
+    }).last().expect("No save sent");
+    let mut replica = Replica::new(save, server.g_state.defs());
+    run_tick(&mut server, 1, Vec::new());
+
+    // Leaving changes nothing until the next turn runs, which it is sent out in
+    server.dispatch(ClientMsg::Leave(), 2);
+    let team_of = |server: &Server| server.g_state.entities.iter()
+                                                          .find(|ent| ent.id == 102)
+                                                          .unwrap()
+                                                          .team_id;
+    assert_eq!(team_of(&server), Some(2));
+    server.dispatch(ClientMsg::Turn(2, Vec::new()), 1);
+    server.update();
+    assert_eq!(team_of(&server), None);
+
+    let turns: Vec<ServerMsg> = sent_to(&server, &captured, 1);
+    assert!(turns.contains(&ServerMsg::Turn(2, vec![(2, ClientMsg::HandOn(None))])));
+    for msg in turns {
+        if let ServerMsg::Turn(tick, turn) = msg {
+            replica.run_turn(tick, &turn).unwrap();
+        }
+    }
+    assert_eq!(replica.g_state().checksum(), server.g_state.checksum());
+
+    // Nobody else can hand a player on
+    server.dispatch(ClientMsg::Turn(3, vec![ClientMsg::HandOn(Some(1))]), 1);
+    server.update();
+    assert!(sent_to(&server, &captured, 1).contains(&ServerMsg::Turn(3, Vec::new())));
+}
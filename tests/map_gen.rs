@@ -0,0 +1,116 @@
+extern crate spacefort;
+
+// Std lib imports
+use std::path::Path;
+
+// Local imports
+use spacefort::*;
+use gen::map::init_gen_map;
+use map::constants::Mode;
+use map::material::material_id;
+use map::tiles::Map;
+
+
+const SIZE: (i32, i32, i32) = (64, 48, 5);
+
+fn gen(seed: u32) -> Map {
+    // Root points to the directory containing
+    // static where assets are loaded from
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    init_gen_map(root, seed, SIZE)
+}
+
+#[test]
+fn test_same_seed_same_map() {
+    assert_eq!(gen(42), gen(42));
+}
+
+#[test]
+fn test_different_seed_different_map() {
+    assert!(gen(1) != gen(2));
+}
+
+#[test]
+fn test_strata() {
+    let map = gen(7);
+    let (xlen, ylen, zlen) = map.size();
+    let id = |name| material_id(&map.materials, name).unwrap();
+
+    for y in 0..ylen {
+        for x in 0..xlen {
+            // The surface is all grass
+            let top = map.get_tile((x, y, 0)).unwrap();
+            assert!([id("grass"), id("grass_floor"), id("downstairs")].contains(&top.material));
+
+            // The first level down is soil, the bottom level is rock
+            let soil = map.get_tile((x, y, 1)).unwrap();
+            if soil.mode == Mode::Block {
+                assert_eq!(soil.material, id("dirt"));
+            }
+            let rock = map.get_tile((x, y, zlen - 1)).unwrap();
+            if rock.mode == Mode::Block {
+                assert_eq!(rock.material, id("stone"));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_caves() {
+    let map = gen(7);
+    let (xlen, ylen, zlen) = map.size();
+
+    for z in 1..zlen {
+        let open = (0..ylen).flat_map(|y| (0..xlen).map(move |x| (x, y)))
+                            .filter(|&(x, y)| map.passable((x, y, z)))
+                            .count();
+        assert!(open > 0, "Level {} has no caves", z);
+    }
+}
+
+#[test]
+fn test_stairs_connect_levels() {
+    let map = gen(1234);
+    let (xlen, ylen, zlen) = map.size();
+
+    for z in 0..(zlen - 1) {
+        let mut connected = false;
+        for y in 0..ylen {
+            for x in 0..xlen {
+                let top = map.get_tile((x, y, z)).unwrap();
+                let bottom = map.get_tile((x, y, z + 1)).unwrap();
+                if top.mode == Mode::DownStairs {
+                    assert_eq!(bottom.mode, Mode::UpStairs);
+                    connected = true;
+                }
+            }
+        }
+        assert!(connected, "No stairs from level {} to {}", z, z + 1);
+    }
+}
+
+#[test]
+fn test_stairs_stay_paired() {
+    // On a map this small there is only one tile stairs can go on, which
+    // the stairs up from the level above take
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    for seed in 0..20 {
+        let map = init_gen_map(root, seed, (5, 5, 4));
+        let (xlen, ylen, zlen) = map.size();
+        for z in 0..zlen {
+            for y in 0..ylen {
+                for x in 0..xlen {
+                    match map.get_tile((x, y, z)).unwrap().mode {
+                        Mode::DownStairs => {
+                            assert_eq!(map.get_tile((x, y, z + 1)).unwrap().mode, Mode::UpStairs);
+                        },
+                        Mode::UpStairs => {
+                            assert_eq!(map.get_tile((x, y, z - 1)).unwrap().mode, Mode::DownStairs);
+                        },
+                        _ => {},
+                    }
+                }
+            }
+        }
+    }
+}
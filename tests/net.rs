@@ -0,0 +1,664 @@
+extern crate bincode;
+extern crate spacefort;
+
+mod common;
+
+// Std lib imports
+use std::collections::HashSet;
+use std::io::Cursor;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc::{channel, sync_channel};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Local imports
+use spacefort::*;
+use common::{Captured, capture_server, clear_sent, root, sent_to};
+use entities::actions::Task;
+use entities::creatures::init_creatures;
+use entities::entity::{Entities, Entity, EntID};
+use defs::packs::{BASE_PACK, PackInfo};
+use game::base::PlayerID;
+use game::server::{DisconnectPolicy, RESUME_PERIOD, Server, TIMEOUT_MS};
+use map::tiles::{Map, blank_map, chunk_origin};
+use net::base::{BootReason, ClientMsg, EntBaseline, FrameCodec, FrameError, Hello, LeaveReason};
+use net::base::{ConnEvent, PlayerJoin, ServerMsg, decode, unreadable_hello};
+use net::base::{PROTOCOL_VERSION, VIEW_MARGIN, View};
+use net::server::handle_client;
+use net::web::Encoding;
+
+
+/// A server with two players who each have a unit and a dig task
+fn two_player_server() -> (Server, Captured) {
+    let (mut server, captured) = capture_server();
+    for player_id in 1..3 {
+        server.pending.insert(player_id);
+        server.dispatch(ClientMsg::Hello(Hello::new("urist")), player_id);
+
+        let pos = (player_id as i32 * 10, 1, 0);
+        server.g_state.map.mark(pos);
+        server.players.get_mut(&player_id).unwrap().tasks.push(Task::dig(pos));
+
+        let ent = Entity::new(100 + player_id as EntID, 1, pos, Some(player_id),
+                              &server.g_state.creature_types);
+        server.g_state.entities.push(ent);
+    }
+    clear_sent(&server, &captured);
+
+    (server, captured)
+}
+
+fn team_of(server: &Server, ent_id: EntID) -> Option<u16> {
+    server.g_state.entities.iter().find(|ent| ent.id == ent_id).unwrap().team_id
+}
+
+fn changed(ent_ids: &[EntID]) -> HashSet<EntID> {
+    ent_ids.iter().cloned().collect()
+}
+
+#[test]
+fn test_ent_spawn_and_despawn() {
+    let creature_types = init_creatures(root());
+    let mut entities = vec![Entity::new(1, 1, (1, 1, 0), Some(1), &creature_types),
+                            Entity::new(2, 2, (5, 1, 0), Some(2), &creature_types)];
+    let mut baseline = EntBaseline::new();
+    let mut client_ents = Entities::new();
+
+    // Everything is new to a player that just joined
+    let update = baseline.update(&entities, &HashSet::new(), false, None);
+    assert!(update.keyframe);
+    assert_eq!(update.spawned.len(), 2);
+    assert!(update.changed.is_empty() && update.despawned.is_empty());
+    update.apply(&mut client_ents);
+    assert_eq!(client_ents, entities);
+
+    // A unit trained after joining is spawned
+    entities[0].pos = (2, 1, 0);
+    entities.push(Entity::new(3, 1, (1, 2, 0), Some(1), &creature_types));
+    let update = baseline.update(&entities, &changed(&[1, 3]), false, None);
+    assert_eq!(update.spawned.len(), 1);
+    assert_eq!(update.spawned[0].id, 3);
+    assert_eq!(update.changed.len(), 1);
+    update.apply(&mut client_ents);
+    assert_eq!(client_ents.len(), 3);
+    assert_eq!(client_ents[0].pos, (2, 1, 0));
+
+    // The dead get a single tombstone
+    entities[1].alive = false;
+    let update = baseline.update(&entities, &changed(&[2]), false, None);
+    assert_eq!(update.despawned, vec![2]);
+    update.apply(&mut client_ents);
+    assert!(client_ents.iter().all(|ent| ent.id != 2));
+
+    let update = baseline.update(&entities, &changed(&[2]), false, None);
+    assert!(update.is_empty());
+
+    // Entities removed outright are caught by the next keyframe
+    entities.remove(0);
+    let update = baseline.update(&entities, &HashSet::new(), true, None);
+    assert_eq!(update.despawned, vec![1]);
+    update.apply(&mut client_ents);
+    assert_eq!(client_ents.iter().map(|ent| ent.id).collect::<Vec<_>>(), vec![3]);
+}
+
+#[test]
+fn test_ent_deltas() {
+    let creature_types = init_creatures(root());
+    let mut entities = vec![Entity::new(1, 1, (1, 1, 0), Some(1), &creature_types),
+                            Entity::new(2, 2, (5, 1, 0), Some(2), &creature_types)];
+    let mut baseline = EntBaseline::new();
+    let mut client_ents = Entities::new();
+    baseline.update(&entities, &HashSet::new(), false, None).apply(&mut client_ents);
+
+    // Only what changed is sent
+    entities[1].health -= 10;
+    let update = baseline.update(&entities, &changed(&[1, 2]), false, None);
+    assert!(!update.keyframe);
+    assert_eq!(update.changed.iter().map(|snap| snap.id).collect::<Vec<_>>(), vec![2]);
+    update.apply(&mut client_ents);
+    assert_eq!(client_ents[1].health, entities[1].health);
+
+    // Keyframes send everything, and drop whatever the client should not have
+    client_ents.push(Entity::new(9, 1, (3, 3, 0), Some(1), &creature_types));
+    let update = baseline.update(&entities, &HashSet::new(), true, None);
+    assert!(update.keyframe);
+    assert_eq!(update.changed.len(), 2);
+    update.apply(&mut client_ents);
+    assert_eq!(client_ents, entities);
+
+    // As does the next update after asking for a resend
+    baseline.request_keyframe();
+    assert_eq!(baseline.update(&entities, &HashSet::new(), false, None).changed.len(), 2);
+    assert!(baseline.update(&entities, &HashSet::new(), false, None).is_empty());
+}
+
+#[test]
+fn test_view_moves() {
+    let creature_types = init_creatures(root());
+    let entities = vec![Entity::new(1, 1, (1, 1, 0), Some(1), &creature_types),
+                        Entity::new(2, 1, (5, 1, 0), Some(1), &creature_types),
+                        Entity::new(3, 2, (9, 1, 0), Some(2), &creature_types)];
+    let mut baseline = EntBaseline::new();
+    let mut client_ents = Entities::new();
+    let view = View::new((0, 0, 0), (5, 5, 0));
+    baseline.update(&entities, &HashSet::new(), false, Some(view)).apply(&mut client_ents);
+    assert_eq!(client_ents.len(), 2);
+
+    // Sliding the view over only sends what came into and went out of it
+    let view = View::new((4, 0, 0), (9, 5, 0));
+    baseline.move_view();
+    let update = baseline.update(&entities, &HashSet::new(), false, Some(view));
+    assert!(!update.keyframe);
+    assert_eq!(update.spawned.iter().map(|snap| snap.id).collect::<Vec<_>>(), vec![3]);
+    assert!(update.changed.is_empty());
+    assert_eq!(update.despawned, vec![1]);
+    update.apply(&mut client_ents);
+    assert_eq!(client_ents.iter().map(|ent| ent.id).collect::<Vec<_>>(), vec![2, 3]);
+
+    assert!(baseline.update(&entities, &HashSet::new(), false, Some(view)).is_empty());
+}
+
+#[test]
+fn test_bandwidth() {
+    let (mut server, _captured) = capture_server();
+    server.pending.insert(1);
+    server.dispatch(ClientMsg::Hello(Hello::new("urist")), 1);
+    let joined = server.comm.bytes_sent(1);
+    assert!(joined > 0);
+
+    // Standing around costs little once the first keyframe is out
+    server.update();
+    let first_update = server.comm.bytes_sent(1) - joined;
+    for _ in 0..10 {
+        server.update();
+    }
+    let idle = server.comm.bytes_sent(1) - joined - first_update;
+    assert!(idle < first_update);
+
+    for _ in 0..60 {
+        server.update();
+    }
+    assert!(server.players[&1].bandwidth > 0);
+}
+
+#[test]
+fn test_frames_round_trip() {
+    let codec = FrameCodec::default();
+    let mut stream = Vec::new();
+    codec.write(&mut stream, &ClientMsg::MarkDig(((1, 2, 0), (3, 4, 1)))).unwrap();
+    codec.write(&mut stream, &ClientMsg::Leave()).unwrap();
+
+    let mut stream = Cursor::new(stream);
+    let msg: ClientMsg = codec.read(&mut stream).unwrap();
+    assert_eq!(msg, ClientMsg::MarkDig(((1, 2, 0), (3, 4, 1))));
+    let msg: ClientMsg = codec.read(&mut stream).unwrap();
+    assert_eq!(msg, ClientMsg::Leave());
+
+    match codec.read::<_, ClientMsg>(&mut stream) {
+        Err(FrameError::Io(_)) => {},
+        other => panic!("Expected the stream to run out, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_large_frames() {
+    // A whole map is far bigger than the old 4KB buffer
+    let map = Map::new((90, 40, 3), blank_map(root()).materials);
+    let chunk = map.get_chunk((0, 0, 0), map.size());
+    let msg = ServerMsg::SendMapChunk(chunk);
+
+    let codec = FrameCodec::default();
+    let mut stream = Vec::new();
+    codec.write(&mut stream, &msg).unwrap();
+    assert!(stream.len() > 4096);
+
+    let received: ServerMsg = codec.read(&mut Cursor::new(stream)).unwrap();
+    assert_eq!(received, msg);
+}
+
+#[test]
+fn test_oversize_frames_rejected() {
+    let small = FrameCodec::new(8);
+    let msg = ClientMsg::MarkDig(((1, 2, 0), (3, 4, 1)));
+
+    let mut stream = Vec::new();
+    match small.write(&mut stream, &msg) {
+        Err(FrameError::TooLarge(_)) => assert!(stream.is_empty()),
+        other => panic!("Expected the message to be too large, got {:?}", other),
+    }
+
+    FrameCodec::default().write(&mut stream, &msg).unwrap();
+    match small.read::<_, ClientMsg>(&mut Cursor::new(stream)) {
+        Err(FrameError::TooLarge(size)) => assert!(size > 8),
+        other => panic!("Expected the frame to be too large, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_malformed_frames() {
+    let codec = FrameCodec::default();
+
+    // A well framed payload that is not a message, followed by a good one
+    let mut stream = vec![0, 0, 0, 4, 0xff, 0xff, 0xff, 0xff];
+    codec.write(&mut stream, &ClientMsg::Leave()).unwrap();
+    let mut stream = Cursor::new(stream);
+
+    match codec.read::<_, ClientMsg>(&mut stream) {
+        Err(FrameError::Decode(_)) => {},
+        other => panic!("Expected a decode error, got {:?}", other),
+    }
+    let msg: ClientMsg = codec.read(&mut stream).unwrap();
+    assert_eq!(msg, ClientMsg::Leave());
+
+    // A frame cut short
+    let mut stream = Vec::new();
+    codec.write(&mut stream, &ClientMsg::MarkDig(((1, 2, 0), (3, 4, 1)))).unwrap();
+    let len = stream.len();
+    stream.truncate(len - 3);
+    match codec.read::<_, ClientMsg>(&mut Cursor::new(stream)) {
+        Err(FrameError::Io(_)) => {},
+        other => panic!("Expected a short read, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_hello_accepted() {
+    let (mut server, captured) = capture_server();
+    server.pending.insert(1);
+
+    server.dispatch(ClientMsg::Hello(Hello::new("urist")), 1);
+    assert!(server.pending.is_empty());
+    assert_eq!(server.players[&1].name, "urist");
+
+    let sent = sent_to(&server, &captured, 1);
+    match sent[0] {
+        ServerMsg::ReplyJoin(player_join) => assert_eq!(player_join.player_id, 1),
+        ref msg => panic!("Expected a join reply, got {:?}", msg),
+    }
+}
+
+#[test]
+fn test_hello_rejected() {
+    let (mut server, captured) = capture_server();
+    server.pending.insert(1);
+    server.pending.insert(2);
+
+    let mut hello = Hello::new("time_traveller");
+    hello.protocol_version = PROTOCOL_VERSION + 1;
+    server.dispatch(ClientMsg::Hello(hello), 1);
+    server.dispatch(ClientMsg::MarkDig(((0, 0, 0), (1, 1, 0))), 2);
+
+    assert!(server.players.is_empty());
+    assert!(server.pending.is_empty());
+    assert_eq!(sent_to(&server, &captured, 1),
+               vec![ServerMsg::Boot(BootReason::ProtocolMismatch {
+                   server: PROTOCOL_VERSION,
+                   client: PROTOCOL_VERSION + 1,
+               })]);
+}
+
+#[test]
+fn test_pack_mismatch_rejected() {
+    let (mut server, captured) = capture_server();
+    let base = PackInfo { name: BASE_PACK.to_owned(), hash: 1 };
+    server.packs = vec![base.clone()];
+    server.pending.insert(1);
+    server.pending.insert(2);
+
+    // Missing a pack, or running a changed copy of one, gets a client booted
+    let changed = PackInfo { hash: 2, ..base.clone() };
+    let hellos = vec![Hello::new("urist"), Hello { packs: vec![changed], ..Hello::new("cog") }];
+    for (player_id, hello) in (1..3).zip(hellos) {
+        server.dispatch(ClientMsg::Hello(hello), player_id);
+        match sent_to(&server, &captured, player_id)[..] {
+            [ServerMsg::Boot(BootReason::PackMismatch(_))] => {},
+            ref msgs => panic!("Expected a pack mismatch, got {:?}", msgs),
+        }
+    }
+    assert!(server.players.is_empty());
+
+    server.pending.insert(3);
+    server.dispatch(ClientMsg::Hello(Hello { packs: vec![base], ..Hello::new("urist") }), 3);
+    assert!(server.players.contains_key(&3));
+}
+
+#[test]
+fn test_unreadable_hello() {
+    // A hello from a later version, with fields this one does not know of
+    let future = bincode::serialize(&(0u32, PROTOCOL_VERSION + 1, "urist", 7u8),
+                                    bincode::Infinite).unwrap();
+    assert!(decode::<ClientMsg>(Encoding::Bincode, &future).is_err());
+    let mismatch = BootReason::ProtocolMismatch { server: PROTOCOL_VERSION,
+                                                  client: PROTOCOL_VERSION + 1 };
+    assert_eq!(unreadable_hello(Encoding::Bincode, &future), mismatch);
+
+    let future = format!("{{\"Hello\":{{\"protocol_version\":{},\"colour\":\"red\"}}}}",
+                         PROTOCOL_VERSION + 1);
+    assert_eq!(unreadable_hello(Encoding::Json, future.as_bytes()), mismatch);
+
+    // Anything else, including a broken hello of our own version, did not say hello
+    let broken = bincode::serialize(&(0u32, PROTOCOL_VERSION), bincode::Infinite).unwrap();
+    assert_eq!(unreadable_hello(Encoding::Bincode, &broken), BootReason::NoHello);
+    assert_eq!(unreadable_hello(Encoding::Bincode, &[0xff; 4]), BootReason::NoHello);
+}
+
+#[test]
+fn test_unreadable_first_message() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+
+    let (send_incoming, recv_incoming) = sync_channel(16);
+    let (send_conn, recv_conn) = channel();
+    let reader = thread::spawn(move || {
+        handle_client(stream, FrameCodec::default(), send_incoming, send_conn, 1);
+    });
+
+    let codec = FrameCodec::default();
+    // The start of a hello, from a later version
+    codec.write(&mut client, &(0u32, PROTOCOL_VERSION + 1)).unwrap();
+    match recv_conn.recv().unwrap() {
+        ConnEvent::Unreadable(1, BootReason::ProtocolMismatch { client, .. }) => {
+            assert_eq!(client, PROTOCOL_VERSION + 1);
+        },
+        event => panic!("Expected the connection to be unreadable, got {:?}", event),
+    }
+
+    // Nothing more gets through while the boot goes out
+    codec.write(&mut client, &ClientMsg::Leave()).unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    reader.join().unwrap();
+    assert!(recv_incoming.try_recv().is_err());
+}
+
+#[test]
+fn test_message_before_hello() {
+    let (mut server, captured) = capture_server();
+    server.pending.insert(2);
+
+    server.dispatch(ClientMsg::MarkDig(((0, 0, 0), (1, 1, 0))), 2);
+    assert!(server.players.is_empty());
+    assert_eq!(sent_to(&server, &captured, 2), vec![ServerMsg::Boot(BootReason::NoHello)]);
+}
+
+#[test]
+fn test_chunks_in() {
+    let map = Map::new((20, 10, 2), blank_map(root()).materials);
+
+    assert_eq!(chunk_origin((9, 7, 1)), (8, 0, 1));
+    assert_eq!(map.chunks_in((7, 7, 0), (8, 8, 0)),
+               vec![(0, 0, 0), (0, 8, 0), (8, 0, 0), (8, 8, 0)]);
+    // Nothing past the edge of the map
+    assert_eq!(map.chunks_in((-10, -10, -1), (100, 5, 0)),
+               vec![(0, 0, 0), (8, 0, 0), (16, 0, 0)]);
+    assert!(map.chunks_in((30, 0, 0), (40, 5, 0)).is_empty());
+
+    let chunk = map.get_chunk_at((16, 8, 1));
+    assert_eq!((chunk.xlen, chunk.ylen, chunk.zlen), (4, 2, 1));
+}
+
+#[test]
+fn test_views() {
+    let view = View::new((10, 10, 1), (2, 4, 1));
+    assert_eq!(view.start, (2, 4, 1));
+    assert!(view.contains((10, 4, 1)));
+    assert!(!view.contains((11, 4, 1)) && !view.contains((5, 5, 0)));
+
+    let grown = view.grow(VIEW_MARGIN);
+    assert!(grown.contains((10 + VIEW_MARGIN, 4 - VIEW_MARGIN, 0)));
+    assert!(grown.overlaps_chunk(chunk_origin((0, 0, 2))));
+    assert!(!view.overlaps_chunk((16, 0, 1)));
+    assert!(view.overlaps_chunk((8, 8, 1)));
+}
+
+#[test]
+fn test_ents_filtered_by_view() {
+    let creature_types = init_creatures(root());
+    let mut entities = vec![Entity::new(1, 1, (1, 1, 0), Some(1), &creature_types),
+                            Entity::new(2, 2, (50, 1, 0), Some(2), &creature_types)];
+    let mut baseline = EntBaseline::new();
+    let view = Some(View::new((0, 0, 0), (10, 10, 0)));
+
+    let update = baseline.update(&entities, &HashSet::new(), false, view);
+    assert_eq!(update.spawned.iter().map(|snap| snap.id).collect::<Vec<_>>(), vec![1]);
+
+    // Walking out of view despawns, and back in spawns again
+    entities[0].pos = (11, 1, 0);
+    entities[1].pos = (10, 1, 0);
+    let update = baseline.update(&entities, &changed(&[1, 2]), false, view);
+    assert_eq!(update.despawned, vec![1]);
+    assert_eq!(update.spawned.iter().map(|snap| snap.id).collect::<Vec<_>>(), vec![2]);
+
+    // Changes out of view are not sent
+    entities[0].pos = (12, 1, 0);
+    assert!(baseline.update(&entities, &changed(&[1]), false, view).is_empty());
+}
+
+#[test]
+fn test_request_map() {
+    let (mut server, captured) = capture_server();
+    server.pending.insert(1);
+    server.dispatch(ClientMsg::Hello(Hello::new("urist")), 1);
+
+    // Joining no longer sends the whole map
+    assert!(!sent_to(&server, &captured, 1).iter().any(|msg| match *msg {
+        ServerMsg::SendMapChunk(_) => true,
+        _ => false,
+    }));
+
+    server.dispatch(ClientMsg::RequestMap(((9, 0, 0), (0, 3, 0))), 1);
+    let origins: Vec<_> = sent_to(&server, &captured, 1).into_iter().map(|msg| match msg {
+        ServerMsg::SendMapChunk(chunk) => chunk.pos,
+        msg => panic!("Expected a map chunk, got {:?}", msg),
+    }).collect();
+    assert_eq!(origins, vec![(0, 0, 0), (8, 0, 0)]);
+
+    server.dispatch(ClientMsg::SetView(View::new((0, 0, 0), (10, 5, 0))), 1);
+    assert_eq!(server.players[&1].view, Some(View::new((0, 0, 0), (10, 5, 0)).grow(VIEW_MARGIN)));
+}
+
+#[test]
+fn test_spectator() {
+    let (mut server, captured) = two_player_server();
+    server.pending.insert(3);
+    server.dispatch(ClientMsg::Hello(Hello::spectate("cog")), 3);
+
+    // Spectators get every chunk without asking
+    let sent = sent_to(&server, &captured, 3);
+    let join = sent.iter().filter_map(|msg| match *msg {
+        ServerMsg::ReplyJoin(ref player_join) => Some(player_join.clone()),
+        _ => None,
+    }).next().unwrap();
+    assert!(join.spectator);
+    assert_eq!(join.team_id, None);
+    let chunks = sent.iter().filter(|msg| match **msg {
+        ServerMsg::SendMapChunk(_) => true,
+        _ => false,
+    }).count();
+    assert_eq!(chunks, server.g_state.map.chunks_in((0, 0, 0), (89, 9, 0)).len());
+
+    // Orders are turned down
+    server.dispatch(ClientMsg::MarkDig(((30, 1, 0), (30, 1, 0))), 3);
+    server.dispatch(ClientMsg::EntMove(101, (5, 5, 0)), 3);
+    server.dispatch(ClientMsg::EntAttack(101, 102), 3);
+    assert!(!server.g_state.map.get_tile((30, 1, 0)).unwrap().marked);
+    assert!(server.g_state.entities.iter().all(|ent| ent.goal.is_none()));
+
+    // Views are ignored, so every entity keeps being sent
+    server.dispatch(ClientMsg::SetView(View::new((0, 0, 0), (1, 1, 0))), 3);
+    assert_eq!(server.players[&3].view, None);
+
+    // Nothing is handed on when a spectator leaves, and no one is handed to them
+    server.disconnect_policy = DisconnectPolicy::Reassign;
+    server.dispatch(ClientMsg::Leave(), 3);
+    assert_eq!(team_of(&server, 101), Some(1));
+    server.dispatch(ClientMsg::Leave(), 1);
+    assert_eq!(team_of(&server, 101), Some(2));
+}
+
+#[test]
+fn test_leave_releases() {
+    let (mut server, captured) = two_player_server();
+
+    server.dispatch(ClientMsg::Leave(), 1);
+    assert!(!server.players.contains_key(&1));
+    assert_eq!(team_of(&server, 101), None);
+    assert_eq!(team_of(&server, 102), Some(2));
+    assert!(!server.g_state.map.get_tile((10, 1, 0)).unwrap().marked);
+    assert!(server.g_state.map.get_tile((20, 1, 0)).unwrap().marked);
+
+    let sent = sent_to(&server, &captured, 2);
+    assert!(sent.contains(&ServerMsg::PlayerLeft(1, LeaveReason::Quit)));
+
+    // Leaving twice, such as a leave followed by the connection closing, does nothing
+    server.disconnect(1, LeaveReason::Dropped);
+    assert!(sent_to(&server, &captured, 2).is_empty());
+}
+
+#[test]
+fn test_dropped_player_reassigned() {
+    let (mut server, captured) = two_player_server();
+    server.disconnect_policy = DisconnectPolicy::Reassign;
+
+    // Dropped players are held onto for a while in case they come back
+    server.disconnect(2, LeaveReason::Dropped);
+    assert_eq!(server.players.keys().collect::<Vec<_>>(), vec![&1]);
+    assert!(server.dropped.contains_key(&2));
+    assert_eq!(team_of(&server, 102), Some(2));
+    let left = ServerMsg::PlayerLeft(2, LeaveReason::Dropped);
+    assert!(sent_to(&server, &captured, 1).contains(&left));
+
+    server.g_state.ticks += RESUME_PERIOD;
+    server.update();
+    assert!(server.dropped.is_empty());
+    assert_eq!(team_of(&server, 102), Some(1));
+    assert_eq!(server.players[&1].tasks.len(), 2);
+    assert!(server.g_state.map.get_tile((20, 1, 0)).unwrap().marked);
+
+    // With nobody left to take over, the last player's things are released
+    server.disconnect(1, LeaveReason::Quit);
+    assert!(server.players.is_empty());
+    assert_eq!(team_of(&server, 101), None);
+    assert_eq!(team_of(&server, 102), None);
+    assert!(!server.g_state.map.get_tile((20, 1, 0)).unwrap().marked);
+}
+
+/// The join reply sent to a player
+fn join_reply(server: &Server, captured: &Captured, player_id: PlayerID) -> PlayerJoin {
+    sent_to(server, captured, player_id).into_iter()
+                                        .filter_map(|msg| match msg {
+                                            ServerMsg::ReplyJoin(player_join) => Some(player_join),
+                                            _ => None,
+                                        })
+                                        .next()
+                                        .expect("No join reply")
+}
+
+#[test]
+fn test_resume() {
+    let (mut server, captured) = capture_server();
+    server.pending.insert(1);
+    server.dispatch(ClientMsg::Hello(Hello::new("urist")), 1);
+    let resume_token = join_reply(&server, &captured, 1).resume_token;
+    server.players.get_mut(&1).unwrap().tasks.push(Task::dig((5, 5, 0)));
+    // Long enough for the bandwidth to be measured
+    for _ in 0..60 {
+        server.update();
+    }
+
+    server.disconnect(1, LeaveReason::Dropped);
+    server.update();
+
+    // Coming back on a new connection gives the same player back, with a full resync
+    server.pending.insert(2);
+    server.dispatch(ClientMsg::Hello(Hello::resume("urist", resume_token)), 2);
+    assert!(server.dropped.is_empty() && server.pending.is_empty());
+    assert_eq!(server.players.keys().collect::<Vec<_>>(), vec![&1]);
+    assert_eq!(server.players[&1].tasks.len(), 1);
+
+    // Which arrives on the new connection
+    let player_join = join_reply(&server, &captured, 2);
+    assert_eq!(player_join.player_id, 1);
+    assert_eq!(player_join.team_id, Some(1));
+    assert_eq!(player_join.resume_token, resume_token);
+    server.update();
+    match sent_to(&server, &captured, 2).into_iter().find(|msg| match *msg {
+        ServerMsg::SendEnts(_) => true,
+        _ => false,
+    }) {
+        Some(ServerMsg::SendEnts(ent_update)) => assert!(ent_update.keyframe),
+        other => panic!("Expected the entities, got {:?}", other),
+    }
+
+    // The count of bytes sent starts over on the new connection
+    for _ in 0..60 {
+        server.update();
+    }
+    assert!(server.players[&1].bandwidth > 0);
+
+    // A token nobody has joins afresh
+    server.pending.insert(3);
+    server.dispatch(ClientMsg::Hello(Hello::resume("urist", resume_token + 1)), 3);
+    assert!(server.players.contains_key(&3));
+    assert!(join_reply(&server, &captured, 3).resume_token != resume_token);
+}
+
+#[test]
+fn test_resume_before_drop_noticed() {
+    let (mut server, captured) = capture_server();
+    server.pending.insert(1);
+    server.dispatch(ClientMsg::Hello(Hello::new("urist")), 1);
+    let resume_token = join_reply(&server, &captured, 1).resume_token;
+
+    server.pending.insert(2);
+    server.dispatch(ClientMsg::Hello(Hello::resume("urist", resume_token)), 2);
+    assert_eq!(server.players.keys().collect::<Vec<_>>(), vec![&1]);
+    assert_eq!(join_reply(&server, &captured, 2).player_id, 1);
+}
+
+#[test]
+fn test_heartbeats() {
+    let (mut server, captured) = capture_server();
+    server.pending.insert(1);
+    server.dispatch(ClientMsg::Hello(Hello::new("urist")), 1);
+    clear_sent(&server, &captured);
+
+    server.heartbeats();
+    let stamp = sent_to(&server, &captured, 1).into_iter()
+                                          .filter_map(|msg| match msg {
+                                              ServerMsg::Heartbeat(stamp) => Some(stamp),
+                                              _ => None,
+                                          })
+                                          .next()
+                                          .expect("No heartbeat");
+    assert_eq!(server.players[&1].rtt, None);
+    server.dispatch(ClientMsg::Ack(stamp), 1);
+    assert!(server.players[&1].rtt.is_some());
+
+    // Heartbeats from the client are answered in kind, and ours are only
+    // sent once a period
+    server.heartbeats();
+    server.dispatch(ClientMsg::Heartbeat(1234), 1);
+    assert_eq!(sent_to(&server, &captured, 1), vec![ServerMsg::Ack(1234)]);
+}
+
+#[test]
+fn test_timeout() {
+    let (mut server, _captured) = capture_server();
+    for player_id in 1..3 {
+        server.pending.insert(player_id);
+        server.dispatch(ClientMsg::Hello(Hello::new("urist")), player_id);
+    }
+
+    // Only the clock counts, not how many ticks have run
+    let quiet = Instant::now() - Duration::from_millis(TIMEOUT_MS);
+    for player in server.players.values_mut() {
+        player.last_heard = Some(quiet);
+    }
+    server.dispatch(ClientMsg::Heartbeat(0), 2);
+    server.heartbeats();
+
+    // Only the quiet player is dropped, and can still come back
+    assert!(server.dropped.contains_key(&1));
+    assert!(server.players.contains_key(&2));
+}
@@ -0,0 +1,243 @@
+extern crate spacefort;
+
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use spacefort::*;
+use defs::loader::DefError;
+use defs::packs::{BASE_PACK, DataPack, PackInfo, find_file, load_packs, merge_tables};
+use defs::packs::{fnv1a_hash, pack_mismatch};
+use entities::creatures::{ProtoCreature, load_creatures};
+use objects::items::load_items;
+
+
+fn root() -> &'static Path {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+}
+
+fn base_pack() -> DataPack {
+    DataPack {
+        name: BASE_PACK.to_owned(),
+        dir: root().join("static"),
+    }
+}
+
+/// Make a pack in a temporary folder out of (path, contents) pairs
+fn write_pack(name: &str, files: &[(&str, &str)]) -> DataPack {
+    let dir = env::temp_dir().join("spacefort_packs").join(name);
+    let _ = fs::remove_dir_all(&dir);
+
+    for &(rel_path, contents) in files {
+        let path = dir.join(rel_path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let mut file = File::create(&path).unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+    }
+
+    DataPack {
+        name: name.to_owned(),
+        dir: dir,
+    }
+}
+
+fn temp_root(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join("spacefort_roots").join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_base_pack_by_default() {
+    let packs = load_packs(root()).unwrap();
+    assert_eq!(packs, vec![base_pack()]);
+}
+
+#[test]
+fn test_later_packs_override() {
+    let heavy_stone = write_pack("heavy_stone", &[
+        ("inc/items/items.toml", "
+[[items]]
+name = \"stone_chunk\"
+template = \"default\"
+id = 1
+color = [0.44, 0.49, 0.57, 1.0]
+weight = 50
+
+[[items]]
+name = \"iron_ore\"
+template = \"stone_chunk\"
+id = 40
+"),
+    ]);
+
+    let (base_items, conflicts) = load_items(&[base_pack()]).unwrap();
+    assert!(conflicts.is_empty());
+    let (items, conflicts) = load_items(&[base_pack(), heavy_stone.clone()]).unwrap();
+    assert_eq!(items.len(), base_items.len() + 1);
+    assert_eq!(conflicts.len(), 1);
+    assert_eq!(conflicts[0].entry, "stone_chunk");
+    assert_eq!(items[&1].weight, 50);
+    // Templates can come from an earlier pack, and see the overridden entry
+    assert_eq!(items[&40].weight, 50);
+    assert_eq!(items[&2], base_items[&2]);
+
+    // Reversing the order lets the base pack win
+    let (items, _) = load_items(&[heavy_stone, base_pack()]).unwrap();
+    assert_eq!(items[&1].weight, base_items[&1].weight);
+}
+
+#[test]
+fn test_item_textures() {
+    let crates = write_pack("crates", &[
+        ("inc/items/items.toml", "
+[[items]]
+name = \"crate\"
+id = 50
+texture = \"crate.png\"
+color = [0.5, 0.3, 0.1, 1.0]
+weight = 10
+
+[[items]]
+name = \"small_crate\"
+template = \"crate\"
+id = 51
+"),
+    ]);
+
+    // The texture of an item is kept, and passed on to those templated on it
+    let (items, _) = load_items(&[crates]).unwrap();
+    assert_eq!(items[&50].texture, Some("crate.png".to_owned()));
+    assert_eq!(items[&51].texture, Some("crate.png".to_owned()));
+}
+
+#[test]
+fn test_conflicts_reported() {
+    let big_trolls = write_pack("big_trolls", &[
+        ("inc/creatures/creatures.toml", "
+[[creatures]]
+name = \"big_troll\"
+template = \"humanoid\"
+id = 3
+health = 500
+"),
+    ]);
+
+    let packs = [base_pack(), big_trolls];
+    let (protos, conflicts) = merge_tables::<ProtoCreature>(&packs, "inc/creatures/creatures.toml",
+                                                            "creatures").unwrap();
+    assert_eq!(conflicts.len(), 1);
+    assert_eq!(conflicts[0].pack, "big_trolls");
+    assert_eq!(conflicts[0].entry, "big_troll");
+    assert_eq!(conflicts[0].overridden, BASE_PACK);
+    assert!(protos.iter().any(|proto| proto.name == "big_troll"));
+    assert!(!protos.iter().any(|proto| proto.name == "troll"));
+
+    // Packs without attacks still pick them up from the base pack
+    let (creatures, conflicts) = load_creatures(&packs).unwrap();
+    assert_eq!(conflicts.len(), 1);
+    assert_eq!(creatures[&3].health, 500);
+    assert_eq!(creatures[&3].attacks, creatures[&100].attacks);
+    assert!(!creatures[&3].attacks.is_empty());
+}
+
+#[test]
+fn test_duplicate_within_pack() {
+    let twins = write_pack("twins", &[
+        ("inc/items/items.toml", "
+[[items]]
+name = \"left\"
+template = \"default\"
+id = 50
+
+[[items]]
+name = \"right\"
+template = \"default\"
+id = 50
+"),
+    ]);
+
+    match load_items(&[base_pack(), twins]) {
+        Err(DefError::DuplicateID { entry, .. }) => assert_eq!(entry, "right"),
+        other => panic!("Expected a duplicate id, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_find_file() {
+    let map_pack = write_pack("map_pack", &[("inc/maps/smol_map_excel.sfm.csv", "")]);
+    let packs = [base_pack(), map_pack.clone()];
+
+    assert_eq!(find_file(&packs, "inc/maps/smol_map_excel.sfm.csv"),
+               Some(map_pack.dir.join("inc/maps/smol_map_excel.sfm.csv")));
+    assert_eq!(find_file(&packs, "inc/textures/materials/grass.png"),
+               Some(base_pack().dir.join("inc/textures/materials/grass.png")));
+    assert_eq!(find_file(&packs, "inc/maps/nowhere.sfm"), None);
+}
+
+#[test]
+fn test_pack_list() {
+    let root = temp_root("pack_list");
+    fs::create_dir_all(root.join("static")).unwrap();
+    fs::create_dir_all(root.join("mods/more_trolls")).unwrap();
+
+    File::create(root.join("packs.toml")).unwrap()
+        .write_all(b"packs = [\"base\", \"more_trolls\"]").unwrap();
+    let packs = load_packs(&root).unwrap();
+    assert_eq!(packs.iter().map(|pack| pack.name.as_str()).collect::<Vec<_>>(),
+               vec!["base", "more_trolls"]);
+    assert_eq!(packs[1].dir, root.join("mods/more_trolls"));
+
+    File::create(root.join("packs.toml")).unwrap()
+        .write_all(b"packs = [\"base\", \"fewer_trolls\"]").unwrap();
+    match load_packs(&root) {
+        Err(DefError::MissingPack(name)) => assert_eq!(name, "fewer_trolls"),
+        other => panic!("Expected a missing pack, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_pack_hashes() {
+    let pack1 = write_pack("hash1", &[("inc/textures/grass.png", "green")]);
+    let pack2 = write_pack("hash2", &[("inc/textures/grass.png", "green")]);
+    let moved = write_pack("hash3", &[("inc/textures/lawn.png", "green")]);
+    let changed = write_pack("hash4", &[("inc/textures/grass.png", "green\n")]);
+
+    assert_eq!(pack1.texture_hash().unwrap(), pack2.texture_hash().unwrap());
+    assert!(pack1.texture_hash().unwrap() != moved.texture_hash().unwrap());
+    assert!(pack1.texture_hash().unwrap() != changed.texture_hash().unwrap());
+}
+
+#[test]
+fn test_texture_hashes() {
+    let plain = write_pack("textures1", &[("inc/textures/grass.png", "green")]);
+    let with_items = write_pack("textures2", &[("inc/textures/grass.png", "green"),
+                                               ("inc/items/items.toml", "[[items]]")]);
+    let repainted = write_pack("textures3", &[("inc/textures/grass.png", "blue")]);
+    let bare = write_pack("textures4", &[("inc/items/items.toml", "[[items]]")]);
+
+    // Only the textures count, as clients get their definitions from the server
+    assert_eq!(plain.info().unwrap().hash, with_items.info().unwrap().hash);
+    assert!(plain.info().unwrap().hash != repainted.info().unwrap().hash);
+    assert!(bare.info().is_ok());
+
+    // The same on every platform, with the path in the pack joined by slashes
+    assert_eq!(plain.texture_hash().unwrap(), fnv1a_hash(b"inc/textures/grass.pnggreen"));
+}
+
+#[test]
+fn test_pack_mismatch() {
+    let base = PackInfo { name: BASE_PACK.to_owned(), hash: 1 };
+    let extra = PackInfo { name: "extra".to_owned(), hash: 2 };
+    let changed_base = PackInfo { name: BASE_PACK.to_owned(), hash: 3 };
+
+    assert_eq!(pack_mismatch(&[base.clone(), extra.clone()], &[base.clone(), extra.clone()]),
+               None);
+    assert!(pack_mismatch(&[base.clone()], &[base.clone(), extra.clone()]).is_some());
+    assert!(pack_mismatch(&[base.clone(), extra.clone()], &[base.clone()]).is_some());
+    assert!(pack_mismatch(&[changed_base], &[base.clone()]).is_some());
+    assert!(pack_mismatch(&[extra.clone(), base.clone()], &[base, extra]).is_some());
+}
@@ -0,0 +1,144 @@
+extern crate spacefort;
+
+mod common;
+
+// Std lib imports
+use std::env;
+use std::io::ErrorKind;
+
+// Local imports
+use spacefort::*;
+use common::{open_map, server_on};
+use entities::entity::{Entity, EntID};
+use game::lockstep::Lockstep;
+use game::replay::{ReplayEvent, ReplayRunner, load_replay};
+use game::save::{SaveGame, save_game};
+use game::server::Server;
+use net::base::{ClientMsg, Hello};
+
+
+/// A server with a unit for each of two players, the first of whom has joined
+fn test_server() -> Server {
+    let mut server = server_on(open_map());
+    for player_id in 1..3 {
+        let ent = Entity::new(100 + player_id as EntID, 1, (player_id as i32 * 10, 1, 0),
+                              Some(player_id), &server.g_state.creature_types);
+        server.g_state.entities.push(ent);
+    }
+    server.pending.insert(1);
+    server.dispatch(ClientMsg::Hello(Hello::new("urist")), 1);
+
+    server
+}
+
+#[test]
+fn test_replay_reproduces_game() {
+    let path = env::temp_dir().join("spacefort_test_replay.sfr");
+    let mut server = test_server();
+
+    // Player 2 is part way through connecting when the recording starts
+    server.pending.insert(2);
+    server.start_recording(&path).unwrap();
+    server.dispatch(ClientMsg::Hello(Hello::new("cog")), 2);
+
+    server.dispatch(ClientMsg::EntMove(101, (40, 5, 0)), 1);
+    server.dispatch(ClientMsg::MarkStockpile(((30, 1, 0), (31, 2, 0)), Vec::new()), 2);
+    for _ in 0..30 {
+        server.update();
+    }
+    server.dispatch(ClientMsg::EntAttack(101, 102), 1);
+    for _ in 0..30 {
+        server.update();
+    }
+    server.dispatch(ClientMsg::Leave(), 2);
+    for _ in 0..10 {
+        server.update();
+    }
+
+    let replay = load_replay(&path).unwrap();
+    assert_eq!(replay.start.ticks, 0);
+    assert_eq!(replay.events[0], (0, ReplayEvent::Connected(2)));
+    assert_eq!(replay.events.last(), Some(&(60, ReplayEvent::Message(2, ClientMsg::Leave()))));
+
+    let mut runner = ReplayRunner::new(replay);
+    while runner.g_state().ticks < server.g_state.ticks {
+        runner.step();
+    }
+    assert!(runner.done());
+    assert_eq!(runner.g_state().checksum(), server.g_state.checksum());
+    assert_eq!(SaveGame::new(runner.g_state(), &runner.server.players),
+               SaveGame::new(&server.g_state, &server.players));
+}
+
+#[test]
+fn test_replay_reproduces_lockstep() {
+    let path = env::temp_dir().join("spacefort_test_lockstep_replay.sfr");
+    let mut server = test_server();
+    server.lockstep = Some(Lockstep::new());
+    server.pending.insert(2);
+    server.dispatch(ClientMsg::Hello(Hello::new("cog")), 2);
+    server.start_recording(&path).unwrap();
+
+    server.dispatch(ClientMsg::Turn(1, vec![ClientMsg::EntMove(101, (40, 5, 0))]), 1);
+    server.dispatch(ClientMsg::Turn(1, Vec::new()), 2);
+    server.update();
+    for tick in 2..20 {
+        server.dispatch(ClientMsg::Turn(tick, Vec::new()), 1);
+        server.dispatch(ClientMsg::Turn(tick, Vec::new()), 2);
+        server.update();
+    }
+    // A tick run without one player's turn is run without it again
+    server.dispatch(ClientMsg::Turn(20, vec![ClientMsg::EntAttack(101, 102)]), 1);
+    while server.g_state.ticks < 20 {
+        server.update();
+    }
+    assert_eq!(server.g_state.ticks, 20);
+
+    let replay = load_replay(&path).unwrap();
+    assert!(replay.lockstep);
+
+    let mut runner = ReplayRunner::new(replay);
+    while runner.g_state().ticks < server.g_state.ticks {
+        runner.step();
+    }
+    assert!(runner.done());
+    assert_eq!(runner.g_state().checksum(), server.g_state.checksum());
+    assert_eq!(SaveGame::new(runner.g_state(), &runner.server.players),
+               SaveGame::new(&server.g_state, &server.players));
+}
+
+#[test]
+fn test_replay_resumes_players() {
+    let path = env::temp_dir().join("spacefort_test_resume_replay.sfr");
+    let mut server = test_server();
+
+    // Player 1 comes back on another connection, with the token they had
+    // before the recording started
+    server.pending.insert(3);
+    server.start_recording(&path).unwrap();
+    let resume_token = server.players[&1].resume_token;
+    server.dispatch(ClientMsg::Hello(Hello::resume("urist", resume_token)), 3);
+    server.dispatch(ClientMsg::EntMove(101, (40, 5, 0)), 3);
+    for _ in 0..30 {
+        server.update();
+    }
+    assert_eq!(server.players.keys().collect::<Vec<_>>(), vec![&1]);
+
+    let mut runner = ReplayRunner::new(load_replay(&path).unwrap());
+    assert_eq!(runner.server.players[&1].name, "urist");
+    while runner.g_state().ticks < server.g_state.ticks {
+        runner.step();
+    }
+    assert_eq!(runner.server.players.keys().collect::<Vec<_>>(), vec![&1]);
+    assert_eq!(runner.g_state().checksum(), server.g_state.checksum());
+}
+
+#[test]
+fn test_replay_rejects_other_files() {
+    let path = env::temp_dir().join("spacefort_test_not_replay.sav");
+    let server = test_server();
+    save_game(&path, &SaveGame::new(&server.g_state, &server.players)).unwrap();
+
+    let err = load_replay(&path).err().expect("Loaded a save as a replay");
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
@@ -0,0 +1,159 @@
+extern crate spacefort;
+
+// Std lib imports
+use std::net::{Ipv4Addr, TcpListener};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Local imports
+use spacefort::*;
+use defs::packs::PackInfos;
+use entities::entity::init_entities;
+use game::server::Server;
+use map::tiles::{Map, blank_map};
+use net::base::{BootReason, LeaveReason, MAX_MSG_SIZE, PlayerJoin, ServerMsg};
+use net::client::{NetComm, init_network, init_network_over};
+use net::server::init_local_network;
+use net::transport::MemoryTransport;
+use objects::{buildings, items};
+
+
+fn root() -> &'static Path {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+}
+
+/// A server that clients join in memory, through the transport
+fn local_server() -> (Server, MemoryTransport) {
+    let (comm, transport) = init_local_network();
+    let (entities, creature_types) = init_entities(root());
+    let map = Map::new((90, 10, 1), blank_map(root()).materials);
+    let server = Server::new(map, entities, creature_types, items::init_items(root()),
+                             buildings::init_buildings(root()), comm);
+
+    (server, transport)
+}
+
+/// Run the server until a message it sends the client matches, returning
+/// everything the client got along the way
+fn run_until<F>(server: &mut Server, client: &mut NetComm, found: F) -> Vec<ServerMsg>
+    where F: Fn(&ServerMsg) -> bool {
+    let started = Instant::now();
+    let mut msgs = Vec::new();
+    while started.elapsed() < Duration::from_secs(5) {
+        server.update();
+        while let Some(msg) = client.get_incoming_msgs() {
+            msgs.push(msg);
+        }
+        if msgs.iter().any(&found) {
+            return msgs;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    panic!("Gave up waiting on the server, which sent {:?}", msgs);
+}
+
+/// Say hello and wait to be let in
+fn join(server: &mut Server, client: &mut NetComm) -> PlayerJoin {
+    client.hello("urist", &PackInfos::new());
+    let msgs = run_until(server, client, |msg| match *msg {
+        ServerMsg::ReplyJoin(_) => true,
+        _ => false,
+    });
+    msgs.into_iter().filter_map(|msg| match msg {
+        ServerMsg::ReplyJoin(join) => Some(join),
+        _ => None,
+    }).next().unwrap()
+}
+
+#[test]
+fn test_join_in_memory() {
+    let (mut server, transport) = local_server();
+    let mut first = init_network_over(Box::new(transport.clone())).unwrap();
+    let mut second = init_network_over(Box::new(transport)).unwrap();
+
+    let first_join = join(&mut server, &mut first);
+    let second_join = join(&mut server, &mut second);
+    assert!(first_join.player_id != second_join.player_id);
+    assert!(server.players.contains_key(&first_join.player_id));
+    assert!(server.players.contains_key(&second_join.player_id));
+
+    // The others hear about a player leaving
+    first.leave();
+    let left = ServerMsg::PlayerLeft(first_join.player_id, LeaveReason::Quit);
+    run_until(&mut server, &mut second, |msg| *msg == left);
+    assert!(!server.players.contains_key(&first_join.player_id));
+}
+
+#[test]
+fn test_client_gone_in_memory() {
+    let (mut server, transport) = local_server();
+    let mut client = init_network_over(Box::new(transport)).unwrap();
+    let player_id = join(&mut server, &mut client).player_id;
+
+    // Dropping the client is the same as its connection dropping
+    drop(client);
+    let started = Instant::now();
+    while !server.dropped.contains_key(&player_id) {
+        assert!(started.elapsed() < Duration::from_secs(5), "Drop never noticed");
+        server.update();
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn test_booted_in_memory() {
+    let (mut server, transport) = local_server();
+    let mut client = init_network_over(Box::new(transport)).unwrap();
+
+    // Anything but a hello first gets the client booted and hung up on
+    client.heartbeat();
+    run_until(&mut server, &mut client, |msg| *msg == ServerMsg::Boot(BootReason::NoHello));
+    let started = Instant::now();
+    while client.connected() {
+        assert!(started.elapsed() < Duration::from_secs(5), "Never hung up on");
+        client.get_incoming_msgs();
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn test_reconnect_in_memory() {
+    let (mut server, transport) = local_server();
+    let mut client = init_network_over(Box::new(transport)).unwrap();
+    let first_join = join(&mut server, &mut client);
+
+    // Reconnecting hangs up on the old connection, which the server sees drop
+    // while the new one is still being opened
+    assert_eq!(client.reconnect(), None);
+    assert!(!client.connected());
+    let started = Instant::now();
+    let reconnected = loop {
+        assert!(started.elapsed() < Duration::from_secs(10), "Never reconnected");
+        server.update();
+        if let Some(reconnected) = client.reconnect() {
+            break reconnected;
+        }
+        thread::sleep(Duration::from_millis(1));
+    };
+    assert!(reconnected);
+    assert!(server.dropped.contains_key(&first_join.player_id));
+
+    // And takes the player back on the new one
+    client.resume("urist", first_join.resume_token, &PackInfos::new());
+    let msgs = run_until(&mut server, &mut client, |msg| match *msg {
+        ServerMsg::ReplyJoin(_) => true,
+        _ => false,
+    });
+    assert!(msgs.iter().any(|msg| match *msg {
+        ServerMsg::ReplyJoin(ref join) => join.player_id == first_join.player_id,
+        _ => false,
+    }));
+}
+
+#[test]
+fn test_no_server_to_connect_to() {
+    // A port that was free a moment ago, which nothing is listening on now
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    assert!(init_network(Ipv4Addr::new(127, 0, 0, 1), port, MAX_MSG_SIZE).is_err());
+}
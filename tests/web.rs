@@ -0,0 +1,190 @@
+extern crate spacefort;
+
+// Std lib imports
+use std::collections::HashMap;
+use std::io::{Cursor, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::mpsc::{channel, sync_channel};
+use std::thread;
+use std::time::Duration;
+
+// Local imports
+use spacefort::*;
+use defs::packs::init_packs;
+use entities::entity::{Entities, init_entities};
+use game::base::GameState;
+use game::server::ServerPlayer;
+use game::save::SaveGame;
+use map::tiles::blank_map;
+use net::base::{ClientMsg, Conn, ConnEvent, FrameCodec, FrameError, Hello, JSON_SAFE_MAX};
+use net::base::{ServerMsg, Stamp, Wire};
+use net::server::{handle_web_client, init_local_network};
+use net::web;
+use net::web::{Encoding, WebCodec};
+use objects::{buildings, items};
+
+
+fn root() -> &'static Path {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+}
+
+/// Send messages over a loopback WebSocket, with the server end acking each
+/// one with a stamp, and return what the server read
+fn echo(encoding: Encoding, msgs: Vec<ClientMsg>, stamp: Stamp) -> Vec<ClientMsg> {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let count = msgs.len();
+
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let agreed = web::accept(&mut stream).unwrap();
+        let codec = WebCodec::server(agreed);
+        let mut received = Vec::new();
+        for _ in 0..count {
+            let msg: ClientMsg = codec.read(&mut stream).unwrap();
+            codec.write(&mut stream, &ServerMsg::Ack(stamp)).unwrap();
+            received.push(msg);
+        }
+        (agreed, received)
+    });
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    web::connect(&mut stream, &addr.to_string(), encoding).unwrap();
+    let codec = WebCodec::client(encoding);
+    for msg in msgs {
+        codec.write(&mut stream, &msg).unwrap();
+        let answer: ServerMsg = codec.read(&mut stream).unwrap();
+        assert_eq!(answer, ServerMsg::Ack(stamp));
+    }
+
+    let (agreed, received) = server.join().unwrap();
+    assert_eq!(agreed, encoding);
+    received
+}
+
+#[test]
+fn test_websocket_round_trip() {
+    let msgs = vec![ClientMsg::Hello(Hello::new("urist")),
+                    ClientMsg::MarkStockpile(((30, 1, 0), (31, 2, 0)), vec![1, 2]),
+                    ClientMsg::EntMove(101, (40, 5, 0)),
+                    ClientMsg::Leave()];
+    assert_eq!(echo(Encoding::Json, msgs.clone(), 77), msgs);
+    assert_eq!(echo(Encoding::Bincode, msgs.clone(), 78), msgs);
+}
+
+#[test]
+fn test_json_carries_whole_game() {
+    // The definitions and the save are full of maps keyed by number, which
+    // JSON only has strings for
+    let (_, creature_types) = init_entities(root());
+    let g_state = GameState::new(blank_map(root()), Entities::new(), creature_types,
+                                 items::init_items(root()), buildings::init_buildings(root()));
+    let mut players = HashMap::new();
+    players.insert(1, ServerPlayer::new(1, Some(1)));
+    let save = ServerMsg::SendSave(SaveGame::new(&g_state, &players));
+    let defs = ServerMsg::SendDefs(g_state.defs());
+
+    let codec = WebCodec::server(Encoding::Json);
+    for msg in vec![save, defs] {
+        let mut written = Vec::new();
+        codec.write(&mut written, &msg).unwrap();
+        let mut stream = Cursor::new(written);
+        let read: ServerMsg = codec.read(&mut stream).unwrap();
+        assert_eq!(read, msg);
+    }
+}
+
+#[test]
+fn test_json_numbers_fit_browsers() {
+    // Browsers read every JSON number as a double, which drops anything past 53 bits
+    let survives = |value: u64| value <= JSON_SAFE_MAX && value as f64 as u64 == value;
+    for player_id in 1..100 {
+        assert!(survives(ServerPlayer::new(player_id, Some(1)).resume_token));
+    }
+
+    let (_, creature_types) = init_entities(root());
+    let g_state = GameState::new(blank_map(root()), Entities::new(), creature_types,
+                                 items::init_items(root()), buildings::init_buildings(root()));
+    assert!(survives(g_state.checksum()));
+    for pack in init_packs(root()) {
+        assert!(survives(pack.info().unwrap().hash));
+    }
+}
+
+#[test]
+fn test_rejects_plain_http() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        web::accept(&mut stream)
+    });
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    match server.join().unwrap() {
+        Err(FrameError::Io(_)) => {},
+        result => panic!("Expected the handshake to fail, got {:?}", result),
+    }
+}
+
+#[test]
+fn test_pings_answered_by_outgoing_thread() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+
+    let (send_incoming, recv_incoming) = sync_channel(16);
+    let (send_conn_to_game, _recv_conn_to_game) = channel();
+    let (send_conn_to_net_out, recv_conn_to_net_out) = channel();
+    let reader = thread::spawn(move || {
+        handle_web_client(stream, WebCodec::server(Encoding::Json), send_incoming,
+                          send_conn_to_game, send_conn_to_net_out, 1);
+    });
+
+    // A masked ping with a payload of "hi", then a message after it
+    client.write_all(&[0x89, 0x82, 0, 0, 0, 0, b'h', b'i']).unwrap();
+    WebCodec::client(Encoding::Json).write(&mut client, &ClientMsg::Leave()).unwrap();
+    assert_eq!(recv_incoming.recv().unwrap(), (ClientMsg::Leave(), 1));
+
+    // The pong is handed over to be written, rather than written from here
+    match recv_conn_to_net_out.recv().unwrap() {
+        ConnEvent::Answer(_, answer) => assert_eq!(answer, vec![0x8A, 2, b'h', b'i']),
+        event => panic!("Expected a pong to write, got {:?}", event),
+    }
+    client.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+    assert!(client.peek(&mut [0u8; 1]).is_err());
+
+    client.shutdown(Shutdown::Both).unwrap();
+    reader.join().unwrap();
+}
+
+#[test]
+fn test_bytes_sent_in_own_encoding() {
+    // Frames of every length are sized as they are written
+    for &encoding in &[Encoding::Json, Encoding::Bincode] {
+        let codec = WebCodec::server(encoding);
+        for &commands in &[0, 50, 3000] {
+            let turn = vec![(1, ClientMsg::Hello(Hello::new("urist"))); commands];
+            let msg = ServerMsg::Turn(1, turn);
+            let mut written = Vec::new();
+            codec.write(&mut written, &msg).unwrap();
+            assert_eq!(codec.frame_size(&msg).unwrap(), written.len() as u64);
+        }
+    }
+
+    // A browser reading JSON is counted in JSON
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let _server_end = listener.accept().unwrap();
+    let (mut comm, _) = init_local_network();
+    comm.setup_out_stream((Conn::Socket(stream, Wire::WebSocket(Encoding::Json)), 1));
+    comm.heartbeat(1, 1234);
+
+    let msg = ServerMsg::Heartbeat(1234);
+    let json_size = WebCodec::server(Encoding::Json).frame_size(&msg).unwrap();
+    assert_eq!(comm.bytes_sent(1), json_size);
+    assert!(json_size != FrameCodec::default().frame_size(&msg));
+}